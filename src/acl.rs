@@ -0,0 +1,109 @@
+//! 每个用户能碰哪些key。按key的前缀配，一个key看最长的那个对得上的前缀，一个都对不上的不能碰
+//!
+//! 只有登录了的连接才有用户，所以要和 `KvsServer::authenticated` 一起用
+
+use crate::KvsError;
+use crate::Result;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use std::collections::HashMap;
+use std::path::Path;
+
+/// 从小到大排好的，`ReadWrite` 能做 `Read` 能做的所有事
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Access {
+    /// 用来在放开的前缀里面再挖掉一块，比如 `app1:` 能读写但是 `app1:secret:` 不行
+    Deny,
+    Read,
+    ReadWrite,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Acl {
+    /// 每个用户的前缀和权限
+    rules: HashMap<String, Vec<(Vec<u8>, Access)>>,
+}
+
+impl Acl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `prefix` 后面的 `*` 可写可不写，`*` 或者空的前缀是所有key。同一个用户同一个前缀再设一次会覆盖
+    pub fn allow(&mut self, user: &str, prefix: &str, access: Access) {
+        let prefix = prefix
+            .strip_suffix('*')
+            .unwrap_or(prefix)
+            .as_bytes()
+            .to_vec();
+        let rules = self.rules.entry(user.to_string()).or_default();
+        rules.retain(|(v, _)| *v != prefix);
+        rules.push((prefix, access));
+    }
+
+    /// 一行一条 `user prefix access` ，`access` 是 `read` 、`readwrite` 或者 `none` 。空行和 `#` 开头的行不管
+    ///
+    /// ```text
+    /// alice config:* read
+    /// alice app1:* readwrite
+    /// admin * readwrite
+    /// ```
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|error| KvsError::File {
+            path: path.to_path_buf(),
+            offset: None,
+            error,
+        })?;
+        let mut acl = Self::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let access = match fields.get(2).copied() {
+                Some("read") => Some(Access::Read),
+                Some("readwrite") => Some(Access::ReadWrite),
+                Some("none") => Some(Access::Deny),
+                _ => None,
+            };
+            match (&fields[..], access) {
+                ([user, prefix, _], Some(access)) => acl.allow(user, prefix, access),
+                _ => {
+                    return Err(KvsError::InvalidArgument {
+                        name: format!("{}:{}", path.display(), i + 1),
+                        value: "expected user prefix read|readwrite|none".to_string(),
+                    })
+                }
+            }
+        }
+        Ok(acl)
+    }
+
+    /// `user` 对 `key` 有什么权限
+    pub fn access(&self, user: &str, key: &[u8]) -> Access {
+        self.rules
+            .get(user)
+            .and_then(|rules| {
+                rules
+                    .iter()
+                    .filter(|(prefix, _)| key.starts_with(prefix))
+                    .max_by_key(|(prefix, _)| prefix.len())
+            })
+            .map_or(Access::Deny, |(_, access)| *access)
+    }
+
+    /// 对所有的key都至少有 `access` 。事先不知道会碰到哪些key的请求（比如按filter删）要这样才行
+    pub(crate) fn everywhere(&self, user: &str, access: Access) -> bool {
+        match self.rules.get(user) {
+            Some(rules) => {
+                rules.iter().any(|(prefix, _)| prefix.is_empty())
+                    && rules.iter().all(|(_, v)| *v >= access)
+            }
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,88 @@
+//! 目录里的 `.kvs` ：这个目录是哪个engine的、磁盘格式是第几版、最后一次是用什么选项打开的
+//!
+//! 以前里面只有 `kvs` 或者 `sled` 几个字，读到这种的就当是第0版，open的时候升级上来
+
+use crate::fs;
+use crate::fs::Context;
+use crate::KvsError;
+use crate::Result;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use std::path::Path;
+
+pub(crate) const FILE: &str = ".kvs";
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct Archive {
+    engine: String,
+    /// 磁盘格式的版本，只会往上涨
+    format: u32,
+    /// 只是记下来给人看的，下次open用的还是传进来的选项
+    #[serde(default)]
+    options: serde_json::Value,
+}
+
+fn read(root: &Path) -> Result<Option<Archive>> {
+    let path = root.join(FILE);
+    let content = match std::fs::read(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e).at(&path),
+    };
+    let archive = match serde_json::from_slice::<Archive>(&content[..]) {
+        Ok(archive) => archive,
+        Err(_) => Archive {
+            engine: String::from_utf8_lossy(&content[..]).trim().to_string(), // 老版本只有engine的名字
+            format: 0,
+            options: serde_json::Value::Null,
+        },
+    };
+    Ok(Some(archive))
+}
+
+/// 目录是哪个engine的，还没有 `.kvs` 的话是 `None`
+pub(crate) fn engine(root: &Path) -> Result<Option<String>> {
+    Ok(read(root)?.map(|archive| archive.engine))
+}
+
+/// 看看目录是不是 `engine` 的、格式是不是认识，返回磁盘格式的版本。还没有 `.kvs` 的新目录返回 `None`
+pub(crate) fn check(root: &Path, engine: &str, current: u32) -> Result<Option<u32>> {
+    let archive = match read(root)? {
+        Some(archive) => archive,
+        None => return Ok(None),
+    };
+    if archive.engine != engine {
+        // 发现当前目录存了其他engine的记录
+        return Err(KvsError::BadArchive {
+            path: root.to_path_buf(),
+            should: archive.engine,
+            tried: engine.to_string(),
+        });
+    }
+    if archive.format > current {
+        // 新版本写的，老版本不知道怎么读
+        return Err(KvsError::UnsupportedFormat {
+            path: root.to_path_buf(),
+            found: archive.format,
+            supported: current,
+        });
+    }
+    Ok(Some(archive.format))
+}
+
+/// 升级完了、或者新目录，写上现在的版本和这次打开用的选项
+pub(crate) fn write<O: Serialize>(
+    root: &Path,
+    engine: &str,
+    format: u32,
+    options: &O,
+) -> Result<()> {
+    let archive = Archive {
+        engine: engine.to_string(),
+        format,
+        options: serde_json::to_value(options)?,
+    };
+    fs::write_atomic(&root.join(FILE), &serde_json::to_vec_pretty(&archive)?[..])
+}
@@ -0,0 +1,144 @@
+//! 异步的engine接口，给以后用tokio写的服务器和客户端用
+//!
+//! 不想为了这个拉一整个tokio进来。同步的engine是扔到自己开的几个线程上跑的，future只是等结果，不会卡住executor的线程，用哪个executor都行
+
+use crate::KeyFilter;
+use crate::KvsEngine;
+use crate::KvsError;
+use crate::Result;
+use crate::SharedQueueThreadPool;
+use crate::ThreadPool;
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::task::Context;
+use std::task::Poll;
+use std::task::Waker;
+
+/// `KvsEngine` 的异步版本，只有最常用的几个操作
+///
+/// key和value都是owned的，future不借着参数，可以随便扔到别的task里去等
+pub trait AsyncKvsEngine: Clone + Send + 'static {
+    fn get(&self, key: String) -> impl Future<Output = Result<Option<String>>> + Send;
+
+    fn set(&self, key: String, value: String) -> impl Future<Output = Result<()>> + Send;
+
+    /// key不存在的话报 `NotFound`
+    fn remove(&self, key: String) -> impl Future<Output = Result<()>> + Send;
+
+    fn scan(&self, filter: KeyFilter)
+        -> impl Future<Output = Result<Vec<(String, String)>>> + Send;
+}
+
+/// 把同步的engine包成 `AsyncKvsEngine` ，`store.into_async(4)` 拿到
+///
+/// 调用的时候操作就已经扔给线程去跑了，不poll也会执行，future只是拿结果用的。clone出来的共用一组线程，最后一个clone没了线程才退出
+#[derive(Clone)]
+pub struct AsyncAdapter<E> {
+    engine: E,
+    pool: SharedQueueThreadPool,
+}
+
+impl<E: KvsEngine + Clone> AsyncAdapter<E> {
+    /// 开 `threads` 个线程专门跑同步的操作，0个当1个
+    pub fn new(engine: E, threads: usize) -> Self {
+        Self {
+            engine,
+            pool: SharedQueueThreadPool::new(threads).expect("unable to spawn threads"),
+        }
+    }
+
+    pub fn inner(&self) -> &E {
+        &self.engine
+    }
+
+    fn spawn<T, F>(&self, f: F) -> Pending<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&E) -> Result<T> + Send + 'static,
+    {
+        let shared = Arc::new(Mutex::new(Shared {
+            value: None,
+            closed: false,
+            waker: None,
+        }));
+        let complete = Complete {
+            shared: shared.clone(),
+        };
+        let engine = self.engine.clone();
+        self.pool.spawn(move || complete.send(f(&engine))); // engine panic了的话future会收到错误
+        Pending { shared }
+    }
+}
+
+impl<E: KvsEngine + Clone> AsyncKvsEngine for AsyncAdapter<E> {
+    fn get(&self, key: String) -> impl Future<Output = Result<Option<String>>> + Send {
+        self.spawn(move |engine| engine.get(&key[..]))
+    }
+
+    fn set(&self, key: String, value: String) -> impl Future<Output = Result<()>> + Send {
+        self.spawn(move |engine| engine.set(key, value))
+    }
+
+    fn remove(&self, key: String) -> impl Future<Output = Result<()>> + Send {
+        self.spawn(move |engine| engine.remove(&key[..]))
+    }
+
+    fn scan(
+        &self,
+        filter: KeyFilter,
+    ) -> impl Future<Output = Result<Vec<(String, String)>>> + Send {
+        self.spawn(move |engine| engine.scan_matching(&filter))
+    }
+}
+
+struct Shared<T> {
+    value: Option<Result<T>>,
+    /// 跑的那边已经结束了，没有 `value` 的话就是panic了
+    closed: bool,
+    waker: Option<Waker>,
+}
+
+/// 跑的那边拿着的，drop的时候叫醒等着的future
+struct Complete<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+impl<T> Complete<T> {
+    fn send(self, value: Result<T>) {
+        self.shared.lock().unwrap().value = Some(value);
+    }
+}
+
+impl<T> Drop for Complete<T> {
+    fn drop(&mut self) {
+        let mut shared = self.shared.lock().unwrap();
+        shared.closed = true;
+        if let Some(waker) = shared.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// 等着的那边
+struct Pending<T> {
+    shared: Arc<Mutex<Shared<T>>>,
+}
+
+impl<T> Future for Pending<T> {
+    type Output = Result<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut shared = self.shared.lock().unwrap();
+        if let Some(value) = shared.value.take() {
+            return Poll::Ready(value);
+        }
+        if shared.closed {
+            return Poll::Ready(Err(KvsError::Io(std::io::Error::other("engine panicked"))));
+        }
+        shared.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
@@ -0,0 +1,112 @@
+//! 审计日志：谁在什么时候改了哪个key。只记成功了的写，一行一条json，只往后追加
+//!
+//! 文件写到 `AuditConfig::max_bytes` 就换一个新的，旧的依次改名成 `path.1` 、`path.2` ……，`path.1` 是最近的
+
+use crate::fs;
+use crate::fs::Context;
+use crate::Result;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+
+/// `KvsServer::audited` 的配置
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AuditConfig {
+    pub path: PathBuf,
+    /// 连value一起记。value可能很大，也可能是不该出现在日志里的东西，一般不记
+    pub values: bool,
+    /// 一个文件超过这么多字节就换，`None` 是一直写在一个文件里
+    pub max_bytes: Option<u64>,
+    /// 换下来的旧文件最多留几个，更旧的删掉。0是换的时候直接删
+    pub keep: usize,
+}
+
+/// 审计日志里的一行
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AuditEntry {
+    /// 从UNIX epoch开始的毫秒数
+    pub timestamp: u64,
+    pub peer: String,
+    /// 登录的用户，服务器没要求登录的话是 `None`
+    pub user: Option<String>,
+    pub op: String,
+    /// 一次改好几个key的（bulk load、事务、按filter删）没有
+    pub key: Option<String>,
+    /// `AuditConfig::values` 的时候才记，请求里带着的value，删的话没有
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+}
+
+pub(crate) struct AuditLog {
+    config: AuditConfig,
+    file: File,
+    /// 现在这个文件有多大
+    size: u64,
+}
+
+impl AuditLog {
+    /// 文件已经有了的话接着往后写
+    pub(crate) fn open(config: AuditConfig) -> Result<Self> {
+        let file = append(&config)?;
+        let size = file.metadata().at(&config.path)?.len();
+        Ok(Self { config, file, size })
+    }
+
+    pub(crate) fn values(&self) -> bool {
+        self.config.values
+    }
+
+    /// 不fsync，机器崩了可能丢最后几条，和engine不设 `Durability::Always` 的时候一样
+    pub(crate) fn record(&mut self, entry: &AuditEntry) -> Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        if let Some(max) = self.config.max_bytes {
+            if self.size > 0 && self.size + line.len() as u64 > max {
+                self.rotate()?;
+            }
+        }
+        self.file.write_all(line.as_bytes()).at(&self.config.path)?; // 一次写一整行，进程崩了也不会留下半行
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        let path = &self.config.path;
+        let keep = self.config.keep;
+        if keep == 0 {
+            fs::remove(path)?;
+        } else {
+            fs::remove(&rotated(path, keep))?; // 最旧的
+            for i in (1..keep).rev() {
+                let from = rotated(path, i);
+                if from.exists() {
+                    fs::replace(&from, &rotated(path, i + 1))?;
+                }
+            }
+            fs::replace(path, &rotated(path, 1))?;
+        }
+        self.file = append(&self.config)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn append(config: &AuditConfig) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&config.path)
+        .at(&config.path)
+}
+
+/// 换下来的第 `i` 个，`audit.log` 的是 `audit.log.1`
+fn rotated(path: &std::path::Path, i: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{}", i));
+    PathBuf::from(name)
+}
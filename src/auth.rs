@@ -0,0 +1,123 @@
+//! 用户名和密码。服务器要求认证的话，连接上除了 `Hello` 和 `Ping` 以外的请求都要先 `Auth`
+//!
+//! 密码就是明文存的，文件的权限自己管好。线上要配合TLS用，不然密码也是明文在网上跑
+
+use crate::KvsError;
+use crate::Result;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+/// 只给了一个密码（ `--require-auth` ）的时候的用户名，和Redis一样
+pub const DEFAULT_USER: &str = "default";
+
+/// 客户端登录用的用户名和密码
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    pub user: String,
+    pub password: String,
+}
+
+impl Credentials {
+    pub fn new(user: &str, password: &str) -> Self {
+        Self {
+            user: user.to_string(),
+            password: password.to_string(),
+        }
+    }
+}
+
+// 打日志的时候别把密码打出来
+impl fmt::Debug for Credentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Credentials")
+            .field("user", &self.user)
+            .field("password", &"***")
+            .finish()
+    }
+}
+
+/// 服务器认识的用户
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Accounts {
+    passwords: HashMap<String, String>,
+}
+
+impl Accounts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 只有一个 `DEFAULT_USER`
+    pub fn with_password(password: &str) -> Self {
+        let mut accounts = Self::new();
+        accounts.add(Credentials::new(DEFAULT_USER, password));
+        accounts
+    }
+
+    /// 同名的用户会被替换掉
+    pub fn add(&mut self, credentials: Credentials) {
+        self.passwords
+            .insert(credentials.user, credentials.password);
+    }
+
+    /// 一行一个 `user:password` ，空行和 `#` 开头的行不管。密码里可以有冒号，用户名里不行
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|error| KvsError::File {
+            path: path.to_path_buf(),
+            offset: None,
+            error,
+        })?;
+        let mut accounts = Self::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once(':') {
+                Some((user, password)) if !user.is_empty() => {
+                    accounts.add(Credentials::new(user, password))
+                }
+                _ => {
+                    return Err(KvsError::InvalidArgument {
+                        name: format!("{}:{}", path.display(), i + 1),
+                        value: "expected user:password".to_string(),
+                    })
+                }
+            }
+        }
+        Ok(accounts)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.passwords.is_empty()
+    }
+
+    pub(crate) fn verify(&self, credentials: &Credentials) -> bool {
+        match self.passwords.get(&credentials.user) {
+            Some(password) => same(password.as_bytes(), credentials.password.as_bytes()),
+            None => false,
+        }
+    }
+}
+
+impl fmt::Debug for Accounts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut users: Vec<&String> = self.passwords.keys().collect();
+        users.sort();
+        f.debug_struct("Accounts").field("users", &users).finish()
+    }
+}
+
+/// 比较花的时间不看前面有几个字节对上了，别让人一个字节一个字节地猜
+fn same(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
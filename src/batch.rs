@@ -0,0 +1,158 @@
+use crate::bytes;
+use crate::KvsEngine;
+use crate::KvsError;
+use crate::Result;
+
+use std::collections::HashMap;
+
+/// batch里的一个写
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BatchOp {
+    Set(Vec<u8>, Vec<u8>),
+    Remove(Vec<u8>),
+}
+
+impl BatchOp {
+    pub fn key(&self) -> &[u8] {
+        match self {
+            BatchOp::Set(key, _) => key,
+            BatchOp::Remove(key) => key,
+        }
+    }
+}
+
+/// `apply` 里的一个操作，和 `KvsEngine` 上同名的方法一样
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+    Get(String),
+    Set(String, String),
+    Remove(String),
+}
+
+/// `apply` 里每个操作的结果，和 `ops` 一一对应
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OpResult {
+    Value(Option<String>), // `Get` 的结果
+    Done,                  // `Set` 和 `Remove`
+}
+
+/// `apply` 的默认实现，按顺序一个一个做，碰到错误就停，前面做了的不会撤回
+pub(crate) fn apply_each<E: KvsEngine + ?Sized>(engine: &E, ops: &[Op]) -> Result<Vec<OpResult>> {
+    let mut results = Vec::with_capacity(ops.len());
+    for op in ops {
+        results.push(match op {
+            Op::Get(key) => OpResult::Value(engine.get(key)?),
+            Op::Set(key, value) => {
+                engine.set(key.clone(), value.clone())?;
+                OpResult::Done
+            }
+            Op::Remove(key) => {
+                engine.remove(key)?;
+                OpResult::Done
+            }
+        });
+    }
+    Ok(results)
+}
+
+/// 全是写的话合成一个 `write_batch` ，能原子地写的engine就要么全写进去要么都没写。夹着 `Get` 的话还是一个一个做
+pub(crate) fn apply_as_batch<E: KvsEngine + ?Sized>(
+    engine: &E,
+    ops: &[Op],
+) -> Result<Vec<OpResult>> {
+    let mut writes = Vec::with_capacity(ops.len());
+    for op in ops {
+        match op {
+            Op::Get(_) => return apply_each(engine, ops),
+            Op::Set(key, value) => writes.push(BatchOp::Set(
+                key.as_bytes().to_vec(),
+                value.as_bytes().to_vec(),
+            )),
+            Op::Remove(key) => writes.push(BatchOp::Remove(key.as_bytes().to_vec())),
+        }
+    }
+    if !writes.is_empty() {
+        engine.write_batch(writes)?;
+    }
+    Ok(vec![OpResult::Done; ops.len()])
+}
+
+/// 攒一批set和remove，`commit` 的时候一起生效，要么全都生效，要么一个都不生效
+///
+/// 没commit就drop掉的话什么都不会发生
+pub struct WriteBatch<'a, E: KvsEngine> {
+    engine: &'a E,
+    ops: Vec<BatchOp>,
+}
+
+impl<'a, E: KvsEngine> WriteBatch<'a, E> {
+    pub(crate) fn new(engine: &'a E) -> Self {
+        Self {
+            engine,
+            ops: vec![],
+        }
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> &mut Self {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> &mut Self {
+        self.ops.push(BatchOp::Set(key, value));
+        self
+    }
+
+    pub fn remove(&mut self, key: &str) -> &mut Self {
+        self.remove_bytes(key.as_bytes())
+    }
+
+    pub fn remove_bytes(&mut self, key: &[u8]) -> &mut Self {
+        self.ops.push(BatchOp::Remove(key.to_vec()));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn commit(self) -> Result<()> {
+        if self.ops.is_empty() {
+            return Ok(());
+        }
+        self.engine.write_batch(self.ops)
+    }
+}
+
+/// 写之前先检查一遍：remove了一个不存在的key（算上batch前面的写）的话整个batch都不能写，报 `NotFound`
+///
+/// `exists` 问的是batch开始之前key在不在
+pub(crate) fn validate<F>(ops: &[BatchOp], mut exists: F) -> Result<()>
+where
+    F: FnMut(&[u8]) -> Result<bool>,
+{
+    let mut staged: HashMap<&[u8], bool> = HashMap::new();
+    for op in ops {
+        match op {
+            BatchOp::Set(key, _) => {
+                staged.insert(key, true);
+            }
+            BatchOp::Remove(key) => {
+                let present = match staged.get(&key[..]) {
+                    Some(present) => *present,
+                    None => exists(key)?,
+                };
+                if !present {
+                    return Err(KvsError::NotFound {
+                        key: bytes::lossy(key),
+                    });
+                }
+                staged.insert(key, false);
+            }
+        }
+    }
+    Ok(())
+}
@@ -2,9 +2,28 @@ use clap::App;
 use clap::AppSettings;
 use clap::Arg;
 
+use kvs::Credentials;
+use kvs::ErrorKind;
+use kvs::KeyFilter;
 use kvs::KvsClient;
 use kvs::KvsError;
 use kvs::Result;
+use kvs::DEFAULT_USER;
+
+use std::fs::File;
+use std::io::stdin;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::thread::sleep;
+use std::time::Duration;
+use std::time::Instant;
+
+/// load的时候每个请求最多带多少个
+const BATCH: usize = 10000;
+
+/// latency探测的时候读写的key，探测完就删掉
+const PROBE_KEY: &str = "kvs-latency-probe";
 
 // 从project 2的main.rs搬过来的
 
@@ -46,15 +65,201 @@ fn main() -> Result<()> {
                         .value_name("IP-PORT"),
                 ),
         )
+        .subcommand(
+            App::new("stat")
+                .about("Show when a key was created and modified, its size and version")
+                .arg(Arg::with_name("KEY").required(true))
+                .arg(
+                    Arg::with_name("IP-PORT")
+                        .long("--addr")
+                        .takes_value(true)
+                        .value_name("IP-PORT"),
+                ),
+        )
+        .subcommand(
+            App::new("load")
+                .about("Bulk load tab-separated key/value lines from a file or stdin")
+                .arg(Arg::with_name("FILE").help("Read from FILE instead of stdin"))
+                .arg(
+                    Arg::with_name("IP-PORT")
+                        .long("--addr")
+                        .takes_value(true)
+                        .value_name("IP-PORT"),
+                ),
+        )
+        .subcommand(
+            App::new("dump")
+                .about("Export a key as a portable blob that can be restored on another server")
+                .arg(Arg::with_name("KEY").required(true))
+                .arg(
+                    Arg::with_name("IP-PORT")
+                        .long("--addr")
+                        .takes_value(true)
+                        .value_name("IP-PORT"),
+                ),
+        )
+        .subcommand(
+            App::new("restore")
+                .about("Import a blob produced by dump")
+                .arg(Arg::with_name("BLOB").help("Read the blob from stdin if omitted"))
+                .arg(
+                    Arg::with_name("REPLACE")
+                        .long("--replace")
+                        .help("Overwrite the key if it already exists"),
+                )
+                .arg(
+                    Arg::with_name("IP-PORT")
+                        .long("--addr")
+                        .takes_value(true)
+                        .value_name("IP-PORT"),
+                ),
+        )
+        .subcommand(
+            App::new("latency")
+                .about("Probe round-trip latency with PINGs and small GETs/SETs, then print min/avg/p99 and jitter")
+                .arg(
+                    Arg::with_name("DURATION")
+                        .long("--duration")
+                        .takes_value(true)
+                        .value_name("DURATION")
+                        .help("How long to probe, like 30s, 500ms or 2m [default: 10s]"),
+                )
+                .arg(
+                    Arg::with_name("INTERVAL")
+                        .long("--interval")
+                        .takes_value(true)
+                        .value_name("INTERVAL")
+                        .help("Pause between probes [default: 100ms]"),
+                )
+                .arg(
+                    Arg::with_name("IP-PORT")
+                        .long("--addr")
+                        .takes_value(true)
+                        .value_name("IP-PORT"),
+                ),
+        )
+        .subcommand(
+            App::new("monitor")
+                .about("Print every command the server executes until interrupted")
+                .arg(
+                    Arg::with_name("TOKEN")
+                        .long("--token")
+                        .takes_value(true)
+                        .value_name("TOKEN")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("IP-PORT")
+                        .long("--addr")
+                        .takes_value(true)
+                        .value_name("IP-PORT"),
+                ),
+        )
+        .subcommand(
+            App::new("scan")
+                .about(
+                    "List key/value pairs whose keys match a glob or regex, filtered on the server",
+                )
+                .arg(
+                    Arg::with_name("GLOB")
+                        .long("--glob")
+                        .takes_value(true)
+                        .value_name("PATTERN")
+                        .conflicts_with("REGEX"),
+                )
+                .arg(
+                    Arg::with_name("REGEX")
+                        .long("--regex")
+                        .takes_value(true)
+                        .value_name("PATTERN"),
+                )
+                .arg(
+                    Arg::with_name("IP-PORT")
+                        .long("--addr")
+                        .takes_value(true)
+                        .value_name("IP-PORT"),
+                ),
+        )
+        .subcommand(
+            App::new("rm-matching")
+                .about("Remove every key matching a glob or regex on the server and print how many were removed")
+                .arg(
+                    Arg::with_name("GLOB")
+                        .long("--glob")
+                        .takes_value(true)
+                        .value_name("PATTERN")
+                        .conflicts_with("REGEX")
+                        .required_unless("REGEX"),
+                )
+                .arg(
+                    Arg::with_name("REGEX")
+                        .long("--regex")
+                        .takes_value(true)
+                        .value_name("PATTERN"),
+                )
+                .arg(
+                    Arg::with_name("IP-PORT")
+                        .long("--addr")
+                        .takes_value(true)
+                        .value_name("IP-PORT"),
+                ),
+        )
+        .subcommand(
+            App::new("reload")
+                .about("Make the server reread its credentials, ACL and TLS certificate files")
+                .arg(
+                    Arg::with_name("USER")
+                        .long("--user")
+                        .takes_value(true)
+                        .value_name("USER")
+                        .requires("PASSWORD"),
+                )
+                .arg(
+                    Arg::with_name("PASSWORD")
+                        .long("--password")
+                        .takes_value(true)
+                        .value_name("PASSWORD"),
+                )
+                .arg(
+                    Arg::with_name("IP-PORT")
+                        .long("--addr")
+                        .takes_value(true)
+                        .value_name("IP-PORT"),
+                ),
+        )
+        .subcommand(
+            App::new("stats")
+                .about("Show the server version, uptime, engine, key count, disk usage, cache hit rate and connections")
+                .arg(
+                    Arg::with_name("USER")
+                        .long("--user")
+                        .takes_value(true)
+                        .value_name("USER")
+                        .requires("PASSWORD"),
+                )
+                .arg(
+                    Arg::with_name("PASSWORD")
+                        .long("--password")
+                        .takes_value(true)
+                        .value_name("PASSWORD"),
+                )
+                .arg(
+                    Arg::with_name("IP-PORT")
+                        .long("--addr")
+                        .takes_value(true)
+                        .value_name("IP-PORT"),
+                ),
+        )
         .setting(AppSettings::ArgRequiredElseHelp)
         .get_matches();
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
 
     match matches.subcommand() {
         ("get", Some(app)) => {
             let address = app.value_of("IP-PORT").unwrap_or("127.0.0.1:4000");
             let mut client = KvsClient::connect(address.to_string())?;
             let key = app.value_of("KEY").unwrap();
-            let some = client.get(&key)?;
+            let some = client.get(key)?;
             if let Some(value) = some {
                 println!("{}", value);
                 Ok(())
@@ -75,14 +280,231 @@ fn main() -> Result<()> {
             let address = app.value_of("IP-PORT").unwrap_or("127.0.0.1:4000");
             let mut client = KvsClient::connect(address.to_string())?;
             let key = app.value_of("KEY").unwrap();
-            match client.remove(&key) {
-                Err(KvsError::NotFound { key: k }) => {
-                    println!("Key not found: {}", k);
-                    Err(KvsError::NotFound { key: k }) // get不存在返回的是0，可是rm不存在返回的却是1……
+            match client.remove(key) {
+                Err(e) if ErrorKind::from_code(e.code()) == ErrorKind::NotFound => {
+                    println!("Key not found: {}", key);
+                    Err(e) // get不存在返回的是0，可是rm不存在返回的却是1……
                 }
                 v => v,
             }
         }
+        ("stat", Some(app)) => {
+            let address = app.value_of("IP-PORT").unwrap_or("127.0.0.1:4000");
+            let mut client = KvsClient::connect(address.to_string())?;
+            let key = app.value_of("KEY").unwrap();
+            match client.metadata(key)? {
+                Some(meta) => {
+                    // 时间是毫秒，懒得为了格式化时间引入chrono了
+                    println!("created: {}", meta.created);
+                    println!("modified: {}", meta.modified);
+                    println!("size: {}", meta.size);
+                    println!("version: {}", meta.version);
+                    if meta.expires != 0 {
+                        println!("expires: {}", meta.expires);
+                    }
+                }
+                None => println!("Key not found: {}", key), // 和get保持一致
+            }
+            Ok(())
+        }
+        ("load", Some(app)) => {
+            let address = app.value_of("IP-PORT").unwrap_or("127.0.0.1:4000");
+            let mut client = KvsClient::connect(address.to_string())?;
+            let reader: Box<dyn BufRead> = match app.value_of("FILE") {
+                Some(path) => Box::new(BufReader::new(File::open(path)?)),
+                None => Box::new(BufReader::new(stdin())),
+            };
+
+            // 一行一个 `key\tvalue` ，value里可以有tab，key里不行
+            let mut count = 0;
+            let mut batch = vec![];
+            for line in reader.lines() {
+                let line = line?;
+                if line.is_empty() {
+                    continue;
+                }
+                let (key, value) = line.split_once('\t').unwrap_or((&line[..], ""));
+                batch.push((key.to_string(), value.to_string()));
+                if batch.len() >= BATCH {
+                    count += client.bulk_load(std::mem::take(&mut batch))?;
+                }
+            }
+            if !batch.is_empty() {
+                count += client.bulk_load(batch)?;
+            }
+            println!("{}", count);
+            Ok(())
+        }
+        ("dump", Some(app)) => {
+            let address = app.value_of("IP-PORT").unwrap_or("127.0.0.1:4000");
+            let mut client = KvsClient::connect(address.to_string())?;
+            let key = app.value_of("KEY").unwrap();
+            match client.dump(key)? {
+                Some(blob) => println!("{}", blob),
+                None => println!("Key not found: {}", key), // 和get保持一致
+            }
+            Ok(())
+        }
+        ("restore", Some(app)) => {
+            let address = app.value_of("IP-PORT").unwrap_or("127.0.0.1:4000");
+            let mut client = KvsClient::connect(address.to_string())?;
+            let blob = match app.value_of("BLOB") {
+                Some(blob) => blob.to_string(),
+                None => {
+                    let mut blob = String::new();
+                    stdin().read_to_string(&mut blob)?; // 这样就能 `kvs-client dump KEY | kvs-client restore` 了
+                    blob
+                }
+            };
+            client.restore(&blob[..], app.is_present("REPLACE"))
+        }
+        ("latency", Some(app)) => {
+            let address = app.value_of("IP-PORT").unwrap_or("127.0.0.1:4000");
+            let mut client = KvsClient::connect(address.to_string())?;
+            let duration = parse_duration(app.value_of("DURATION").unwrap_or("10s"), "--duration")?;
+            let interval =
+                parse_duration(app.value_of("INTERVAL").unwrap_or("100ms"), "--interval")?;
+
+            // ping只有网络，get/set还要加上engine，两个一比就知道慢在哪了
+            let mut samples = vec![vec![]; 3];
+            let start = Instant::now();
+            while start.elapsed() < duration {
+                let begin = Instant::now();
+                client.ping()?;
+                samples[0].push(begin.elapsed());
+                let begin = Instant::now();
+                client.set(PROBE_KEY.to_string(), "x".to_string())?;
+                samples[1].push(begin.elapsed());
+                let begin = Instant::now();
+                client.get(PROBE_KEY)?;
+                samples[2].push(begin.elapsed());
+                sleep(interval);
+            }
+            client.remove(PROBE_KEY)?;
+
+            println!("op\tcount\tmin\tavg\tp99\tjitter (ms)");
+            for (op, samples) in ["ping", "set", "get"].iter().zip(samples) {
+                report(op, samples);
+            }
+            Ok(())
+        }
+        ("monitor", Some(app)) => {
+            let address = app.value_of("IP-PORT").unwrap_or("127.0.0.1:4000");
+            let mut client = KvsClient::connect(address.to_string())?;
+            for event in client.monitor(app.value_of("TOKEN").unwrap())? {
+                let event = event?;
+                println!(
+                    "{} {} {} {}",
+                    event.timestamp,
+                    event.peer,
+                    event.op,
+                    event.key.unwrap_or_default()
+                );
+            }
+            Ok(())
+        }
+        ("scan", Some(app)) => {
+            let address = app.value_of("IP-PORT").unwrap_or("127.0.0.1:4000");
+            let mut client = KvsClient::connect(address.to_string())?;
+            let filter = match (app.value_of("GLOB"), app.value_of("REGEX")) {
+                (Some(pattern), _) => KeyFilter::Glob(pattern.to_string()),
+                (_, Some(pattern)) => KeyFilter::Regex(pattern.to_string()),
+                _ => KeyFilter::All,
+            };
+            // 和load一样用tab隔开，这样scan的输出可以直接喂给load
+            for (key, value) in client.scan(filter)? {
+                println!("{}\t{}", key, value);
+            }
+            Ok(())
+        }
+        ("rm-matching", Some(app)) => {
+            let address = app.value_of("IP-PORT").unwrap_or("127.0.0.1:4000");
+            let mut client = KvsClient::connect(address.to_string())?;
+            let filter = match (app.value_of("GLOB"), app.value_of("REGEX")) {
+                (Some(pattern), _) => KeyFilter::Glob(pattern.to_string()),
+                (_, Some(pattern)) => KeyFilter::Regex(pattern.to_string()),
+                _ => unreachable!(), // 一个都不给的话clap就不让过
+            };
+            println!("{}", client.remove_matching(filter)?);
+            Ok(())
+        }
+        ("reload", Some(app)) => {
+            let address = app.value_of("IP-PORT").unwrap_or("127.0.0.1:4000");
+            let mut client = KvsClient::connect(address.to_string())?;
+            if let Some(password) = app.value_of("PASSWORD") {
+                let user = app.value_of("USER").unwrap_or(DEFAULT_USER);
+                client = client.credentials(Credentials::new(user, password));
+            }
+            client.reload()
+        }
+        ("stats", Some(app)) => {
+            let address = app.value_of("IP-PORT").unwrap_or("127.0.0.1:4000");
+            let mut client = KvsClient::connect(address.to_string())?;
+            if let Some(password) = app.value_of("PASSWORD") {
+                let user = app.value_of("USER").unwrap_or(DEFAULT_USER);
+                client = client.credentials(Credentials::new(user, password));
+            }
+            let stats = client.stats()?;
+            println!("version: {}", stats.version);
+            println!("uptime: {}s", stats.uptime_secs);
+            println!("engine: {}", stats.engine);
+            println!("keys: {}", stats.keys);
+            println!("disk_bytes: {}", stats.disk_bytes);
+            println!("cache_hit_rate: {:.2}", stats.cache_hit_rate);
+            println!("connections: {}", stats.connections);
+            Ok(())
+        }
         _ => Ok(()),
     }
 }
+
+/// 支持 `500ms` 、 `30s` 、 `2m` ，光写数字的话当成秒
+fn parse_duration(value: &str, flag: &str) -> Result<Duration> {
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => value.split_at(i),
+        None => (value, "s"),
+    };
+    let number: u64 = number.parse().map_err(|_| KvsError::InvalidArgument {
+        name: flag.to_string(),
+        value: value.to_string(),
+    })?;
+    match unit {
+        "ms" => Ok(Duration::from_millis(number)),
+        "s" => Ok(Duration::from_secs(number)),
+        "m" => Ok(Duration::from_secs(number * 60)),
+        _ => Err(KvsError::InvalidArgument {
+            name: flag.to_string(),
+            value: value.to_string(),
+        }),
+    }
+}
+
+/// jitter是相邻两次延迟之差的平均值，和RFC 3550里的意思差不多
+fn report(op: &str, mut samples: Vec<Duration>) {
+    if samples.is_empty() {
+        println!("{}\t0\t-\t-\t-\t-", op);
+        return;
+    }
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+    let jitter = if samples.len() > 1 {
+        samples
+            .windows(2)
+            .map(|w| (ms(w[1]) - ms(w[0])).abs())
+            .sum::<f64>()
+            / (samples.len() - 1) as f64
+    } else {
+        0.0
+    };
+    let average = samples.iter().map(|d| ms(*d)).sum::<f64>() / samples.len() as f64;
+    samples.sort();
+    let p99 = samples[(samples.len() * 99).div_ceil(100) - 1]; // 向上取整，样本少的时候就是最大值
+    println!(
+        "{}\t{}\t{:.3}\t{:.3}\t{:.3}\t{:.3}",
+        op,
+        samples.len(),
+        ms(samples[0]),
+        average,
+        ms(p99),
+        jitter
+    );
+}
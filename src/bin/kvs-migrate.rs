@@ -0,0 +1,42 @@
+use clap::App;
+use clap::Arg;
+
+use kvs::Result;
+
+// 想从kvs换到sled，或者反过来，总不能让人自己写个程序一个一个key倒
+fn main() -> Result<()> {
+    let matches = App::new("kvs-migrate")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Copy every live key from one store directory into a new one using another engine")
+        .arg(
+            Arg::with_name("SOURCE")
+                .required(true)
+                .help("Directory of the existing store"),
+        )
+        .arg(
+            Arg::with_name("DESTINATION")
+                .required(true)
+                .help("Empty or missing directory for the new store"),
+        )
+        .arg(
+            Arg::with_name("ENGINE-NAME")
+                .long("--engine")
+                .value_name("ENGINE-NAME")
+                .required(true)
+                .help("Engine of the new store, kvs or sled"),
+        )
+        .get_matches();
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let count = kvs::migrate(
+        matches.value_of("SOURCE").unwrap(),
+        matches.value_of("DESTINATION").unwrap(),
+        matches.value_of("ENGINE-NAME").unwrap(),
+    )
+    .map_err(|e| {
+        eprintln!("{}", e);
+        e
+    })?;
+    println!("{} keys migrated", count);
+    Ok(())
+}
@@ -1,14 +1,63 @@
 use clap::App;
 use clap::AppSettings;
 use clap::Arg;
+use clap::ArgMatches;
 
+use kvs::Accounts;
+use kvs::Acl;
+use kvs::AuditConfig;
+use kvs::CacheConfig;
+use kvs::Compression;
+use kvs::Durability;
+use kvs::EngineExt;
 use kvs::KvStore;
+use kvs::KvStoreOptions;
+use kvs::KvsEngine;
 use kvs::KvsError;
 use kvs::KvsServer;
+use kvs::MonitorConfig;
+use kvs::NaiveThreadPool;
+use kvs::Protocol;
+use kvs::RayonThreadPool;
+#[cfg(feature = "redb")]
+use kvs::RedbKvsEngine;
+#[cfg(feature = "redb")]
+use kvs::RedbOptions;
+use kvs::ReloadHandle;
 use kvs::Result;
+use kvs::SharedQueueThreadPool;
+use kvs::ShutdownHandle;
+use kvs::SizeLimits;
+#[cfg(feature = "sled")]
 use kvs::SledKvsEngine;
+#[cfg(feature = "sled")]
+use kvs::SledOptions;
+use kvs::ThreadPool;
+use kvs::Timeouts;
+use kvs::WriteBehind;
+
+use log::error;
+use log::info;
+use log::warn;
+use log::Log;
+use log::Metadata;
+use log::Record;
+
+#[cfg(unix)]
+use signal_hook::consts::SIGHUP;
+#[cfg(unix)]
+use signal_hook::consts::SIGINT;
+#[cfg(unix)]
+use signal_hook::consts::SIGTERM;
+#[cfg(unix)]
+use signal_hook::iterator::Signals;
 
 use std::env::current_dir;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::RwLock;
+use std::thread;
+use std::time::Duration;
 
 fn main() -> Result<()> {
     let matches = App::new("kvs")
@@ -16,36 +65,722 @@ fn main() -> Result<()> {
         .arg(
             Arg::with_name("IP-PORT")
                 .long("--addr")
-                .value_name("IP-PORT"),
+                .value_name("IP-PORT")
+                .help("Listen on IP-PORT, or on a Unix socket with unix:PATH [default: 127.0.0.1:4000]"),
         )
         .arg(
             Arg::with_name("ENGINE-NAME")
                 .long("--engine")
                 .value_name("ENGINE-NAME"),
         )
+        .arg(
+            Arg::with_name("CAPACITY")
+                .long("--cache-capacity")
+                .value_name("CAPACITY")
+                .help("Cache up to CAPACITY values in front of the engine"),
+        )
+        .arg(
+            Arg::with_name("MILLISECONDS")
+                .long("--write-behind-ms")
+                .value_name("MILLISECONDS")
+                .requires("CAPACITY")
+                .help("Buffer writes in the cache for at most MILLISECONDS before writing them to the engine"),
+        )
+        .arg(
+            Arg::with_name("FILES")
+                .long("--max-open-files")
+                .value_name("FILES")
+                .help("Keep at most FILES log files open (kvs engine only)"),
+        )
+        .arg(
+            Arg::with_name("BYTES")
+                .long("--preload")
+                .value_name("BYTES")
+                .help("Load every value into memory on startup, failing if there are more than BYTES (kvs engine only)"),
+        )
+        .arg(
+            Arg::with_name("QUOTA")
+                .long("--max-disk-bytes")
+                .value_name("QUOTA")
+                .help("Reject writes once the log would grow past QUOTA bytes, after trying to compact it (kvs engine only)"),
+        )
+        .arg(
+            Arg::with_name("DURABILITY")
+                .long("--durability")
+                .value_name("DURABILITY")
+                .help("When to fsync writes: always, never, or at most every N milliseconds [default: never for kvs, always for sled and redb]"),
+        )
+        .arg(
+            Arg::with_name("ALGORITHM")
+                .long("--compression")
+                .value_name("ALGORITHM")
+                .help("Compress newly written values with none, lz4 or zstd [default: none] (kvs engine only)"),
+        )
+        .arg(
+            Arg::with_name("SKIP-CORRUPT")
+                .long("--skip-corrupt")
+                .help("Skip corrupt records in the log on startup instead of refusing to start (kvs engine only)"),
+        )
+        .arg(
+            Arg::with_name("KEY-BYTES")
+                .long("--max-key-bytes")
+                .value_name("KEY-BYTES")
+                .help("Reject keys longer than KEY-BYTES bytes"),
+        )
+        .arg(
+            Arg::with_name("VALUE-BYTES")
+                .long("--max-value-bytes")
+                .value_name("VALUE-BYTES")
+                .help("Reject values larger than VALUE-BYTES bytes"),
+        )
+        .arg(
+            Arg::with_name("REQUEST-BYTES")
+                .long("--max-request-bytes")
+                .value_name("REQUEST-BYTES")
+                .help("Reject requests larger than REQUEST-BYTES bytes and close the connection [default: 536870912]"),
+        )
+        .arg(
+            Arg::with_name("READ-ONLY")
+                .long("--read-only")
+                .help("Reject every write request"),
+        )
+        .arg(
+            Arg::with_name("TOKEN")
+                .long("--monitor-token")
+                .value_name("TOKEN")
+                .help("Let clients presenting TOKEN subscribe to every command the server executes"),
+        )
+        .arg(
+            Arg::with_name("EVENTS")
+                .long("--monitor-rate")
+                .value_name("EVENTS")
+                .requires("TOKEN")
+                .help("Send each monitor subscriber at most EVENTS events per second [default: 1000]"),
+        )
+        .arg(
+            Arg::with_name("AUDIT-LOG")
+                .long("--audit-log")
+                .value_name("FILE")
+                .help("Append a JSON line to FILE for every successful write, with time, client, user and key"),
+        )
+        .arg(
+            Arg::with_name("AUDIT-VALUES")
+                .long("--audit-values")
+                .requires("AUDIT-LOG")
+                .help("Also record the values written in the audit log"),
+        )
+        .arg(
+            Arg::with_name("AUDIT-BYTES")
+                .long("--audit-max-bytes")
+                .value_name("BYTES")
+                .requires("AUDIT-LOG")
+                .help("Rotate the audit log to FILE.1, FILE.2, ... once it reaches BYTES, 0 never rotates [default: 104857600]"),
+        )
+        .arg(
+            Arg::with_name("AUDIT-KEEP")
+                .long("--audit-keep")
+                .value_name("N")
+                .requires("AUDIT-LOG")
+                .help("Keep at most N rotated audit logs [default: 10]"),
+        )
+        .arg(
+            Arg::with_name("THREADS")
+                .long("--threads")
+                .value_name("THREADS")
+                .help("Size of the shared or rayon thread pool. Every open connection holds a thread until it closes, idle ones included, so this is also the most connections served at once; pair it with --idle-timeout-ms [default: number of CPUs]"),
+        )
+        .arg(
+            Arg::with_name("CONNECTIONS")
+                .long("--max-connections")
+                .value_name("N")
+                .help("Keep at most N connections open; further clients wait in the listen backlog until one closes"),
+        )
+        .arg(
+            Arg::with_name("IDLE-MS")
+                .long("--idle-timeout-ms")
+                .value_name("MILLISECONDS")
+                .help("Close connections that send no request for MILLISECONDS"),
+        )
+        .arg(
+            Arg::with_name("READ-MS")
+                .long("--read-timeout-ms")
+                .value_name("MILLISECONDS")
+                .help("Drop clients that stall for MILLISECONDS in the middle of sending a request"),
+        )
+        .arg(
+            Arg::with_name("WRITE-MS")
+                .long("--write-timeout-ms")
+                .value_name("MILLISECONDS")
+                .help("Drop clients that stop reading responses for MILLISECONDS"),
+        )
+        .arg(
+            Arg::with_name("SLOW-MS")
+                .long("--slow-request-ms")
+                .value_name("MILLISECONDS")
+                .help("Log a warning with the command, key and duration of requests taking MILLISECONDS or longer"),
+        )
+        .arg(
+            Arg::with_name("POOL")
+                .long("--thread-pool")
+                .value_name("POOL")
+                .help("Run connections on a naive (one thread per connection), shared or rayon thread pool [default: naive]"),
+        )
+        .arg(
+            Arg::with_name("ASYNC")
+                .long("--async")
+                .conflicts_with_all(&["THREADS", "POOL"])
+                .help("Accept connections on a tokio runtime, so idle connections don't each hold a thread"),
+        )
+        .arg(
+            Arg::with_name("PROTOCOL")
+                .long("--protocol")
+                .value_name("PROTOCOL")
+                .help("Speak kvs or resp, the Redis protocol, so redis-cli can connect [default: kvs]"),
+        )
+        .arg(
+            Arg::with_name("CERT")
+                .long("--tls-cert")
+                .value_name("PEM")
+                .requires("KEY")
+                .conflicts_with("ASYNC")
+                .help("Serve TLS with the certificate chain in PEM, clients then need KvsClient::tls"),
+        )
+        .arg(
+            Arg::with_name("KEY")
+                .long("--tls-key")
+                .value_name("PEM")
+                .requires("CERT")
+                .help("Private key of --tls-cert in PEM"),
+        )
+        .arg(
+            Arg::with_name("CREDENTIALS")
+                .long("--credentials")
+                .value_name("FILE")
+                .help("Require clients to log in as one of the user:password lines in FILE"),
+        )
+        .arg(
+            Arg::with_name("PASSWORD")
+                .long("--require-auth")
+                .value_name("PASSWORD")
+                .conflicts_with("CREDENTIALS")
+                .help("Require clients to log in as the default user with PASSWORD"),
+        )
+        .arg(
+            Arg::with_name("ACL")
+                .long("--acl")
+                .value_name("FILE")
+                .help("Limit each user to the key prefixes in FILE, one \"user prefix read|readwrite|none\" per line"),
+        )
+        .arg(
+            Arg::with_name("LOG-LEVEL")
+                .long("--log-level")
+                .value_name("LEVEL")
+                .possible_values(&["off", "error", "warn", "info", "debug", "trace"])
+                .help("Log at LEVEL and above, debug also logs every connection and request [default: RUST_LOG or info]"),
+        )
+        .arg(
+            Arg::with_name("CONFIG")
+                .long("--config")
+                .value_name("FILE")
+                .help("Read log-level, max-key-bytes, max-value-bytes and max-request-bytes from \"name value\" lines in FILE, over the command line ones, and again on SIGHUP"),
+        )
         .setting(AppSettings::ArgRequiredElseHelp)
         .get_matches();
 
+    let settings = Settings::from_matches(&matches)?;
+    let config = matches.value_of("CONFIG").map(PathBuf::from);
+    let current = match &config {
+        Some(path) => settings.overlay(path)?,
+        None => settings.clone(),
+    };
+    LOGGER.set(current.log_level.as_deref());
+    log::set_logger(&LOGGER).expect("logger already set");
+
+    let mut engine = open_engine(&matches)?;
+
+    // 不限也要套上，不然reload的时候没有地方换
+    let limited = engine.size_limited(current.limits());
+    engine = limited.clone().boxed();
+    if matches.is_present("READ-ONLY") {
+        engine = engine.read_only().boxed();
+    }
+    if let Some(capacity) = matches.value_of("CAPACITY") {
+        let capacity = parse(capacity, "--cache-capacity")?;
+        let write_behind = match matches.value_of("MILLISECONDS") {
+            Some(ms) => Some(WriteBehind {
+                max_pending: capacity, // 积攒的写也占内存，干脆和cache一样大
+                max_staleness: Duration::from_millis(parse(ms, "--write-behind-ms")? as u64),
+            }),
+            None => None,
+        };
+        engine = engine
+            .cached(CacheConfig {
+                capacity,
+                write_behind,
+            })
+            .boxed();
+    }
+
     let address = matches.value_of("IP-PORT").unwrap_or("127.0.0.1:4000");
+    info!("kvs {} {}", env!("CARGO_PKG_VERSION"), address);
+
+    let protocol = match matches.value_of("PROTOCOL").unwrap_or("kvs") {
+        "kvs" => Protocol::Kvs,
+        "resp" => Protocol::Resp,
+        v => {
+            error!("Invalid value for --protocol: {}", v);
+            return Err(KvsError::InvalidArgument {
+                name: "--protocol".to_string(),
+                value: v.to_string(),
+            });
+        }
+    };
+    let mut server = KvsServer::new(engine).protocol(protocol);
+    server.reload_handle().manage_limits(&limited);
+    drop(limited);
+    if let Some(bytes) = current.max_request {
+        server = server.max_request(bytes);
+    }
+    if let Some(max) = matches.value_of("CONNECTIONS") {
+        server = server.max_connections(parse(max, "--max-connections")?);
+    }
+    // 0是不限
+    let millis = |name, flag| -> Result<Option<Duration>> {
+        match matches.value_of(name) {
+            Some(ms) => Ok(Some(parse(ms, flag)?)
+                .filter(|ms| *ms > 0)
+                .map(|ms| Duration::from_millis(ms as u64))),
+            None => Ok(None),
+        }
+    };
+    server = server.timeouts(Timeouts {
+        idle: millis("IDLE-MS", "--idle-timeout-ms")?,
+        read: millis("READ-MS", "--read-timeout-ms")?,
+        write: millis("WRITE-MS", "--write-timeout-ms")?,
+    });
+    if let Some(threshold) = millis("SLOW-MS", "--slow-request-ms")? {
+        server = server.slow_requests(threshold);
+    }
+    if let Some(token) = matches.value_of("TOKEN") {
+        let events_per_sec = match matches.value_of("EVENTS") {
+            Some(events) => match parse(events, "--monitor-rate")? {
+                0 => {
+                    error!("--monitor-rate must be at least 1");
+                    return Err(KvsError::InvalidArgument {
+                        name: "--monitor-rate".to_string(),
+                        value: events.to_string(),
+                    });
+                }
+                events => events as f64,
+            },
+            None => 1000.0,
+        };
+        server = server.monitored(MonitorConfig {
+            token: token.to_string(),
+            events_per_sec,
+        });
+    }
+    if let Some(path) = matches.value_of("AUDIT-LOG") {
+        let max_bytes = match matches.value_of("AUDIT-BYTES") {
+            Some(bytes) => parse(bytes, "--audit-max-bytes")? as u64,
+            None => 100 << 20,
+        };
+        let keep = match matches.value_of("AUDIT-KEEP") {
+            Some(n) => parse(n, "--audit-keep")?,
+            None => 10,
+        };
+        server = server.audited(AuditConfig {
+            path: PathBuf::from(path),
+            values: matches.is_present("AUDIT-VALUES"),
+            max_bytes: Some(max_bytes).filter(|v| *v > 0),
+            keep,
+        })?;
+    }
+    if let Some(path) = matches.value_of("CREDENTIALS") {
+        server = server.authenticated(Accounts::load(path)?);
+    }
+    if let Some(password) = matches.value_of("PASSWORD") {
+        server = server.authenticated(Accounts::with_password(password));
+    }
+    if let Some(path) = matches.value_of("ACL") {
+        if !matches.is_present("CREDENTIALS") && !matches.is_present("PASSWORD") {
+            error!("--acl needs --credentials or --require-auth");
+            return Err(KvsError::InvalidArgument {
+                name: "--acl".to_string(),
+                value: path.to_string(),
+            });
+        }
+        server = server.acl(Acl::load(path)?);
+    }
+    if let (Some(cert), Some(key)) = (matches.value_of("CERT"), matches.value_of("KEY")) {
+        server = tls(server, cert, key)?;
+    }
+    let files = ConfigFiles {
+        config,
+        settings,
+        credentials: matches.value_of("CREDENTIALS").map(PathBuf::from),
+        acl: matches.value_of("ACL").map(PathBuf::from),
+        tls: matches
+            .value_of("CERT")
+            .zip(matches.value_of("KEY"))
+            .map(|(cert, key)| (PathBuf::from(cert), PathBuf::from(key))),
+    };
+    server = server.on_reload(move |config| files.reload(config));
+    handle_signals(server.shutdown_handle(), server.reload_handle())?;
+    if matches.is_present("ASYNC") {
+        if address.starts_with("unix:") {
+            error!("--async only listens on TCP");
+            return Err(KvsError::InvalidArgument {
+                name: "--addr".to_string(),
+                value: address.to_string(),
+            });
+        }
+        return run_async(server, address);
+    }
+
+    let threads = match matches.value_of("THREADS") {
+        Some(threads) => parse(threads, "--threads")?,
+        None => thread::available_parallelism().map_or(1, |v| v.get()),
+    };
+    // 每个连接从连上到断开一直占着一个线程，闲着的也是。固定大小的池子会被几个不说话的连接占满，所以默认还是一个连接一个线程
+    match matches.value_of("POOL").unwrap_or("naive") {
+        "naive" => listen(server.with_pool(NaiveThreadPool::new(threads)?), address),
+        "shared" => listen(
+            server.with_pool(SharedQueueThreadPool::new(threads)?),
+            address,
+        ),
+        "rayon" => listen(server.with_pool(RayonThreadPool::new(threads)?), address),
+        v => {
+            error!("Invalid value for --thread-pool: {}", v);
+            Err(KvsError::InvalidArgument {
+                name: "--thread-pool".to_string(),
+                value: v.to_string(),
+            })
+        }
+    }
+}
+
+/// 启动的时候给的文件，reload的时候重新读一遍
+struct ConfigFiles {
+    /// `--config` ，里面有的盖在 `settings` 上
+    config: Option<PathBuf>,
+    /// 命令行上给的
+    settings: Settings,
+    credentials: Option<PathBuf>,
+    acl: Option<PathBuf>,
+    tls: Option<(PathBuf, PathBuf)>,
+}
+
+impl ConfigFiles {
+    /// 有一个文件读不出来的话都不换，还用原来的
+    fn reload(&self, config: &ReloadHandle) -> Result<()> {
+        let settings = match &self.config {
+            Some(path) => self.settings.overlay(path)?,
+            None => self.settings.clone(),
+        };
+        let accounts = self.credentials.as_ref().map(Accounts::load).transpose()?;
+        let acl = self.acl.as_ref().map(Acl::load).transpose()?;
+        if let Some((cert, key)) = &self.tls {
+            reload_tls(config, cert, key)?;
+        }
+        config.set_limits(settings.limits())?;
+        config.set_max_request(
+            settings
+                .max_request
+                .unwrap_or_else(|| ReloadHandle::default().max_request()), // 文件里删掉了的话回到默认的
+        );
+        LOGGER.set(settings.log_level.as_deref());
+        if let Some(accounts) = accounts {
+            config.set_accounts(accounts);
+        }
+        if let Some(acl) = acl {
+            config.set_acl(acl);
+        }
+        Ok(())
+    }
+}
+
+/// 能reload的几个设置，名字和命令行选项一样
+#[derive(Clone)]
+struct Settings {
+    /// `None` 是 `RUST_LOG` 或者info
+    log_level: Option<String>,
+    max_key: Option<usize>,
+    max_value: Option<usize>,
+    max_request: Option<usize>,
+}
+
+impl Settings {
+    fn from_matches(matches: &ArgMatches) -> Result<Self> {
+        let bytes = |name, flag| matches.value_of(name).map(|v| parse(v, flag)).transpose();
+        Ok(Self {
+            log_level: matches.value_of("LOG-LEVEL").map(str::to_string),
+            max_key: bytes("KEY-BYTES", "--max-key-bytes")?,
+            max_value: bytes("VALUE-BYTES", "--max-value-bytes")?,
+            max_request: bytes("REQUEST-BYTES", "--max-request-bytes")?,
+        })
+    }
+
+    /// 一行一个 `名字 值` ，`#` 开头的是注释。文件里没写的还用自己的
+    fn overlay(&self, path: &Path) -> Result<Self> {
+        let mut settings = self.clone();
+        for line in std::fs::read_to_string(path)?.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, value) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let value = value.trim();
+            match name {
+                "log-level" => settings.log_level = Some(value.to_string()),
+                "max-key-bytes" => settings.max_key = Some(parse(value, "max-key-bytes")?),
+                "max-value-bytes" => settings.max_value = Some(parse(value, "max-value-bytes")?),
+                "max-request-bytes" => {
+                    settings.max_request = Some(parse(value, "max-request-bytes")?)
+                }
+                _ => {
+                    error!("Unknown setting in {:?}: {}", path, line);
+                    return Err(KvsError::InvalidArgument {
+                        name: path.display().to_string(),
+                        value: line.to_string(),
+                    });
+                }
+            }
+        }
+        Ok(settings)
+    }
+
+    fn limits(&self) -> SizeLimits {
+        SizeLimits {
+            max_key: self.max_key,
+            max_value: self.max_value,
+        }
+    }
+}
+
+/// env_logger初始化以后就换不了过滤规则了，套一层，reload的时候整个换掉
+struct ReloadableLogger {
+    inner: RwLock<Option<env_logger::Logger>>,
+}
+
+static LOGGER: ReloadableLogger = ReloadableLogger {
+    inner: RwLock::new(None),
+};
+
+impl ReloadableLogger {
+    /// `level` 是env_logger的过滤规则，`None` 的话看 `RUST_LOG` ，没有就是info
+    fn set(&self, level: Option<&str>) {
+        let mut builder =
+            env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info"));
+        if let Some(level) = level {
+            builder.parse_filters(level);
+        }
+        let logger = builder.build();
+        log::set_max_level(logger.filter());
+        *self.inner.write().unwrap() = Some(logger);
+    }
+}
+
+impl Log for ReloadableLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner
+            .read()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|logger| logger.enabled(metadata))
+    }
+
+    fn log(&self, record: &Record) {
+        if let Some(logger) = &*self.inner.read().unwrap() {
+            logger.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(logger) = &*self.inner.read().unwrap() {
+            logger.flush();
+        }
+    }
+}
+
+/// SIGINT、SIGTERM的时候不收新连接，正在做的请求做完、落盘了再退出。等不及的话再按一次Ctrl-C
+///
+/// SIGHUP的时候重新读 `--config` 、账号、ACL和TLS证书，不用重启
+#[cfg(unix)]
+fn handle_signals(handle: ShutdownHandle, config: ReloadHandle) -> Result<()> {
+    let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP])?;
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if signal == SIGHUP {
+                match config.reload() {
+                    Ok(()) => info!("Configuration reloaded"),
+                    Err(e) => warn!("Reload failed, keeping the old configuration: {}", e),
+                }
+                continue;
+            }
+            if handle.is_shutting_down() {
+                warn!("Received signal {} again, exiting now", signal);
+                std::process::exit(1);
+            }
+            info!("Received signal {}, shutting down", signal);
+            handle.shutdown();
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn handle_signals(_: ShutdownHandle, _: ReloadHandle) -> Result<()> {
+    Ok(())
+}
+
+/// `unix:` 开头的是Unix socket的路径
+fn listen<P>(mut server: KvsServer<Box<dyn KvsEngine>, P>, address: &str) -> Result<()>
+where
+    P: ThreadPool + Clone + Send + 'static,
+{
+    match address.strip_prefix("unix:") {
+        #[cfg(unix)]
+        Some(path) => server.run_unix(path),
+        #[cfg(not(unix))]
+        Some(_) => {
+            error!("Unix sockets are not supported on this platform");
+            Err(KvsError::InvalidArgument {
+                name: "--addr".to_string(),
+                value: address.to_string(),
+            })
+        }
+        None => server.run(address),
+    }
+}
+
+#[cfg(feature = "tls")]
+fn tls(
+    server: KvsServer<Box<dyn KvsEngine>>,
+    cert: &str,
+    key: &str,
+) -> Result<KvsServer<Box<dyn KvsEngine>>> {
+    server.tls(cert, key)
+}
+
+#[cfg(feature = "tls")]
+fn reload_tls(config: &ReloadHandle, cert: &Path, key: &Path) -> Result<()> {
+    config.set_tls(cert, key)
+}
+
+#[cfg(not(feature = "tls"))]
+fn reload_tls(_: &ReloadHandle, _: &Path, _: &Path) -> Result<()> {
+    Ok(()) // 启动的时候就报错了，到不了这里
+}
+
+#[cfg(not(feature = "tls"))]
+fn tls(
+    _: KvsServer<Box<dyn KvsEngine>>,
+    _: &str,
+    _: &str,
+) -> Result<KvsServer<Box<dyn KvsEngine>>> {
+    error!("--tls-cert needs the tls feature");
+    Err(KvsError::InvalidArgument {
+        name: "--tls-cert".to_string(),
+        value: String::new(),
+    })
+}
+
+#[cfg(feature = "tokio")]
+fn run_async(mut server: KvsServer<Box<dyn KvsEngine>>, address: &str) -> Result<()> {
+    server.run_async(address)
+}
+
+#[cfg(not(feature = "tokio"))]
+fn run_async(_: KvsServer<Box<dyn KvsEngine>>, _: &str) -> Result<()> {
+    error!("--async needs the tokio feature");
+    Err(KvsError::InvalidArgument {
+        name: "--async".to_string(),
+        value: String::new(),
+    })
+}
+
+/// 用哪个engine是运行的时候才知道的，装进box里后面就只有一条路了。有自己的命令行选项的engine在这里打开，别的交给 `kvs::open_engine`
+fn open_engine(matches: &ArgMatches) -> Result<Box<dyn KvsEngine>> {
     match matches.value_of("ENGINE-NAME").unwrap_or("kvs") {
         "kvs" => {
-            let engine = KvStore::open(current_dir()?)?;
-            let mut server = KvsServer::new(engine);
-            eprintln!("kvs {} {}", env!("CARGO_PKG_VERSION"), address); // 懒得用log库了。这个信息为什么输出到stderr呢，我觉得应该输出到stdout，毕竟不算错误
-            server.run(address)?;
+            let mut options = KvStoreOptions::default();
+            if let Some(files) = matches.value_of("FILES") {
+                options.max_open_files = parse(files, "--max-open-files")?;
+            }
+            if let Some(bytes) = matches.value_of("BYTES") {
+                options.preload = Some(parse(bytes, "--preload")? as u64);
+            }
+            if let Some(quota) = matches.value_of("QUOTA") {
+                options.max_disk_bytes = Some(parse(quota, "--max-disk-bytes")? as u64);
+            }
+            options.skip_corrupt = matches.is_present("SKIP-CORRUPT");
+            if let Some(durability) = matches.value_of("DURABILITY") {
+                options.durability = parse_durability(durability)?;
+            }
+            if let Some(algorithm) = matches.value_of("ALGORITHM") {
+                options.compression = parse_compression(algorithm)?;
+            }
+            Ok(KvStore::open_with(current_dir()?, options)?.boxed())
         }
+        #[cfg(feature = "sled")]
         "sled" => {
-            let engine = SledKvsEngine::open(current_dir()?)?;
-            let mut server = KvsServer::new(engine);
-            eprintln!("kvs {} {}", env!("CARGO_PKG_VERSION"), address); // 懒得用log库了。这个信息为什么输出到stderr呢，我觉得应该输出到stdout，毕竟不算错误
-            server.run(address)?;
+            let mut options = SledOptions::default();
+            if let Some(durability) = matches.value_of("DURABILITY") {
+                options.durability = parse_durability(durability)?;
+            }
+            Ok(SledKvsEngine::open_with(current_dir()?, options)?.boxed())
         }
+        #[cfg(feature = "redb")]
+        "redb" => {
+            let mut options = RedbOptions::default();
+            if let Some(durability) = matches.value_of("DURABILITY") {
+                options.durability = parse_durability(durability)?;
+            }
+            Ok(RedbKvsEngine::open_with(current_dir()?, options)?.boxed())
+        }
+        name => match kvs::open_engine(name, current_dir()?) {
+            Err(KvsError::UnsupportedEngine { name }) => {
+                error!("Unsupported engine: {}", name);
+                Err(KvsError::UnsupportedEngine { name })
+            }
+            v => v, // 别的engine没有命令行选项，用默认的打开
+        },
+    }
+}
+
+/// `always` 、 `never` 或者毫秒数
+fn parse_durability(value: &str) -> Result<Durability> {
+    match value {
+        "always" => Ok(Durability::Always),
+        "never" => Ok(Durability::Never),
+        v => Ok(Durability::Every(Duration::from_millis(
+            parse(v, "--durability")? as u64,
+        ))),
+    }
+}
+
+fn parse_compression(value: &str) -> Result<Compression> {
+    match value {
+        "none" => Ok(Compression::None),
+        "lz4" => Ok(Compression::Lz4),
+        "zstd" => Ok(Compression::Zstd),
         v => {
-            eprintln!("Unsupported engine: {}", v);
-            return Err(KvsError::UnsupportedEngine {
-                name: v.to_string(),
-            });
+            error!("Invalid value for --compression: {}", v);
+            Err(KvsError::InvalidArgument {
+                name: "--compression".to_string(),
+                value: v.to_string(),
+            })
         }
     }
-    Ok(())
+}
+
+fn parse(value: &str, flag: &str) -> Result<usize> {
+    value.parse().map_err(|_| {
+        error!("Invalid value for {}: {}", flag, value);
+        KvsError::InvalidArgument {
+            name: flag.to_string(),
+            value: value.to_string(),
+        }
+    })
 }
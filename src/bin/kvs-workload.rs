@@ -0,0 +1,410 @@
+use clap::App;
+use clap::Arg;
+use clap::ArgMatches;
+
+use kvs::KvsClient;
+use kvs::KvsEngine;
+use kvs::KvsError;
+use kvs::Result;
+
+use std::env::current_dir;
+use std::fs::File;
+use std::io::stdout;
+use std::io::Write;
+use std::thread::sleep;
+use std::time::Duration;
+use std::time::Instant;
+
+/// 预先导入数据的时候一批多少个
+const BATCH: usize = 10000;
+
+/// YCSB默认的zipfian常数
+const ZIPFIAN_CONSTANT: f64 = 0.99;
+
+// 想复现别人的benchmark，总得有个和YCSB差不多的东西
+fn main() -> Result<()> {
+    let matches = App::new("kvs-workload")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Generate a YCSB-style workload against an embedded engine or a remote server")
+        .arg(
+            Arg::with_name("IP-PORT")
+                .long("--addr")
+                .value_name("IP-PORT")
+                .conflicts_with("ENGINE-NAME")
+                .help("Run against a remote server instead of an embedded engine"),
+        )
+        .arg(
+            Arg::with_name("ENGINE-NAME")
+                .long("--engine")
+                .value_name("ENGINE-NAME")
+                .help("Embedded engine to open in the current directory [default: kvs]"),
+        )
+        .arg(
+            Arg::with_name("RECORDS")
+                .long("--records")
+                .value_name("RECORDS")
+                .help("Number of keys loaded before the run [default: 1000]"),
+        )
+        .arg(
+            Arg::with_name("OPERATIONS")
+                .long("--operations")
+                .value_name("OPERATIONS")
+                .help("Number of operations in the run [default: 10000]"),
+        )
+        .arg(
+            Arg::with_name("RATIO")
+                .long("--read-ratio")
+                .value_name("RATIO")
+                .help("Fraction of operations that are reads, between 0 and 1 [default: 0.95]"),
+        )
+        .arg(
+            Arg::with_name("DISTRIBUTION")
+                .long("--distribution")
+                .value_name("DISTRIBUTION")
+                .possible_values(&["uniform", "zipfian"])
+                .help("How keys are chosen [default: zipfian]"),
+        )
+        .arg(
+            Arg::with_name("BYTES")
+                .long("--value-size")
+                .value_name("BYTES")
+                .help("Value size, either a fixed N or a uniform range MIN-MAX [default: 100]"),
+        )
+        .arg(
+            Arg::with_name("QPS")
+                .long("--target-qps")
+                .value_name("QPS")
+                .help("Pace operations to at most QPS per second, 0 for as fast as possible [default: 0]"),
+        )
+        .arg(
+            Arg::with_name("SEED")
+                .long("--seed")
+                .value_name("SEED")
+                .help("Seed of the random generator, same seed gives the same workload [default: 42]"),
+        )
+        .arg(
+            Arg::with_name("FILE")
+                .long("--output")
+                .value_name("FILE")
+                .help("Write the CSV to FILE instead of stdout"),
+        )
+        .arg(
+            Arg::with_name("SKIP-LOAD")
+                .long("--skip-load")
+                .help("Assume the records are already loaded"),
+        )
+        .get_matches();
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
+
+    let workload = Workload::from(&matches)?;
+    let report = match matches.value_of("IP-PORT") {
+        Some(address) => workload.run(&mut Remote(KvsClient::connect(address.to_string())?))?,
+        None => {
+            let name = matches.value_of("ENGINE-NAME").unwrap_or("kvs");
+            match kvs::open_engine(name, current_dir()?) {
+                Ok(engine) => workload.run(&mut Local(engine))?,
+                Err(KvsError::UnsupportedEngine { name }) => {
+                    eprintln!("Unsupported engine: {}", name);
+                    return Err(KvsError::UnsupportedEngine { name });
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    };
+
+    let mut output: Box<dyn Write> = match matches.value_of("FILE") {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(stdout()),
+    };
+    report.write_csv(&mut output)
+}
+
+/// 本地engine和远端服务器都当成同一种东西来压
+trait Target {
+    fn read(&mut self, key: &str) -> Result<()>;
+    fn update(&mut self, key: String, value: String) -> Result<()>;
+    fn load(&mut self, pairs: Vec<(String, String)>) -> Result<()>;
+}
+
+struct Local<E>(E);
+
+impl<E: KvsEngine> Target for Local<E> {
+    fn read(&mut self, key: &str) -> Result<()> {
+        self.0.get(key).map(|_| ())
+    }
+
+    fn update(&mut self, key: String, value: String) -> Result<()> {
+        self.0.set(key, value)
+    }
+
+    fn load(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.0.bulk_load(pairs).map(|_| ())
+    }
+}
+
+struct Remote(KvsClient);
+
+impl Target for Remote {
+    fn read(&mut self, key: &str) -> Result<()> {
+        self.0.get(key).map(|_| ())
+    }
+
+    fn update(&mut self, key: String, value: String) -> Result<()> {
+        self.0.set(key, value)
+    }
+
+    fn load(&mut self, pairs: Vec<(String, String)>) -> Result<()> {
+        self.0.bulk_load(pairs).map(|_| ())
+    }
+}
+
+enum Distribution {
+    Uniform,
+    Zipfian(Zipfian),
+}
+
+struct Workload {
+    records: u64,
+    operations: u64,
+    read_ratio: f64,
+    distribution: Distribution,
+    value_size: (usize, usize),
+    target_qps: u64,
+    seed: u64,
+    skip_load: bool,
+}
+
+impl Workload {
+    fn from(matches: &ArgMatches) -> Result<Self> {
+        let records = parse(matches.value_of("RECORDS").unwrap_or("1000"), "--records")?;
+        if records == 0 {
+            return Err(invalid("--records", "0"));
+        }
+        let read_ratio = matches.value_of("RATIO").unwrap_or("0.95");
+        let read_ratio = match read_ratio.parse::<f64>() {
+            Ok(v) if (0.0..=1.0).contains(&v) => v,
+            _ => return Err(invalid("--read-ratio", read_ratio)),
+        };
+        let value_size = matches.value_of("BYTES").unwrap_or("100");
+        let value_size = match value_size.split_once('-') {
+            Some((min, max)) => (
+                parse(min, "--value-size")? as usize,
+                parse(max, "--value-size")? as usize,
+            ),
+            None => {
+                let size = parse(value_size, "--value-size")? as usize;
+                (size, size)
+            }
+        };
+        if value_size.0 > value_size.1 {
+            return Err(invalid("--value-size", matches.value_of("BYTES").unwrap()));
+        }
+
+        Ok(Self {
+            records,
+            operations: parse(
+                matches.value_of("OPERATIONS").unwrap_or("10000"),
+                "--operations",
+            )?,
+            read_ratio,
+            distribution: match matches.value_of("DISTRIBUTION").unwrap_or("zipfian") {
+                "uniform" => Distribution::Uniform,
+                _ => Distribution::Zipfian(Zipfian::new(records)),
+            },
+            value_size,
+            target_qps: parse(matches.value_of("QPS").unwrap_or("0"), "--target-qps")?,
+            seed: parse(matches.value_of("SEED").unwrap_or("42"), "--seed")?,
+            skip_load: matches.is_present("SKIP-LOAD"),
+        })
+    }
+
+    fn run<T: Target>(&self, target: &mut T) -> Result<Report> {
+        let mut rng = Rng::new(self.seed);
+
+        if !self.skip_load {
+            let start = Instant::now();
+            let mut batch = vec![];
+            for i in 0..self.records {
+                batch.push((key(i), self.value(&mut rng)));
+                if batch.len() >= BATCH {
+                    target.load(std::mem::take(&mut batch))?;
+                }
+            }
+            if !batch.is_empty() {
+                target.load(batch)?;
+            }
+            eprintln!("Loaded {} records in {:?}", self.records, start.elapsed());
+        }
+
+        let mut report = Report::default();
+        let interval = match self.target_qps {
+            0 => None,
+            qps => Some(Duration::from_secs_f64(1.0 / qps as f64)),
+        };
+        let start = Instant::now();
+        for i in 0..self.operations {
+            // 按照计划的时间发，而不是每个操作之后睡一会，这样操作本身的耗时不会拖慢QPS
+            if let Some(interval) = interval {
+                let due = interval * i as u32;
+                let elapsed = start.elapsed();
+                if due > elapsed {
+                    sleep(due - elapsed);
+                }
+            }
+
+            let index = match &self.distribution {
+                Distribution::Uniform => rng.below(self.records),
+                Distribution::Zipfian(zipfian) => zipfian.sample(&mut rng),
+            };
+            if rng.unit() < self.read_ratio {
+                let begin = Instant::now();
+                target.read(&key(index)[..])?;
+                report.reads.push(begin.elapsed());
+            } else {
+                let value = self.value(&mut rng);
+                let begin = Instant::now();
+                target.update(key(index), value)?;
+                report.updates.push(begin.elapsed());
+            }
+        }
+        report.elapsed = start.elapsed();
+        Ok(report)
+    }
+
+    fn value(&self, rng: &mut Rng) -> String {
+        let (min, max) = self.value_size;
+        let size = min + rng.below((max - min) as u64 + 1) as usize;
+        (0..size)
+            .map(|_| (b'a' + rng.below(26) as u8) as char)
+            .collect()
+    }
+}
+
+/// 和YCSB一样，key是 `user` 加上一个打散过的编号，这样热点不会全挤在一起
+fn key(index: u64) -> String {
+    format!("user{}", fnv(index))
+}
+
+fn fnv(value: u64) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in value.to_le_bytes().iter() {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash
+}
+
+/// xorshift64*，测试负载用不着密码学安全的随机数，也就懒得加rand依赖了
+struct Rng(u64);
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Self(seed.max(1)) // 种子是0的话会一直是0
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// [0, 1)
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// [0, n)
+    fn below(&mut self, n: u64) -> u64 {
+        (self.unit() * n as f64) as u64
+    }
+}
+
+/// Gray等人的算法，YCSB的ZipfianGenerator也是这么写的。编号越小越热门
+struct Zipfian {
+    items: u64,
+    alpha: f64,
+    zetan: f64,
+    eta: f64,
+}
+
+impl Zipfian {
+    fn new(items: u64) -> Self {
+        let theta = ZIPFIAN_CONSTANT;
+        let zeta = |n: u64| (1..=n).map(|i| 1.0 / (i as f64).powf(theta)).sum::<f64>();
+        let zeta2 = zeta(2);
+        let zetan = zeta(items);
+        Self {
+            items,
+            alpha: 1.0 / (1.0 - theta),
+            zetan,
+            eta: (1.0 - (2.0 / items as f64).powf(1.0 - theta)) / (1.0 - zeta2 / zetan),
+        }
+    }
+
+    fn sample(&self, rng: &mut Rng) -> u64 {
+        let u = rng.unit();
+        let uz = u * self.zetan;
+        if uz < 1.0 {
+            return 0;
+        }
+        if uz < 1.0 + 0.5f64.powf(ZIPFIAN_CONSTANT) {
+            return 1.min(self.items - 1);
+        }
+        let index = (self.items as f64 * (self.eta * u - self.eta + 1.0).powf(self.alpha)) as u64;
+        index.min(self.items - 1)
+    }
+}
+
+#[derive(Default)]
+struct Report {
+    reads: Vec<Duration>,
+    updates: Vec<Duration>,
+    elapsed: Duration,
+}
+
+impl Report {
+    /// 一种操作一行，时间都是微秒
+    fn write_csv<W: Write>(mut self, output: &mut W) -> Result<()> {
+        writeln!(
+            output,
+            "operation,count,throughput,min_us,avg_us,p50_us,p95_us,p99_us,max_us"
+        )?;
+        let seconds = self.elapsed.as_secs_f64();
+        for (name, samples) in [("read", &mut self.reads), ("update", &mut self.updates)] {
+            if samples.is_empty() {
+                writeln!(output, "{},0,0,,,,,,", name)?;
+                continue;
+            }
+            samples.sort();
+            let us = |d: &Duration| d.as_secs_f64() * 1e6;
+            let percentile = |p: usize| us(&samples[(samples.len() * p).div_ceil(100) - 1]);
+            writeln!(
+                output,
+                "{},{},{:.1},{:.1},{:.1},{:.1},{:.1},{:.1},{:.1}",
+                name,
+                samples.len(),
+                samples.len() as f64 / seconds,
+                us(&samples[0]),
+                samples.iter().map(us).sum::<f64>() / samples.len() as f64,
+                percentile(50),
+                percentile(95),
+                percentile(99),
+                us(&samples[samples.len() - 1]),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn parse(value: &str, flag: &str) -> Result<u64> {
+    value.parse().map_err(|_| invalid(flag, value))
+}
+
+fn invalid(flag: &str, value: &str) -> KvsError {
+    eprintln!("Invalid value for {}: {}", flag, value);
+    KvsError::InvalidArgument {
+        name: flag.to_string(),
+        value: value.to_string(),
+    }
+}
@@ -0,0 +1,82 @@
+//! 很大的value不进segment，单独放在 `blobs/` 下面一个文件里，segment里只记一条 `SetBlob` 说value在哪个blob里
+//!
+//! blob写好了就不会再改，跟segment一样只会被compact删掉
+
+use crate::fs;
+use crate::fs::Context;
+use crate::Result;
+
+use std::fs::File;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+pub(crate) const DIR: &str = "blobs";
+
+/// `set_from_reader` 读到这么多还没读完的话就写成blob，短的还是照常写进segment
+pub(crate) const THRESHOLD: usize = 1 << 20;
+
+pub(crate) fn path(root: &Path, id: u64) -> PathBuf {
+    root.join(DIR).join(format!("{}.blob", id))
+}
+
+/// 目录下所有的blob。还没有 `blobs/` 的话就是没有
+pub(crate) fn list(root: &Path) -> Result<Vec<u64>> {
+    let dir = root.join(DIR);
+    if !dir.exists() {
+        return Ok(vec![]);
+    }
+    let mut blobs = vec![];
+    for entry in std::fs::read_dir(&dir).at(&dir)? {
+        let path = entry.at(&dir)?.path();
+        if path.extension().map(|v| v == "blob").unwrap_or(false) {
+            if let Some(id) = path
+                .file_stem()
+                .and_then(|v| v.to_str())
+                .and_then(|v| v.parse().ok())
+            {
+                blobs.push(id);
+            }
+        }
+    }
+    blobs.sort_unstable();
+    Ok(blobs)
+}
+
+/// 写一个新的blob：先是已经读进内存的 `head` ，然后是 `reader` 里剩下的。返回一共多少字节
+///
+/// 先写临时文件，fsync了再rename过去。segment里的 `SetBlob` 是之后才写的，不管durability怎么设，它指着的blob都一定已经在磁盘上了
+pub(crate) fn write(root: &Path, id: u64, head: &[u8], reader: &mut dyn Read) -> Result<u64> {
+    let dir = root.join(DIR);
+    std::fs::create_dir_all(&dir).at(&dir)?;
+    let path = path(root, id);
+    let temporary = fs::temporary_path(&path);
+    let result = (|| -> Result<u64> {
+        let mut file = File::create(&temporary).at(&temporary)?;
+        file.write_all(head).at(&temporary)?;
+        let len = head.len() as u64 + std::io::copy(reader, &mut file)?; // 出错的可能是 `reader` ，不知道怪谁
+        file.sync_all().at(&temporary)?;
+        Ok(len)
+    })();
+    let len = match result {
+        Ok(len) => len,
+        Err(e) => {
+            fs::remove(&temporary)?; // 读到一半出错了，写了一半的不要
+            return Err(e);
+        }
+    };
+    fs::replace(&temporary, &path)?;
+    fs::sync_dir(&dir)?;
+    Ok(len)
+}
+
+pub(crate) fn open(root: &Path, id: u64) -> Result<File> {
+    let path = path(root, id);
+    File::open(&path).at(&path)
+}
+
+pub(crate) fn read(root: &Path, id: u64) -> Result<Vec<u8>> {
+    let path = path(root, id);
+    std::fs::read(&path).at(&path)
+}
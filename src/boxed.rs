@@ -0,0 +1,218 @@
+//! `Box<dyn KvsEngine>` ，用哪个engine可以到运行的时候再决定，比如 `let engine: Box<dyn KvsEngine> = Box::new(MemEngine::new())`
+
+use crate::BatchOp;
+use crate::Dump;
+use crate::Iter;
+use crate::KeyFilter;
+use crate::KeyMeta;
+use crate::Keys;
+use crate::KvsEngine;
+use crate::Op;
+use crate::OpResult;
+use crate::Result;
+use crate::Scan;
+use crate::StoreStats;
+use crate::ValueReader;
+use crate::Watcher;
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::ops::Bound;
+use std::time::Duration;
+
+/// `Clone` 要求 `Sized` ，trait object没法直接clone，只能clone出一个新的box。实现了 `Clone` 的engine自动就有
+pub trait BoxClone {
+    fn box_clone(&self) -> Box<dyn KvsEngine>;
+}
+
+impl<E: KvsEngine + Clone> BoxClone for E {
+    fn box_clone(&self) -> Box<dyn KvsEngine> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<dyn KvsEngine> {
+    fn clone(&self) -> Self {
+        (**self).box_clone()
+    }
+}
+
+/// 有默认实现的方法也要一个一个转过去，不然里面engine自己实现的就被默认实现盖掉了
+impl KvsEngine for Box<dyn KvsEngine> {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        (**self).get(key)
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        (**self).get_bytes(key)
+    }
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        (**self).set_bytes(key, value)
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        (**self).remove_bytes(key)
+    }
+
+    fn multi_get(&self, keys: &[&str]) -> Result<Vec<Option<String>>> {
+        (**self).multi_get(keys)
+    }
+
+    fn set(&self, key: String, value: String) -> Result<()> {
+        (**self).set(key, value)
+    }
+
+    fn remove(&self, key: &str) -> Result<()> {
+        (**self).remove(key)
+    }
+
+    fn get_reader(&self, key: &[u8]) -> Result<Option<ValueReader<'_>>> {
+        (**self).get_reader(key)
+    }
+
+    fn set_from_reader(&self, key: Vec<u8>, reader: &mut dyn Read) -> Result<()> {
+        (**self).set_from_reader(key, reader)
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        (**self).set_bytes_with_ttl(key, value, ttl)
+    }
+
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        (**self).set_with_ttl(key, value, ttl)
+    }
+
+    fn ttl(&self, key: &str) -> Result<Option<Duration>> {
+        (**self).ttl(key)
+    }
+
+    fn write_batch(&self, ops: Vec<BatchOp>) -> Result<()> {
+        (**self).write_batch(ops)
+    }
+
+    fn write_batch_if(
+        &self,
+        expected: &BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+        ops: Vec<BatchOp>,
+    ) -> Result<()> {
+        (**self).write_batch_if(expected, ops)
+    }
+
+    fn apply(&self, ops: &[Op]) -> Result<Vec<OpResult>> {
+        (**self).apply(ops)
+    }
+
+    fn clear(&self) -> Result<()> {
+        (**self).clear()
+    }
+
+    fn metadata(&self, key: &str) -> Result<Option<KeyMeta>> {
+        (**self).metadata(key)
+    }
+
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        (**self).contains_key(key)
+    }
+
+    fn scan_matching(&self, filter: &KeyFilter) -> Result<Vec<(String, String)>> {
+        (**self).scan_matching(filter)
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Scan<'_>> {
+        (**self).scan_prefix(prefix)
+    }
+
+    fn remove_matching(&self, filter: &KeyFilter) -> Result<u64> {
+        (**self).remove_matching(filter)
+    }
+
+    fn scan_bounds(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Result<Scan<'_>> {
+        (**self).scan_bounds(start, end)
+    }
+
+    fn watch(&self, prefix: &[u8]) -> Result<Watcher> {
+        (**self).watch(prefix)
+    }
+
+    fn keys(&self) -> Result<Keys<'_>> {
+        (**self).keys()
+    }
+
+    fn iter(&self) -> Result<Iter<'_>> {
+        (**self).iter()
+    }
+
+    fn len(&self) -> Result<usize> {
+        (**self).len()
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        (**self).is_empty()
+    }
+
+    fn getdel(&self, key: &str) -> Result<Option<String>> {
+        (**self).getdel(key)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        (**self).rename(from, to)
+    }
+
+    fn rename_nx(&self, from: &str, to: &str) -> Result<bool> {
+        (**self).rename_nx(from, to)
+    }
+
+    fn copy(&self, from: &str, to: &str) -> Result<()> {
+        (**self).copy(from, to)
+    }
+
+    fn append(&self, key: &str, suffix: &str) -> Result<usize> {
+        (**self).append(key, suffix)
+    }
+
+    fn merge(&self, key: &[u8], operand: Vec<u8>) -> Result<()> {
+        (**self).merge(key, operand)
+    }
+
+    fn flush(&self) -> Result<()> {
+        (**self).flush()
+    }
+
+    fn engine_name(&self) -> &'static str {
+        (**self).engine_name()
+    }
+
+    fn stats(&self) -> Result<StoreStats> {
+        (**self).stats()
+    }
+
+    fn getset(&self, key: String, value: String) -> Result<Option<String>> {
+        (**self).getset(key, value)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: Option<&str>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        (**self).compare_and_swap(key, expected, new)
+    }
+
+    fn set_nx(&self, key: String, value: String) -> Result<bool> {
+        (**self).set_nx(key, value)
+    }
+
+    fn dump(&self, key: &str) -> Result<Option<Dump>> {
+        (**self).dump(key)
+    }
+
+    fn restore(&self, dump: Dump, replace: bool) -> Result<()> {
+        (**self).restore(dump, replace)
+    }
+
+    fn bulk_load_iter(&self, pairs: &mut dyn Iterator<Item = (String, String)>) -> Result<usize> {
+        (**self).bulk_load_iter(pairs)
+    }
+}
@@ -0,0 +1,112 @@
+use crate::KvsError;
+use crate::Result;
+
+use serde::de::SeqAccess;
+use serde::de::Visitor;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use serde::Serializer;
+
+use std::fmt;
+
+/// 磁盘上和网络上的key、value
+///
+/// 是utf8的话就编码成json字符串，和以前只支持String的时候一模一样，老的segment和老的客户端都不受影响；不是utf8的话才编码成数字数组
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub(crate) struct Bytes(pub(crate) Vec<u8>);
+
+impl From<String> for Bytes {
+    fn from(string: String) -> Self {
+        Bytes(string.into_bytes())
+    }
+}
+
+impl From<Vec<u8>> for Bytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        Bytes(bytes)
+    }
+}
+
+impl Serialize for Bytes {
+    fn serialize<S>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        if !serializer.is_human_readable() {
+            return serializer.serialize_bytes(&self.0[..]); // bincode这种本来就能放二进制的
+        }
+        match std::str::from_utf8(&self.0[..]) {
+            Ok(string) => serializer.serialize_str(string),
+            Err(_) => serializer.collect_seq(self.0.iter()),
+        }
+    }
+}
+
+struct BytesVisitor;
+
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = Bytes;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a string or an array of bytes")
+    }
+
+    fn visit_str<E>(self, value: &str) -> std::result::Result<Bytes, E> {
+        Ok(Bytes(value.as_bytes().to_vec()))
+    }
+
+    fn visit_string<E>(self, value: String) -> std::result::Result<Bytes, E> {
+        Ok(Bytes(value.into_bytes()))
+    }
+
+    fn visit_bytes<E>(self, value: &[u8]) -> std::result::Result<Bytes, E> {
+        Ok(Bytes(value.to_vec()))
+    }
+
+    fn visit_byte_buf<E>(self, value: Vec<u8>) -> std::result::Result<Bytes, E> {
+        Ok(Bytes(value))
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> std::result::Result<Bytes, A::Error>
+    where
+        A: SeqAccess<'de>,
+    {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(Bytes(bytes))
+    }
+}
+
+impl<'de> Deserialize<'de> for Bytes {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        if !deserializer.is_human_readable() {
+            return deserializer.deserialize_byte_buf(BytesVisitor);
+        }
+        deserializer.deserialize_any(BytesVisitor)
+    }
+}
+
+/// 用String的API去读一个不是utf8的value的时候报错
+pub(crate) fn as_str<'a>(key: &[u8], value: &'a [u8]) -> Result<&'a str> {
+    std::str::from_utf8(value).map_err(|_| KvsError::NotUtf8 {
+        key: String::from_utf8_lossy(key).into_owned(),
+    })
+}
+
+/// 同上，owned的版本
+pub(crate) fn into_string(key: &[u8], value: Vec<u8>) -> Result<String> {
+    String::from_utf8(value).map_err(|_| KvsError::NotUtf8 {
+        key: String::from_utf8_lossy(key).into_owned(),
+    })
+}
+
+/// 报错的时候显示key用的
+pub(crate) fn lossy(key: &[u8]) -> String {
+    String::from_utf8_lossy(key).into_owned()
+}
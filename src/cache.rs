@@ -0,0 +1,509 @@
+use crate::bytes;
+use crate::lru::Lru;
+use crate::now_millis;
+use crate::BatchOp;
+use crate::KeyFilter;
+use crate::KeyMeta;
+use crate::Keys;
+use crate::KvsEngine;
+use crate::KvsError;
+use crate::Result;
+use crate::Scan;
+use crate::StoreStats;
+use crate::Watcher;
+
+use log::error;
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::ops::Bound;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::Duration;
+use std::time::Instant;
+
+/// write-behind的配置。积攒了 `max_pending` 个写，或者最老的一个写已经等了 `max_staleness` 了，就一口气写回底下的engine
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WriteBehind {
+    pub max_pending: usize,
+    pub max_staleness: Duration,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// 最多缓存多少个value
+    pub capacity: usize,
+    /// `None` 表示write-through，每个写都直接写到底下的engine
+    pub write_behind: Option<WriteBehind>,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            write_behind: None,
+        }
+    }
+}
+
+/// cache里的一个value，`expires` 和 `KeyMeta` 里的一样，0表示永不过期
+struct Cached {
+    value: Vec<u8>,
+    expires: u64,
+}
+
+impl Cached {
+    fn is_expired(&self) -> bool {
+        self.expires != 0 && self.expires <= now_millis()
+    }
+}
+
+/// 套在任何engine外面的缓存，读的时候read-through，写的时候可以选write-through或者write-behind
+///
+/// write-behind的时候有一个后台线程，每隔 `max_staleness` 的一半看一眼，一直没人来操作的话积攒的写最多也就等1.5个 `max_staleness` 。最后一个clone drop的时候后台线程停下来，把剩下的写回去
+///
+/// cache和pending都在一把锁里面，对底下engine的操作也拿着这把锁做，不然cache会和底下对不上。clone出来的共用一个cache
+#[derive(Clone)]
+pub struct CachedEngine<E: KvsEngine> {
+    inner: E,
+    state: Arc<Mutex<State>>,
+    write_behind: Option<WriteBehind>,
+    /// write-through的时候没有
+    flusher: Option<Arc<Flusher>>,
+}
+
+struct State {
+    cache: Lru<Vec<u8>, Cached>,
+    /// 还没写回去的写，`None` 表示remove。同一个key的多次写只留最后一个
+    pending: HashMap<Vec<u8>, Option<Vec<u8>>>,
+    /// 最老的那个还没写回去的写是什么时候来的
+    oldest: Option<Instant>,
+    /// 读的时候不用去底下的engine的次数，pending里读到的也算
+    hits: u64,
+    misses: u64,
+}
+
+/// 后台写回的线程。所有clone共用一个，最后一个clone drop的时候它也drop，叫线程停下来再等它写完
+struct Flusher {
+    /// `true` 是该停了
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Flusher {
+    fn spawn<E: KvsEngine>(inner: E, state: Arc<Mutex<State>>, config: WriteBehind) -> Self {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let signal = stop.clone();
+        let interval = (config.max_staleness / 2).max(Duration::from_millis(1));
+        let thread = thread::spawn(move || {
+            let (lock, condvar) = &*signal;
+            let mut stopped = lock.lock().unwrap();
+            while !*stopped {
+                stopped = condvar.wait_timeout(stopped, interval).unwrap().0;
+                let mut state = state.lock().unwrap();
+                let stale = state
+                    .oldest
+                    .is_some_and(|v| v.elapsed() >= config.max_staleness);
+                if *stopped || stale {
+                    if let Err(e) = write_back(&inner, &mut state) {
+                        error!("Failed to flush pending writes: {}", e); // 留在pending里，下次再试
+                    }
+                }
+            }
+        });
+        Self {
+            stop,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for Flusher {
+    fn drop(&mut self) {
+        let (lock, condvar) = &*self.stop;
+        *lock.lock().unwrap() = true;
+        condvar.notify_one();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl<E: KvsEngine + Clone> CachedEngine<E> {
+    pub fn new(inner: E, config: CacheConfig) -> Self {
+        let state = Arc::new(Mutex::new(State {
+            cache: Lru::new(config.capacity),
+            pending: HashMap::new(),
+            oldest: None,
+            hits: 0,
+            misses: 0,
+        }));
+        let flusher = config
+            .write_behind
+            .clone()
+            .map(|v| Arc::new(Flusher::spawn(inner.clone(), state.clone(), v)));
+        Self {
+            inner,
+            state,
+            write_behind: config.write_behind,
+            flusher,
+        }
+    }
+}
+
+impl<E: KvsEngine> CachedEngine<E> {
+    /// 直接绕过cache去动底下的engine的话，cache里的东西可能就不对了，小心
+    pub fn inner_mut(&mut self) -> &mut E {
+        &mut self.inner
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    fn write_back(&self, state: &mut State) -> Result<()> {
+        write_back(&self.inner, state)
+    }
+
+    fn maybe_flush(&self, state: &mut State) -> Result<()> {
+        if let (Some(config), Some(oldest)) = (&self.write_behind, state.oldest) {
+            if state.pending.len() >= config.max_pending || oldest.elapsed() >= config.max_staleness
+            {
+                return self.write_back(state);
+            }
+        }
+        Ok(())
+    }
+
+    fn stage(&self, state: &mut State, key: Vec<u8>, value: Option<Vec<u8>>) -> Result<()> {
+        state.cache.remove(&key[..]); // pending里的永远比cache里的新
+        state.pending.insert(key, value);
+        if state.oldest.is_none() {
+            state.oldest = Some(Instant::now());
+        }
+        self.maybe_flush(state)
+    }
+
+    /// 先看pending，再看cache，最后才去底下的engine读，读到了顺便放进cache
+    fn lookup(&self, state: &mut State, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.maybe_flush(state)?;
+
+        // 先看有没有还没写回去的
+        if let Some(value) = state.pending.get(key) {
+            let value = value.clone();
+            state.hits += 1;
+            return Ok(value);
+        }
+
+        if state.cache.contains(key) {
+            state.hits += 1;
+            if state.cache.peek(key).is_some_and(|v| v.is_expired()) {
+                state.cache.remove(key); // 过期了，底下的engine也会当它不存在
+                return Ok(None);
+            }
+            return Ok(state.cache.get(key).map(|v| v.value.clone()));
+        }
+
+        // cache没有，去底下的engine读
+        state.misses += 1;
+        let value = match self.inner.get_bytes(key)? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        // 还得知道什么时候过期，不然过期了cache里还留着。可惜metadata只有String的API，不是utf8的key就不放进cache了
+        let expires = match std::str::from_utf8(key) {
+            Ok(k) => self.inner.metadata(k)?.map(|v| v.expires).unwrap_or(0),
+            Err(_) => return Ok(Some(value)),
+        };
+        state.cache.insert(
+            key.to_vec(),
+            Cached {
+                value: value.clone(),
+                expires,
+            },
+        );
+        Ok(Some(value))
+    }
+}
+
+impl<E: KvsEngine + Clone> KvsEngine for CachedEngine<E> {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        self.get_bytes(key.as_bytes())?
+            .map(|v| bytes::into_string(key.as_bytes(), v))
+            .transpose()
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.lookup(&mut self.state(), key)
+    }
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let mut state = self.state();
+        if self.write_behind.is_some() {
+            self.stage(&mut state, key, Some(value))
+        } else {
+            self.inner.set_bytes(key.clone(), value.clone())?;
+            state.cache.insert(key, Cached { value, expires: 0 });
+            Ok(())
+        }
+    }
+
+    /// 带过期时间的写不攒着，直接写下去，不然flush的时候就只剩普通的set了
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let mut state = self.state();
+        let staged = state.pending.remove(&key[..]); // 反正要被覆盖了
+        if let Err(e) = self
+            .inner
+            .set_bytes_with_ttl(key.clone(), value.clone(), ttl)
+        {
+            if let Some(staged) = staged {
+                state.pending.insert(key, staged);
+            }
+            return Err(e);
+        }
+        let expires = now_millis().saturating_add(ttl.as_millis() as u64);
+        state.cache.insert(key, Cached { value, expires });
+        Ok(())
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        let mut state = self.state();
+        if self.write_behind.is_some() {
+            // 先确认key确实存在，不然remove不存在的key要报错
+            if self.lookup(&mut state, key)?.is_some() {
+                self.stage(&mut state, key.to_vec(), None)
+            } else {
+                Err(KvsError::NotFound {
+                    key: bytes::lossy(key),
+                })
+            }
+        } else {
+            self.inner.remove_bytes(key)?;
+            state.cache.remove(key);
+            Ok(())
+        }
+    }
+
+    /// 积攒的写先写回去再让底下比较，比较完不管成没成功cache里的都扔掉
+    fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: Option<&str>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let mut state = self.state();
+        self.write_back(&mut state)?;
+        let swapped = self.inner.compare_and_swap(key, expected, new)?;
+        state.cache.remove(key.as_bytes());
+        Ok(swapped)
+    }
+
+    /// 默认实现的读和写各拿一次锁，中间会插进别的写。和 `compare_and_swap` 一样先写回去再交给底下的engine
+    fn append(&self, key: &str, suffix: &str) -> Result<usize> {
+        let mut state = self.state();
+        self.write_back(&mut state)?;
+        let len = self.inner.append(key, suffix)?;
+        state.cache.remove(key.as_bytes());
+        Ok(len)
+    }
+
+    fn write_batch(&self, ops: Vec<BatchOp>) -> Result<()> {
+        let mut state = self.state();
+        self.write_back(&mut state)?; // batch不能拆开放进pending，不然flush的时候就不是原子的了
+        let keys: Vec<Vec<u8>> = ops.iter().map(|v| v.key().to_vec()).collect();
+        self.inner.write_batch(ops)?;
+        for key in keys {
+            state.cache.remove(&key[..]); // 下次读的时候再从底下读
+        }
+        Ok(())
+    }
+
+    /// 先把pending写回去，底下的engine对value的时候才看得到
+    fn write_batch_if(
+        &self,
+        expected: &BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+        ops: Vec<BatchOp>,
+    ) -> Result<()> {
+        let mut state = self.state();
+        self.write_back(&mut state)?;
+        let keys: Vec<Vec<u8>> = ops.iter().map(|v| v.key().to_vec()).collect();
+        self.inner.write_batch_if(expected, ops)?;
+        for key in keys {
+            state.cache.remove(&key[..]);
+        }
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<()> {
+        let mut state = self.state();
+        self.write_back(&mut state)?;
+        self.inner.clear()?;
+        state.cache.clear();
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let mut state = self.state();
+        self.write_back(&mut state)?; // 让底下的engine自己去改名，元数据才能留着
+        self.inner.rename(from, to)?;
+        state.cache.remove(to.as_bytes());
+        if let Some(value) = state.cache.remove(from.as_bytes()) {
+            state.cache.insert(to.as_bytes().to_vec(), value);
+        }
+        Ok(())
+    }
+
+    fn copy(&self, from: &str, to: &str) -> Result<()> {
+        let mut state = self.state();
+        self.write_back(&mut state)?;
+        self.inner.copy(from, to)?;
+        state.cache.remove(to.as_bytes());
+        Ok(())
+    }
+
+    /// 叠完是什么样只有下面知道，cache里的扔掉
+    fn merge(&self, key: &[u8], operand: Vec<u8>) -> Result<()> {
+        let mut state = self.state();
+        self.write_back(&mut state)?;
+        self.inner.merge(key, operand)?;
+        state.cache.remove(key);
+        Ok(())
+    }
+
+    /// 积攒的写先写回去，再让底下的engine落盘
+    fn flush(&self) -> Result<()> {
+        self.write_back(&mut self.state())?;
+        self.inner.flush()
+    }
+
+    fn engine_name(&self) -> &'static str {
+        self.inner.engine_name()
+    }
+
+    /// 命中率是这一层cache的，底下engine自己的cache不算
+    fn stats(&self) -> Result<StoreStats> {
+        let mut state = self.state();
+        self.write_back(&mut state)?;
+        Ok(StoreStats {
+            cache_hits: state.hits,
+            cache_misses: state.misses,
+            ..self.inner.stats()?
+        })
+    }
+
+    fn rename_nx(&self, from: &str, to: &str) -> Result<bool> {
+        let mut state = self.state();
+        self.write_back(&mut state)?;
+        let renamed = self.inner.rename_nx(from, to)?;
+        if renamed {
+            if let Some(value) = state.cache.remove(from.as_bytes()) {
+                state.cache.insert(to.as_bytes().to_vec(), value);
+            }
+        }
+        Ok(renamed)
+    }
+
+    fn metadata(&self, key: &str) -> Result<Option<KeyMeta>> {
+        let mut state = self.state();
+        if state.pending.contains_key(key.as_bytes()) {
+            self.write_back(&mut state)?; // 还没写回去的写没有元数据，只好先写回去
+        }
+        self.inner.metadata(key)
+    }
+
+    /// 不往cache里放东西
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        let state = self.state();
+        match state.pending.get(key.as_bytes()) {
+            Some(value) => Ok(value.is_some()),
+            None => self.inner.contains_key(key),
+        }
+    }
+
+    fn scan_matching(&self, filter: &KeyFilter) -> Result<Vec<(String, String)>> {
+        self.write_back(&mut self.state())?; // 不然pending里的key扫不到
+        self.inner.scan_matching(filter)
+    }
+
+    fn scan_bounds(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Result<Scan<'_>> {
+        self.write_back(&mut self.state())?;
+        self.inner.scan_bounds(start, end)
+    }
+
+    /// write-behind积攒着的写要等写回底下的engine的时候才会收到
+    fn watch(&self, prefix: &[u8]) -> Result<Watcher> {
+        self.inner.watch(prefix)
+    }
+
+    fn keys(&self) -> Result<Keys<'_>> {
+        self.write_back(&mut self.state())?;
+        self.inner.keys()
+    }
+
+    fn len(&self) -> Result<usize> {
+        self.write_back(&mut self.state())?;
+        self.inner.len()
+    }
+
+    fn bulk_load_iter(&self, pairs: &mut dyn Iterator<Item = (String, String)>) -> Result<usize> {
+        // 先把积攒的写都写回去，不然之后flush的时候会把新导入的覆盖掉
+        let mut state = self.state();
+        self.write_back(&mut state)?;
+        let count = self.inner.bulk_load(pairs)?;
+        state.cache.clear(); // 不知道导入了哪些key，干脆全扔掉
+        Ok(count)
+    }
+}
+
+/// 把积攒的写全部写回底下的engine
+fn write_back<E: KvsEngine>(inner: &E, state: &mut State) -> Result<()> {
+    let pending: Vec<_> = state.pending.drain().collect();
+    let mut iter = pending.into_iter();
+    while let Some((key, value)) = iter.next() {
+        let result = match &value {
+            Some(v) => inner.set_bytes(key.clone(), v.clone()),
+            None => match inner.remove_bytes(&key[..]) {
+                Err(KvsError::NotFound { .. }) => Ok(()), // set和remove合并之后，底下的engine可能根本没见过这个key
+                v => v,
+            },
+        };
+        if let Err(e) = result {
+            // 没写回去的放回去，下次再试
+            state.pending.insert(key, value);
+            state.pending.extend(iter);
+            return Err(e);
+        }
+        if let Some(v) = value {
+            state.cache.insert(
+                key,
+                Cached {
+                    value: v,
+                    expires: 0,
+                },
+            ); // 普通的set会清掉过期时间
+        }
+    }
+    state.oldest = None;
+    Ok(())
+}
+
+impl<E: KvsEngine> Drop for CachedEngine<E> {
+    fn drop(&mut self) {
+        if self.flusher.is_some() {
+            return; // 后台线程手里也有一份state，最后一个clone drop掉的时候 `Flusher` 跟着drop，线程停下来之前会写回去
+        }
+        if Arc::strong_count(&self.state) > 1 {
+            return; // 别的clone还在用，等最后一个再写回去
+        }
+        // 尽力而为，写不回去也没办法了
+        if let Ok(mut state) = self.state.lock() {
+            if let Err(e) = self.write_back(&mut state) {
+                error!("Failed to flush pending writes: {}", e);
+            }
+        }
+    }
+}
@@ -0,0 +1,30 @@
+//! 就是zlib那个CRC32，dump的blob和segment里的每条记录都用它，懒得为这个加依赖
+
+/// 编译的时候就把表算好
+const TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+pub(crate) fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc = (crc >> 8) ^ TABLE[((crc ^ *byte as u32) & 0xff) as usize];
+    }
+    !crc
+}
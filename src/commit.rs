@@ -0,0 +1,81 @@
+//! 组提交： `Durability::Always` 的时候好几个写一起等一次fsync，不然一秒最多也就写盘能fsync的那么多次
+//!
+//! 每个写追加完拿一个号，然后等自己的号被fsync过。没人在fsync的话自己去fsync，这次fsync把到现在为止追加过的都带上；
+//! 有人在fsync的话就等着，它完了以后还没轮到自己的话再由某一个人去fsync下一批
+
+use crate::Result;
+
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+
+#[derive(Debug, Default)]
+struct State {
+    /// 追加过几个写了，也就是最后发出去的号
+    appended: u64,
+    /// 这个号和之前的写都fsync过了
+    synced: u64,
+    /// 有人正在fsync
+    syncing: bool,
+}
+
+/// clone出来的是同一个队列
+#[derive(Clone, Debug, Default)]
+pub(crate) struct GroupCommit {
+    shared: Arc<(Mutex<State>, Condvar)>,
+}
+
+impl GroupCommit {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// 追加完一个写（一个batch算一个），拿一个号
+    pub(crate) fn appended(&self) -> u64 {
+        let mut state = self.shared.0.lock().unwrap();
+        state.appended += 1;
+        state.appended
+    }
+
+    /// 等到 `ticket` 和它之前的写都fsync过了再返回。 `sync` 是fsync当前segment
+    ///
+    /// fsync失败的话这一批都没有推进，报错返回，等着的人里会有一个再试一次
+    pub(crate) fn wait<F>(&self, ticket: u64, sync: F) -> Result<()>
+    where
+        F: FnOnce() -> Result<()>,
+    {
+        let (lock, condvar) = &*self.shared;
+        let mut state = lock.lock().unwrap();
+        loop {
+            if state.synced >= ticket {
+                return Ok(());
+            }
+            if !state.syncing {
+                break;
+            }
+            state = condvar.wait(state).unwrap();
+        }
+
+        // 轮到自己fsync，锁放开，让后面的写接着追加
+        state.syncing = true;
+        let target = state.appended;
+        drop(state);
+        let result = sync();
+
+        let mut state = lock.lock().unwrap();
+        state.syncing = false;
+        if result.is_ok() {
+            state.synced = state.synced.max(target);
+        }
+        condvar.notify_all();
+        result
+    }
+
+    /// 换segment之前老的已经整个fsync过了，发出去的号都算fsync过
+    pub(crate) fn synced_all(&self) {
+        let (lock, condvar) = &*self.shared;
+        let mut state = lock.lock().unwrap();
+        state.synced = state.appended;
+        condvar.notify_all();
+    }
+}
@@ -0,0 +1,123 @@
+//! 什么时候compact、一次compact哪几个segment
+//!
+//! 一下子把所有segment重写一遍，数据多了以后碰上compact的那个写会卡很久。size-tiered是把大小差不多的segment攒够几个再合成一个大的，
+//! 每次只动几个segment，越大的segment越少被重写
+
+use serde::Serialize;
+
+use std::time::Duration;
+
+/// `KvStoreOptions::compaction`
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum CompactionPolicy {
+    /// 没用的字节攒够了就把所有segment合成一个，和以前一样
+    #[default]
+    Full,
+    /// 大小在同一档的segment攒够 `min_merge` 个，就把最老的最多 `max_merge` 个合成一个。
+    /// 第0档是小于 `ratio` 个segment大小（4MB）的，往上每一档大 `ratio` 倍
+    ///
+    /// 正在写的segment不算。合起来的segment里被覆盖、被删掉的记录就扔掉了，但是老segment里可能还有同一个key，所以删除要留一个墓碑
+    SizeTiered {
+        min_merge: usize,
+        max_merge: usize,
+        ratio: u64,
+    },
+}
+
+impl CompactionPolicy {
+    /// 默认的size-tiered：4个合一个，每档大4倍
+    pub fn size_tiered() -> Self {
+        CompactionPolicy::SizeTiered {
+            min_merge: 4,
+            max_merge: 4,
+            ratio: 4,
+        }
+    }
+}
+
+/// `KvStore::compact` 干了什么
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompactionReport {
+    /// 合掉了几个segment，合出来的那个不算
+    pub segments_rewritten: usize,
+    /// 目录比compact之前小了多少。有快照钉着的老segment还没删，不算在里面
+    pub bytes_reclaimed: u64,
+    pub duration: Duration,
+}
+
+/// compact到哪了，要重写的key一共多少个、写完了几个
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CompactionProgress {
+    pub keys_done: usize,
+    pub keys_total: usize,
+}
+
+/// 多大的segment在第几档
+fn tier(size: u64, base: u64, ratio: u64) -> u32 {
+    let ratio = ratio.max(2);
+    let mut tier = 0;
+    let mut limit = base.saturating_mul(ratio);
+    while size >= limit && limit != u64::MAX {
+        tier += 1;
+        limit = limit.saturating_mul(ratio);
+    }
+    tier
+}
+
+/// 攒够了的档，从小到大。`segments` 是已经写完了的segment的编号和大小，从老到新
+fn ready(segments: &[(u64, u64)], base: u64, min_merge: usize, ratio: u64) -> Vec<Vec<(u64, u64)>> {
+    let mut tiers: Vec<(u32, Vec<(u64, u64)>)> = vec![];
+    for (id, size) in segments {
+        let t = tier(*size, base, ratio);
+        match tiers.iter_mut().find(|(v, _)| *v == t) {
+            Some((_, members)) => members.push((*id, *size)),
+            None => tiers.push((t, vec![(*id, *size)])),
+        }
+    }
+    tiers.sort_by_key(|(t, _)| *t);
+    tiers
+        .into_iter()
+        .map(|(_, members)| members)
+        .filter(|members| members.len() >= min_merge.max(2)) // 一个segment自己合自己没意义
+        .collect()
+}
+
+/// size-tiered下一次要合哪几个segment，没有攒够的档就是 `None` 。小的档先合，合出来的可能又让大的档攒够了
+pub(crate) fn pick(
+    policy: CompactionPolicy,
+    segments: &[(u64, u64)],
+    base: u64,
+) -> Option<Vec<u64>> {
+    match policy {
+        CompactionPolicy::Full => None,
+        CompactionPolicy::SizeTiered {
+            min_merge,
+            max_merge,
+            ratio,
+        } => ready(segments, base, min_merge, ratio)
+            .into_iter()
+            .next()
+            .map(|members| {
+                members
+                    .into_iter()
+                    .take(max_merge.max(2))
+                    .map(|(id, _)| id)
+                    .collect()
+            }),
+    }
+}
+
+/// 攒够了、等着被合的segment一共多少字节。`Full` 的话 `due` 是没用的字节够不够compact了，够了的话所有segment都要重写
+pub(crate) fn debt(policy: CompactionPolicy, segments: &[(u64, u64)], base: u64, due: bool) -> u64 {
+    match policy {
+        CompactionPolicy::Full if due => segments.iter().map(|(_, size)| size).sum(),
+        CompactionPolicy::Full => 0,
+        CompactionPolicy::SizeTiered {
+            min_merge, ratio, ..
+        } => ready(segments, base, min_merge, ratio)
+            .iter()
+            .flatten()
+            .map(|(_, size)| size)
+            .sum(),
+    }
+}
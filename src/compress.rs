@@ -0,0 +1,127 @@
+//! 可选的value压缩。每条压缩过的记录自己带着用的是什么算法，所以中途换算法、关掉压缩都没关系，老的记录照样能读
+//!
+//! segment是一行一行的json，压缩完的二进制直接放进去会变成数字数组，比不压缩还大，所以用base64存
+
+use crate::Result;
+
+use serde::de;
+use serde::Deserialize;
+use serde::Deserializer;
+use serde::Serialize;
+use serde::Serializer;
+
+/// `KvStoreOptions::compression` ，之后新写的value用什么压缩
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Compression {
+    /// 不压缩，和以前一样
+    #[default]
+    None,
+    /// 快，压得没那么小
+    Lz4,
+    /// 慢一点，压得更小
+    Zstd,
+}
+
+/// 比这个短的value压了也省不了几个字节，不压
+const MIN_SIZE: usize = 64;
+
+/// 压缩过的value，segment里就是 `{"algorithm":"Zstd","data":"KLUv/..."}`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Compressed {
+    pub(crate) algorithm: Compression,
+    #[serde(serialize_with = "to_base64", deserialize_with = "from_base64")]
+    pub(crate) data: Vec<u8>,
+}
+
+impl Compressed {
+    /// 压完了（算上base64）没比原来小的话返回 `None` ，还是存原样的
+    pub(crate) fn pack(algorithm: Compression, value: &[u8]) -> Result<Option<Self>> {
+        if value.len() < MIN_SIZE {
+            return Ok(None);
+        }
+        let data = match algorithm {
+            Compression::None => return Ok(None),
+            Compression::Lz4 => lz4_flex::compress_prepend_size(value),
+            Compression::Zstd => zstd::encode_all(value, 0)?, // 0是zstd的默认等级
+        };
+        if data.len().div_ceil(3) * 4 >= value.len() {
+            return Ok(None);
+        }
+        Ok(Some(Self { algorithm, data }))
+    }
+
+    pub(crate) fn unpack(&self) -> Result<Vec<u8>> {
+        match self.algorithm {
+            Compression::None => Ok(self.data.clone()),
+            Compression::Lz4 => lz4_flex::decompress_size_prepended(&self.data[..])
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e).into()),
+            Compression::Zstd => Ok(zstd::decode_all(&self.data[..])?),
+        }
+    }
+}
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// 标准的base64，带 `=` 。和checksum一样，懒得为这个加依赖
+fn encode(bytes: &[u8]) -> String {
+    let mut string = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, byte)| n | (*byte as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                string.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                string.push('=');
+            }
+        }
+    }
+    string
+}
+
+fn digit(c: u8) -> Option<u32> {
+    let digit = match c {
+        b'A'..=b'Z' => c - b'A',
+        b'a'..=b'z' => c - b'a' + 26,
+        b'0'..=b'9' => c - b'0' + 52,
+        b'+' => 62,
+        b'/' => 63,
+        _ => return None,
+    };
+    Some(digit as u32)
+}
+
+fn decode(string: &str) -> Option<Vec<u8>> {
+    let string = string.trim_end_matches('=').as_bytes();
+    if string.len() % 4 == 1 {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(string.len() * 3 / 4);
+    for chunk in string.chunks(4) {
+        let mut n = 0u32;
+        for (i, c) in chunk.iter().enumerate() {
+            n |= digit(*c)? << (18 - 6 * i);
+        }
+        for i in 0..chunk.len() - 1 {
+            bytes.push((n >> (16 - 8 * i)) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+fn to_base64<S>(bytes: &[u8], serializer: S) -> std::result::Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(&encode(bytes))
+}
+
+fn from_base64<'de, D>(deserializer: D) -> std::result::Result<Vec<u8>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let string = String::deserialize(deserializer)?;
+    decode(&string[..]).ok_or_else(|| de::Error::custom("malformed base64"))
+}
@@ -0,0 +1,72 @@
+use crate::checksum::crc32;
+use crate::KeyMeta;
+use crate::KvsError;
+use crate::Result;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+/// 导出格式的版本号，以后改格式的时候要加一
+const DUMP_VERSION: u32 = 1;
+
+/// 一个key导出来的样子，可以拿到另一个服务器上restore
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Dump {
+    pub key: String,
+    pub value: String,
+    /// 导出的时候的元数据，只是给人看的，restore的时候会重新算
+    pub meta: KeyMeta,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    version: u32,
+    dump: Dump,
+}
+
+impl Dump {
+    /// 编码成一串十六进制，最后8个字符是CRC32。全是 `[0-9a-f]` ，放在命令行和管道里都不用转义
+    pub fn to_blob(&self) -> Result<String> {
+        let json = serde_json::to_vec(&Envelope {
+            version: DUMP_VERSION,
+            dump: self.clone(),
+        })?;
+        let mut blob = String::with_capacity(json.len() * 2 + 8);
+        for byte in &json {
+            blob.push_str(&format!("{:02x}", byte));
+        }
+        blob.push_str(&format!("{:08x}", crc32(&json)));
+        Ok(blob)
+    }
+
+    pub fn from_blob(blob: &str) -> Result<Self> {
+        let blob = blob.trim(); // 从管道里读进来多半带个换行
+        if blob.len() < 8 || !blob.len().is_multiple_of(2) || !blob.is_ascii() {
+            return Err(bad("malformed blob"));
+        }
+        let (body, checksum) = blob.split_at(blob.len() - 8);
+        let checksum = u32::from_str_radix(checksum, 16).map_err(|_| bad("malformed checksum"))?;
+
+        let mut json = Vec::with_capacity(body.len() / 2);
+        for i in (0..body.len()).step_by(2) {
+            let byte =
+                u8::from_str_radix(&body[i..i + 2], 16).map_err(|_| bad("malformed blob"))?;
+            json.push(byte);
+        }
+        if crc32(&json) != checksum {
+            return Err(bad("checksum mismatch"));
+        }
+
+        let envelope: Envelope = serde_json::from_slice(&json)?;
+        if envelope.version != DUMP_VERSION {
+            return Err(bad(&format!("unsupported version {}", envelope.version)));
+        }
+        Ok(envelope.dump)
+    }
+}
+
+fn bad(reason: &str) -> KvsError {
+    KvsError::BadDump {
+        reason: reason.to_string(),
+    }
+}
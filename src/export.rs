@@ -0,0 +1,140 @@
+//! 整个store导出成一个文件，再导进另一个store。和 `dump` 不一样，这个是给嵌进别的程序里用的，一次导出所有key
+//!
+//! 格式是一行一个json，第一行是版本，后面每行一个key：
+//!
+//! ```text
+//! {"version":1}
+//! {"key":"a","value":"1"}
+//! {"key":[255,0],"value":"2","expires":1700000000000}
+//! ```
+//!
+//! key和value是utf8的话就是字符串，不是的话是数字数组，和segment里一样
+
+use crate::bytes::Bytes;
+use crate::now_millis;
+use crate::view;
+use crate::BatchOp;
+use crate::KvStore;
+use crate::KvsEngine;
+use crate::KvsError;
+use crate::Result;
+use crate::BULK_BATCH;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+use std::time::Duration;
+
+/// 导出格式的版本号，以后改格式的时候要加一
+const EXPORT_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    version: u32,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Record {
+    key: Bytes,
+    value: Bytes,
+    /// 什么时候过期，和 `KeyMeta::expires` 一样，0表示永不过期
+    #[serde(default, skip_serializing_if = "is_zero")]
+    expires: u64,
+}
+
+fn is_zero(expires: &u64) -> bool {
+    *expires == 0
+}
+
+impl KvStore {
+    /// 把所有还活着的key按顺序写进 `writer` ，返回写了多少个
+    ///
+    /// 导出的是开始那一刻的快照，导出的时候store还能接着写
+    pub fn export_to<W: Write>(&self, writer: W) -> Result<usize> {
+        let view = self.read_view();
+        let mut writer = BufWriter::new(writer);
+        serde_json::to_writer(
+            &mut writer,
+            &Header {
+                version: EXPORT_VERSION,
+            },
+        )?;
+        writer.write_all(b"\n")?;
+
+        let mut count = 0;
+        for (key, entry) in view.map.iter() {
+            if entry.meta.is_expired() {
+                continue;
+            }
+            let value =
+                match view::read_value(&view.handles, &view.root, view.merge.as_ref(), key, entry)?
+                {
+                    Some(value) => value,
+                    None => continue,
+                };
+            let record = Record {
+                key: Bytes(key.clone()),
+                value: Bytes(value),
+                expires: entry.meta.expires,
+            };
+            serde_json::to_writer(&mut writer, &record)?;
+            writer.write_all(b"\n")?;
+            count += 1;
+        }
+        writer.flush()?;
+        Ok(count)
+    }
+
+    /// 把 `export_to` 导出来的东西导进来，返回导入了多少个。已经有的key会被覆盖，导出以后已经过期了的key跳过
+    ///
+    /// 就是普通的写，元数据是在这边重新开始算的
+    pub fn import_from<R: Read>(&self, reader: R) -> Result<usize> {
+        let mut lines = BufReader::new(reader).lines();
+        let header: Header = match lines.next() {
+            Some(line) => serde_json::from_str(&line?[..])?,
+            None => return Err(bad("empty export")),
+        };
+        if header.version != EXPORT_VERSION {
+            return Err(bad(&format!("unsupported version {}", header.version)));
+        }
+
+        let mut count = 0;
+        let mut batch = Vec::new();
+        for line in lines {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let record: Record = serde_json::from_str(&line[..])?;
+            if record.expires == 0 {
+                batch.push(BatchOp::Set(record.key.0, record.value.0));
+                if batch.len() >= BULK_BATCH {
+                    self.write_batch(std::mem::take(&mut batch))?;
+                }
+            } else {
+                let now = now_millis();
+                if record.expires <= now {
+                    continue;
+                }
+                let ttl = Duration::from_millis(record.expires - now);
+                self.set_bytes_with_ttl(record.key.0, record.value.0, ttl)?;
+            }
+            count += 1;
+        }
+        if !batch.is_empty() {
+            self.write_batch(batch)?;
+        }
+        Ok(count)
+    }
+}
+
+fn bad(reason: &str) -> KvsError {
+    KvsError::BadDump {
+        reason: reason.to_string(),
+    }
+}
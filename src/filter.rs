@@ -0,0 +1,115 @@
+use crate::KvsError;
+use crate::Result;
+
+use regex::Regex;
+use serde::Deserialize;
+use serde::Serialize;
+
+/// 在服务器那边过滤key，省得把整个keyspace都传过来再在本地过滤
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum KeyFilter {
+    All,
+    /// `*` 匹配任意多个字符， `?` 匹配一个字符， `\` 转义
+    Glob(String),
+    Regex(String),
+}
+
+/// 编译好的filter，一次scan只编译一次正则
+pub(crate) enum Matcher {
+    All,
+    Glob(Vec<char>),
+    Regex(Regex),
+}
+
+impl KeyFilter {
+    pub(crate) fn compile(&self) -> Result<Matcher> {
+        match self {
+            KeyFilter::All => Ok(Matcher::All),
+            KeyFilter::Glob(pattern) => Ok(Matcher::Glob(pattern.chars().collect())),
+            KeyFilter::Regex(pattern) => match Regex::new(pattern) {
+                Ok(regex) => Ok(Matcher::Regex(regex)),
+                Err(_) => Err(KvsError::InvalidArgument {
+                    name: "regex".to_string(),
+                    value: pattern.to_string(),
+                }),
+            },
+        }
+    }
+
+    /// 能匹配上的key一定以这个开头。sled和redb可以拿它来只扫一部分
+    #[cfg(any(feature = "sled", feature = "redb"))]
+    pub(crate) fn literal_prefix(&self) -> String {
+        match self {
+            KeyFilter::Glob(pattern) => {
+                let mut prefix = String::new();
+                let mut chars = pattern.chars();
+                while let Some(c) = chars.next() {
+                    match c {
+                        '*' | '?' => break,
+                        '\\' => match chars.next() {
+                            Some(c) => prefix.push(c),
+                            None => break,
+                        },
+                        c => prefix.push(c),
+                    }
+                }
+                prefix
+            }
+            _ => String::new(), // 正则就算了，分析正则太麻烦
+        }
+    }
+}
+
+impl Matcher {
+    pub(crate) fn matches(&self, key: &str) -> bool {
+        match self {
+            Matcher::All => true,
+            Matcher::Glob(pattern) => {
+                let key: Vec<char> = key.chars().collect();
+                glob(pattern, &key)
+            }
+            Matcher::Regex(regex) => regex.is_match(key),
+        }
+    }
+}
+
+/// 经典的带回溯的通配符匹配，只需要记住最后一个 `*` 的位置
+fn glob(pattern: &[char], key: &[char]) -> bool {
+    let (mut p, mut k) = (0, 0);
+    let mut star: Option<(usize, usize)> = None; // (pattern里*后面的位置, 这个*当时匹配到key的哪里)
+    while k < key.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, k));
+                p += 1;
+                continue;
+            }
+            Some('?') => {
+                p += 1;
+                k += 1;
+                continue;
+            }
+            Some('\\') if pattern.get(p + 1) == Some(&key[k]) => {
+                p += 2;
+                k += 1;
+                continue;
+            }
+            Some(c) if *c != '\\' && *c == key[k] => {
+                p += 1;
+                k += 1;
+                continue;
+            }
+            _ => {}
+        }
+        // 匹配不上了，让上一个*多吃一个字符再试
+        match star {
+            Some((after, matched)) => {
+                p = after;
+                k = matched + 1;
+                star = Some((after, matched + 1));
+            }
+            None => return false,
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*') // key用完了，pattern剩下的只能全是*
+}
@@ -0,0 +1,141 @@
+//! 所有动磁盘上文件的操作都走这里，Windows和Unix的语义差太多了
+//!
+//! - Windows上rename的目标文件如果被别人打开着（比如杀毒软件、另一个reader），会失败，要重试
+//! - Windows上删除打开着的文件也会失败，同样要重试
+//! - Unix上要fsync目录才能保证新建、重命名的文件真的落盘了，Windows上根本没法打开目录，NTFS的元数据本来就有日志，不需要
+
+use crate::KvsError;
+use crate::Result;
+
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::fs::TryLockError;
+use std::io::Write;
+use std::path::Path;
+
+const LOCK: &str = "LOCK";
+
+/// 给io错误记上是哪个文件、文件里的哪个位置，比如 `File::open(&path).at(&path)?` 。不是io错误的原样返回
+pub(crate) trait Context<T> {
+    fn at(self, path: &Path) -> Result<T>;
+    fn at_offset(self, path: &Path, offset: u64) -> Result<T>;
+}
+
+impl<T, E: Into<KvsError>> Context<T> for std::result::Result<T, E> {
+    fn at(self, path: &Path) -> Result<T> {
+        self.map_err(|e| locate(e.into(), path, None))
+    }
+
+    fn at_offset(self, path: &Path, offset: u64) -> Result<T> {
+        self.map_err(|e| locate(e.into(), path, Some(offset)))
+    }
+}
+
+fn locate(error: KvsError, path: &Path, offset: Option<u64>) -> KvsError {
+    match error {
+        KvsError::Io(error) => KvsError::File {
+            path: path.to_path_buf(),
+            offset,
+            error,
+        },
+        v => v, // 已经记过位置的，里面的更准
+    }
+}
+
+/// 在目录里的 `LOCK` 上加一把排他锁，别的进程（或者同一个进程里另一个store）已经锁上了的话报 `Locked`
+///
+/// 是advisory lock，返回的文件关掉（进程崩了也算）就自动释放，不用担心崩溃以后留下一把死锁
+pub(crate) fn lock(dir: &Path) -> Result<File> {
+    let path = dir.join(LOCK);
+    let file = OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .at(&path)?;
+    match file.try_lock() {
+        Ok(_) => Ok(file),
+        Err(TryLockError::WouldBlock) => Err(KvsError::Locked {
+            path: dir.to_path_buf(),
+        }),
+        Err(TryLockError::Error(e)) => Err(e).at(&path),
+    }
+}
+
+/// 把 `from` 重命名成 `to` ， `to` 已经存在的话直接覆盖
+pub(crate) fn replace(from: &Path, to: &Path) -> Result<()> {
+    retry(|| std::fs::rename(from, to)).at(from)
+}
+
+/// 删掉文件，文件本来就不存在的话也算成功
+pub(crate) fn remove(path: &Path) -> Result<()> {
+    retry(|| match std::fs::remove_file(path) {
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        v => v,
+    })
+    .at(path)
+}
+
+/// 先写到临时文件里再rename过去。这样写到一半崩了也不会把原来的文件搞坏
+///
+/// 这里不fsync，要不要fsync是durability的事，和原子性是两码事
+pub(crate) fn write_atomic(path: &Path, content: &[u8]) -> Result<()> {
+    let temporary = temporary_path(path);
+    let mut file = File::create(&temporary).at(&temporary)?;
+    file.write_all(content).at(&temporary)?;
+    drop(file); // Windows上不关掉的话rename会失败
+    replace(&temporary, path)
+}
+
+/// `path/5` 的临时文件是 `path/5.tmp`
+pub(crate) fn temporary_path(path: &Path) -> std::path::PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".tmp");
+    path.with_file_name(name)
+}
+
+/// 崩溃之后留下来的临时文件都是没写完的，直接删掉
+pub(crate) fn remove_temporaries(dir: &Path) -> Result<()> {
+    for entry in std::fs::read_dir(dir).at(dir)? {
+        let path = entry.at(dir)?.path();
+        if path.extension().map(|v| v == "tmp").unwrap_or(false) {
+            remove(&path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+pub(crate) fn sync_dir(dir: &Path) -> Result<()> {
+    File::open(dir).and_then(|v| v.sync_all()).at(dir)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn sync_dir(_dir: &Path) -> Result<()> {
+    Ok(()) // Windows上File::open打不开目录
+}
+
+#[cfg(windows)]
+fn retry<F>(mut f: F) -> Result<()>
+where
+    F: FnMut() -> std::io::Result<()>,
+{
+    // 被别的进程短暂占用的时候会报PermissionDenied，等一下一般就好了
+    for attempt in 1.. {
+        match f() {
+            Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied && attempt < 10 => {
+                std::thread::sleep(std::time::Duration::from_millis(10 * attempt));
+            }
+            v => return Ok(v?),
+        }
+    }
+    unreachable!()
+}
+
+#[cfg(not(windows))]
+fn retry<F>(mut f: F) -> Result<()>
+where
+    F: FnMut() -> std::io::Result<()>,
+{
+    Ok(f()?)
+}
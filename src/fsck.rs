@@ -0,0 +1,250 @@
+//! 检查KvStore的目录：每条记录的校验和、没写完的尾巴、key指着的blob还在不在、没人要的blob、上次崩了留下的临时文件
+//!
+//! open的时候碰到这些要么报错要么默默截掉，这里是先看清楚有什么问题，报告是能序列化成json的，给脚本用。`repair` 能修的都修了
+
+use crate::archive;
+use crate::blob;
+use crate::bytes::Bytes;
+use crate::fs;
+use crate::fs::Context;
+use crate::segment;
+use crate::segment::Position;
+use crate::segment::Writer;
+use crate::Command;
+use crate::Compression;
+use crate::Result;
+use crate::KVS_FORMAT;
+
+use log::warn;
+
+use serde::Serialize;
+
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// `KvStore::verify` 和 `KvStore::repair` 的结果
+#[derive(Serialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FsckReport {
+    pub segments: usize,
+    /// 解析得了的记录，batch的开头也算一条
+    pub records: usize,
+    /// 重放完了有几个key，过期了的也算
+    pub live_keys: usize,
+    /// 校验和对不上或者解析不了的记录
+    pub corrupt: Vec<CorruptRecord>,
+    /// 里面有坏记录的batch。open的时候剩下的好记录照样算，修的时候整个batch都扔掉，不然就不是原子的了
+    pub damaged_batches: Vec<SegmentRange>,
+    /// 每个segment最后没写完的部分
+    pub torn: Vec<SegmentRange>,
+    /// key指着的blob不见了，读这个key会出错。修的时候把这些key删掉
+    pub missing_blobs: Vec<MissingBlob>,
+    /// segment里没有记录指着的blob，compact的时候本来也会删
+    pub orphan_blobs: Vec<u64>,
+    /// 写到一半崩了留下来的 `.tmp`
+    pub temporaries: Vec<PathBuf>,
+    /// 是 `repair` 的话，上面这些都已经修好了
+    pub repaired: bool,
+}
+
+impl FsckReport {
+    /// 什么问题都没有
+    pub fn is_clean(&self) -> bool {
+        self.corrupt.is_empty()
+            && self.damaged_batches.is_empty()
+            && self.torn.is_empty()
+            && self.missing_blobs.is_empty()
+            && self.orphan_blobs.is_empty()
+            && self.temporaries.is_empty()
+    }
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct CorruptRecord {
+    pub segment: u64,
+    pub offset: u64,
+    pub reason: String,
+}
+
+/// segment里 `start` 到 `end` 的字节
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SegmentRange {
+    pub segment: u64,
+    pub start: u64,
+    pub end: u64,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct MissingBlob {
+    /// 二进制的key是有损的
+    pub key: String,
+    pub blob: u64,
+}
+
+/// 拿着目录的锁检查，store开着的时候不行。`repair` 的话顺便修好
+pub(crate) fn check(root: &Path, repair: bool) -> Result<FsckReport> {
+    let _lock = fs::lock(root)?;
+    archive::check(root, "kvs", KVS_FORMAT)?;
+
+    let mut report = FsckReport {
+        repaired: repair,
+        ..Default::default()
+    };
+    let segments = segment::list(root)?;
+    report.segments = segments.len();
+    let mut live: BTreeMap<Vec<u8>, Option<u64>> = BTreeMap::new(); // key和它指着的blob
+    let mut referenced = BTreeSet::new(); // 被覆盖了的也算，可能是留着的老版本
+    for id in &segments {
+        let path = segment::path(root, *id);
+        let bytes = std::fs::read(&path).at(&path)?;
+        let parsed = segment::parse(&bytes[..], *id);
+        let dropped = |position: &Position| {
+            repair
+                && parsed
+                    .damaged
+                    .iter()
+                    .any(|(start, end)| (*start..*end).contains(&position.offset))
+        };
+
+        let mut kept = vec![];
+        for (command, position) in &parsed.commands {
+            if dropped(position) {
+                continue;
+            }
+            report.records += 1;
+            kept.push(*position);
+            match command {
+                Command::Set(key, ..) | Command::SetCompressed(key, ..) => {
+                    live.insert(key.0.clone(), None);
+                }
+                Command::SetBlob(key, blob, _) => {
+                    live.insert(key.0.clone(), Some(*blob));
+                    referenced.insert(*blob);
+                }
+                Command::Merge(key, ..) => {
+                    live.entry(key.0.clone()).or_insert(None); // 叠在原来的value上，blob还是那个blob
+                }
+                Command::Remove(key) => {
+                    live.remove(&key.0);
+                }
+                Command::Batch(_) => {}
+            }
+        }
+        for (offset, reason) in &parsed.corrupt {
+            report.corrupt.push(CorruptRecord {
+                segment: *id,
+                offset: *offset,
+                reason: reason.clone(),
+            });
+        }
+        for (start, end) in &parsed.damaged {
+            report.damaged_batches.push(SegmentRange {
+                segment: *id,
+                start: *start,
+                end: *end,
+            });
+        }
+        if parsed.end < bytes.len() {
+            report.torn.push(SegmentRange {
+                segment: *id,
+                start: parsed.end as u64,
+                end: bytes.len() as u64,
+            });
+        }
+
+        let clean = parsed.corrupt.is_empty() && parsed.end == bytes.len();
+        if repair && !clean {
+            rewrite(&path, &bytes[..], &kept[..])?;
+        }
+    }
+    report.live_keys = live.len();
+
+    for (key, blob) in &live {
+        if let Some(blob) = blob {
+            if !blob::path(root, *blob).exists() {
+                report.missing_blobs.push(MissingBlob {
+                    key: crate::bytes::lossy(key),
+                    blob: *blob,
+                });
+            }
+        }
+    }
+
+    for blob in blob::list(root)? {
+        if !referenced.contains(&blob) {
+            report.orphan_blobs.push(blob);
+        }
+    }
+    report.temporaries = temporaries(root)?;
+    if root.join(blob::DIR).exists() {
+        report
+            .temporaries
+            .extend(temporaries(&root.join(blob::DIR))?);
+    }
+
+    if repair {
+        let missing: Vec<&Vec<u8>> = live
+            .iter()
+            .filter(|(_, blob)| blob.is_some_and(|v| !blob::path(root, v).exists()))
+            .map(|(key, _)| key)
+            .collect();
+        if !missing.is_empty() {
+            // 追加到一个新的segment里，老的segment不动
+            let id = segments.last().map_or(0, |v| v + 1);
+            let mut writer = Writer::open(root, id)?;
+            for key in missing {
+                let command = Command::Remove(Bytes(key.clone()));
+                writer.append(&segment::encode(&command, Compression::None)?.0[..])?;
+            }
+            writer.sync()?;
+        }
+        for blob in &report.orphan_blobs {
+            fs::remove(&blob::path(root, *blob))?;
+        }
+        for path in &report.temporaries {
+            fs::remove(path)?;
+        }
+        fs::sync_dir(root)?;
+    }
+    Ok(report)
+}
+
+/// 只留下 `kept` 这些记录，扔掉的字节存到 `.torn` 里
+fn rewrite(path: &Path, bytes: &[u8], kept: &[Position]) -> Result<()> {
+    let mut content = vec![];
+    let mut removed = vec![];
+    let mut offset = 0;
+    for position in kept {
+        let (start, end) = (
+            position.offset as usize,
+            (position.offset + position.len) as usize + 1,
+        );
+        removed.extend_from_slice(&bytes[offset..start]);
+        content.extend_from_slice(&bytes[start..end]);
+        offset = end;
+    }
+    removed.extend_from_slice(&bytes[offset..]);
+    warn!(
+        "Removing {} bytes of damaged records from {:?}",
+        removed.len(),
+        path
+    );
+    segment::quarantine(path, &removed[..])?;
+    fs::write_atomic(path, &content[..])?;
+    std::fs::File::open(path)
+        .and_then(|file| file.sync_all())
+        .at(path)
+}
+
+/// 目录里的 `.tmp`
+fn temporaries(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut paths = vec![];
+    for entry in std::fs::read_dir(dir).at(dir)? {
+        let path = entry.at(dir)?.path();
+        if path.extension().is_some_and(|v| v == "tmp") {
+            paths.push(path);
+        }
+    }
+    Ok(paths)
+}
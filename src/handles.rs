@@ -0,0 +1,143 @@
+use crate::lru::Lru;
+use crate::Result;
+
+use std::fs::File;
+#[cfg(not(feature = "mmap"))]
+use std::io::Read;
+#[cfg(not(feature = "mmap"))]
+use std::io::Seek;
+#[cfg(not(feature = "mmap"))]
+use std::io::SeekFrom;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+/// 打开的文件句柄的命中情况
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HandleStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// 现在开着多少个文件
+    pub open: usize,
+}
+
+impl HandleStats {
+    pub fn hit_rate(&self) -> f64 {
+        if self.hits + self.misses == 0 {
+            0.0
+        } else {
+            self.hits as f64 / (self.hits + self.misses) as f64
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Handle {
+    file: File,
+    /// 整个文件映射进内存，读的时候直接切一段出来。正在写的segment会变长，读到映射外面的时候重新映射一次
+    #[cfg(feature = "mmap")]
+    map: Option<memmap2::Mmap>,
+}
+
+impl Handle {
+    fn open(path: &Path) -> Result<Self> {
+        Ok(Self {
+            file: File::open(path)?,
+            #[cfg(feature = "mmap")]
+            map: None,
+        })
+    }
+
+    #[cfg(not(feature = "mmap"))]
+    fn read<T, F>(&mut self, offset: u64, len: usize, f: F) -> Result<T>
+    where
+        F: FnOnce(&[u8]) -> Result<T>,
+    {
+        self.file.seek(SeekFrom::Start(offset))?; // 句柄是复用的，上次读完不知道停在哪
+        let mut bytes = vec![0; len];
+        self.file.read_exact(&mut bytes)?;
+        f(&bytes[..])
+    }
+
+    #[cfg(feature = "mmap")]
+    fn read<T, F>(&mut self, offset: u64, len: usize, f: F) -> Result<T>
+    where
+        F: FnOnce(&[u8]) -> Result<T>,
+    {
+        let end = offset as usize + len;
+        if self.map.as_ref().is_none_or(|map| map.len() < end) {
+            // segment只会往后追加，删掉之前都会先evict，所以映射着的那部分不会变
+            self.map = Some(unsafe { memmap2::Mmap::map(&self.file)? });
+        }
+        match self.map.as_ref().unwrap().get(offset as usize..end) {
+            Some(bytes) => f(bytes),
+            None => Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Handles {
+    files: Lru<usize, Handle>,
+    hits: u64,
+    misses: u64,
+}
+
+/// 缓存打开的文件，最多同时开 `capacity` 个，多了就把最久没用的关掉
+///
+/// clone出来的共享同一个缓存，可以丢给好几个读线程
+#[derive(Clone, Debug)]
+pub(crate) struct HandleCache {
+    inner: Arc<Mutex<Handles>>,
+}
+
+impl HandleCache {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Handles {
+                files: Lru::new(capacity.max(1)), // 至少要能开一个吧
+                hits: 0,
+                misses: 0,
+            })),
+        }
+    }
+
+    /// 读编号为 `id` 的文件从 `offset` 开始的 `len` 个字节交给 `f` ，没开过的话就打开 `path`
+    ///
+    /// 开了 `mmap` feature的话 `f` 拿到的直接是映射的内存，不用拷贝
+    pub(crate) fn read<T, F>(
+        &self,
+        id: usize,
+        path: &Path,
+        offset: u64,
+        len: usize,
+        f: F,
+    ) -> Result<T>
+    where
+        F: FnOnce(&[u8]) -> Result<T>,
+    {
+        let mut handles = self.inner.lock().unwrap();
+        if handles.files.contains(&id) {
+            handles.hits += 1;
+        } else {
+            handles.misses += 1;
+            let handle = Handle::open(path)?;
+            handles.files.insert(id, handle); // 踢出来的那个drop的时候自然就关掉了
+        }
+        handles.files.get(&id).unwrap().read(offset, len, f)
+    }
+
+    /// 文件被替换或者删掉之前一定要先调这个，不然读到的是旧文件（Windows上干脆rename不了）
+    pub(crate) fn evict(&self, id: usize) {
+        self.inner.lock().unwrap().files.remove(&id);
+    }
+
+    pub(crate) fn stats(&self) -> HandleStats {
+        let handles = self.inner.lock().unwrap();
+        HandleStats {
+            hits: handles.hits,
+            misses: handles.misses,
+            open: handles.files.len(),
+        }
+    }
+}
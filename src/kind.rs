@@ -0,0 +1,114 @@
+//! 错误的大类，客户端按这个分情况处理，不用去匹配 `Display` 出来的字符串
+
+use crate::KvsError;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+/// 错误属于哪一类。以后 `KvsError` 加了新的variant，也只是归到已有的某一类里，这个enum和 `code` 不会变
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ErrorKind {
+    /// 不知道该归到哪一类的，比如老版本的服务器回的错误
+    Other,
+    NotFound,
+    /// 磁盘上或者收到的数据坏了、解析不了
+    Corruption,
+    Io,
+    /// 目录已经被别人打开了
+    Locked,
+    /// engine、磁盘格式、操作不支持
+    Unsupported,
+    /// 事务commit的时候读过的key被别人改了
+    Conflict,
+    ReadOnly,
+    /// 参数不对，比如正则写错了、用String的API读二进制的value
+    InvalidInput,
+    /// 超过了大小、配额、速率的限制
+    LimitExceeded,
+    AlreadyExists,
+    PermissionDenied,
+    /// 服务器那边出的错， `code` 是那边的错误的 `code` ，可以用 `ErrorKind::from_code` 变回来
+    Remote {
+        code: u32,
+    },
+}
+
+impl ErrorKind {
+    /// 在网络上传的数字，定下来了就不能再改
+    pub fn code(self) -> u32 {
+        match self {
+            ErrorKind::Other => 0,
+            ErrorKind::NotFound => 1,
+            ErrorKind::Corruption => 2,
+            ErrorKind::Io => 3,
+            ErrorKind::Locked => 4,
+            ErrorKind::Unsupported => 5,
+            ErrorKind::Conflict => 6,
+            ErrorKind::ReadOnly => 7,
+            ErrorKind::InvalidInput => 8,
+            ErrorKind::LimitExceeded => 9,
+            ErrorKind::AlreadyExists => 10,
+            ErrorKind::PermissionDenied => 11,
+            ErrorKind::Remote { code } => code, // 转发别人的错误的时候原样传下去
+        }
+    }
+
+    /// 不认识的数字（更新的版本加的）当 `Other`
+    pub fn from_code(code: u32) -> Self {
+        match code {
+            1 => ErrorKind::NotFound,
+            2 => ErrorKind::Corruption,
+            3 => ErrorKind::Io,
+            4 => ErrorKind::Locked,
+            5 => ErrorKind::Unsupported,
+            6 => ErrorKind::Conflict,
+            7 => ErrorKind::ReadOnly,
+            8 => ErrorKind::InvalidInput,
+            9 => ErrorKind::LimitExceeded,
+            10 => ErrorKind::AlreadyExists,
+            11 => ErrorKind::PermissionDenied,
+            _ => ErrorKind::Other,
+        }
+    }
+}
+
+impl KvsError {
+    pub fn kind(&self) -> ErrorKind {
+        match self {
+            KvsError::Io(_) | KvsError::File { .. } => ErrorKind::Io,
+            #[cfg(feature = "sled")]
+            KvsError::Sled(_) => ErrorKind::Io,
+            #[cfg(feature = "redb")]
+            KvsError::Redb(_) => ErrorKind::Io,
+            #[cfg(feature = "tls")]
+            KvsError::Tls(_) => ErrorKind::InvalidInput,
+            KvsError::Serde(_)
+            | KvsError::Bincode(_)
+            | KvsError::Corrupt { .. }
+            | KvsError::BadDump { .. }
+            | KvsError::UnexpectedResponse { .. } => ErrorKind::Corruption,
+            KvsError::NotFound { .. } => ErrorKind::NotFound,
+            KvsError::Remote { code, .. } => ErrorKind::Remote { code: *code },
+            KvsError::UnsupportedEngine { .. }
+            | KvsError::BadArchive { .. }
+            | KvsError::UnsupportedFormat { .. }
+            | KvsError::NoMergeOperator => ErrorKind::Unsupported,
+            KvsError::InvalidArgument { .. } | KvsError::NotUtf8 { .. } => ErrorKind::InvalidInput,
+            KvsError::ReadOnly => ErrorKind::ReadOnly,
+            KvsError::RateLimited
+            | KvsError::PreloadTooLarge { .. }
+            | KvsError::QuotaExceeded { .. }
+            | KvsError::KeyTooLarge { .. }
+            | KvsError::ValueTooLarge { .. }
+            | KvsError::RequestTooLarge { .. } => ErrorKind::LimitExceeded,
+            KvsError::KeyExists { .. } => ErrorKind::AlreadyExists,
+            KvsError::Conflict { .. } => ErrorKind::Conflict,
+            KvsError::Locked { .. } => ErrorKind::Locked,
+        }
+    }
+
+    /// `kind().code()` ，服务器回错误的时候带上
+    pub fn code(&self) -> u32 {
+        self.kind().code()
+    }
+}
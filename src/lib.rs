@@ -1,57 +1,328 @@
 use serde::Deserialize;
 use serde::Serialize;
 
+#[cfg(feature = "sled")]
+use sled::transaction::abort;
+#[cfg(feature = "sled")]
+use sled::transaction::ConflictableTransactionResult;
+#[cfg(feature = "sled")]
+use sled::transaction::TransactionError;
+#[cfg(feature = "sled")]
+use sled::transaction::TransactionalTree;
+#[cfg(feature = "sled")]
 use sled::Db;
+#[cfg(feature = "sled")]
+use sled::Transactional;
 
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::collections::HashMap;
 use std::error::Error;
 use std::fmt::Display;
 use std::fs::create_dir_all;
-use std::fs::remove_file;
-use std::fs::rename;
 use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
 use std::io::Read;
-use std::io::Write;
-use std::net::Shutdown;
 use std::net::TcpListener;
 use std::net::TcpStream;
 use std::net::ToSocketAddrs;
+use std::ops::Bound;
+use std::ops::RangeBounds;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+mod acl;
+mod archive;
+mod asynchronous;
+mod audit;
+mod auth;
+mod batch;
+mod blob;
+mod boxed;
+mod bytes;
+mod cache;
+mod checksum;
+mod commit;
+mod compaction;
+mod compress;
+mod dump;
+mod export;
+mod filter;
+mod fs;
+mod fsck;
+mod handles;
+mod kind;
+mod lru;
+mod memory;
+mod merge;
+mod middleware;
+mod migrate;
+mod monitor;
+mod namespace;
+mod net;
+mod pitr;
+mod pool;
+mod rate;
+#[cfg(feature = "tokio")]
+mod reactor;
+#[cfg(feature = "redb")]
+mod redb;
+mod registry;
+mod reload;
+mod resp;
+mod scan;
+mod secondary;
+mod segment;
+mod shutdown;
+mod snapshot;
+mod stats;
+mod stream;
+#[cfg(feature = "tls")]
+mod tls;
+mod txn;
+mod typed;
+mod view;
+mod watch;
+mod wire;
+
+#[cfg(feature = "redb")]
+pub use self::redb::RedbKvsEngine;
+#[cfg(feature = "redb")]
+pub use self::redb::RedbOptions;
+pub use acl::Access;
+pub use acl::Acl;
+pub use asynchronous::AsyncAdapter;
+pub use asynchronous::AsyncKvsEngine;
+pub use audit::AuditConfig;
+pub use audit::AuditEntry;
+pub use auth::Accounts;
+pub use auth::Credentials;
+pub use auth::DEFAULT_USER;
+pub use batch::BatchOp;
+pub use batch::Op;
+pub use batch::OpResult;
+pub use batch::WriteBatch;
+pub use boxed::BoxClone;
+pub use cache::CacheConfig;
+pub use cache::CachedEngine;
+pub use cache::WriteBehind;
+pub use compaction::CompactionPolicy;
+pub use compaction::CompactionProgress;
+pub use compaction::CompactionReport;
+pub use compress::Compression;
+pub use dump::Dump;
+pub use filter::KeyFilter;
+pub use fsck::CorruptRecord;
+pub use fsck::FsckReport;
+pub use fsck::MissingBlob;
+pub use fsck::SegmentRange;
+pub use handles::HandleStats;
+pub use kind::ErrorKind;
+pub use memory::MemEngine;
+pub use merge::MergeOperator;
+pub use middleware::EngineExt;
+pub use middleware::FaultInjectingEngine;
+pub use middleware::Faults;
+pub use middleware::LoggingEngine;
+pub use middleware::MeteredEngine;
+pub use middleware::Metrics;
+pub use middleware::ReadOnlyEngine;
+pub use middleware::SizeLimitedEngine;
+pub use middleware::SizeLimits;
+pub use migrate::migrate;
+pub use monitor::Monitor;
+pub use monitor::MonitorConfig;
+pub use monitor::MonitorEvent;
+pub use namespace::Namespace;
+pub use net::Timeouts;
+pub use pool::NaiveThreadPool;
+pub use pool::RayonThreadPool;
+pub use pool::SharedQueueThreadPool;
+pub use pool::ThreadPool;
+pub use rate::RateLimit;
+pub use rate::Throttle;
+pub use registry::engine_names;
+pub use registry::open_engine;
+pub use registry::register_engine;
+pub use reload::ReloadHandle;
+pub use scan::Iter;
+pub use scan::Keys;
+pub use scan::Scan;
+pub use secondary::IndexedEngine;
+pub use secondary::SecondaryIndex;
+pub use shutdown::ShutdownHandle;
+pub use snapshot::Manifest;
+pub use snapshot::SegmentInfo;
+pub use stats::ServerStats;
+pub use stats::StoreStats;
+pub use stream::ValueReader;
+#[cfg(feature = "tls")]
+pub use tls::TlsOptions;
+pub use txn::RemoteTransaction;
+pub use txn::Transaction;
+pub use typed::TypedStore;
+pub use view::ReadView;
+pub use watch::WatchEvent;
+pub use watch::Watcher;
+pub use wire::Encoding;
+pub use wire::Hello;
+pub use wire::PROTOCOL_VERSION;
+
+use audit::AuditLog;
+use bytes::Bytes;
+use commit::GroupCommit;
+use compress::Compressed;
+use fs::Context;
+use handles::HandleCache;
+use log::debug;
+use log::error;
+use log::info;
+use log::warn;
+use lru::Lru;
+use monitor::Subscribers;
+use net::ClientTls;
+use net::Stream;
+use rate::TokenBucket;
+use segment::Position;
+use segment::Writer;
+use shutdown::Listener;
+use txn::Staged;
+use view::Index;
+use watch::Watchers;
 
 pub type Result<T> = std::result::Result<T, KvsError>;
 
 #[derive(Debug)]
 pub enum KvsError {
     Io(std::io::Error),
+    File {
+        path: PathBuf,
+        offset: Option<u64>,
+        error: std::io::Error,
+    }, // 读写 `path` 的时候出的io错误。 `offset` 是正在读写的那条记录在文件里的位置，不是对着某条记录的话是 `None`
     Serde(serde_json::Error),
+    Bincode(bincode::Error), // 收到的bincode消息解不开
+    #[cfg(feature = "sled")]
     Sled(sled::Error),
+    #[cfg(feature = "redb")]
+    Redb(Box<::redb::Error>), // 太大了，放在Box里。和本crate的 `redb` 模块重名，要写 `::redb`
+    #[cfg(feature = "tls")]
+    Tls(rustls::Error), // 证书、私钥不对，或者配置不出TLS
     NotFound {
         key: String,
     }, // 我不明白为什么not found是个错误，明明用None就能表示
     Remote {
         message: String,
+        code: u32, // 服务器那边的 `ErrorKind::code` ，老版本的服务器不发，是0
     }, // 远端错误
     UnsupportedEngine {
         name: String,
     },
+    InvalidArgument {
+        name: String,
+        value: String,
+    }, // 命令行参数不对
+    ReadOnly,    // 在只读的engine上写
+    RateLimited, // 客户端自己限流，令牌用完了
+    UnexpectedResponse {
+        response: String,
+    }, // 服务器回了个牛头不对马嘴的响应
+    PreloadTooLarge {
+        size: u64,
+        limit: u64,
+    }, // 要预加载的数据比允许的多
     BadArchive {
         path: PathBuf,
         should: String, // 应该是什么engine
         tried: String,  // 现在试图用什么engine打开
     }, // 如果磁盘上的持久化明明是sled engine，但是现在要运行kvs engine，就会出这个错误
+    BadDump {
+        reason: String,
+    }, // restore的时候blob坏了或者版本不认识
+    KeyExists {
+        key: String,
+    }, // restore的时候key已经有了，又没说要覆盖
+    NotUtf8 {
+        key: String,
+    }, // 用String的API去读存进去的二进制value
+    Corrupt {
+        path: PathBuf,
+        offset: u64,
+        reason: String,
+    }, // segment中间有一条记录坏了，校验和对不上或者根本解析不了
+    Conflict {
+        key: String,
+    }, // 事务读过的key在commit之前被别人改了，重来一遍就好
+    Locked {
+        path: PathBuf,
+    }, // 这个目录已经被别的store打开了，两个一起写会把对方的文件搞坏
+    UnsupportedFormat {
+        path: PathBuf,
+        found: u32,
+        supported: u32,
+    }, // 目录是更新的版本写的，这个版本不认识
+    NoMergeOperator, // merge或者读有merge的key的时候，engine上没设置merge operator
+    QuotaExceeded {
+        size: u64,
+        limit: u64,
+    }, // 再写的话目录就比 `max_disk_bytes` 大了，compact过了也不够
+    KeyTooLarge {
+        size: u64,
+        limit: u64,
+    }, // key比 `SizeLimits` 允许的长
+    ValueTooLarge {
+        size: u64,
+        limit: u64,
+    }, // value比 `SizeLimits` 允许的大
+    RequestTooLarge {
+        limit: u64,
+    }, // 客户端发来的一个请求比 `KvsServer::max_request` 允许的长
 }
 
 impl Display for KvsError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         match self {
             KvsError::NotFound { key: k } => write!(f, "Key not found: {}", k),
-            _ => write!(f, "{}", format!("{:#?}", self)),
+            KvsError::File {
+                path,
+                offset: Some(offset),
+                error,
+            } => write!(f, "{} at offset {} of {:?}", error, offset, path),
+            KvsError::File {
+                path,
+                offset: None,
+                error,
+            } => write!(f, "{} in {:?}", error, path),
+            KvsError::Corrupt {
+                path,
+                offset,
+                reason,
+            } => write!(
+                f,
+                "Corrupt record at offset {} of {:?}: {}",
+                offset, path, reason
+            ),
+            _ => write!(f, "{:#?}", self),
         }
     }
 }
 
-impl Error for KvsError {}
+impl Error for KvsError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            KvsError::Io(error) | KvsError::File { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
 
 // 我一直以为From和Into是完全一样的
 impl From<std::io::Error> for KvsError {
@@ -66,486 +337,4553 @@ impl From<serde_json::Error> for KvsError {
     }
 }
 
+impl From<bincode::Error> for KvsError {
+    fn from(error: bincode::Error) -> Self {
+        KvsError::Bincode(error)
+    }
+}
+
+#[cfg(feature = "sled")]
 impl From<sled::Error> for KvsError {
     fn from(error: sled::Error) -> Self {
         KvsError::Sled(error)
     }
 }
 
-// 听说要支持sled后端
-pub trait KvsEngine {
-    fn get(&mut self, key: &str) -> Result<Option<&str>>;
-    fn set(&mut self, key: String, value: String) -> Result<()>;
-    fn remove(&mut self, key: &str) -> Result<()>;
+#[cfg(feature = "tls")]
+impl From<rustls::Error> for KvsError {
+    fn from(error: rustls::Error) -> Self {
+        KvsError::Tls(error)
+    }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
-enum Command {
-    Set(String, String),
-    Remove(String),
-}
+// 听说要支持sled后端
+/// 所有方法都只要 `&self` ，engine自己管里面的锁。clone出来的和原来的是同一份数据，每个线程拿一个clone就能一起用
+///
+/// 可以做成 `Box<dyn KvsEngine>` ，运行的时候再决定用哪个engine。带泛型参数的方法只有具体类型上能调
+pub trait KvsEngine: BoxClone + Send + 'static {
+    /// value不是utf8的话报 `NotUtf8` ，这时候要用 `get_bytes`
+    fn get(&self, key: &str) -> Result<Option<String>>;
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-enum Storage {
-    /// value在硬盘上，要去读名为 `value` 的文件
-    Disk(usize),
-    /// value已经缓存在内存里了，可以直接读出来
-    Memory(String),
-}
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>>;
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+    fn remove_bytes(&self, key: &[u8]) -> Result<()>;
 
-#[derive(Debug)]
-pub struct KvStore {
-    /// `map["a"] == 2` 表示 `"a": "33"` 存在磁盘上名为 `2` 的文件里，同时`logs[2] == ("a", Disk(2))` 或者 `("a", Memory("33"))`
-    map: HashMap<String, usize>, // 感觉是个坑啊，key就一定要是utf8吗？不能是bytes吗？
-    /// `logs[2] == ("a", Disk(2))` 表示 `"a": "33"` 存在磁盘上名为 `2` 的文件里
-    logs: Vec<(String, Storage)>,
-    /// 下一个包含没有出现过的key的command应该存在名为 `seek` 的文件里，比如假如之前从来没出现过 `"a": "33"` ，`seek` 目前是8，那么set的时候这个command会存到名为 `8` 的文件里
-    seek: usize,
-    /// 存log的目录。PathBuf和Path的关系类似String和&str
-    root: PathBuf,
-}
+    /// String的API只是包了一层bytes的
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
 
-/// 目录下面建一个叫做.kvs的文件，如果里面存kvs，说明当前目录的记录是kvs engine；如果存sled，说明是sled engine
-fn archive_type<T>(root: T) -> Result<String>
-where
-    T: AsRef<Path>,
-{
-    match File::open(root.as_ref().join(".kvs")) {
-        Ok(mut manifest) => {
-            let mut string = String::new();
-            manifest.read_to_string(&mut string)?;
-            Ok(string)
+    fn remove(&self, key: &str) -> Result<()> {
+        self.remove_bytes(key.as_bytes())
+    }
+
+    /// 一次拿好几个key，顺序和 `keys` 一样，不存在的是 `None`
+    fn multi_get(&self, keys: &[&str]) -> Result<Vec<Option<String>>> {
+        let mut values = Vec::with_capacity(keys.len());
+        for key in keys {
+            values.push(self.get(key)?);
         }
-        Err(e) => Err(KvsError::Io(e)),
+        Ok(values)
     }
-}
 
-impl KvStore {
-    pub fn new() -> Self {
-        Self {
-            map: HashMap::new(),
-            logs: vec![],
-            seek: 0,
-            root: PathBuf::new(), // 空的path会是啥呢……
+    /// 一点一点读value，很大的value不用一次全读进内存。默认是读出来再包一层，只有KvStore的大value真的是边读边给
+    fn get_reader(&self, key: &[u8]) -> Result<Option<ValueReader<'_>>> {
+        Ok(self.get_bytes(key)?.map(ValueReader::from_bytes))
+    }
+
+    /// value从 `reader` 里来，读到EOF为止。默认是全读进来再 `set_bytes` ，KvStore碰到很大的value会边读边写到磁盘上
+    fn set_from_reader(&self, key: Vec<u8>, reader: &mut dyn Read) -> Result<()> {
+        let mut value = vec![];
+        reader.read_to_end(&mut value)?;
+        self.set_bytes(key, value)
+    }
+
+    /// 过了 `ttl` 以后key就当不存在了，磁盘上的什么时候真的删掉看engine。之后普通的set会把过期时间清掉
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.into_bytes(), value.into_bytes(), ttl)
+    }
+
+    /// 还有多久过期，没设过期时间的话是 `None` 。key不存在（或者已经过期了）的话报 `NotFound`
+    fn ttl(&self, key: &str) -> Result<Option<Duration>> {
+        match self.metadata(key)? {
+            Some(meta) if meta.expires == 0 => Ok(None),
+            Some(meta) => Ok(Some(Duration::from_millis(
+                meta.expires.saturating_sub(now_millis()),
+            ))),
+            None => Err(KvsError::NotFound {
+                key: key.to_string(),
+            }),
         }
     }
 
-    pub fn open<T>(root: T) -> Result<Self>
+    /// 攒一批写，`commit` 的时候一起生效
+    fn batch(&self) -> WriteBatch<'_, Self>
     where
-        T: Into<PathBuf>,
+        Self: Sized,
     {
-        let root = root.into();
-        create_dir_all(&root)?; // 把存log的目录先建了
-
-        match archive_type(&root) {
-            Ok(name) => {
-                if name != "kvs" {
-                    // 发现当前目录存了其他engine的记录
-                    return Err(KvsError::BadArchive {
-                        path: root,
-                        should: name,
-                        tried: format!("kvs"),
-                    });
-                }
-            }
-            Err(KvsError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {
-                // 当前目录是新的，没有存过任何engine的记录
-                let mut file = File::create(root.join(".kvs"))?;
-                file.write("kvs".as_bytes())?;
-            }
-            Err(e) => {
-                return Err(e);
-            }
-        }
-
-        let mut map = HashMap::new();
-        let mut logs = vec![];
-        let mut seek = 0;
-
-        for i in 0.. {
-            // 把command一个一个读出来
-            let path = root.join(format!("{}", i)); // 第10个command的路径是path/10
-            if let Ok(mut file) = File::open(&path) {
-                let mut string = String::new();
-                file.read_to_string(&mut string)?;
-                let command: Command = serde_json::from_str(&string[..])?;
-                match command {
-                    Command::Set(key, _) => {
-                        if let Some(offset) = map.get(&key[..]).cloned() {
-                            // 之前出现过a: 1了，假设存在文件1里，现在又来了个a: 2，假设存在文件5里。直接把5重命名为2就好了，其他什么都不用变
-                            let new_path = root.join(format!("{}", offset)); // 原来还有join这个好用的方法……
-                            rename(&path, &new_path)?; // 把5重命名为2
-                        } else {
-                            // 来了个a: 1，之前没见过，把a: 1存在名为seek的文件里
-                            let new_path = root.join(format!("{}", seek));
-                            rename(&path, &new_path)?;
-
-                            map.insert(key.clone(), seek); // 更新map，让map[a] = seek
-                            logs.push((key, Storage::Disk(seek))); // 更新logs，让logs[seek] = (a, Disk(seek))
-                            seek += 1;
-                        }
-                    }
-                    Command::Remove(key) => {
-                        if let Some(offset) = map.get(&key[..]).cloned() {
-                            // 之前出现过a: 1，假设存在文件2里。那么要删掉文件2，可是这样就留下了2这个空洞，怎么办呢？把最后一个command放到2里，填充这个空洞
-                            if seek != 0 {
-                                // 假设这时候有6个command，那么此时seek = 6
-                                seek -= 1; // 先把seek往下移动一格，这样seek = 5
-                                let path = root.join(format!("{}", seek)); // 最后一个command存放在文件5里
-                                let new_path = root.join(format!("{}", offset)); // 假设要删除的a: 1存在文件2里
-                                rename(&path, &new_path)?; // 把文件5重命名为2就好了，这样a: 1就跑到文件2里去了
-
-                                // 更新一下内存里的表示
-                                let mut log = logs.pop().unwrap(); // 最后一个command
-                                match log.1 {
-                                    Storage::Disk(_) => {
-                                        log.1 = Storage::Disk(offset); // 最后一个command本来存在文件5里的，现在存到文件2里面去了
-                                    }
-                                    _ => {} // 如果已经缓存到内存里了，就不用管了
-                                }
-                                logs[offset] = log; // 内存里的空洞也要填充一下
-                                map.insert(logs[offset].0.clone(), offset); // 更新map
-                            } // 出现了奇怪的情况，文件0里面是Remove(a, 2)，按理说是无效command
-                        }
-                        // 如果log本身就有问题呢……比如出现了Remove(key)而key当时还并不存在
-                        map.remove(&key[..]);
-                    }
-                }
-            } else {
-                // 0, 1, 2发现没有3，说明读完了
-                // [seek, i)之间的文件都是冗余的，全部删掉
-                for j in seek..i {
-                    let path = root.join(format!("{}", j));
-                    remove_file(&path)?;
-                }
+        WriteBatch::new(self)
+    }
+
+    /// 名字叫 `name` 的一个keyspace，和别的namespace、和外面的key互不干扰，可以用 `clear` 整个删掉
+    fn namespace(&self, name: &str) -> Result<Namespace<Self>>
+    where
+        Self: Clone,
+    {
+        Namespace::new(self.clone(), name)
+    }
+
+    /// 开一个事务，读和写都通过它，`commit` 的时候一起生效
+    fn begin(&self) -> Transaction<'_, Self>
+    where
+        Self: Sized,
+    {
+        Transaction::new(self)
+    }
+
+    /// `f` 返回 `Ok` 就commit，返回 `Err` 就rollback，比如 `store.transaction(|txn| { txn.set(..); Ok(()) })`
+    fn transaction<F, T>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut Transaction<'_, Self>) -> Result<T>,
+        Self: Sized,
+    {
+        let mut txn = self.begin();
+        let result = f(&mut txn)?;
+        txn.commit()?;
+        Ok(result)
+    }
 
-                break;
-                // 标准答案里面是用扩展名来判断是不是log的，所以没有空洞的问题
+    /// 一批写要么全都生效，要么一个都不生效。remove了不存在的key的话整批都不写，报 `NotFound`
+    ///
+    /// 默认实现是先检查一遍再一个一个写，检查能拦住 `NotFound` ，但是写到一半磁盘坏了的话前面的就已经写进去了。能做到真正原子的engine要自己实现
+    fn write_batch(&self, ops: Vec<BatchOp>) -> Result<()> {
+        batch::validate(&ops[..], |key| Ok(self.get_bytes(key)?.is_some()))?;
+        for op in ops {
+            match op {
+                BatchOp::Set(key, value) => self.set_bytes(key, value)?,
+                BatchOp::Remove(key) => self.remove_bytes(&key[..])?,
             }
         }
+        Ok(())
+    }
 
-        return Ok(Self {
-            map: map,
-            logs: logs,
-            seek: seek,
-            root: root,
-        });
+    /// `expected` 里的key现在都还是那个value（`None` 是不存在）的话才把 `ops` 当一个batch写下去，有一个对不上就报 `Conflict` ，什么都不写。事务的commit用的
+    ///
+    /// 默认实现是先对一遍再 `write_batch` ，对完到写下去之间别人的写是拦不住的。KvStore、sled、MemEngine在一把锁或者一个sled事务里做
+    fn write_batch_if(
+        &self,
+        expected: &BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+        ops: Vec<BatchOp>,
+    ) -> Result<()> {
+        txn::check(expected, |key| self.get_bytes(key))?;
+        if ops.is_empty() {
+            return Ok(());
+        }
+        self.write_batch(ops)
     }
-}
 
-impl KvsEngine for KvStore {
-    // 标准答案里面key是String，但我觉得……怎么能传owned呢，所以改掉了
-    fn get(&mut self, key: &str) -> Result<Option<&str>> {
-        // 假设现在get("a")
-        match self.map.get_mut(key) {
-            None => Ok(None), // 内存和磁盘永远是一致的，内存里没有，磁盘上肯定也没有
-            Some(offset) => {
-                // 发现a存在文件2里
-                let storage = &mut self.logs.get_mut(*offset).unwrap().1; // logs[2] == ("a", Disk(2))或者logs[2] == ("a", Memory("1"))
-                match storage {
-                    Storage::Disk(offset) => {
-                        // logs[2] == ("a", Disk(2))，在磁盘上还没读出来
-                        let path = self.root.join(format!("{}", offset)); // a存在文件2里
-                        let mut file = File::open(&path)?;
-
-                        let mut string = String::new();
-                        file.read_to_string(&mut string)?;
-                        let command: Command = serde_json::from_str(&string[..])?;
-
-                        match command {
-                            Command::Set(_, value) => {
-                                *storage = Storage::Memory(value); // 先放进cache
-                                match storage {
-                                    Storage::Memory(value) => Ok(Some(&value[..])),
-                                    _ => unreachable!(),
-                                } // 虽然这里确定了storage肯定是Memory，但是流程还是要这么写哈哈
-                            }
-                            _ => {
-                                // 如果读到的是Remove(a)，那么key应该在内存里也不存在……出现了不一致，按理说这种情况是不允许发生的
-                                eprintln!(
-                                    "Inconsistency detected: {} in memory but not on disk",
-                                    key
-                                );
-                                self.map.remove(key);
-                                Ok(None)
-                            }
-                        }
-                    }
-                    Storage::Memory(value) => Ok(Some(&value[..])), // 已经在内存里的话，就直接返回好了
-                }
+    /// 按顺序做一串 `Get` 、 `Set` 、 `Remove` ，结果和 `ops` 一一对应，比如 `store.apply(&[Op::Get(..), Op::Set(..)])`
+    ///
+    /// 默认实现是一个一个做，碰到错误就停，前面写了的不会撤回。KvStore和sled在全是写的时候会合成一个 `write_batch` 原子地写
+    fn apply(&self, ops: &[Op]) -> Result<Vec<OpResult>> {
+        batch::apply_each(self, ops)
+    }
+
+    /// 删掉所有key，要么全删掉，要么一个都没删。namespace里的不动，它们要用 `Namespace::clear` 删
+    ///
+    /// 默认实现是把所有key放进一个batch里remove，原子不原子要看engine的 `write_batch`
+    fn clear(&self) -> Result<()> {
+        let keys = self.keys()?.collect::<Result<Vec<_>>>()?;
+        if keys.is_empty() {
+            return Ok(());
+        }
+        self.write_batch(keys.into_iter().map(BatchOp::Remove).collect())
+    }
+
+    /// key什么时候建的、什么时候改的、多大、改过几次。key不存在的话是 `None`
+    fn metadata(&self, key: &str) -> Result<Option<KeyMeta>>;
+
+    /// key在不在。只想知道在不在的话别用 `get` ，它会把value读进来
+    ///
+    /// 默认实现是看有没有元数据，元数据和value是分开放的，不用碰value
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        Ok(self.metadata(key)?.is_some())
+    }
+
+    /// 所有匹配 `filter` 的key和value，按key排好序
+    fn scan_matching(&self, filter: &KeyFilter) -> Result<Vec<(String, String)>>;
+
+    /// 范围里的key和value，按key从小到大，比如 `store.scan(b"a".to_vec()..b"c".to_vec())`
+    fn scan<R>(&self, range: R) -> Result<Scan<'_>>
+    where
+        R: RangeBounds<Vec<u8>>,
+        Self: Sized,
+    {
+        self.scan_bounds(
+            range.start_bound().map(|v| &v[..]),
+            range.end_bound().map(|v| &v[..]),
+        )
+    }
+
+    /// 以 `prefix` 开头的key和value，比如 `store.scan_prefix(b"user:")`
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Scan<'_>> {
+        let end = scan::prefix_end(prefix);
+        self.scan_bounds(Bound::Included(prefix), end.as_ref().map(|v| &v[..]))
+    }
+
+    /// 删掉所有匹配 `filter` 的key，返回删了几个，比如 `store.remove_matching(&KeyFilter::Glob("session:*".to_string()))`
+    ///
+    /// 和 `scan_matching` 一样，不是utf8的key匹配不上。用一个batch删，要么全删了要么都没删
+    fn remove_matching(&self, filter: &KeyFilter) -> Result<u64> {
+        let matcher = filter.compile()?;
+        let mut ops = vec![];
+        for key in self.keys()? {
+            let key = key?;
+            if std::str::from_utf8(&key[..]).is_ok_and(|key| matcher.matches(key)) {
+                ops.push(BatchOp::Remove(key));
             }
         }
+        let count = ops.len() as u64;
+        if count > 0 {
+            self.write_batch(ops)?;
+        }
+        Ok(count)
     }
 
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        // 假设set("a", "1")
-        if let Some(offset) = self.map.get(&key[..]) {
-            // 之前已经有a: 2了，要覆盖掉
-            let path = self.root.join(format!("{}", offset)); // 假设之前的a: 2存在文件5里
-            let mut file = File::create(&path)?; // 直接把文件5清空，写入a: 1
+    /// `scan` 真正干活的地方，engine要实现的是这个
+    fn scan_bounds(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Result<Scan<'_>>;
+
+    /// 订阅以 `prefix` 开头的key之后的变化，空的前缀就是所有key。比如 `for event in store.watch(b"config:")? { ... }`
+    ///
+    /// 过期的key在被发现过期的时候（读到它、compact）才会收到 `Remove`
+    fn watch(&self, prefix: &[u8]) -> Result<Watcher>;
 
-            let command = Command::Set(key.clone(), value.clone());
-            let string = serde_json::to_string(&command)?;
-            file.write(string.as_bytes())?;
+    /// 所有还活着的key，按key从小到大，namespace里的不算。默认实现是从头扫一遍再把value扔掉，key都在内存里的engine最好自己实现
+    fn keys(&self) -> Result<Keys<'_>> {
+        let iter = self
+            .scan_bounds(Bound::Unbounded, Bound::Unbounded)?
+            .filter(|v| {
+                v.as_ref()
+                    .map_or(true, |(key, _)| !namespace::is_namespaced(key))
+            })
+            .map(|v| v.map(|(key, _)| key));
+        Ok(Box::new(iter))
+    }
 
-            // 更新内存里的表示
-            let log = &mut self.logs[*offset];
-            match &log.1 {
-                Storage::Memory(_) => {
-                    log.1 = Storage::Memory(value); // 如果已经读出来了，要把a: 2刷成a: 1
+    /// 所有还活着的key和value，按key从小到大，namespace里的不算
+    ///
+    /// 是String版的 `scan(..)` ，key或者value不是utf8的那一项报 `NotUtf8` ，后面的照样接着给
+    fn iter(&self) -> Result<Iter<'_>> {
+        let iter = self
+            .scan_bounds(Bound::Unbounded, Bound::Unbounded)?
+            .filter(|v| {
+                v.as_ref()
+                    .map_or(true, |(key, _)| !namespace::is_namespaced(key))
+            })
+            .map(|v| {
+                let (key, value) = v?;
+                let value = bytes::into_string(&key[..], value)?;
+                match String::from_utf8(key) {
+                    Ok(key) => Ok((key, value)),
+                    Err(e) => Err(KvsError::NotUtf8 {
+                        key: bytes::lossy(e.as_bytes()),
+                    }),
                 }
-                _ => {} // 如果没读出来，不用管
+            });
+        Ok(Box::new(iter))
+    }
+
+    /// 一共有多少个还活着的key，namespace里的不算
+    fn len(&self) -> Result<usize> {
+        let mut count = 0;
+        for key in self.keys()? {
+            key?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        match self.keys()?.next() {
+            Some(key) => key.map(|_| false),
+            None => Ok(true),
+        }
+    }
+
+    /// 读出来的同时删掉，key不存在的话返回 `None` 而不是报错
+    ///
+    /// 默认实现是读出来以后用 `compare_and_swap` 删，中间被别人改了的话重来一遍，所以也是原子的
+    fn getdel(&self, key: &str) -> Result<Option<String>> {
+        loop {
+            let value = match self.get(key)? {
+                Some(value) => value,
+                None => return Ok(None),
+            };
+            if self.compare_and_swap(key, Some(&value[..]), None)? {
+                return Ok(Some(value));
             }
-        } else {
-            // 之前没见过a，假设当前总共有6个command，那么要把a: 1写到文件6里
-            let path = self.root.join(format!("{}", self.seek)); // a: 1应该存到文件6里
-            let mut file = File::create(&path)?; // 但万一这里提前return了……
+        }
+    }
+
+    /// 把 `from` 改名成 `to` ， `to` 已经存在的话会被覆盖。 `from` 不存在的话报 `NotFound`
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        match self.getdel(from)? {
+            Some(value) => self.set(to.to_string(), value),
+            None => Err(KvsError::NotFound {
+                key: from.to_string(),
+            }),
+        }
+    }
 
-            let command = Command::Set(key.clone(), value.clone());
-            let string = serde_json::to_string(&command)?;
-            file.write(string.as_bytes())?;
+    /// 和 `rename` 一样，但是 `to` 已经存在的话什么都不做，返回 `false`
+    fn rename_nx(&self, from: &str, to: &str) -> Result<bool> {
+        if self.get(from)?.is_none() {
+            return Err(KvsError::NotFound {
+                key: from.to_string(),
+            });
+        }
+        if self.get(to)?.is_some() {
+            return Ok(false);
+        }
+        self.rename(from, to)?;
+        Ok(true)
+    }
 
-            // 更新内存里的表示
-            self.map.insert(key.clone(), self.seek);
-            self.logs.push((key, Storage::Memory(value))); // write-through策略？set的时候不仅写到磁盘里，也写到内存里
-            self.seek += 1;
+    /// 把 `from` 的value复制一份到 `to` ， `to` 已经存在的话会被覆盖。 `from` 不存在的话报 `NotFound`
+    ///
+    /// 对 `to` 来说就是一次普通的set，元数据和TTL不会跟过去
+    fn copy(&self, from: &str, to: &str) -> Result<()> {
+        match self.get_bytes(from.as_bytes())? {
+            Some(value) => self.set_bytes(to.as_bytes().to_vec(), value),
+            None => Err(KvsError::NotFound {
+                key: from.to_string(),
+            }),
         }
+    }
+
+    /// 在value后面接上 `suffix` ，返回接完以后有多少字节。key不存在的话就当原来是空的
+    ///
+    /// log-structured的engine没法真的原地改，还是读出来接好再写一遍，省的是客户端来回的那一趟。
+    /// 默认实现读和写之间可能插进别人的写，value不一定是utf8，没法用 `compare_and_swap` 兜着，要原子的话engine自己实现
+    fn append(&self, key: &str, suffix: &str) -> Result<usize> {
+        let mut value = self.get_bytes(key.as_bytes())?.unwrap_or_default();
+        value.extend_from_slice(suffix.as_bytes());
+        let len = value.len();
+        self.set_bytes(key.as_bytes().to_vec(), value)?;
+        Ok(len)
+    }
+
+    /// 把 `operand` 叠到 `key` 的value上，怎么叠看engine上设置的 `MergeOperator` 。不改TTL
+    ///
+    /// 支持merge的engine自己实现，默认是没设置operator
+    fn merge(&self, key: &[u8], operand: Vec<u8>) -> Result<()> {
+        let _ = (key, operand);
+        Err(KvsError::NoMergeOperator)
+    }
 
+    /// 把到现在为止的写都落到磁盘上再返回，不管打开的时候选的是什么 `Durability`
+    ///
+    /// 用 `Never` 打开、只在自己觉得要紧的地方调一下，比每个写都fsync快得多。纯内存的engine什么都不用做
+    fn flush(&self) -> Result<()> {
         Ok(())
     }
 
-    // 标准答案里key也是String，我给改了
-    fn remove(&mut self, key: &str) -> Result<()> {
-        // 假设删除a: 1
-        if let Some(offset) = self.map.get(key).cloned() {
-            // a: 1确实在数据库里，假设存在文件2里，那么如果删掉文件2，会留下2这个空洞。把最后一个command填充到文件2里，就没有空洞啦
-            self.seek -= 1; // 假设现在数据库里有6个command，所以seek是6，最后一个command存在文件5里
-            let path = self.root.join(format!("{}", self.seek)); // 最后一个command存在文件5里
-            let new_path = self.root.join(format!("{}", offset)); // 要删除的a: 1存在文件2里
-
-            if self.seek != offset {
-                rename(&path, &new_path)?; // 把文件5重命名为2，就填充了2这个空洞
-
-                // 不要忘了更新内存里的表示
-                let mut log = self.logs.pop().unwrap();
-                match log.1 {
-                    Storage::Disk(_) => {
-                        log.1 = Storage::Disk(offset); // 现在最后一个command存在文件2里了
-                    }
-                    _ => {} // 已经在内存里缓存的话就不用管了
-                }
-                self.logs[offset] = log;
-                self.map.insert(self.logs[offset].0.clone(), offset);
-            } else {
-                // 也有可能a: 1是数据库里唯一的entry
-                remove_file(&path)?; // 直接删掉就好了
+    /// `open_engine` 用的那个名字，服务器的 `Stats` 里报这个。默认是类型名
+    fn engine_name(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
 
-                self.logs.pop(); // 内存里也是
-                self.map.remove(key);
-            }
+    /// 有多少key、磁盘上占了多少、cache的命中率。默认只数一下key
+    fn stats(&self) -> Result<StoreStats> {
+        Ok(StoreStats {
+            keys: self.len()?,
+            ..Default::default()
+        })
+    }
 
-            Ok(())
-        } else {
-            // a: 1不在数据库里，数据库里面没有a这个key
-            Err(KvsError::NotFound {
-                key: key.to_string(),
-            }) // 再次提问……remove的时候key不存在，不管不就好了吗
+    /// 换上新的value，返回旧的value。原来没有这个key的话返回 `None`
+    ///
+    /// 和 `getdel` 一样是用 `compare_and_swap` 换的，换不上就重来
+    fn getset(&self, key: String, value: String) -> Result<Option<String>> {
+        loop {
+            let previous = self.get(&key[..])?;
+            if self.compare_and_swap(&key[..], previous.as_deref(), Some(value.clone()))? {
+                return Ok(previous);
+            }
         }
     }
-}
 
-// 这个名字起的实在是太奇怪了，Engine让人感觉是interface，可是这里SledKvsEngine却又是个struct。按照这样的命名，KvsStore也应该改名叫KvsStoreEngine
-pub struct SledKvsEngine {
-    store: Db,
-    stash: Option<String>,
-}
+    /// 现在的value是 `expected` 的话才换成 `new` ，换了返回 `true` 。 `None` 表示key不存在：`expected` 是 `None` 就是只在key不存在的时候写， `new` 是 `None` 就是删掉
+    ///
+    /// 好几个线程一起用的时候，读和写之间不能插进别人的写，这只有engine自己做得到，所以没有默认实现。
+    /// `getdel` 、 `getset` 、 `set_nx` 这些的默认实现都是靠它保证原子的
+    fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: Option<&str>,
+        new: Option<String>,
+    ) -> Result<bool>;
 
-impl SledKvsEngine {
-    pub fn open<T>(root: T) -> Result<Self>
+    /// key不存在的时候才写，写了返回 `true` 。拿来当锁用的话，谁写进去了谁就拿到了锁
+    fn set_nx(&self, key: String, value: String) -> Result<bool> {
+        self.compare_and_swap(&key[..], None, Some(value))
+    }
+
+    /// key存在的话返回现在的value，不存在的话用 `f` 算一个写进去再返回。 `f` 只在key不存在的时候调用
+    ///
+    /// 算的时候别人先写进去了的话，返回别人写的那个
+    fn get_or_insert_with<F>(&self, key: &str, f: F) -> Result<String>
     where
-        T: Into<PathBuf>,
+        F: FnOnce() -> String,
+        Self: Sized,
     {
-        let root = root.into();
-        create_dir_all(&root)?;
-
-        match archive_type(&root) {
-            Ok(name) => {
-                if name != "sled" {
-                    return Err(KvsError::BadArchive {
-                        path: root,
-                        should: name,
-                        tried: format!("sled"),
-                    });
-                }
-            }
-            Err(KvsError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {
-                let mut file = File::create(root.join(".kvs"))?;
-                file.write("sled".as_bytes())?;
+        if let Some(value) = self.get(key)? {
+            return Ok(value);
+        }
+        let value = f();
+        loop {
+            if self.set_nx(key.to_string(), value.clone())? {
+                return Ok(value);
             }
-            Err(e) => {
-                return Err(e);
+            if let Some(existing) = self.get(key)? {
+                return Ok(existing);
             }
         }
+    }
 
-        Ok(Self {
-            store: sled::open(root)?,
-            stash: None,
-        })
+    /// 把一个key连同元数据导出来，key不存在的话是 `None`
+    fn dump(&self, key: &str) -> Result<Option<Dump>> {
+        let value = match self.get(key)? {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let meta = self.metadata(key)?.unwrap_or_default();
+        Ok(Some(Dump {
+            key: key.to_string(),
+            value,
+            meta,
+        }))
     }
-}
 
-impl KvsEngine for SledKvsEngine {
-    fn get(&mut self, key: &str) -> Result<Option<&str>> {
-        match self.store.get(key.as_bytes()) {
-            Ok(Some(v)) => {
-                self.stash = Some(std::str::from_utf8(v.as_ref()).unwrap().to_string());
-                Ok(self.stash.as_ref().map(|v| &v[..])) // 因为存的时候只允许存String，所以这里应该不会panic
-            }
-            Ok(None) => Ok(None),
-            Err(e) => Err(KvsError::Sled(e)),
+    /// 把 `dump` 导出的key导进来。key已经存在的话，除非 `replace` ，不然报 `KeyExists`
+    ///
+    /// 就是一次普通的set，所以元数据是在这边重新开始算的
+    fn restore(&self, dump: Dump, replace: bool) -> Result<()> {
+        if !replace && self.get(&dump.key[..])?.is_some() {
+            return Err(KvsError::KeyExists { key: dump.key });
         }
+        self.set(dump.key, dump.value)
     }
 
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        match self.store.insert(key.as_bytes(), value.as_bytes()) {
-            Ok(_) => {
-                self.store.flush()?; // 巨坑，千万千万不要忘记flush，这样才会写回磁盘
-                Ok(())
-            }
-            Err(e) => Err(KvsError::Sled(e)),
-        }
+    /// 一口气导入一大堆数据，返回导入了多少个。同一个key出现多次的话以最后一次为准
+    fn bulk_load<I>(&self, pairs: I) -> Result<usize>
+    where
+        I: IntoIterator<Item = (String, String)>,
+        Self: Sized,
+    {
+        self.bulk_load_iter(&mut pairs.into_iter())
     }
 
-    fn remove(&mut self, key: &str) -> Result<()> {
-        match self.store.remove(key.as_bytes()) {
-            Ok(Some(_)) => {
-                self.store.flush()?;
-                Ok(())
-            }
-            Ok(None) => Err(KvsError::NotFound {
-                key: key.to_string(),
-            }), // 到底是为什么key不存在算是个错误
-            Err(e) => Err(KvsError::Sled(e)),
+    /// `bulk_load` 真正干活的地方，engine要实现的是这个
+    ///
+    /// 默认就是一个一个set，engine可以自己实现更快的办法，比如不要每个写都flush
+    fn bulk_load_iter(&self, pairs: &mut dyn Iterator<Item = (String, String)>) -> Result<usize> {
+        let mut count = 0;
+        for (key, value) in pairs {
+            self.set(key, value)?;
+            count += 1;
         }
+        Ok(count)
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-enum Request {
-    Get(String),
-    Set(String, String),
-    Remove(String),
+/// bulk load的时候一批排序多少个
+const BULK_BATCH: usize = 10000;
+
+/// 一个key的元数据，时间都是从UNIX epoch开始的毫秒数
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct KeyMeta {
+    /// 第一次set的时间。老版本写的记录里没有时间，是0
+    pub created: u64,
+    /// 最后一次set的时间
+    pub modified: u64,
+    /// value有多少字节
+    pub size: u64,
+    /// 第几次set，第一次set是1。老版本写的记录是0
+    pub version: u64,
+    /// 什么时候过期，0表示永不过期
+    #[serde(default)]
+    pub expires: u64,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-enum Response {
-    Done(Option<String>),
-    Failed(String),
+impl KeyMeta {
+    /// 在 `previous` 的基础上又set了一次
+    pub(crate) fn next(previous: Option<&KeyMeta>, size: usize) -> Self {
+        let now = now_millis();
+        match previous {
+            Some(previous) => Self {
+                created: previous.created,
+                modified: now,
+                size: size as u64,
+                version: previous.version + 1,
+                expires: 0,
+            },
+            None => Self {
+                created: now,
+                modified: now,
+                size: size as u64,
+                version: 1,
+                expires: 0,
+            },
+        }
+    }
+
+    /// 过期时间从现在开始算
+    pub(crate) fn with_ttl(mut self, ttl: Duration) -> Self {
+        self.expires = now_millis().saturating_add(ttl.as_millis() as u64);
+        self
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires != 0 && self.expires <= now_millis()
+    }
 }
 
-pub struct KvsClient {
-    address: String,
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|v| v.as_millis() as u64)
+        .unwrap_or(0) // 时钟被调到1970年以前了……
 }
 
-impl KvsClient {
-    pub fn connect(address: String) -> Result<Self> {
-        Ok(Self { address: address }) // 假的connect，每次请求都要打开新的socket，不能复用socket
-    }
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+enum Command {
+    Set(Bytes, Bytes, #[serde(default)] KeyMeta), // 老版本的记录只有key和value
+    /// 墓碑：删除也是追加一条记录，不动前面的记录。被删的Set和墓碑自己都算没用的，等compact的时候一起扔掉
+    Remove(Bytes),
+    /// 后面紧跟着的这么多个command是一个batch，没写全的话一个都不算
+    Batch(u64),
+    /// value在这个编号的blob里
+    SetBlob(Bytes, u64, KeyMeta),
+    /// 压缩过的Set，不用自己写，`segment::encode` 的时候按store的设置换的
+    SetCompressed(Bytes, Compressed, KeyMeta),
+    /// merge的操作数，叠在前面的value上。compact的时候叠好写成Set
+    Merge(Bytes, Bytes, KeyMeta),
+}
 
-    /// 发送请求，等待回应
-    fn request(&mut self, request: Request) -> Result<Response> {
-        let mut stream = TcpStream::connect(&self.address)?; // 打开socket
-        let mut string = serde_json::to_string(&request)?;
-        stream.write_all(string.as_bytes())?; // 发请求
-        stream.shutdown(Shutdown::Write)?; // 这很关键，要关闭上传通道，这样服务器才会收到EOF，不然死锁
+/// 一个key的command写在了哪，顺便缓存读出来的value
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Entry {
+    position: Position,
+    meta: KeyMeta,
+    /// 读过一次或者刚set过的话，value就留在内存里了。和快照共享，复制索引的时候不用把value也复制一遍
+    value: Option<Arc<Vec<u8>>>,
+    /// value太大，单独放在这个blob里
+    blob: Option<u64>,
+    /// `position` 之后还没叠上去的merge操作数。 `position` 指着的本身就是merge的话，它也在里面
+    operands: Vec<Vec<u8>>,
+    /// 被覆盖了的老版本，新的在前面。开了 `keep_versions` 才有
+    history: Vec<Version>,
+}
 
-        string.clear();
-        stream.read_to_string(&mut string)?; // 收响应
-        let response: Response = serde_json::from_str(&string[..])?;
-        return Ok(response);
+impl Entry {
+    /// 这个key被覆盖或者删掉以后，compact能省下多少字节，留着的老版本也算
+    fn garbage(&self) -> u64 {
+        let own = match self.blob {
+            Some(_) => self.position.len + self.meta.size,
+            None => self.position.len,
+        };
+        own + self.history.iter().map(Version::garbage).sum::<u64>()
     }
+}
 
-    /// 无聊的CRUD……
-    pub fn get(&mut self, key: &str) -> Result<Option<String>> {
-        let response = self.request(Request::Get(key.to_string()))?;
-        match response {
-            Response::Done(v) => Ok(v),
-            Response::Failed(e) => Err(KvsError::Remote { message: e }),
-        }
-    }
+/// 一个被覆盖了但是还留着的value，磁盘上的记录compact的时候不扔
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Version {
+    position: Position,
+    meta: KeyMeta,
+    blob: Option<u64>,
+}
 
-    pub fn set(&mut self, key: String, value: String) -> Result<()> {
-        let response = self.request(Request::Set(key, value))?;
-        match response {
-            Response::Done(_) => Ok(()),
-            Response::Failed(e) => Err(KvsError::Remote { message: e }),
+impl Version {
+    fn garbage(&self) -> u64 {
+        match self.blob {
+            Some(_) => self.position.len + self.meta.size,
+            None => self.position.len,
         }
     }
 
-    pub fn remove(&mut self, key: &str) -> Result<()> {
-        let response = self.request(Request::Remove(key.to_string()))?;
-        match response {
-            Response::Done(_) => Ok(()),
-            Response::Failed(e) => Err(KvsError::Remote { message: e }),
+    /// 当成一个只有这个版本的entry，读的时候和当前的value走一样的路
+    fn entry(&self) -> Entry {
+        Entry {
+            position: self.position,
+            meta: self.meta,
+            value: None,
+            blob: self.blob,
+            operands: vec![],
+            history: vec![],
         }
     }
 }
 
-pub struct KvsServer<T> {
-    engine: T,
+/// 当前segment超过这么大就换一个新的
+const SEGMENT_SIZE: u64 = 4 << 20;
+
+/// 被覆盖、被删掉的command攒够这么多字节就compact一次
+const COMPACTION_THRESHOLD: u64 = 1 << 20;
+
+/// KvStore的磁盘格式版本，改了格式要加一，然后在open里写上怎么从上一版升级
+///
+/// - 0： `.kvs` 里只有 `kvs` ，可能还有一个command一个文件的老格式
+/// - 1： `.kvs` 是json，数据都在segment里
+const KVS_FORMAT: u32 = 1;
+
+/// SledKvsEngine的磁盘格式版本
+///
+/// - 0： `.kvs` 里只有 `sled`
+/// - 1： `.kvs` 是json
+#[cfg(feature = "sled")]
+const SLED_FORMAT: u32 = 1;
+
+/// 所有key的索引都在内存里，value按需去segment里读
+///
+/// 所以get一个不存在的key查一下索引就知道了，根本不碰磁盘，用不着给segment加bloom filter。
+/// clone出来的是同一个store，好几个线程一人拿一个clone就行：改索引、追加segment要拿一把锁，去磁盘上读value和 `Always` 的fsync都在锁外面做
+#[derive(Clone, Debug)]
+pub struct KvStore {
+    state: Arc<Mutex<State>>,
 }
 
-impl<T> KvsServer<T>
-where
-    T: KvsEngine,
-{
-    pub fn new(engine: T) -> Self {
-        Self { engine: engine }
+/// KvStore锁着的那些东西
+#[derive(Debug)]
+struct State {
+    /// `map["a"].position == (3, 120, 40)` 表示 `"a"` 最新的command在 `3.log` 的第120个字节开始，一共40个字节
+    ///
+    /// 按key排好序的，范围扫描的时候直接在上面走。和 `ReadView` 共享，有快照的时候改之前先复制一份
+    map: Arc<Index>, // 以前key只能是utf8，现在终于可以是bytes了
+    /// 正在追加的segment，第一次写的时候才打开
+    writer: Option<Writer>,
+    /// 磁盘上有多少字节的command是已经没用了的，compact的时候能省下来
+    stale: u64,
+    /// 存log的目录。PathBuf和Path的关系类似String和&str
+    root: PathBuf,
+    /// 打开的segment不关，下次读同一个segment就不用再open了
+    handles: HandleCache,
+    durability: Durability,
+    /// 上次fsync是什么时候， `Durability::Every` 用的
+    synced: Instant,
+    /// `Durability::Always` 的时候几个写一起fsync
+    commit: GroupCommit,
+    /// 哪些key的value在内存里，按最近用过的排。`None` 是不限
+    resident: Option<Lru<Vec<u8>, ()>>,
+    /// 打开以后写了几次，每次写（一个batch算一次）加一
+    version: u64,
+    /// 每个 `ReadView` 拿一个clone，只有store自己拿着的时候才没有快照
+    pins: Arc<()>,
+    /// compact完了但是有快照钉着、还不能删的segment和blob
+    retired: Vec<PathBuf>,
+    /// 下一个blob的编号
+    next_blob: u64,
+    /// 新写的value用什么压缩
+    compression: Compression,
+    /// 读value的时候在内存里的次数、要去磁盘上读的次数
+    hits: u64,
+    misses: u64,
+    /// 拿着目录的锁，drop的时候释放。 `new` 出来的没有目录，也就没有锁
+    lock: Option<File>,
+    /// `watch` 拿到的订阅，写成功了以后挨个发
+    watchers: Watchers,
+    /// 用来叠merge的操作数，没设置的话merge会报错
+    merge: Option<MergeOperator>,
+    compaction: CompactionPolicy,
+    /// 上次看过以后segment有没有变多或者变少，没变的话size-tiered不用再去看一遍
+    segments_changed: bool,
+    /// 目录大概有多大，每次写加上去，compact完了重新数一遍
+    disk_bytes: u64,
+    max_disk_bytes: Option<u64>,
+    /// 归档目录，segment删之前先链接到这里
+    archive: Option<PathBuf>,
+    /// 每个key留几个被覆盖了的老版本
+    keep_versions: usize,
+    /// `Always` 的时候追加完了还没等的fsync，放开锁以后再等
+    pending: Option<(u64, Arc<File>)>,
+}
+
+/// 写下去以后什么时候fsync
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Durability {
+    /// 每个写都fsync，最慢，但是返回了就肯定在磁盘上了。同时在写的几个会排队一起fsync（组提交）
+    Always,
+    /// 离上次fsync超过这么久了，下一个写的时候顺便fsync。没有后台线程，所以之后一直没人写的话，最后那几个写会一直没fsync
+    Every(Duration),
+    /// 从来不主动fsync，交给操作系统，断电的话可能丢掉最近的写。要紧的地方自己调 `KvsEngine::flush`
+    Never,
+}
+
+/// 打开的时候先把哪些value读进内存，第一批请求就不用等磁盘了
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub enum WarmUp {
+    /// 最近写的这么多个key
+    Recent(usize),
+    /// 就这些key，不存在的跳过
+    Keys(Vec<Vec<u8>>),
+}
+
+/// 打开KvStore时候的各种选项
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct KvStoreOptions {
+    /// 最多同时开着多少个文件
+    pub max_open_files: usize,
+    /// 打开的时候就把所有value都读进内存，之后读就不用碰磁盘了。数字是最多读多少字节，数据比这个大的话open会失败
+    pub preload: Option<u64>,
+    /// segment中间坏了的记录跳过去（打一行日志），不然open直接报 `Corrupt`
+    pub skip_corrupt: bool,
+    /// 默认是 `Never` ，和以前一样
+    pub durability: Durability,
+    /// 内存里最多留多少个value，多了就把最久没读过的扔掉，下次读的时候再去segment里读。`None` 是不限，和以前一样
+    ///
+    /// 和 `preload` 一起用的话，预加载完只留最后读进来的那些
+    pub cache_capacity: Option<usize>,
+    /// 之后写的value用什么压缩，默认不压。已经写下去的不受影响，不管用什么压的都能读
+    pub compression: Compression,
+    /// 什么时候compact、一次compact多少，默认是 `Full` ，和以前一样
+    pub compaction: CompactionPolicy,
+    /// 打开的时候用几个线程一起读segment。`None` 是有几个CPU就用几个，`Some(1)` 就是以前那样一个一个读
+    pub recovery_threads: Option<usize>,
+    /// segment和blob加起来最多多大。再写就超了的话先compact一下，还是不够就报 `QuotaExceeded` 。`None` 是不限，和以前一样
+    ///
+    /// 只有删除的写不受限制，不然满了以后连删都删不掉。compact的时候新segment写完了才删老的，所以磁盘上要比这个多留一点地方
+    pub max_disk_bytes: Option<u64>,
+    /// 归档目录。设置了的话写完了的segment、写进去的blob都会硬链接一份到这里，compact也不会把它们真的删掉，之后可以用 `restore_to` 恢复到以前的任何时刻
+    ///
+    /// 归档只会越来越大，不用了的自己去删。最好和数据在同一个文件系统上，不然链接不了只能拷贝
+    pub archive: Option<PathBuf>,
+    /// 打开以后先读进内存的value，`None` 是用到了再读，和以前一样。和 `cache_capacity` 一起用的话，多出来的会被挤掉
+    pub warm_up: Option<WarmUp>,
+    /// 覆盖的时候每个key留下最近的几个老版本，可以用 `get_version` 、 `history` 读回来，当撤销用。默认是0，和以前一样不留
+    ///
+    /// 老版本就是segment里原来的那条记录，不另外占地方，只是compact的时候不扔。删掉、过期了的key连同老版本一起没了，
+    /// 调小了的话多出来的下次compact的时候扔掉
+    pub keep_versions: usize,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        Self {
+            max_open_files: 64,
+            preload: None,
+            skip_corrupt: false,
+            durability: Durability::Never,
+            cache_capacity: None,
+            compression: Compression::None,
+            compaction: CompactionPolicy::Full,
+            recovery_threads: None,
+            max_disk_bytes: None,
+            archive: None,
+            warm_up: None,
+            keep_versions: 0,
+        }
     }
+}
 
-    /// 只服务一次请求就return
-    fn serve(&mut self, stream: &mut TcpStream) -> Result<()> {
-        let mut string = String::new();
-        stream.read_to_string(&mut string)?; // 收请求
-        let request: Request = serde_json::from_str(&string[..])?;
-        let response = match request {
-            Request::Get(key) => match self.engine.get(&key[..]) {
-                Ok(value) => Response::Done(value.map(|v| v.to_string())),
-                Err(e) => Response::Failed(format!("{}", e)),
-            },
-            Request::Set(key, value) => match self.engine.set(key, value) {
-                Ok(_) => Response::Done(None),
-                Err(e) => Response::Failed(format!("{}", e)),
-            },
-            Request::Remove(key) => match self.engine.remove(&key[..]) {
-                Ok(_) => Response::Done(None),
-                Err(e) => Response::Failed(format!("{}", e)),
-            },
-        };
-        let string = serde_json::to_string(&response)?;
-        stream.write_all(string.as_bytes())?; // 发响应
-        Ok(())
+impl Default for KvStore {
+    fn default() -> Self {
+        Self::new()
     }
+}
 
-    /// 在某个ip:port上一直处理请求
-    pub fn run<U>(&mut self, address: U) -> Result<()>
+impl State {
+    fn new() -> Self {
+        Self {
+            map: Arc::new(BTreeMap::new()),
+            writer: None,
+            stale: 0,
+            root: PathBuf::new(), // 空的path会是啥呢……
+            handles: HandleCache::new(KvStoreOptions::default().max_open_files),
+            durability: KvStoreOptions::default().durability,
+            synced: Instant::now(),
+            commit: GroupCommit::new(),
+            resident: None,
+            version: 0,
+            pins: Arc::new(()),
+            retired: vec![],
+            next_blob: 0,
+            compression: Compression::None,
+            hits: 0,
+            misses: 0,
+            lock: None,
+            watchers: Watchers::default(),
+            merge: None,
+            compaction: CompactionPolicy::Full,
+            segments_changed: false,
+            disk_bytes: 0,
+            max_disk_bytes: None,
+            archive: None,
+            keep_versions: 0,
+            pending: None,
+        }
+    }
+
+    fn open_with<T>(root: T, options: KvStoreOptions) -> Result<Self>
     where
-        U: ToSocketAddrs,
+        T: Into<PathBuf>,
     {
-        let listener = TcpListener::bind(address)?;
-        for stream in listener.incoming() {
-            match stream {
-                Ok(mut stream) => match self.serve(&mut stream) {
-                    Ok(_) => {
-                        println!("{:?}", stream);
+        let root = root.into();
+        create_dir_all(&root).at(&root)?; // 把存log的目录先建了
+
+        let lock = fs::lock(&root)?; // 之后的操作都会改目录里的文件，先把目录占住。看格式、升级格式也要在锁里面
+        let format = archive::check(&root, "kvs", KVS_FORMAT)?.unwrap_or(KVS_FORMAT); // 新目录直接就是最新的格式
+
+        fs::remove_temporaries(&root)?; // 上次写到一半崩了留下来的
+        if root.join(blob::DIR).exists() {
+            fs::remove_temporaries(&root.join(blob::DIR))?;
+        }
+
+        let mut store = Self {
+            map: Arc::new(BTreeMap::new()),
+            writer: None,
+            stale: 0,
+            root,
+            handles: HandleCache::new(options.max_open_files),
+            durability: options.durability,
+            synced: Instant::now(),
+            commit: GroupCommit::new(),
+            resident: options.cache_capacity.map(|v| Lru::new(v.max(1))), // 至少要留得住刚读的那一个
+            version: 0,
+            pins: Arc::new(()),
+            retired: vec![],
+            next_blob: 0,
+            compression: options.compression,
+            hits: 0,
+            misses: 0,
+            lock: Some(lock),
+            watchers: Watchers::default(),
+            merge: None,
+            compaction: options.compaction,
+            segments_changed: true,
+            disk_bytes: 0,
+            max_disk_bytes: None, // 搬老文件不受限制，搬完了再设置
+            archive: options.archive.clone(),
+            keep_versions: options.keep_versions,
+            pending: None,
+        };
+
+        // 从老到新重放每个segment，读可以好几个segment一起读
+        let root = store.root.clone();
+        let threads = options.recovery_threads.unwrap_or_else(|| {
+            std::thread::available_parallelism().map_or(1, |threads| threads.get())
+        });
+        let segments = segment::list(&root)?;
+        segment::read_parallel(
+            &root,
+            &segments,
+            options.skip_corrupt,
+            threads,
+            |commands| {
+                for (command, position) in commands {
+                    match command {
+                        Command::Set(key, value, mut meta) => {
+                            if meta.version == 0 {
+                                meta.size = value.0.len() as u64; // 老版本的记录，至少大小是知道的
+                            }
+                            store.index(key.0.clone(), position, meta, None);
+                            store.expire(&key.0[..]);
+                        }
+                        Command::Remove(key) => {
+                            if let Some(entry) = store.unindex(&key.0[..]) {
+                                store.stale += entry.garbage();
+                            }
+                            store.stale += position.len; // remove自己也是没用的
+                        }
+                        Command::Batch(_) => store.stale += position.len,
+                        Command::SetCompressed(key, _, meta) => {
+                            store.index(key.0.clone(), position, meta, None); // 用到的时候再解压
+                            store.expire(&key.0[..]);
+                        }
+                        Command::SetBlob(key, id, meta) => {
+                            store.index_blob(key.0.clone(), position, meta, id);
+                            store.expire(&key.0[..]);
+                        }
+                        Command::Merge(key, operand, meta) => {
+                            store.expire(&key.0[..]);
+                            store.stale += position.len; // 不用operator，先记下来，读的时候再叠
+                            store.index_operand(key.0, position, meta, operand.0, None);
+                        }
                     }
-                    Err(e) => {
-                        eprintln!("{}", e);
+                }
+                Ok(())
+            },
+        )?;
+        if let Some(id) = segments.last() {
+            store.writer = Some(Writer::open(&store.root, *id)?); // 接着往最后一个segment里写
+        }
+        if let Some(archive) = &store.archive {
+            create_dir_all(archive).at(archive)?;
+            for id in &segments[..segments.len().saturating_sub(1)] {
+                store.archive_segment(*id)?; // 刚开始归档，或者上次换segment的时候还没来得及链接就崩了
+            }
+        }
+        store.next_blob = blob::list(&store.root)?.last().map(|v| v + 1).unwrap_or(0);
+
+        // 第0版：一个command一个文件，全部搬到segment里，然后删掉
+        let (legacy, paths) = match format {
+            0 => segment::read_legacy(&store.root, options.skip_corrupt)?,
+            _ => (vec![], vec![]),
+        };
+        if !paths.is_empty() {
+            info!("Migrating {} legacy files into segments", paths.len());
+            let positions = store.append(&legacy[..])?;
+            for (command, position) in legacy.into_iter().zip(positions) {
+                if let Command::Set(key, _, meta) = command {
+                    store.index(key.0, position, meta, None);
+                }
+            }
+            if let Some(writer) = &store.writer {
+                writer.sync()?; // 老文件删掉之前，新的一定要落盘
+            }
+            segment::remove_legacy(&store.root, &paths)?;
+        }
+        archive::write(&store.root, "kvs", KVS_FORMAT, &options)?; // 升级完了才能写新的版本号
+        store.disk_bytes = store.disk_usage()?;
+        store.max_disk_bytes = options.max_disk_bytes;
+
+        if let Some(limit) = options.preload {
+            // 先看看一共有多大，太大了塞不进内存就别硬塞了
+            let mut size = 0;
+            for id in segment::list(&store.root)? {
+                size += segment::path(&store.root, id).metadata()?.len();
+            }
+            if size > limit {
+                return Err(KvsError::PreloadTooLarge { size, limit });
+            }
+
+            // 一个segment一口气读完，比一个一个key去读快多了
+            let segments = segment::list(&root)?;
+            segment::read_parallel(
+                &root,
+                &segments,
+                options.skip_corrupt,
+                threads,
+                |commands| {
+                    for (command, position) in commands {
+                        let (key, value) = match command {
+                            Command::Set(key, value, _) => (key, value.0),
+                            Command::SetCompressed(key, compressed, _) => {
+                                (key, compressed.unpack()?)
+                            }
+                            _ => continue,
+                        };
+                        match Arc::make_mut(&mut store.map).get_mut(&key.0[..]) {
+                            Some(entry)
+                                if entry.position == position && entry.operands.is_empty() =>
+                            {
+                                entry.value = Some(Arc::new(value))
+                            }
+                            _ => continue,
+                        }
+                        store.touch(&key.0[..]);
                     }
+                    Ok(())
                 },
-                Err(e) => eprintln!("{}", e),
+            )?;
+        }
+        if let Some(warm_up) = &options.warm_up {
+            store.warm_up(warm_up)?;
+        }
+
+        Ok(store)
+    }
+
+    /// 按 `warm_up` 把value读进内存。这不算读，命中率从0开始算
+    fn warm_up(&mut self, warm_up: &WarmUp) -> Result<()> {
+        let keys: Vec<Vec<u8>> = match warm_up {
+            WarmUp::Recent(n) => {
+                let mut recent: Vec<(u64, u64, &Vec<u8>)> = self
+                    .map
+                    .iter()
+                    .map(|(key, entry)| (entry.position.segment, entry.position.offset, key))
+                    .collect();
+                recent.sort_unstable_by(|a, b| b.cmp(a));
+                recent
+                    .into_iter()
+                    .take(*n)
+                    .rev() // 最新的最后读，cache放不下的话留下的是它
+                    .map(|(_, _, key)| key.clone())
+                    .collect()
+            }
+            WarmUp::Keys(keys) => keys.clone(),
+        };
+        for key in keys {
+            self.load(&key[..])?;
+        }
+        self.hits = 0;
+        self.misses = 0;
+        Ok(())
+    }
+
+    fn read_view(&self) -> ReadView {
+        ReadView {
+            map: self.map.clone(),
+            handles: self.handles.clone(),
+            root: self.root.clone(),
+            version: self.version,
+            merge: self.merge.clone(),
+            _pin: self.pins.clone(),
+        }
+    }
+
+    fn stats(&self) -> Result<StoreStats> {
+        let segments = segment::list(&self.root)?;
+        let compaction_debt = compaction::debt(
+            self.compaction,
+            &self.closed_segments()?[..],
+            SEGMENT_SIZE,
+            self.stale >= COMPACTION_THRESHOLD,
+        );
+        Ok(StoreStats {
+            keys: self
+                .map
+                .values()
+                .filter(|entry| !entry.meta.is_expired())
+                .count(),
+            disk_bytes: self.disk_usage()?,
+            garbage_bytes: Some(self.stale),
+            segments: Some(segments.len()),
+            compaction_debt: Some(compaction_debt),
+            cache_hits: self.hits,
+            cache_misses: self.misses,
+        })
+    }
+
+    /// segment和blob一共多少字节
+    fn disk_usage(&self) -> Result<u64> {
+        let mut size = 0;
+        for id in segment::list(&self.root)? {
+            size += segment::path(&self.root, id).metadata()?.len();
+        }
+        for id in blob::list(&self.root)? {
+            size += blob::path(&self.root, id).metadata()?.len();
+        }
+        Ok(size)
+    }
+
+    /// 再写 `len` 个字节会不会超过 `max_disk_bytes` ，会的话先compact一下再看
+    fn reserve(&mut self, len: u64) -> Result<()> {
+        let limit = match self.max_disk_bytes {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let over = self.disk_bytes + len > limit;
+        if over && self.stale > 0 && self.disk_bytes.saturating_sub(self.stale) + len <= limit {
+            self.compact()?; // 扔掉没用的字节就放得下的话才compact，不然每个写都白白compact一遍
+        }
+        if self.disk_bytes + len > limit {
+            return Err(KvsError::QuotaExceeded {
+                size: self.disk_bytes + len,
+                limit,
+            });
+        }
+        Ok(())
+    }
+
+    fn snapshot<T>(&mut self, dest: T) -> Result<Manifest>
+    where
+        T: AsRef<Path>,
+    {
+        let dest = dest.as_ref();
+        snapshot::prepare(dest)?;
+
+        let active = match &self.writer {
+            Some(writer) => {
+                writer.sync()?; // 拷贝的是磁盘上的，先落盘
+                Some((writer.segment(), writer.len()))
+            }
+            None => None,
+        };
+        let mut segments = vec![];
+        for id in segment::list(&self.root)? {
+            let len = match active {
+                Some((segment, len)) if segment == id => {
+                    snapshot::copy_prefix(&self.root, dest, id, len)?;
+                    len
+                }
+                _ => snapshot::link(&self.root, dest, id)?,
+            };
+            segments.push(SegmentInfo { id, len });
+        }
+        let blobs: BTreeSet<u64> = self.map.values().filter_map(|entry| entry.blob).collect(); // 已经没用了的不要
+        for id in &blobs {
+            snapshot::link_blob(&self.root, dest, *id)?;
+        }
+        if !blobs.is_empty() {
+            fs::sync_dir(&dest.join(blob::DIR))?;
+        }
+
+        let manifest = Manifest {
+            created: now_millis(),
+            segments,
+            blobs: blobs.into_iter().collect(),
+        };
+        snapshot::finish(&self.root, dest, &manifest)?;
+        Ok(manifest)
+    }
+
+    fn checkpoint<T>(&mut self, dest: T) -> Result<Manifest>
+    where
+        T: AsRef<Path>,
+    {
+        if self.writer.as_ref().is_some_and(|writer| writer.len() > 0) {
+            self.rotate()?;
+        }
+        self.snapshot(dest) // 新的空segment拷贝过去还是空的，备份打开以后往它自己的那个里面写，碰不到这边的segment
+    }
+
+    fn restore_to<T>(&mut self, dest: T, timestamp: u64) -> Result<()>
+    where
+        T: AsRef<Path>,
+    {
+        let archive = self
+            .archive
+            .clone()
+            .ok_or_else(|| KvsError::InvalidArgument {
+                name: "archive".to_string(),
+                value: "none".to_string(),
+            })?;
+        if self.writer.as_ref().is_some_and(|writer| writer.len() > 0) {
+            self.rotate()?;
+        }
+        pitr::restore(&archive, dest.as_ref(), timestamp)
+    }
+
+    fn get_version(&mut self, key: &[u8], n: usize) -> Result<Option<Vec<u8>>> {
+        if n == 0 {
+            return self.get_bytes(key);
+        }
+        self.expire(key);
+        let version = match self.map.get(key).and_then(|entry| entry.history.get(n - 1)) {
+            Some(version) => version.entry(),
+            None => return Ok(None),
+        };
+        view::read_value(&self.handles, &self.root, None, key, &version)
+    }
+
+    fn history(&mut self, key: &[u8]) -> Result<Vec<(KeyMeta, Vec<u8>)>> {
+        let current = match self.get_bytes(key)? {
+            Some(value) => value,
+            None => return Ok(vec![]),
+        };
+        let entry = &self.map[key];
+        let mut versions = vec![(entry.meta, current)];
+        for version in &entry.history {
+            let value = view::read_value(&self.handles, &self.root, None, key, &version.entry())?;
+            versions.extend(value.map(|value| (version.meta, value)));
+        }
+        Ok(versions)
+    }
+
+    /// 更新内存里的索引，被覆盖的那个command就变成没用的了
+    fn index(&mut self, key: Vec<u8>, position: Position, meta: KeyMeta, value: Option<Vec<u8>>) {
+        let entry = Entry {
+            position,
+            meta,
+            value: value.map(Arc::new),
+            blob: None,
+            operands: vec![],
+            history: vec![],
+        };
+        let resident = entry.value.is_some();
+        if let Some(mut previous) = Arc::make_mut(&mut self.map).insert(key.clone(), entry) {
+            // 还在的、没有没叠的merge的老value留下来，留不下的才变成没用的
+            if self.keep_versions > 0 && previous.operands.is_empty() && !previous.meta.is_expired()
+            {
+                let mut history = std::mem::take(&mut previous.history);
+                history.insert(
+                    0,
+                    Version {
+                        position: previous.position,
+                        meta: previous.meta,
+                        blob: previous.blob,
+                    },
+                );
+                for version in history.drain(history.len().min(self.keep_versions)..) {
+                    self.stale += version.garbage();
+                }
+                Arc::make_mut(&mut self.map)
+                    .get_mut(&key[..])
+                    .unwrap()
+                    .history = history;
+            } else {
+                self.stale += previous.garbage();
+            }
+        }
+        match &mut self.resident {
+            Some(_) if resident => self.touch(&key[..]),
+            Some(lru) => {
+                lru.remove(&key[..]);
+            }
+            None => {}
+        }
+    }
+
+    /// value在blob里的key，value不放进内存
+    fn index_blob(&mut self, key: Vec<u8>, position: Position, meta: KeyMeta, id: u64) {
+        self.index(key.clone(), position, meta, None);
+        if let Some(entry) = Arc::make_mut(&mut self.map).get_mut(&key[..]) {
+            entry.blob = Some(id);
+        }
+    }
+
+    /// 记下一条merge，key不在的话这条merge就是开头。 `merged` 是已经叠好的value，有的话放进内存
+    fn index_operand(
+        &mut self,
+        key: Vec<u8>,
+        position: Position,
+        meta: KeyMeta,
+        operand: Vec<u8>,
+        merged: Option<Vec<u8>>,
+    ) {
+        if !self.map.contains_key(&key[..]) {
+            self.index(key.clone(), position, meta, None);
+        }
+        let resident = merged.is_some();
+        let entry = Arc::make_mut(&mut self.map).get_mut(&key[..]).unwrap();
+        entry.meta = meta;
+        entry.operands.push(operand);
+        entry.value = merged.map(Arc::new); // 没叠的话内存里那份就不对了
+        match &mut self.resident {
+            Some(_) if resident => self.touch(&key[..]),
+            Some(lru) => {
+                lru.remove(&key[..]);
+            }
+            None => {}
+        }
+    }
+
+    /// 从索引里拿掉
+    fn unindex(&mut self, key: &[u8]) -> Option<Entry> {
+        if let Some(lru) = &mut self.resident {
+            lru.remove(key);
+        }
+        Arc::make_mut(&mut self.map).remove(key)
+    }
+
+    /// 刚读过或者刚写过，value留在内存里。超过容量的话把最久没用过的value扔掉，只留位置
+    fn touch(&mut self, key: &[u8]) {
+        if let Some(lru) = &mut self.resident {
+            if let Some((evicted, _)) = lru.insert(key.to_vec(), ()) {
+                if let Some(entry) = Arc::make_mut(&mut self.map).get_mut(&evicted[..]) {
+                    entry.value = None;
+                }
+            }
+        }
+    }
+
+    /// 过期了的key从索引里拿掉，就当它不存在了。磁盘上的那条Set变成没用的，等compact的时候再扔掉
+    fn expire(&mut self, key: &[u8]) {
+        if let Some(entry) = self.map.get(key) {
+            if entry.meta.is_expired() {
+                self.stale += entry.garbage();
+                self.unindex(key);
+                self.notify(key, None);
+            }
+        }
+    }
+
+    /// 有人在看这个key的话发一个事件， `value` 是 `None` 就是没了
+    fn notify(&mut self, key: &[u8], value: Option<&[u8]>) {
+        if !self.watchers.watching(key) {
+            return;
+        }
+        self.watchers.publish(match value {
+            Some(value) => WatchEvent::Set {
+                key: key.to_vec(),
+                value: value.to_vec(),
+            },
+            None => WatchEvent::Remove { key: key.to_vec() },
+        });
+    }
+
+    /// 还没过期的key的元数据
+    fn live_meta(&self, key: &[u8]) -> Option<KeyMeta> {
+        self.map
+            .get(key)
+            .map(|v| v.meta)
+            .filter(|v| !v.is_expired())
+    }
+
+    /// set和set_with_ttl都是这个
+    fn put(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Option<Duration>) -> Result<()> {
+        let mut meta = KeyMeta::next(self.live_meta(&key[..]).as_ref(), value.len());
+        if let Some(ttl) = ttl {
+            meta = meta.with_ttl(ttl);
+        }
+        let command = Command::Set(Bytes(key), Bytes(value), meta);
+        let position = self.append(std::slice::from_ref(&command))?[0];
+        if let Command::Set(key, value, _) = command {
+            self.notify(&key.0[..], Some(&value.0[..]));
+            self.index(key.0, position, meta, Some(value.0)); // write-through，set的时候不仅写到磁盘里，也写到内存里
+        }
+        self.maybe_compact()
+    }
+
+    /// 开了归档的话，把这个segment链接到归档目录里，已经有了就不用了
+    fn archive_segment(&self, id: u64) -> Result<()> {
+        if let Some(archive) = &self.archive {
+            if !segment::path(archive, id).exists() {
+                snapshot::link(&self.root, archive, id)?;
+                fs::sync_dir(archive)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// 换一个新的segment接着写
+    fn rotate(&mut self) -> Result<()> {
+        let id = match &self.writer {
+            Some(writer) => writer.segment() + 1,
+            None => segment::list(&self.root)?
+                .last()
+                .map(|v| v + 1)
+                .unwrap_or(0),
+        };
+        if let Some(writer) = &self.writer {
+            // 老segment里还在排队的写，换segment之前一起fsync掉。不是 `Always` 也要，不然 `flush` 只fsync正在写的segment就不够了
+            writer.sync()?;
+            self.commit.synced_all();
+            self.archive_segment(writer.segment())?; // 不会再变了
+        }
+        self.writer = Some(Writer::open(&self.root, id)?);
+        fs::sync_dir(&self.root)?; // 新建了文件
+        self.segments_changed = true;
+        Ok(())
+    }
+
+    /// 一次write把这几个command都追加到当前segment的末尾，返回每个command的位置。不更新索引
+    fn append(&mut self, commands: &[Command]) -> Result<Vec<Position>> {
+        let mut bytes = vec![];
+        let mut lens = vec![];
+        for command in commands {
+            let (encoded, len) = segment::encode(command, self.compression)?;
+            bytes.extend(encoded);
+            lens.push(len);
+        }
+        let removes_only = commands
+            .iter()
+            .all(|command| matches!(command, Command::Remove(_) | Command::Batch(_)));
+        if !removes_only {
+            self.reserve(bytes.len() as u64)?;
+        }
+
+        let rotate = match &self.writer {
+            Some(writer) => writer.len() >= SEGMENT_SIZE,
+            None => true,
+        };
+        if rotate {
+            self.rotate()?;
+        }
+        let writer = self.writer.as_mut().unwrap();
+        self.version += 1;
+
+        let mut offset = writer.append(&bytes[..])?;
+        self.disk_bytes += bytes.len() as u64;
+        let mut positions = vec![];
+        for len in lens {
+            positions.push(Position {
+                segment: writer.segment(),
+                offset,
+                len,
+            });
+            offset += len + 1;
+        }
+
+        let sync = match self.durability {
+            Durability::Always => {
+                self.pending = Some((self.commit.appended(), writer.file())); // 放开锁以后再等，别人的fsync已经带上了的话就不用再fsync了
+                false
+            }
+            Durability::Every(interval) => self.synced.elapsed() >= interval,
+            Durability::Never => false,
+        };
+        if sync {
+            writer.sync()?;
+            self.synced = Instant::now();
+        }
+        Ok(positions)
+    }
+
+    /// 没用的command太多了就compact一下
+    fn maybe_compact(&mut self) -> Result<()> {
+        let segments = match self.compaction {
+            CompactionPolicy::Full if self.stale >= COMPACTION_THRESHOLD => {
+                return self.compact().map(|_| ())
+            }
+            CompactionPolicy::Full => None,
+            _ if !self.segments_changed => None,
+            policy => {
+                self.segments_changed = false;
+                compaction::pick(policy, &self.closed_segments()?[..], SEGMENT_SIZE)
+            }
+        };
+        match segments {
+            Some(segments) => self
+                .compact_segments(&segments[..], &mut |_| {})
+                .map(|_| ()),
+            None => self.remove_retired(), // 快照没了以后第一次写的时候顺手删
+        }
+    }
+
+    /// 已经写完了的segment的编号和大小，从老到新。正在写的、compact完了等着删的不算
+    fn closed_segments(&self) -> Result<Vec<(u64, u64)>> {
+        let active = self.writer.as_ref().map(|writer| writer.segment());
+        let mut segments = vec![];
+        for id in segment::list(&self.root)? {
+            let path = segment::path(&self.root, id);
+            if Some(id) == active || self.retired.contains(&path) {
+                continue;
+            }
+            segments.push((id, path.metadata()?.len()));
+        }
+        Ok(segments)
+    }
+
+    fn compact(&mut self) -> Result<CompactionReport> {
+        self.compact_with_progress(|_| {})
+    }
+
+    fn compact_with_progress<F>(&mut self, mut progress: F) -> Result<CompactionReport>
+    where
+        F: FnMut(CompactionProgress),
+    {
+        let segments = segment::list(&self.root)?;
+        self.compact_segments(&segments[..], &mut progress)
+    }
+
+    /// 把 `merged` 这几个segment里还活着的key写到一个新的segment里，然后把它们删掉。有merge操作数的key不管在哪个segment都重写一遍
+    ///
+    /// 新的segment编号最大，重放的时候最后放。里面的key要么是最新的value，要么是墓碑，放在最后结果是一样的，所以删之前崩了也没关系
+    fn compact_segments(
+        &mut self,
+        merged: &[u64],
+        progress: &mut dyn FnMut(CompactionProgress),
+    ) -> Result<CompactionReport> {
+        let start = Instant::now();
+        let before = self.disk_usage()?;
+        let old = segment::list(&self.root)?;
+        let id = old.last().map(|v| v + 1).unwrap_or(0);
+        let merged: BTreeSet<u64> = merged.iter().copied().collect();
+        for id in &merged {
+            self.archive_segment(*id)?; // 正在写的那个也在里面的话，合完了就不会再往里写了
+        }
+
+        // 留下来的segment里有比要合的老的话，那里面可能还有被删掉的key的老value，合完了要留墓碑，不然重放的时候又回来了
+        let newest = merged.iter().next_back().copied().unwrap_or(0);
+        let mut touched = BTreeSet::new();
+        if old.iter().any(|v| !merged.contains(v) && *v < newest) {
+            for segment in &merged {
+                for (command, _) in segment::read(&self.root, *segment, true)? {
+                    let key = match command {
+                        Command::Set(key, ..)
+                        | Command::Remove(key)
+                        | Command::SetBlob(key, ..)
+                        | Command::SetCompressed(key, ..)
+                        | Command::Merge(key, ..) => key,
+                        Command::Batch(_) => continue,
+                    };
+                    touched.insert(key.0);
+                }
+            }
+        }
+        let partial = old.iter().any(|v| !merged.contains(v));
+        let mut writer = Writer::open(&self.root, id)?;
+
+        let expired: Vec<Vec<u8>> = self
+            .map
+            .iter()
+            .filter(|(_, entry)| entry.meta.is_expired())
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            self.unindex(&key[..]); // 过期了的就不往新segment里写了
+            self.notify(&key[..], None);
+        }
+
+        let keys: Vec<Vec<u8>> = self
+            .map
+            .iter()
+            .filter(|(_, entry)| {
+                merged.contains(&entry.position.segment)
+                    || !entry.operands.is_empty()
+                    || entry
+                        .history
+                        .iter()
+                        .any(|v| merged.contains(&v.position.segment))
+            })
+            .map(|(key, _)| key.clone())
+            .collect();
+        let total = keys.len();
+        for (done, key) in keys.into_iter().enumerate() {
+            if done % 1000 == 0 {
+                progress(CompactionProgress {
+                    keys_done: done,
+                    keys_total: total,
+                });
+            }
+            if !self.map[&key[..]].history.is_empty() {
+                self.copy_history(&mut writer, &key[..], partial)?;
+            }
+            let entry = &self.map[&key[..]];
+            let command = match (entry.blob, &entry.value) {
+                _ if !entry.operands.is_empty() && self.merge.is_some() => {
+                    let value = view::read_value(
+                        &self.handles,
+                        &self.root,
+                        self.merge.as_ref(),
+                        &key[..],
+                        entry,
+                    )?
+                    .unwrap_or_default();
+                    let meta = KeyMeta {
+                        size: value.len() as u64,
+                        ..entry.meta
+                    };
+                    Command::Set(Bytes(key.clone()), Bytes(value), meta) // 叠好了写成一个Set，原来的blob就没用了
+                }
+                _ if !entry.operands.is_empty() => {
+                    self.copy_unmerged(&mut writer, &key[..])?;
+                    continue;
+                }
+                (Some(id), _) => Command::SetBlob(Bytes(key.clone()), id, entry.meta), // blob原样留着
+                (None, Some(value)) => {
+                    Command::Set(Bytes(key.clone()), Bytes(value.to_vec()), entry.meta)
+                }
+                (None, None) => segment::read_at(&self.handles, &self.root, entry.position)?, // 不经过get，免得把所有value都留在内存里
+            };
+            let (bytes, len) = segment::encode(&command, self.compression)?;
+            let offset = writer.append(&bytes[..])?;
+            let entry = Arc::make_mut(&mut self.map).get_mut(&key[..]).unwrap();
+            entry.position = Position {
+                segment: id,
+                offset,
+                len,
+            };
+            if let Command::Set(_, _, meta) = command {
+                entry.meta = meta;
+                entry.operands.clear();
+                entry.blob = None;
             }
         }
+        progress(CompactionProgress {
+            keys_done: total,
+            keys_total: total,
+        });
+        for key in touched {
+            if !self.map.contains_key(&key[..]) {
+                let (bytes, _) = segment::encode(&Command::Remove(Bytes(key)), self.compression)?;
+                writer.append(&bytes[..])?;
+            }
+        }
+        writer.sync()?;
+        fs::sync_dir(&self.root)?;
+        if let Some(active) = &self.writer {
+            // 锁外面可能还有人拿着老的segment在等fsync，换掉之前先fsync掉，不然它fsync老的就以为把新segment也带上了
+            active.sync()?;
+            self.commit.synced_all();
+        }
+
+        let mut garbage: Vec<PathBuf> = merged
+            .iter()
+            .map(|id| segment::path(&self.root, *id))
+            .collect();
+        let mut freed = 0;
+        for id in &merged {
+            freed += segment::path(&self.root, *id).metadata()?.len();
+            self.handles.evict(*id as usize); // 快照要读的话会自己再打开
+        }
+        let live: BTreeSet<u64> = self
+            .map
+            .values()
+            .flat_map(|entry| {
+                let history = entry.history.iter().filter_map(|v| v.blob);
+                entry.blob.into_iter().chain(history)
+            })
+            .collect();
+        for id in blob::list(&self.root)? {
+            if !live.contains(&id) {
+                garbage.push(blob::path(&self.root, id)); // 被覆盖、被删掉的，还有写完blob没来得及写segment就崩了的
+            }
+        }
+        for path in garbage {
+            if !self.retired.contains(&path) {
+                self.retired.push(path); // 上次compact还没删掉的这次又会列出来
+            }
+        }
+        self.remove_retired()?;
+
+        // 只合了一部分的话，没用的字节大概少了这么多；剩下的segment里被覆盖的记录还在
+        if old.iter().all(|v| merged.contains(v)) {
+            self.stale = 0;
+        } else {
+            self.stale = self
+                .stale
+                .saturating_sub(freed.saturating_sub(writer.len()));
+        }
+        self.writer = Some(writer);
+        self.segments_changed = true; // 合出来的可能让大一档也攒够了
+        self.disk_bytes = self.disk_usage()?;
+        Ok(CompactionReport {
+            segments_rewritten: merged.len(),
+            bytes_reclaimed: before.saturating_sub(self.disk_bytes),
+            duration: start.elapsed(),
+        })
+    }
+
+    /// 老版本从老到新先写到新segment里，重放的时候一个一个覆盖上去，最后留下来的还是这些
+    ///
+    /// 只合了一部分的话，没合的segment里可能还有这个key的记录，先写一个墓碑，不然重放的时候老版本会多出来
+    fn copy_history(&mut self, writer: &mut Writer, key: &[u8], partial: bool) -> Result<()> {
+        if partial {
+            let command = Command::Remove(Bytes(key.to_vec()));
+            writer.append(&segment::encode(&command, self.compression)?.0[..])?;
+        }
+        let mut history = self.map[key].history.clone();
+        for version in history.iter_mut().rev() {
+            let command = match version.blob {
+                Some(id) => Command::SetBlob(Bytes(key.to_vec()), id, version.meta),
+                None => segment::read_at(&self.handles, &self.root, version.position)?,
+            };
+            let (bytes, len) = segment::encode(&command, self.compression)?;
+            let offset = writer.append(&bytes[..])?;
+            version.position = Position {
+                segment: writer.segment(),
+                offset,
+                len,
+            };
+        }
+        Arc::make_mut(&mut self.map).get_mut(key).unwrap().history = history;
+        Ok(())
+    }
+
+    /// 没设置merge operator的时候compact，base和还没叠的操作数原样搬到新segment里
+    fn copy_unmerged(&mut self, writer: &mut Writer, key: &[u8]) -> Result<()> {
+        let entry = &self.map[key];
+        let base = segment::read_at(&self.handles, &self.root, entry.position)?;
+        let skip = match base {
+            Command::Merge(..) => 1, // 开头就是merge的话它自己也在操作数里
+            _ => 0,
+        };
+        let mut commands = vec![base];
+        for operand in &entry.operands[skip..] {
+            commands.push(Command::Merge(
+                Bytes(key.to_vec()),
+                Bytes(operand.clone()),
+                entry.meta,
+            ));
+        }
+        let mut position = None;
+        for command in commands {
+            let (bytes, len) = segment::encode(&command, self.compression)?;
+            let offset = writer.append(&bytes[..])?;
+            position.get_or_insert(Position {
+                segment: writer.segment(),
+                offset,
+                len,
+            });
+        }
+        Arc::make_mut(&mut self.map).get_mut(key).unwrap().position = position.unwrap();
         Ok(())
     }
+
+    /// compact剩下来的老segment和没用了的blob，没有快照钉着的话就删掉
+    ///
+    /// store比快照先drop的话这些文件就留在磁盘上了，不过segment编号都比compact出来的小，重放的时候会被覆盖，下次compact的时候会删掉
+    fn remove_retired(&mut self) -> Result<()> {
+        if self.retired.is_empty() || Arc::strong_count(&self.pins) > 1 {
+            return Ok(());
+        }
+        for path in self.retired.drain(..) {
+            fs::remove(&path)?;
+        }
+        self.disk_bytes = self.disk_usage()?;
+        fs::sync_dir(&self.root)
+    }
+
+    /// 把value读进内存（已经在内存里的话就不用读了），返回内存里的那份
+    fn load(&mut self, key: &[u8]) -> Result<Option<&Vec<u8>>> {
+        self.expire(key);
+        let resident = match self.map.get(key) {
+            None => return Ok(None), // 内存和磁盘永远是一致的，内存里没有，磁盘上肯定也没有
+            Some(entry) => entry.value.is_some(),
+        };
+        if resident {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+        }
+        if !resident {
+            // 还没读过，去segment里把这个command读出来。blob要的是一整个value，只好全读进来；压缩过的放进内存的是解压过的
+            let entry = &self.map[key];
+            match view::read_value(&self.handles, &self.root, self.merge.as_ref(), key, entry)? {
+                Some(value) => {
+                    let entry = Arc::make_mut(&mut self.map).get_mut(key).unwrap();
+                    if !entry.operands.is_empty() {
+                        entry.meta.size = value.len() as u64; // merge的时候不知道叠完多大
+                    }
+                    entry.value = Some(Arc::new(value)) // 先放进cache
+                }
+                None => {
+                    // 如果读到的是Remove(a)，那么key应该在内存里也不存在……出现了不一致，按理说这种情况是不允许发生的
+                    error!(
+                        "Inconsistency detected: {} in memory but not on disk",
+                        bytes::lossy(key)
+                    );
+                    self.unindex(key);
+                    return Ok(None);
+                }
+            }
+        }
+        self.touch(key);
+        Ok(self.map[key].value.as_deref())
+    }
+}
+
+impl State {
+    fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.load(key)?.cloned())
+    }
+
+    fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.put(key, value, None)
+    }
+
+    fn set_from_reader(&mut self, key: Vec<u8>, reader: &mut dyn Read) -> Result<()> {
+        let mut head = vec![];
+        reader
+            .take(blob::THRESHOLD as u64 + 1)
+            .read_to_end(&mut head)?;
+        if head.len() <= blob::THRESHOLD {
+            return self.put(key, head, None);
+        }
+
+        let id = self.next_blob;
+        self.next_blob += 1;
+        let len = blob::write(&self.root, id, &head[..], reader)?;
+        self.disk_bytes += len;
+        if let Some(archive) = &self.archive {
+            snapshot::link_blob(&self.root, archive, id)?;
+        }
+        let meta = KeyMeta::next(self.live_meta(&key[..]).as_ref(), len as usize);
+        let command = Command::SetBlob(Bytes(key.clone()), id, meta);
+        let position = self.append(std::slice::from_ref(&command))?[0];
+        if self.watchers.watching(&key[..]) {
+            let value = blob::read(&self.root, id)?; // 有人在看才去读
+            self.notify(&key[..], Some(&value[..]));
+        }
+        self.index_blob(key, position, meta, id);
+        self.maybe_compact()
+    }
+
+    fn set_bytes_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.put(key, value, Some(ttl))
+    }
+
+    fn remove_bytes(&mut self, key: &[u8]) -> Result<()> {
+        self.expire(key);
+        if !self.map.contains_key(key) {
+            return Err(KvsError::NotFound {
+                key: bytes::lossy(key),
+            }); // 再次提问……remove的时候key不存在，不管不就好了吗
+        }
+
+        let position = self.append(&[Command::Remove(Bytes(key.to_vec()))])?[0];
+        let entry = self.unindex(key).unwrap();
+        self.stale += entry.garbage() + position.len;
+        self.notify(key, None);
+        self.maybe_compact()
+    }
+
+    fn rename(&mut self, from: &str, to: &str) -> Result<()> {
+        let (from, to) = (from.as_bytes(), to.as_bytes());
+        self.expire(from);
+        if let Some((id, meta)) = self
+            .map
+            .get(from)
+            .filter(|entry| entry.operands.is_empty())
+            .and_then(|entry| entry.blob.map(|id| (id, entry.meta)))
+        {
+            if from == to {
+                return Ok(());
+            }
+            // blob不用动，换个key指着它就行
+            let positions = self.append(&[
+                Command::SetBlob(Bytes(to.to_vec()), id, meta),
+                Command::Remove(Bytes(from.to_vec())),
+            ])?;
+            let entry = self.unindex(from).unwrap();
+            self.stale += entry.position.len + positions[1].len;
+            self.notify(from, None);
+            if self.watchers.watching(to) {
+                let value = blob::read(&self.root, id)?;
+                self.notify(to, Some(&value[..]));
+            }
+            self.index_blob(to.to_vec(), positions[0], meta, id);
+            return self.maybe_compact();
+        }
+        let value = match self.load(from)? {
+            Some(value) => value.clone(),
+            None => {
+                return Err(KvsError::NotFound {
+                    key: bytes::lossy(from),
+                })
+            }
+        };
+        if from == to {
+            return Ok(());
+        }
+
+        let meta = self.map[from].meta; // 改名不算修改，元数据原样保留
+        let positions = self.append(&[
+            Command::Set(Bytes(to.to_vec()), Bytes(value.clone()), meta),
+            Command::Remove(Bytes(from.to_vec())),
+        ])?;
+        let entry = self.unindex(from).unwrap();
+        self.stale += entry.garbage() + positions[1].len;
+        self.notify(from, None);
+        self.notify(to, Some(&value[..]));
+        self.index(to.to_vec(), positions[0], meta, Some(value));
+        self.maybe_compact()
+    }
+
+    fn scan_matching(&mut self, filter: &KeyFilter) -> Result<Vec<(String, String)>> {
+        let matcher = filter.compile()?;
+        let keys: Vec<String> = self
+            .map
+            .keys()
+            .filter(|key| !namespace::is_namespaced(key))
+            .filter_map(|key| std::str::from_utf8(key).ok()) // 不是utf8的key没法用glob和正则匹配，String的API就当看不见
+            .filter(|key| matcher.matches(key))
+            .map(|key| key.to_string())
+            .collect(); // key都在内存里，而且是排好序的，先筛完再去读value
+
+        let mut pairs = Vec::with_capacity(keys.len());
+        for key in keys {
+            if let Some(value) = self.load(key.as_bytes())? {
+                let value = bytes::as_str(key.as_bytes(), value)?.to_string();
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// 还没过期的key，namespace里的不算
+    fn live_keys(&self) -> Vec<Vec<u8>> {
+        self.map
+            .iter()
+            .filter(|(key, entry)| !namespace::is_namespaced(key) && !entry.meta.is_expired())
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    fn len(&mut self) -> Result<usize> {
+        Ok(self
+            .map
+            .iter()
+            .filter(|(key, entry)| !namespace::is_namespaced(key) && !entry.meta.is_expired())
+            .count())
+    }
+
+    /// batch前面加一个 `Batch(n)` ，整个batch一次write写下去。崩在中间的话重新打开的时候会连着 `Batch(n)` 一起截掉
+    fn write_batch(&mut self, ops: Vec<BatchOp>) -> Result<()> {
+        batch::validate(&ops[..], |key| Ok(self.live_meta(key).is_some()))?;
+
+        let mut metas: HashMap<&[u8], Option<KeyMeta>> = HashMap::new(); // batch里前面的写也要算进版本号
+        let mut commands = vec![Command::Batch(ops.len() as u64)];
+        for op in &ops {
+            match op {
+                BatchOp::Set(key, value) => {
+                    let previous = match metas.get(&key[..]) {
+                        Some(meta) => *meta,
+                        None => self.live_meta(&key[..]),
+                    };
+                    let meta = KeyMeta::next(previous.as_ref(), value.len());
+                    metas.insert(key, Some(meta));
+                    commands.push(Command::Set(Bytes(key.clone()), Bytes(value.clone()), meta));
+                }
+                BatchOp::Remove(key) => {
+                    metas.insert(key, None);
+                    commands.push(Command::Remove(Bytes(key.clone())));
+                }
+            }
+        }
+
+        let positions = self.append(&commands[..])?;
+        self.stale += positions[0].len;
+        for (command, position) in commands.into_iter().zip(positions).skip(1) {
+            match command {
+                Command::Set(key, value, meta) => {
+                    self.notify(&key.0[..], Some(&value.0[..]));
+                    self.index(key.0, position, meta, Some(value.0))
+                }
+                Command::Remove(key) => {
+                    if let Some(entry) = self.unindex(&key.0[..]) {
+                        self.stale += entry.garbage();
+                    }
+                    self.stale += position.len;
+                    self.notify(&key.0[..], None);
+                }
+                Command::Batch(_)
+                | Command::SetBlob(..)
+                | Command::SetCompressed(..)
+                | Command::Merge(..) => {} // batch里没有blob和merge，压缩是encode的时候才做的
+            }
+        }
+        self.maybe_compact()
+    }
+
+    /// 只追加一条merge记录。value已经在内存里的话顺手叠上去，不在的话等读的时候再叠，不碰磁盘
+    ///
+    /// 元数据里的 `size` 要等叠完才准
+    fn merge(&mut self, key: &[u8], operand: Vec<u8>) -> Result<()> {
+        let operator = self.merge.clone().ok_or(KvsError::NoMergeOperator)?;
+        self.expire(key);
+        let previous = self.live_meta(key);
+        let merged = match self.map.get(key) {
+            Some(entry) => entry
+                .value
+                .as_ref()
+                .map(|value| operator.apply(key, Some(&value[..]), &operand[..])),
+            None => Some(operator.apply(key, None, &operand[..])),
+        };
+        let size = match &merged {
+            Some(merged) => merged.len(),
+            None => previous.map_or(0, |v| v.size as usize),
+        };
+        let meta = KeyMeta {
+            expires: previous.map_or(0, |v| v.expires),
+            ..KeyMeta::next(previous.as_ref(), size)
+        };
+
+        let command = Command::Merge(Bytes(key.to_vec()), Bytes(operand), meta);
+        let position = self.append(std::slice::from_ref(&command))?[0];
+        self.stale += position.len; // compact的时候会叠进Set里，这条记录本身就没用了
+        if let Command::Merge(_, operand, _) = command {
+            if let Some(merged) = &merged {
+                self.notify(key, Some(&merged[..]));
+            }
+            let watched = merged.is_none() && self.watchers.watching(key);
+            self.index_operand(key.to_vec(), position, meta, operand.0, merged);
+            if watched {
+                let value = self.load(key)?.cloned(); // 有人在看才去叠
+                self.notify(key, value.as_deref());
+            }
+        }
+        self.maybe_compact()
+    }
+
+    /// fsync正在写的segment就够了，老的segment换下来的时候已经fsync过了
+    fn flush(&mut self) -> Result<()> {
+        if let Some(writer) = &self.writer {
+            writer.sync()?;
+            self.commit.synced_all();
+        }
+        self.synced = Instant::now();
+        Ok(())
+    }
+
+    /// 先用一个batch把所有key删掉，再compact，磁盘上就只剩一个空的segment了
+    ///
+    /// 有快照钉着的话老segment要等快照没了才删，这时候崩了也没关系，重放到那个batch的时候又全删掉了
+    fn clear(&mut self) -> Result<()> {
+        let keys = self.live_keys();
+        if !keys.is_empty() {
+            self.write_batch(keys.into_iter().map(BatchOp::Remove).collect())?;
+        }
+        self.compact()?;
+        Ok(())
+    }
+
+    /// 一批按key排好序一次write，写的时候不放进cache。中间不管 `Durability` 是什么都不fsync，最后fsync一次segment和目录
+    fn bulk_load<I>(&mut self, pairs: I) -> Result<usize>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let durability = std::mem::replace(&mut self.durability, Durability::Never);
+        let result = self.bulk_append(pairs);
+        self.durability = durability;
+        let count = result?;
+
+        if let Some(writer) = &self.writer {
+            writer.sync()?; // 换segment的时候老的已经fsync过了，只剩正在写的这个
+            self.commit.synced_all();
+            self.synced = Instant::now();
+        }
+        fs::sync_dir(&self.root)?; // 目录的fsync，保证所有新建的segment都在
+        self.maybe_compact()?;
+        Ok(count)
+    }
+
+    fn bulk_append<I>(&mut self, pairs: I) -> Result<usize>
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut count = 0;
+        let mut pairs = pairs.into_iter().peekable();
+        while pairs.peek().is_some() {
+            let mut batch: Vec<(String, String)> = pairs.by_ref().take(BULK_BATCH).collect();
+            batch.sort_by(|a, b| a.0.cmp(&b.0)); // 稳定排序，同一个key后出现的还在后面
+            let mut metas: HashMap<String, KeyMeta> = HashMap::new(); // 同一批里同一个key出现好几次的话，版本号要接着算
+            let mut commands = vec![];
+            for (key, value) in batch {
+                let previous = match metas.get(&key) {
+                    Some(meta) => Some(*meta),
+                    None => self.live_meta(key.as_bytes()),
+                };
+                let meta = KeyMeta::next(previous.as_ref(), value.len());
+                metas.insert(key.clone(), meta);
+                commands.push(Command::Set(Bytes::from(key), Bytes::from(value), meta));
+            }
+
+            let positions = self.append(&commands[..])?;
+            for (command, position) in commands.into_iter().zip(positions) {
+                if let Command::Set(key, value, meta) = command {
+                    self.notify(&key.0[..], Some(&value.0[..]));
+                    self.index(key.0, position, meta, None);
+                }
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    fn write_batch_if(
+        &mut self,
+        expected: &BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+        ops: Vec<BatchOp>,
+    ) -> Result<()> {
+        txn::check(expected, |key| self.get_bytes(key))?;
+        if ops.is_empty() {
+            return Ok(());
+        }
+        self.write_batch(ops)
+    }
+
+    /// 拿着锁读完再写，中间插不进别人的写
+    fn compare_and_swap(
+        &mut self,
+        key: &str,
+        expected: Option<&str>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        if self.load(key.as_bytes())?.map(|v| &v[..]) != expected.map(|v| v.as_bytes()) {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => self.put(key.as_bytes().to_vec(), value.into_bytes(), None)?,
+            None if expected.is_some() => self.remove_bytes(key.as_bytes())?,
+            None => {} // 本来就不存在，删一个不存在的key什么都不用做
+        }
+        Ok(true)
+    }
+
+    fn getdel(&mut self, key: &str) -> Result<Option<String>> {
+        let value = match self.load(key.as_bytes())? {
+            Some(value) => bytes::into_string(key.as_bytes(), value.clone())?,
+            None => return Ok(None),
+        };
+        self.remove_bytes(key.as_bytes())?;
+        Ok(Some(value))
+    }
+
+    fn getset(&mut self, key: String, value: String) -> Result<Option<String>> {
+        let previous = match self.load(key.as_bytes())? {
+            Some(previous) => Some(bytes::into_string(key.as_bytes(), previous.clone())?),
+            None => None,
+        };
+        self.put(key.into_bytes(), value.into_bytes(), None)?;
+        Ok(previous)
+    }
+
+    fn append_to(&mut self, key: &str, suffix: &str) -> Result<usize> {
+        let mut value = self.load(key.as_bytes())?.cloned().unwrap_or_default();
+        value.extend_from_slice(suffix.as_bytes());
+        let len = value.len();
+        self.put(key.as_bytes().to_vec(), value, None)?;
+        Ok(len)
+    }
+
+    fn rename_nx(&mut self, from: &str, to: &str) -> Result<bool> {
+        self.expire(from.as_bytes());
+        self.expire(to.as_bytes());
+        if !self.map.contains_key(from.as_bytes()) {
+            return Err(KvsError::NotFound {
+                key: from.to_string(),
+            });
+        }
+        if self.map.contains_key(to.as_bytes()) {
+            return Ok(false);
+        }
+        self.rename(from, to)?;
+        Ok(true)
+    }
+
+    fn remove_matching(&mut self, filter: &KeyFilter) -> Result<u64> {
+        let matcher = filter.compile()?;
+        let ops: Vec<BatchOp> = self
+            .live_keys()
+            .into_iter()
+            .filter(|key| std::str::from_utf8(key).is_ok_and(|key| matcher.matches(key)))
+            .map(BatchOp::Remove)
+            .collect();
+        let count = ops.len() as u64;
+        if count > 0 {
+            self.write_batch(ops)?;
+        }
+        Ok(count)
+    }
+
+    /// 锁外面读出来的value放回内存。读的时候这个key被改掉了的话就不放了，读到的已经是老的了
+    fn cache(&mut self, key: &[u8], read: &Entry, value: Option<&[u8]>) {
+        match self.map.get(key) {
+            Some(entry)
+                if entry.position == read.position
+                    && entry.operands.len() == read.operands.len()
+                    && entry.value.is_none() => {}
+            _ => return,
+        }
+        let value = match value {
+            Some(value) => value,
+            None => {
+                // 和 `load` 里一样，按理说不会发生
+                error!(
+                    "Inconsistency detected: {} in memory but not on disk",
+                    bytes::lossy(key)
+                );
+                self.unindex(key);
+                return;
+            }
+        };
+        let entry = Arc::make_mut(&mut self.map).get_mut(key).unwrap();
+        if !entry.operands.is_empty() {
+            entry.meta.size = value.len() as u64;
+        }
+        entry.value = Some(Arc::new(value.to_vec()));
+        self.touch(key);
+    }
+}
+
+impl KvStore {
+    pub fn new() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State::new())),
+        }
+    }
+
+    pub fn open<T>(root: T) -> Result<Self>
+    where
+        T: Into<PathBuf>,
+    {
+        Self::open_with(root, KvStoreOptions::default())
+    }
+
+    pub fn open_with<T>(root: T, options: KvStoreOptions) -> Result<Self>
+    where
+        T: Into<PathBuf>,
+    {
+        Ok(Self {
+            state: Arc::new(Mutex::new(State::open_with(root, options)?)),
+        })
+    }
+
+    /// 别的线程拿着锁panic了的话，索引可能改到一半，不能接着用了
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// 拿着锁改，`Always` 的fsync放开锁以后再等。等的时候别的线程可以接着追加，下一次fsync一起带上
+    fn write<T, F>(&self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut State) -> Result<T>,
+    {
+        let mut state = self.state();
+        let result = f(&mut state);
+        let pending = state.pending.take();
+        let commit = state.commit.clone();
+        drop(state);
+        if let Some((ticket, file)) = pending {
+            commit.wait(ticket, || Ok(file.sync_all()?))?;
+        }
+        result
+    }
+
+    /// 设置merge用的operator。operator不存在磁盘上，每次打开都要重新设置，而且要和以前用的一样
+    ///
+    /// 没设置的话也能打开有merge的目录，只是读那些key会报 `NoMergeOperator` 。所有clone都会用上
+    pub fn set_merge_operator(&mut self, operator: MergeOperator) {
+        self.state().merge = Some(operator);
+    }
+
+    /// 打开以后写了几次
+    pub fn version(&self) -> u64 {
+        self.state().version
+    }
+
+    /// 钉住现在的样子，之后的写都看不见。拿着快照的时候compact删不了老的segment，用完了早点drop
+    pub fn read_view(&self) -> ReadView {
+        self.state().read_view()
+    }
+
+    /// 文件句柄缓存的命中率
+    pub fn handle_stats(&self) -> HandleStats {
+        self.state().handles.stats()
+    }
+
+    /// 有多少key、磁盘上占了多少、其中多少是没用的、value缓存的命中率
+    pub fn stats(&self) -> Result<StoreStats> {
+        self.state().stats()
+    }
+
+    /// 把写都fsync掉、放开目录的锁再关。直接drop也会试着fsync，但是失败了只能打一行日志，想知道有没有成功的话用这个
+    ///
+    /// 索引每次open都是从segment重放出来的，没有别的文件要写。别的clone还开着的话只fsync，目录的锁等最后一个clone关掉的时候再放
+    pub fn close(mut self) -> Result<()> {
+        let state = match Arc::get_mut(&mut self.state) {
+            Some(state) => state.get_mut().unwrap(),
+            None => return self.state().flush(),
+        };
+        state.flush()?;
+        state.writer = None;
+        state.lock = None;
+        Ok(())
+    }
+
+    /// 把现在的数据备份到 `dest` ， `dest` 必须是空的或者不存在。备份出来的目录可以直接 `open`
+    ///
+    /// 老的segment是硬链接过去的，只有正在写的那个要真的拷贝，所以很快，不用停服务器
+    pub fn snapshot<T>(&self, dest: T) -> Result<Manifest>
+    where
+        T: AsRef<Path>,
+    {
+        self.state().snapshot(dest)
+    }
+
+    /// 和 `snapshot` 一样，不过先把正在写的segment封起来、换一个新的空segment接着写，这样所有有数据的segment都是硬链接过去的，一个字节都不用拷贝
+    ///
+    /// 代价是每次checkpoint都会多一个小segment，攒多了compact的时候会合掉
+    pub fn checkpoint<T>(&self, dest: T) -> Result<Manifest>
+    where
+        T: AsRef<Path>,
+    {
+        self.state().checkpoint(dest)
+    }
+
+    /// 把归档里 `timestamp` （从UNIX epoch开始的毫秒数）和之前的写重放到 `dest` 里，`dest` 必须是空的或者不存在，之后可以直接 `open`
+    ///
+    /// 正在写的segment先封起来归档，所以刚刚的写也能恢复。没开归档的话报 `InvalidArgument`
+    pub fn restore_to<T>(&self, dest: T, timestamp: u64) -> Result<()>
+    where
+        T: AsRef<Path>,
+    {
+        self.state().restore_to(dest, timestamp)
+    }
+
+    /// 和 `restore_to` 一样，不过只用归档目录，store已经打不开了、或者整个目录都没了也能恢复
+    pub fn restore_archive<P, T>(archive: P, dest: T, timestamp: u64) -> Result<()>
+    where
+        P: AsRef<Path>,
+        T: AsRef<Path>,
+    {
+        pitr::restore(archive.as_ref(), dest.as_ref(), timestamp)
+    }
+
+    /// `key` 往前数第 `n` 个版本，0就是现在的value。没开 `keep_versions` 或者没留那么多的话是 `None`
+    pub fn get_version(&self, key: &[u8], n: usize) -> Result<Option<Vec<u8>>> {
+        self.state().get_version(key, n)
+    }
+
+    /// 现在的value和留着的老版本，从新到老排，第 `n` 个就是 `get_version(key, n)` 。key不在的话是空的
+    pub fn history(&self, key: &[u8]) -> Result<Vec<(KeyMeta, Vec<u8>)>> {
+        self.state().history(key)
+    }
+
+    /// 检查 `path` 下的store：每条记录的校验和、没写完的尾巴、丢了的blob、没人要的blob和临时文件，什么都不改
+    ///
+    /// 要拿目录的锁，store开着的时候报 `Locked` 。报告能直接用serde_json输出
+    pub fn verify<T: AsRef<Path>>(path: T) -> Result<FsckReport> {
+        fsck::check(path.as_ref(), false)
+    }
+
+    /// 和 `verify` 一样检查，顺便把能修的修了：坏记录和没写完的尾巴挪到 `.torn` 里（坏了的batch整个挪走），
+    /// blob丢了的key删掉，没人要的blob和临时文件删掉。返回的是修之前看到的问题
+    pub fn repair<T: AsRef<Path>>(path: T) -> Result<FsckReport> {
+        fsck::check(path.as_ref(), true)
+    }
+
+    /// 现在就把所有segment合成一个，不管没用的字节够不够多。返回省了多少地方、花了多久
+    ///
+    /// 合的时候一直拿着锁，别的线程的读写要等它合完
+    pub fn compact(&self) -> Result<CompactionReport> {
+        self.write(|state| state.compact())
+    }
+
+    /// 和 `compact` 一样，每重写一批key调一次 `progress` ，最后一次是全部写完的时候
+    pub fn compact_with_progress<F>(&self, progress: F) -> Result<CompactionReport>
+    where
+        F: FnMut(CompactionProgress),
+    {
+        self.write(|state| state.compact_with_progress(progress))
+    }
+}
+
+impl Drop for KvStore {
+    /// 只有最后一个clone drop的时候才fsync
+    fn drop(&mut self) {
+        if let Some(Ok(state)) = Arc::get_mut(&mut self.state).map(Mutex::get_mut) {
+            if let Err(e) = state.flush() {
+                error!("Failed to flush on drop: {}", e);
+            }
+        }
+    }
+}
+
+impl KvsEngine for KvStore {
+    // 标准答案里面key是String，但我觉得……怎么能传owned呢，所以改掉了
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        self.get_bytes(key.as_bytes())?
+            .map(|v| bytes::into_string(key.as_bytes(), v))
+            .transpose()
+    }
+
+    /// 在内存里的话拿着锁拷一份出来。不在的话放开锁去segment里读，这时候别的线程照样能读写，读完了再放回内存
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let (entry, handles, root, merge, _pin) = {
+            let mut state = self.state();
+            state.expire(key);
+            let entry = match state.map.get(key) {
+                Some(entry) => entry.clone(),
+                None => return Ok(None), // 内存和磁盘永远是一致的，内存里没有，磁盘上肯定也没有
+            };
+            if let Some(value) = &entry.value {
+                state.hits += 1;
+                state.touch(key);
+                return Ok(Some(value.to_vec()));
+            }
+            state.misses += 1;
+            let pin = state.pins.clone(); // 读完之前compact删不掉这个segment
+            (
+                entry,
+                state.handles.clone(),
+                state.root.clone(),
+                state.merge.clone(),
+                pin,
+            )
+        };
+        let value = view::read_value(&handles, &root, merge.as_ref(), key, &entry)?;
+        self.state().cache(key, &entry, value.as_deref());
+        Ok(value)
+    }
+
+    /// blob直接从文件里读，别的value和get一样读出来再包一层
+    fn get_reader(&self, key: &[u8]) -> Result<Option<ValueReader<'_>>> {
+        {
+            let mut state = self.state();
+            state.expire(key);
+            match state.map.get(key) {
+                Some(entry) if entry.operands.is_empty() => {
+                    if let Some(id) = entry.blob {
+                        // 拿着锁打开，打开了以后compact把文件删了也照样能读完
+                        let file = blob::open(&state.root, id)?;
+                        return Ok(Some(ValueReader::new(entry.meta.size, Box::new(file))));
+                    }
+                }
+                Some(_) => {} // 有merge的话要叠完才知道value是什么
+                None => return Ok(None),
+            }
+        }
+        Ok(self.get_bytes(key)?.map(ValueReader::from_bytes))
+    }
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write(|state| state.set_bytes(key, value))
+    }
+
+    /// 先读一段，没超过 `blob::THRESHOLD` 的话和set一样。超过了就把剩下的边读边写到一个blob里，segment里只记blob的编号
+    ///
+    /// 写blob的时候一直拿着锁，不然compact会把还没记进索引的blob当成没人要的删掉
+    fn set_from_reader(&self, key: Vec<u8>, reader: &mut dyn Read) -> Result<()> {
+        self.write(|state| state.set_from_reader(key, reader))
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.write(|state| state.set_bytes_with_ttl(key, value, ttl))
+    }
+
+    // 标准答案里key也是String，我给改了
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        self.write(|state| state.remove_bytes(key))
+    }
+
+    /// 新名字的set和老名字的remove一次write写下去
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.write(|state| state.rename(from, to))
+    }
+
+    fn rename_nx(&self, from: &str, to: &str) -> Result<bool> {
+        self.write(|state| state.rename_nx(from, to))
+    }
+
+    fn getdel(&self, key: &str) -> Result<Option<String>> {
+        self.write(|state| state.getdel(key))
+    }
+
+    fn getset(&self, key: String, value: String) -> Result<Option<String>> {
+        self.write(|state| state.getset(key, value))
+    }
+
+    /// 拿着锁读完接好再写，不会丢别人的append
+    fn append(&self, key: &str, suffix: &str) -> Result<usize> {
+        self.write(|state| state.append_to(key, suffix))
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: Option<&str>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.write(|state| state.compare_and_swap(key, expected, new))
+    }
+
+    fn metadata(&self, key: &str) -> Result<Option<KeyMeta>> {
+        Ok(self.state().live_meta(key.as_bytes())) // 元数据都在内存里
+    }
+
+    /// 只看索引，不读segment，也不会把value放进内存
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        Ok(self.state().live_meta(key.as_bytes()).is_some())
+    }
+
+    fn scan_matching(&self, filter: &KeyFilter) -> Result<Vec<(String, String)>> {
+        self.state().scan_matching(filter)
+    }
+
+    /// 在索引的快照上走，value是一个一个按需读的，读过的也不放进内存。迭代器拿着快照，用完了早点drop
+    fn scan_bounds(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Result<Scan<'_>> {
+        Ok(view::scan_view(self.read_view(), start, end))
+    }
+
+    /// 用一个batch删，找key和删在同一把锁里
+    fn remove_matching(&self, filter: &KeyFilter) -> Result<u64> {
+        self.write(|state| state.remove_matching(filter))
+    }
+
+    fn watch(&self, prefix: &[u8]) -> Result<Watcher> {
+        Ok(self.state().watchers.add(prefix))
+    }
+
+    /// key都在内存里，不用碰磁盘
+    fn keys(&self) -> Result<Keys<'_>> {
+        Ok(Box::new(self.state().live_keys().into_iter().map(Ok)))
+    }
+
+    fn len(&self) -> Result<usize> {
+        self.state().len()
+    }
+
+    fn write_batch(&self, ops: Vec<BatchOp>) -> Result<()> {
+        self.write(|state| state.write_batch(ops))
+    }
+
+    /// 对value和写batch在同一把锁里，中间插不进别人的写
+    fn write_batch_if(
+        &self,
+        expected: &BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+        ops: Vec<BatchOp>,
+    ) -> Result<()> {
+        self.write(|state| state.write_batch_if(expected, ops))
+    }
+
+    fn apply(&self, ops: &[Op]) -> Result<Vec<OpResult>> {
+        batch::apply_as_batch(self, ops)
+    }
+
+    fn merge(&self, key: &[u8], operand: Vec<u8>) -> Result<()> {
+        self.write(|state| state.merge(key, operand))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.state().flush()
+    }
+
+    fn engine_name(&self) -> &'static str {
+        "kvs"
+    }
+
+    fn stats(&self) -> Result<StoreStats> {
+        KvStore::stats(self)
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.write(|state| state.clear())
+    }
+
+    fn bulk_load_iter(&self, pairs: &mut dyn Iterator<Item = (String, String)>) -> Result<usize> {
+        self.write(|state| state.bulk_load(pairs))
+    }
+}
+
+// 这个名字起的实在是太奇怪了，Engine让人感觉是interface，可是这里SledKvsEngine却又是个struct。按照这样的命名，KvsStore也应该改名叫KvsStoreEngine
+/// 打开SledKvsEngine时候的选项
+#[cfg(feature = "sled")]
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct SledOptions {
+    /// 默认是 `Always` ，和以前一样每个写都flush。 `Every` 交给sled自己的后台线程定时flush， `Never` 的话只有调 `KvsEngine::flush` 的时候才flush
+    pub durability: Durability,
+}
+
+#[cfg(feature = "sled")]
+impl Default for SledOptions {
+    fn default() -> Self {
+        Self {
+            durability: Durability::Always,
+        }
+    }
+}
+
+/// sled的 `Db` 本来就是可以clone的，clone出来的是同一个数据库
+#[cfg(feature = "sled")]
+#[derive(Clone)]
+pub struct SledKvsEngine {
+    store: Db,
+    /// 每个key的元数据单独放在一个tree里，value是json
+    meta: sled::Tree,
+    durability: Durability,
+    merge: Option<MergeOperator>,
+}
+
+#[cfg(feature = "sled")]
+impl SledKvsEngine {
+    pub fn open<T>(root: T) -> Result<Self>
+    where
+        T: Into<PathBuf>,
+    {
+        Self::open_with(root, SledOptions::default())
+    }
+
+    pub fn open_with<T>(root: T, options: SledOptions) -> Result<Self>
+    where
+        T: Into<PathBuf>,
+    {
+        let root = root.into();
+        create_dir_all(&root)?;
+
+        archive::check(&root, "sled", SLED_FORMAT)?; // 从第0版升上来什么都不用做
+        archive::write(&root, "sled", SLED_FORMAT, &options)?;
+
+        let flush_every_ms = match options.durability {
+            Durability::Every(interval) => Some(interval.as_millis().max(1) as u64),
+            _ => None, // Always的话每个写自己flush，不用后台线程了
+        };
+        let store = sled::Config::new()
+            .path(root)
+            .flush_every_ms(flush_every_ms)
+            .open()?;
+        let meta = store.open_tree("meta")?;
+        Ok(Self {
+            store,
+            meta,
+            durability: options.durability,
+            merge: None,
+        })
+    }
+
+    /// 和KvStore一样，每次打开都要重新设置。只管这一个handle，要在clone之前设置
+    pub fn set_merge_operator(&mut self, operator: MergeOperator) {
+        self.merge = Some(operator);
+    }
+
+    /// 只有key数和磁盘占用，没用的字节数、cache命中率sled不告诉我们
+    pub fn stats(&self) -> Result<StoreStats> {
+        Ok(StoreStats {
+            keys: self.len()?,
+            disk_bytes: self.store.size_on_disk()?,
+            ..Default::default()
+        })
+    }
+
+    /// `Always` 的话每个写完都要flush，其他的交给sled
+    fn maybe_flush(&self) -> Result<()> {
+        if self.durability == Durability::Always {
+            self.store.flush()?; // 巨坑，千万千万不要忘记flush，这样才会写回磁盘
+        }
+        Ok(())
+    }
+
+    fn read_meta(&self, key: &[u8]) -> Result<Option<KeyMeta>> {
+        read_meta(&self.meta, key)
+    }
+
+    /// sled没有我们自己的compact，过期了的key碰到的时候就顺手删掉
+    fn expire(&self, key: &[u8]) -> Result<()> {
+        if let Some(meta) = self.read_meta(key)? {
+            if meta.is_expired() {
+                self.store.remove(key)?;
+                self.meta.remove(key)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn put(&self, key: Vec<u8>, value: Vec<u8>, ttl: Option<Duration>) -> Result<()> {
+        self.expire(&key[..])?;
+        let mut meta = KeyMeta::next(self.read_meta(&key)?.as_ref(), value.len());
+        if let Some(ttl) = ttl {
+            meta = meta.with_ttl(ttl);
+        }
+        match self.store.insert(&key[..], value) {
+            Ok(_) => {
+                self.meta.insert(&key[..], serde_json::to_vec(&meta)?)?;
+                self.maybe_flush()?;
+                Ok(())
+            }
+            Err(e) => Err(KvsError::Sled(e)),
+        }
+    }
+}
+
+/// scan的迭代器里只借着 `meta` 这棵树，借不了整个engine，只好单独写一个
+#[cfg(feature = "sled")]
+fn read_meta(meta: &sled::Tree, key: &[u8]) -> Result<Option<KeyMeta>> {
+    match meta.get(key)? {
+        Some(v) => Ok(Some(serde_json::from_slice(v.as_ref())?)),
+        None => Ok(None),
+    }
+}
+
+/// 在sled事务里读元数据，解析不了的话整个事务abort
+#[cfg(feature = "sled")]
+fn read_meta_in(
+    meta: &TransactionalTree,
+    key: &[u8],
+) -> ConflictableTransactionResult<Option<KeyMeta>, KvsError> {
+    match meta.get(key)? {
+        Some(v) => match serde_json::from_slice(v.as_ref()) {
+            Ok(meta) => Ok(Some(meta)),
+            Err(e) => abort(KvsError::from(e)),
+        },
+        None => Ok(None),
+    }
+}
+
+#[cfg(feature = "sled")]
+fn encode_meta(meta: &KeyMeta) -> ConflictableTransactionResult<Vec<u8>, KvsError> {
+    match serde_json::to_vec(meta) {
+        Ok(v) => Ok(v),
+        Err(e) => abort(KvsError::from(e)),
+    }
+}
+
+#[cfg(feature = "sled")]
+impl KvsEngine for SledKvsEngine {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        self.expire(key.as_bytes())?;
+        match self.store.get(key.as_bytes()) {
+            Ok(Some(v)) => Ok(Some(bytes::into_string(key.as_bytes(), v.to_vec())?)), // 现在可以存二进制了，不是utf8的话就报错
+            Ok(None) => Ok(None),
+            Err(e) => Err(KvsError::Sled(e)),
+        }
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.expire(key)?;
+        Ok(self.store.get(key)?.map(|v| v.to_vec()))
+    }
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.put(key, value, None)
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.put(key, value, Some(ttl))
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        self.expire(key)?;
+        match self.store.remove(key) {
+            Ok(Some(_)) => {
+                self.meta.remove(key)?;
+                self.maybe_flush()?;
+                Ok(())
+            }
+            Ok(None) => Err(KvsError::NotFound {
+                key: bytes::lossy(key),
+            }), // 到底是为什么key不存在算是个错误
+            Err(e) => Err(KvsError::Sled(e)),
+        }
+    }
+
+    /// 读、接、写value和元数据放在一个sled事务里，几个线程同时append到一个key上不会互相盖掉
+    fn append(&self, key: &str, suffix: &str) -> Result<usize> {
+        self.expire(key.as_bytes())?;
+        let data: &sled::Tree = &self.store;
+        let result = (data, &self.meta).transaction(|(data, meta)| {
+            let mut value = data
+                .get(key.as_bytes())?
+                .map(|v| v.to_vec())
+                .unwrap_or_default();
+            value.extend_from_slice(suffix.as_bytes());
+            let previous = read_meta_in(meta, key.as_bytes())?;
+            let next = KeyMeta::next(previous.as_ref(), value.len());
+            data.insert(key.as_bytes(), &value[..])?;
+            meta.insert(key.as_bytes(), encode_meta(&next)?)?;
+            Ok(value.len())
+        });
+        match result {
+            Ok(len) => {
+                self.maybe_flush()?;
+                Ok(len)
+            }
+            Err(TransactionError::Abort(e)) => Err(e),
+            Err(TransactionError::Storage(e)) => Err(KvsError::Sled(e)),
+        }
+    }
+
+    /// sled自己的merge也是读出来叠完再CAS回去的，这里直接用 `update_and_fetch` ，顺便拿到叠完的大小
+    fn merge(&self, key: &[u8], operand: Vec<u8>) -> Result<()> {
+        let operator = self.merge.clone().ok_or(KvsError::NoMergeOperator)?;
+        self.expire(key)?;
+        let merged = self.store.update_and_fetch(key, |existing| {
+            Some(operator.apply(key, existing, &operand[..]))
+        })?;
+        let previous = self.read_meta(key)?;
+        let meta = KeyMeta {
+            expires: previous.map_or(0, |v| v.expires),
+            ..KeyMeta::next(previous.as_ref(), merged.map_or(0, |v| v.len()))
+        };
+        self.meta.insert(key, serde_json::to_vec(&meta)?)?;
+        self.maybe_flush()
+    }
+
+    /// 不管 `durability` 是什么都flush
+    fn flush(&self) -> Result<()> {
+        self.store.flush()?;
+        Ok(())
+    }
+
+    fn engine_name(&self) -> &'static str {
+        "sled"
+    }
+
+    fn stats(&self) -> Result<StoreStats> {
+        SledKvsEngine::stats(self)
+    }
+
+    fn getdel(&self, key: &str) -> Result<Option<String>> {
+        self.expire(key.as_bytes())?;
+        match self.store.remove(key.as_bytes())? {
+            Some(value) => {
+                self.meta.remove(key.as_bytes())?;
+                self.maybe_flush()?;
+                Ok(Some(bytes::into_string(key.as_bytes(), value.to_vec())?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// 用sled的事务，两个tree一起改
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.expire(from.as_bytes())?;
+        let data: &sled::Tree = &self.store;
+        let result = (data, &self.meta).transaction(|(data, meta)| {
+            let value = match data.remove(from.as_bytes())? {
+                Some(value) => value,
+                None => return abort(()),
+            };
+            data.insert(to.as_bytes(), value)?;
+            match meta.remove(from.as_bytes())? {
+                Some(m) => meta.insert(to.as_bytes(), m)?,
+                None => meta.remove(to.as_bytes())?,
+            };
+            Ok(())
+        });
+        match result {
+            Ok(()) => {
+                self.maybe_flush()?;
+                Ok(())
+            }
+            Err(TransactionError::Abort(())) => Err(KvsError::NotFound {
+                key: from.to_string(),
+            }),
+            Err(TransactionError::Storage(e)) => Err(KvsError::Sled(e)),
+        }
+    }
+
+    fn scan_bounds(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Result<Scan<'_>> {
+        if scan::is_empty(start, end) {
+            return Ok(Box::new(std::iter::empty()));
+        }
+        let meta = &self.meta;
+        let iter = self
+            .store
+            .range::<&[u8], _>((start, end))
+            .filter_map(move |entry| {
+                let (key, value) = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => return Some(Err(KvsError::Sled(e))),
+                };
+                match read_meta(meta, key.as_ref()) {
+                    Ok(Some(meta)) if meta.is_expired() => None, // 扫的时候只跳过，不删
+                    Ok(_) => Some(Ok((key.to_vec(), value.to_vec()))),
+                    Err(e) => Some(Err(e)),
+                }
+            });
+        Ok(Box::new(iter))
+    }
+
+    /// sled自己就能订阅，元数据在另一棵树上，不会混进来
+    fn watch(&self, prefix: &[u8]) -> Result<Watcher> {
+        Ok(Watcher::sled(self.store.watch_prefix(prefix)))
+    }
+
+    /// 只在sled的key上走，不读value
+    fn keys(&self) -> Result<Keys<'_>> {
+        let meta = &self.meta;
+        let iter = self.store.iter().keys().filter_map(move |key| {
+            let key = match key {
+                Ok(key) => key,
+                Err(e) => return Some(Err(KvsError::Sled(e))),
+            };
+            if namespace::is_namespaced(key.as_ref()) {
+                return None;
+            }
+            match read_meta(meta, key.as_ref()) {
+                Ok(Some(meta)) if meta.is_expired() => None,
+                Ok(_) => Some(Ok(key.to_vec())),
+                Err(e) => Some(Err(e)),
+            }
+        });
+        Ok(Box::new(iter))
+    }
+
+    /// sled自己记着有多少个key，减掉过期了还没来得及删的，再减掉namespace里的
+    fn len(&self) -> Result<usize> {
+        let mut expired = 0;
+        for entry in self.meta.iter() {
+            let (key, meta) = entry?;
+            let meta: KeyMeta = serde_json::from_slice(meta.as_ref())?;
+            if meta.is_expired() && !namespace::is_namespaced(key.as_ref()) {
+                expired += 1;
+            }
+        }
+        let namespaced = self.store.scan_prefix([0]).keys().count();
+        Ok(self.store.len() - expired - namespaced)
+    }
+
+    /// 数据和元数据各一个sled的batch，放在一个transaction里一起apply
+    fn write_batch(&self, ops: Vec<BatchOp>) -> Result<()> {
+        for op in &ops {
+            self.expire(op.key())?;
+        }
+        batch::validate(&ops[..], |key| Ok(self.store.contains_key(key)?))?;
+
+        let mut metas: HashMap<&[u8], Option<KeyMeta>> = HashMap::new();
+        let mut data_batch = sled::Batch::default();
+        let mut meta_batch = sled::Batch::default();
+        for op in &ops {
+            match op {
+                BatchOp::Set(key, value) => {
+                    let previous = match metas.get(&key[..]) {
+                        Some(meta) => *meta,
+                        None => self.read_meta(key)?,
+                    };
+                    let meta = KeyMeta::next(previous.as_ref(), value.len());
+                    metas.insert(key, Some(meta));
+                    data_batch.insert(&key[..], &value[..]);
+                    meta_batch.insert(&key[..], serde_json::to_vec(&meta)?);
+                }
+                BatchOp::Remove(key) => {
+                    metas.insert(key, None);
+                    data_batch.remove(&key[..]);
+                    meta_batch.remove(&key[..]);
+                }
+            }
+        }
+
+        let data: &sled::Tree = &self.store;
+        let result: std::result::Result<(), TransactionError<()>> =
+            (data, &self.meta).transaction(|(data, meta)| {
+                data.apply_batch(&data_batch)?;
+                meta.apply_batch(&meta_batch)?;
+                Ok(())
+            });
+        match result {
+            Ok(()) => {
+                self.maybe_flush()?;
+                Ok(())
+            }
+            Err(TransactionError::Abort(())) => unreachable!(), // 上面没有abort
+            Err(TransactionError::Storage(e)) => Err(KvsError::Sled(e)),
+        }
+    }
+
+    /// 对value和写都在一个sled事务里，元数据也在事务里读，事务看得到自己前面的写
+    fn write_batch_if(
+        &self,
+        expected: &BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+        ops: Vec<BatchOp>,
+    ) -> Result<()> {
+        for key in expected.keys() {
+            self.expire(key)?;
+        }
+        for op in &ops {
+            self.expire(op.key())?;
+        }
+        let data: &sled::Tree = &self.store;
+        let result = (data, &self.meta).transaction(|(data, meta)| {
+            for (key, value) in expected {
+                if data.get(&key[..])?.as_deref() != value.as_deref() {
+                    return abort(KvsError::Conflict {
+                        key: bytes::lossy(key),
+                    });
+                }
+            }
+            for op in &ops {
+                match op {
+                    BatchOp::Set(key, value) => {
+                        let previous = read_meta_in(meta, key)?;
+                        let next = KeyMeta::next(previous.as_ref(), value.len());
+                        data.insert(&key[..], &value[..])?;
+                        meta.insert(&key[..], encode_meta(&next)?)?;
+                    }
+                    BatchOp::Remove(key) => {
+                        if data.remove(&key[..])?.is_none() {
+                            return abort(KvsError::NotFound {
+                                key: bytes::lossy(key),
+                            });
+                        }
+                        meta.remove(&key[..])?;
+                    }
+                }
+            }
+            Ok(())
+        });
+        match result {
+            Ok(()) => {
+                if !ops.is_empty() {
+                    self.maybe_flush()?;
+                }
+                Ok(())
+            }
+            Err(TransactionError::Abort(e)) => Err(e),
+            Err(TransactionError::Storage(e)) => Err(KvsError::Sled(e)),
+        }
+    }
+
+    fn apply(&self, ops: &[Op]) -> Result<Vec<OpResult>> {
+        batch::apply_as_batch(self, ops)
+    }
+
+    fn getset(&self, key: String, value: String) -> Result<Option<String>> {
+        self.expire(key.as_bytes())?;
+        let meta = KeyMeta::next(self.read_meta(key.as_bytes())?.as_ref(), value.len());
+        let previous = self.store.insert(key.as_bytes(), value.as_bytes())?; // sled的insert本来就会返回旧值
+        self.meta
+            .insert(key.as_bytes(), serde_json::to_vec(&meta)?)?;
+        self.maybe_flush()?;
+        match previous {
+            Some(v) => Ok(Some(bytes::into_string(key.as_bytes(), v.to_vec())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// 比较、写数据、写元数据放在一个sled事务里，元数据也是在事务里读的
+    fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: Option<&str>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.expire(key.as_bytes())?;
+        let data: &sled::Tree = &self.store;
+        let result = (data, &self.meta).transaction(|(data, meta)| {
+            if data.get(key.as_bytes())?.as_deref() != expected.map(|v| v.as_bytes()) {
+                return Ok(false);
+            }
+            match &new {
+                Some(value) => {
+                    let previous = read_meta_in(meta, key.as_bytes())?;
+                    let next = KeyMeta::next(previous.as_ref(), value.len());
+                    data.insert(key.as_bytes(), value.as_bytes())?;
+                    meta.insert(key.as_bytes(), encode_meta(&next)?)?;
+                }
+                None => {
+                    data.remove(key.as_bytes())?;
+                    meta.remove(key.as_bytes())?;
+                }
+            }
+            Ok(true)
+        });
+        match result {
+            Ok(swapped) => {
+                if swapped {
+                    self.maybe_flush()?;
+                }
+                Ok(swapped)
+            }
+            Err(TransactionError::Abort(e)) => Err(e),
+            Err(TransactionError::Storage(e)) => Err(KvsError::Sled(e)),
+        }
+    }
+
+    fn metadata(&self, key: &str) -> Result<Option<KeyMeta>> {
+        self.expire(key.as_bytes())?;
+        match self.store.get(key.as_bytes())? {
+            Some(value) => match self.read_meta(key.as_bytes())? {
+                Some(meta) => Ok(Some(meta)),
+                None => Ok(Some(KeyMeta {
+                    size: value.len() as u64,
+                    ..Default::default()
+                })), // 老版本写的，没有元数据
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// glob开头没有通配符的那一段可以交给sled的scan_prefix，不用扫整个库
+    fn scan_matching(&self, filter: &KeyFilter) -> Result<Vec<(String, String)>> {
+        let matcher = filter.compile()?;
+        let mut pairs = vec![];
+        for entry in self.store.scan_prefix(filter.literal_prefix().as_bytes()) {
+            let (key, value) = entry?;
+            let key = match std::str::from_utf8(key.as_ref()) {
+                Ok(key) => key,
+                Err(_) => continue, // 不是utf8的key没法匹配
+            };
+            if matcher.matches(key)
+                && !namespace::is_namespaced(key.as_bytes())
+                && !self
+                    .read_meta(key.as_bytes())?
+                    .is_some_and(|v| v.is_expired())
+            {
+                let value = bytes::into_string(key.as_bytes(), value.to_vec())?;
+                pairs.push((key.to_string(), value));
+            }
+        }
+        Ok(pairs) // sled本来就是按key排好序的
+    }
+
+    /// 攒成sled的batch，最后只flush一次
+    fn bulk_load_iter(&self, pairs: &mut dyn Iterator<Item = (String, String)>) -> Result<usize> {
+        let mut count = 0;
+        let mut pairs = pairs.into_iter().peekable();
+        while pairs.peek().is_some() {
+            let mut batch = sled::Batch::default();
+            let mut metas = HashMap::new(); // 同一批里同一个key出现好几次的话，版本号要接着算
+            for (key, value) in pairs.by_ref().take(BULK_BATCH) {
+                let previous = match metas.get(&key) {
+                    Some(meta) => Some(*meta),
+                    None => self.read_meta(key.as_bytes())?.filter(|v| !v.is_expired()),
+                };
+                metas.insert(key.clone(), KeyMeta::next(previous.as_ref(), value.len()));
+                batch.insert(key.as_bytes(), value.as_bytes());
+                count += 1;
+            }
+            self.store.apply_batch(batch)?;
+
+            let mut batch = sled::Batch::default();
+            for (key, meta) in metas {
+                batch.insert(key.as_bytes(), serde_json::to_vec(&meta)?);
+            }
+            self.meta.apply_batch(batch)?;
+        }
+        self.maybe_flush()?;
+        Ok(count)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+enum Request {
+    Get(Bytes),
+    MultiGet(Vec<String>),
+    Set(Bytes, Bytes),
+    Remove(Bytes),
+    BulkLoad(Vec<(String, String)>),
+    Metadata(String),
+    GetDel(String),
+    GetSet(String, String),
+    SetNx(String, String),
+    Append(String, String),
+    ContainsKey(String),
+    /// key不存在的话写进去，返回最后的value
+    GetOrInsert(String, String),
+    CompareAndSwap {
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    },
+    Rename {
+        from: String,
+        to: String,
+        overwrite: bool,
+    },
+    Copy {
+        from: String,
+        to: String,
+    },
+    Scan(KeyFilter),
+    RemoveMatching(KeyFilter),
+    Dump(String),
+    Restore {
+        blob: String,
+        replace: bool,
+    },
+    Monitor {
+        token: String,
+    },
+    /// 事务里读到过的value和要写的，`None` 是不存在/删掉
+    Commit {
+        reads: Vec<(Bytes, Option<Bytes>)>,
+        writes: Vec<(Bytes, Option<Bytes>)>,
+    },
+    Ping,
+    /// 握手，见 `Hello`
+    Hello(Hello),
+    /// 服务器要求认证的话，别的请求之前先发这个
+    Auth(Credentials),
+    /// 回一行 `Stream` ，后面跟着value的原始字节
+    GetStream(Bytes),
+    /// 这一行后面跟着这么多字节的value
+    SetStream(Bytes, u64),
+    /// 让服务器重新读配置，见 `ReloadHandle`
+    Reload,
+    /// 回 `ServerStats`
+    Stats,
+}
+
+impl Request {
+    /// MONITOR里显示的命令名和key，二进制的key显示的时候有损也没关系
+    fn describe(&self) -> (&'static str, Option<String>) {
+        match self {
+            Request::Get(key) => ("get", Some(bytes::lossy(&key.0))),
+            Request::MultiGet(_) => ("multi_get", None),
+            Request::Set(key, _) => ("set", Some(bytes::lossy(&key.0))),
+            Request::Remove(key) => ("remove", Some(bytes::lossy(&key.0))),
+            Request::BulkLoad(_) => ("bulk_load", None),
+            Request::Metadata(key) => ("metadata", Some(key.clone())),
+            Request::GetDel(key) => ("getdel", Some(key.clone())),
+            Request::GetSet(key, _) => ("getset", Some(key.clone())),
+            Request::SetNx(key, _) => ("set_nx", Some(key.clone())),
+            Request::Append(key, _) => ("append", Some(key.clone())),
+            Request::ContainsKey(key) => ("contains_key", Some(key.clone())),
+            Request::GetOrInsert(key, _) => ("get_or_insert", Some(key.clone())),
+            Request::CompareAndSwap { key, .. } => ("compare_and_swap", Some(key.clone())),
+            Request::Rename { from, .. } => ("rename", Some(from.clone())),
+            Request::Copy { from, .. } => ("copy", Some(from.clone())),
+            Request::Scan(_) => ("scan", None),
+            Request::RemoveMatching(_) => ("remove_matching", None),
+            Request::Dump(key) => ("dump", Some(key.clone())),
+            Request::Restore { .. } => ("restore", None), // key在blob里面，不值得为了这个解一遍
+            Request::Monitor { .. } => ("monitor", None),
+            Request::Commit { .. } => ("commit", None),
+            Request::Ping => ("ping", None),
+            Request::Hello(_) => ("hello", None),
+            Request::Auth(credentials) => ("auth", Some(credentials.user.clone())),
+            Request::GetStream(key) => ("get", Some(bytes::lossy(&key.0))),
+            Request::SetStream(key, _) => ("set", Some(bytes::lossy(&key.0))),
+            Request::Reload => ("reload", None),
+            Request::Stats => ("stats", None),
+        }
+    }
+
+    /// 会不会改数据，审计日志只记这些
+    fn writes(&self) -> bool {
+        matches!(
+            self,
+            Request::Set(..)
+                | Request::Remove(_)
+                | Request::BulkLoad(_)
+                | Request::GetDel(_)
+                | Request::GetSet(..)
+                | Request::SetNx(..)
+                | Request::Append(..)
+                | Request::GetOrInsert(..)
+                | Request::CompareAndSwap { .. }
+                | Request::Rename { .. }
+                | Request::Copy { .. }
+                | Request::Restore { .. }
+                | Request::RemoveMatching(_)
+                | Request::Commit { .. }
+                | Request::SetStream(..)
+        )
+    }
+
+    /// 请求里带着的value，流式set的value还没读，也不值得为了审计存一份
+    fn value(&self) -> Option<String> {
+        match self {
+            Request::Set(_, value) => Some(bytes::lossy(&value.0)),
+            Request::GetSet(_, value)
+            | Request::SetNx(_, value)
+            | Request::Append(_, value)
+            | Request::GetOrInsert(_, value) => Some(value.clone()),
+            Request::CompareAndSwap { new, .. } => new.clone(),
+            _ => None,
+        }
+    }
+
+    /// 要碰的每个key都问一遍 `allowed` 有没有这个权限。key是 `None` 的意思是事先不知道会碰到哪些key
+    fn permitted<F>(&self, allowed: F) -> bool
+    where
+        F: Fn(Option<&[u8]>, Access) -> bool,
+    {
+        let read = |key: &[u8]| allowed(Some(key), Access::Read);
+        let write = |key: &[u8]| allowed(Some(key), Access::ReadWrite);
+        match self {
+            Request::Get(key) | Request::GetStream(key) => read(&key.0),
+            Request::MultiGet(keys) => keys.iter().all(|v| read(v.as_bytes())),
+            Request::Set(key, _) | Request::Remove(key) | Request::SetStream(key, _) => {
+                write(&key.0)
+            }
+            Request::BulkLoad(pairs) => pairs.iter().all(|(v, _)| write(v.as_bytes())),
+            Request::Metadata(key) | Request::ContainsKey(key) | Request::Dump(key) => {
+                read(key.as_bytes())
+            }
+            Request::GetDel(key)
+            | Request::GetSet(key, _)
+            | Request::SetNx(key, _)
+            | Request::Append(key, _)
+            | Request::GetOrInsert(key, _)
+            | Request::CompareAndSwap { key, .. } => write(key.as_bytes()),
+            Request::Rename { from, to, .. } => write(from.as_bytes()) && write(to.as_bytes()),
+            Request::Copy { from, to } => read(from.as_bytes()) && write(to.as_bytes()),
+            Request::Scan(_) => true, // 看不到的key从结果里去掉
+            Request::RemoveMatching(_) => allowed(None, Access::ReadWrite),
+            Request::Restore { blob, .. } => match Dump::from_blob(&blob[..]) {
+                Ok(dump) => write(dump.key.as_bytes()),
+                Err(_) => true, // 反正也restore不了，让后面报错
+            },
+            Request::Monitor { .. } => allowed(None, Access::Read), // 能看到所有人碰的key
+            Request::Commit { reads, writes } => {
+                reads.iter().all(|(v, _)| read(&v.0)) && writes.iter().all(|(v, _)| write(&v.0))
+            }
+            Request::Reload => allowed(None, Access::ReadWrite), // 管理员才能做
+            Request::Stats => allowed(None, Access::Read),       // key数、磁盘占用是所有key加起来的
+            Request::Ping | Request::Hello(_) | Request::Auth(_) => true,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+enum Response {
+    Done(Option<Bytes>),
+    Values(Vec<Option<String>>),
+    /// 错误信息和 `ErrorKind::code` ，老版本的服务器只发错误信息
+    Failed(String, #[serde(default)] u32),
+    Count(usize),
+    Metadata(Option<KeyMeta>),
+    Flag(bool),
+    Pairs(Vec<(String, String)>),
+    /// 事务没commit成，这个key被别人改过了
+    Conflict(String),
+    /// value有多长，后面紧跟着就是value。key不存在的话是 `None` ，后面什么都没有
+    Stream(Option<u64>),
+    /// 握手定下来的，之后就用这里的编码
+    Hello(Hello),
+    Stats(ServerStats),
+}
+
+impl Response {
+    fn failed(error: &KvsError) -> Self {
+        Response::Failed(format!("{}", error), error.code())
+    }
+}
+
+/// 请求一个接一个地在同一个连接上发。连接断了的话下一个请求会重新连
+pub struct KvsClient {
+    address: String,
+    tls: Option<Arc<ClientTls>>,
+    credentials: Option<Credentials>,
+    limiter: Option<TokenBucket>,
+    encoding: Encoding,
+    connection: Option<Connection>,
+}
+
+/// 一条请求一条响应，流式的value跟在后面
+struct Connection {
+    reader: BufReader<Stream>,
+    writer: Stream,
+    encoding: Encoding,
+    /// 服务器握手的时候回的
+    hello: Hello,
+}
+
+impl Connection {
+    /// 连上以后先握手，想用 `encoding` 。服务器不认识握手的话重新连一次，就用json
+    fn open(address: &str, tls: Option<&ClientTls>, encoding: Encoding) -> Result<Self> {
+        let mut connection = Self::plain(address, tls)?;
+        connection.send(&Request::Hello(Hello {
+            version: PROTOCOL_VERSION,
+            encoding,
+            capabilities: vec![],
+        }))?;
+        match connection.receive() {
+            Ok(Response::Hello(hello)) => {
+                connection.encoding = hello.encoding;
+                connection.hello = hello;
+                Ok(connection)
+            }
+            Ok(Response::Failed(message, code)) => Err(KvsError::Remote { message, code }),
+            Ok(v) => Err(KvsError::UnexpectedResponse {
+                response: format!("{:?}", v),
+            }),
+            Err(KvsError::Io(e)) if closed(&e) => Self::plain(address, tls), // 老的服务器解析不了，把连接关了
+            Err(e) => Err(e),
+        }
+    }
+
+    /// 不握手，和老的服务器说话
+    fn plain(address: &str, tls: Option<&ClientTls>) -> Result<Self> {
+        let writer = Stream::connect(address, tls)?;
+        Ok(Self {
+            reader: BufReader::new(writer.try_clone()?),
+            writer,
+            encoding: Encoding::Json,
+            hello: Hello::legacy(),
+        })
+    }
+
+    fn login(&mut self, credentials: &Credentials) -> Result<()> {
+        self.send(&Request::Auth(credentials.clone()))?;
+        match self.receive()? {
+            Response::Done(_) => Ok(()),
+            Response::Failed(message, code) => Err(KvsError::Remote { message, code }),
+            v => Err(KvsError::UnexpectedResponse {
+                response: format!("{:?}", v),
+            }),
+        }
+    }
+
+    fn send(&mut self, request: &Request) -> Result<()> {
+        self.encoding.write(&mut self.writer, request)
+    }
+
+    fn receive(&mut self) -> Result<Response> {
+        match self.encoding.read(&mut self.reader)? {
+            Some(response) => Ok(response),
+            None => Err(KvsError::Io(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "connection closed by server",
+            ))),
+        }
+    }
+}
+
+impl KvsClient {
+    /// 真正连上是在第一个请求的时候
+    pub fn connect(address: String) -> Result<Self> {
+        Ok(Self {
+            address,
+            tls: None,
+            credentials: None,
+            limiter: None,
+            encoding: Encoding::Json,
+            connection: None,
+        })
+    }
+
+    /// 想用什么编码，默认是json。握手的时候服务器不同意（比如老版本）的话还是json
+    pub fn encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self.connection = None;
+        self
+    }
+
+    /// 用TLS连服务器，按 `options` 验证服务器的证书
+    #[cfg(feature = "tls")]
+    pub fn tls(mut self, options: TlsOptions) -> Result<Self> {
+        self.tls = Some(Arc::new(ClientTls::new(&options)?));
+        self.connection = None;
+        Ok(self)
+    }
+
+    /// 每次连上以后先用这个用户登录。服务器不要求认证的话不用设
+    pub fn credentials(mut self, credentials: Credentials) -> Self {
+        self.credentials = Some(credentials);
+        self.connection = None;
+        self
+    }
+
+    /// 限制自己发请求的速度，跑批量任务的时候别把服务器打爆。`ops_per_sec` 要是正数，`burst` 至少是1
+    pub fn rate_limited(mut self, limit: RateLimit) -> Result<Self> {
+        self.limiter = Some(TokenBucket::new(limit)?);
+        Ok(self)
+    }
+
+    /// 发送请求，等待回应
+    ///
+    /// 用的是上一个请求留下的连接的话，服务器可能早就把它关了，这时候重新连上再发一次
+    fn request(&mut self, request: Request) -> Result<Response> {
+        self.acquire()?;
+        let reused = self.connection.is_some();
+        match self.exchange(&request) {
+            Err(KvsError::Io(e)) if reused && closed(&e) => self.exchange(&request),
+            v => v,
+        }
+    }
+
+    fn acquire(&mut self) -> Result<()> {
+        match &mut self.limiter {
+            Some(limiter) => limiter.acquire(),
+            None => Ok(()),
+        }
+    }
+
+    fn exchange(&mut self, request: &Request) -> Result<Response> {
+        let result = self.connection().and_then(|connection| {
+            connection.send(request)?;
+            connection.receive()
+        });
+        if result.is_err() {
+            self.connection = None; // 连接里可能还剩着半截，不能再用了
+        }
+        result
+    }
+
+    /// 没有连接或者上一个请求把连接弄坏了的话重新连
+    fn connection(&mut self) -> Result<&mut Connection> {
+        if self.connection.is_none() {
+            self.connection = Some(self.dial(self.encoding)?);
+        }
+        Ok(self.connection.as_mut().unwrap())
+    }
+
+    /// 连上、握手、登录
+    fn dial(&self, encoding: Encoding) -> Result<Connection> {
+        let mut connection = Connection::open(&self.address[..], self.tls.as_deref(), encoding)?;
+        if let Some(credentials) = &self.credentials {
+            connection.login(credentials)?;
+        }
+        Ok(connection)
+    }
+
+    /// 服务器握手的时候回的：协议版本、用的编码、支持什么。还没连上的话现在连
+    pub fn hello(&mut self) -> Result<Hello> {
+        Ok(self.connection()?.hello.clone())
+    }
+
+    /// 无聊的CRUD……
+    pub fn get(&mut self, key: &str) -> Result<Option<String>> {
+        self.get_bytes(key.as_bytes())?
+            .map(|v| bytes::into_string(key.as_bytes(), v))
+            .transpose()
+    }
+
+    pub fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let response = self.request(Request::Get(Bytes::from(key.to_vec())))?;
+        match response {
+            Response::Done(v) => Ok(v.map(|v| v.0)),
+            Response::Failed(message, code) => Err(KvsError::Remote { message, code }),
+            v => Err(KvsError::UnexpectedResponse {
+                response: format!("{:?}", v),
+            }),
+        }
+    }
+
+    /// value边收边读，不用整个放进内存。读到一半服务器断了的话 `read` 会报 `UnexpectedEof`
+    ///
+    /// 连接交给了返回的reader，下一个请求会重新连
+    pub fn get_reader(&mut self, key: &[u8]) -> Result<Option<ValueReader<'static>>> {
+        let request = Request::GetStream(Bytes::from(key.to_vec()));
+        match self.request(request)? {
+            Response::Stream(Some(len)) => {
+                let connection = self.connection.take().unwrap(); // 成功了连接肯定还在
+                Ok(Some(ValueReader::new(
+                    len,
+                    Box::new(stream::exact(connection.reader, len)),
+                )))
+            }
+            Response::Stream(None) => Ok(None),
+            Response::Failed(message, code) => Err(KvsError::Remote { message, code }),
+            v => Err(KvsError::UnexpectedResponse {
+                response: format!("{:?}", v),
+            }),
+        }
+    }
+
+    /// 从 `reader` 里读 `len` 个字节当value，边读边发。要先说好多长，服务器才知道value到哪结束
+    ///
+    /// `reader` 读一次就没了，连接断了也不会重发
+    pub fn set_from_reader(&mut self, key: Vec<u8>, reader: &mut dyn Read, len: u64) -> Result<()> {
+        self.acquire()?;
+        let request = Request::SetStream(Bytes(key), len);
+        let result = self.connection().and_then(|connection| {
+            connection.send(&request)?;
+            std::io::copy(&mut stream::exact(reader, len), &mut connection.writer)?; // 不够len的话别发出去半截
+            connection.receive()
+        });
+        if result.is_err() {
+            self.connection = None;
+        }
+        match result? {
+            Response::Done(_) => Ok(()),
+            Response::Failed(message, code) => Err(KvsError::Remote { message, code }),
+            v => Err(KvsError::UnexpectedResponse {
+                response: format!("{:?}", v),
+            }),
+        }
+    }
+
+    /// 开一个事务，commit之前别的客户端改了事务读过的key的话commit会报 `Conflict`
+    pub fn begin(&mut self) -> RemoteTransaction<'_> {
+        RemoteTransaction::new(self)
+    }
+
+    /// `f` 返回 `Ok` 就commit，返回 `Err` 就rollback
+    pub fn transaction<F, T>(&mut self, f: F) -> Result<T>
+    where
+        F: FnOnce(&mut RemoteTransaction<'_>) -> Result<T>,
+    {
+        let mut txn = self.begin();
+        let result = f(&mut txn)?;
+        txn.commit()?;
+        Ok(result)
+    }
+
+    /// 好几个key一个请求拿回来，不用一个key开一次连接
+    pub fn multi_get(&mut self, keys: &[&str]) -> Result<Vec<Option<String>>> {
+        let keys = keys.iter().map(|v| v.to_string()).collect();
+        let response = self.request(Request::MultiGet(keys))?;
+        match response {
+            Response::Values(values) => Ok(values),
+            Response::Failed(message, code) => Err(KvsError::Remote { message, code }),
+            v => Err(KvsError::UnexpectedResponse {
+                response: format!("{:?}", v),
+            }),
+        }
+    }
+
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let response = self.request(Request::Set(Bytes(key), Bytes(value)))?;
+        match response {
+            Response::Done(_) => Ok(()),
+            Response::Failed(message, code) => Err(KvsError::Remote { message, code }),
+            v => Err(KvsError::UnexpectedResponse {
+                response: format!("{:?}", v),
+            }),
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) -> Result<()> {
+        self.remove_bytes(key.as_bytes())
+    }
+
+    pub fn remove_bytes(&mut self, key: &[u8]) -> Result<()> {
+        let response = self.request(Request::Remove(Bytes::from(key.to_vec())))?;
+        match response {
+            Response::Done(_) => Ok(()),
+            Response::Failed(message, code) => Err(KvsError::Remote { message, code }),
+            v => Err(KvsError::UnexpectedResponse {
+                response: format!("{:?}", v),
+            }),
+        }
+    }
+
+    pub fn getdel(&mut self, key: &str) -> Result<Option<String>> {
+        let response = self.request(Request::GetDel(key.to_string()))?;
+        match response {
+            Response::Done(v) => v
+                .map(|v| bytes::into_string(key.as_bytes(), v.0))
+                .transpose(),
+            Response::Failed(message, code) => Err(KvsError::Remote { message, code }),
+            v => Err(KvsError::UnexpectedResponse {
+                response: format!("{:?}", v),
+            }),
+        }
+    }
+
+    pub fn getset(&mut self, key: String, value: String) -> Result<Option<String>> {
+        let response = self.request(Request::GetSet(key.clone(), value))?;
+        match response {
+            Response::Done(v) => v
+                .map(|v| bytes::into_string(key.as_bytes(), v.0))
+                .transpose(),
+            Response::Failed(message, code) => Err(KvsError::Remote { message, code }),
+            v => Err(KvsError::UnexpectedResponse {
+                response: format!("{:?}", v),
+            }),
+        }
+    }
+
+    /// 多个客户端抢同一个key的时候只有一个能返回 `true`
+    pub fn set_nx(&mut self, key: String, value: String) -> Result<bool> {
+        let response = self.request(Request::SetNx(key, value))?;
+        match response {
+            Response::Flag(written) => Ok(written),
+            Response::Failed(message, code) => Err(KvsError::Remote { message, code }),
+            v => Err(KvsError::UnexpectedResponse {
+                response: format!("{:?}", v),
+            }),
+        }
+    }
+
+    /// value再大也不会传过来
+    pub fn contains_key(&mut self, key: &str) -> Result<bool> {
+        let response = self.request(Request::ContainsKey(key.to_string()))?;
+        match response {
+            Response::Flag(exists) => Ok(exists),
+            Response::Failed(message, code) => Err(KvsError::Remote { message, code }),
+            v => Err(KvsError::UnexpectedResponse {
+                response: format!("{:?}", v),
+            }),
+        }
+    }
+
+    /// 在服务器那边接好，返回接完以后的长度
+    pub fn append(&mut self, key: &str, suffix: &str) -> Result<usize> {
+        let response = self.request(Request::Append(key.to_string(), suffix.to_string()))?;
+        match response {
+            Response::Count(len) => Ok(len),
+            Response::Failed(message, code) => Err(KvsError::Remote { message, code }),
+            v => Err(KvsError::UnexpectedResponse {
+                response: format!("{:?}", v),
+            }),
+        }
+    }
+
+    /// 没法把闭包发过去，只能先把value算好。返回的是服务器上最后的value，不一定是传过去的这个
+    pub fn get_or_insert(&mut self, key: String, value: String) -> Result<String> {
+        let response = self.request(Request::GetOrInsert(key.clone(), value))?;
+        match response {
+            Response::Done(Some(v)) => bytes::into_string(key.as_bytes(), v.0),
+            Response::Failed(message, code) => Err(KvsError::Remote { message, code }),
+            v => Err(KvsError::UnexpectedResponse {
+                response: format!("{:?}", v),
+            }),
+        }
+    }
+
+    /// 多个客户端之间的乐观锁：读出来，改好，再用这个写回去，返回 `false` 说明中间被别人改过了，重来
+    pub fn compare_and_swap(
+        &mut self,
+        key: &str,
+        expected: Option<&str>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let response = self.request(Request::CompareAndSwap {
+            key: key.to_string(),
+            expected: expected.map(|v| v.to_string()),
+            new,
+        })?;
+        match response {
+            Response::Flag(swapped) => Ok(swapped),
+            Response::Failed(message, code) => Err(KvsError::Remote { message, code }),
+            v => Err(KvsError::UnexpectedResponse {
+                response: format!("{:?}", v),
+            }),
+        }
+    }
+
+    pub fn rename(&mut self, from: &str, to: &str) -> Result<()> {
+        self.rename_with(from, to, true).map(|_| ())
+    }
+
+    pub fn rename_nx(&mut self, from: &str, to: &str) -> Result<bool> {
+        self.rename_with(from, to, false)
+    }
+
+    fn rename_with(&mut self, from: &str, to: &str, overwrite: bool) -> Result<bool> {
+        let response = self.request(Request::Rename {
+            from: from.to_string(),
+            to: to.to_string(),
+            overwrite,
+        })?;
+        match response {
+            Response::Flag(renamed) => Ok(renamed),
+            Response::Failed(message, code) => Err(KvsError::Remote { message, code }),
+            v => Err(KvsError::UnexpectedResponse {
+                response: format!("{:?}", v),
+            }),
+        }
+    }
+
+    /// 服务器那边一次做完，不会有别的客户端在get和set中间插进来
+    pub fn copy(&mut self, from: &str, to: &str) -> Result<()> {
+        let response = self.request(Request::Copy {
+            from: from.to_string(),
+            to: to.to_string(),
+        })?;
+        match response {
+            Response::Done(_) => Ok(()),
+            Response::Failed(message, code) => Err(KvsError::Remote { message, code }),
+            v => Err(KvsError::UnexpectedResponse {
+                response: format!("{:?}", v),
+            }),
+        }
+    }
+
+    pub fn metadata(&mut self, key: &str) -> Result<Option<KeyMeta>> {
+        let response = self.request(Request::Metadata(key.to_string()))?;
+        match response {
+            Response::Metadata(meta) => Ok(meta),
+            Response::Failed(message, code) => Err(KvsError::Remote { message, code }),
+            v => Err(KvsError::UnexpectedResponse {
+                response: format!("{:?}", v),
+            }),
+        }
+    }
+
+    /// 导出来的是一个不透明的字符串，原样交给另一个服务器的 `restore` 就行
+    pub fn dump(&mut self, key: &str) -> Result<Option<String>> {
+        let response = self.request(Request::Dump(key.to_string()))?;
+        match response {
+            Response::Done(blob) => blob
+                .map(|v| bytes::into_string(key.as_bytes(), v.0))
+                .transpose(),
+            Response::Failed(message, code) => Err(KvsError::Remote { message, code }),
+            v => Err(KvsError::UnexpectedResponse {
+                response: format!("{:?}", v),
+            }),
+        }
+    }
+
+    /// 校验和由服务器检查，这边只管发
+    pub fn restore(&mut self, blob: &str, replace: bool) -> Result<()> {
+        let response = self.request(Request::Restore {
+            blob: blob.to_string(),
+            replace,
+        })?;
+        match response {
+            Response::Done(_) => Ok(()),
+            Response::Failed(message, code) => Err(KvsError::Remote { message, code }),
+            v => Err(KvsError::UnexpectedResponse {
+                response: format!("{:?}", v),
+            }),
+        }
+    }
+
+    /// 不碰engine，只看网络和服务器本身有多慢
+    pub fn ping(&mut self) -> Result<()> {
+        let response = self.request(Request::Ping)?;
+        match response {
+            Response::Done(_) => Ok(()),
+            Response::Failed(message, code) => Err(KvsError::Remote { message, code }),
+            v => Err(KvsError::UnexpectedResponse {
+                response: format!("{:?}", v),
+            }),
+        }
+    }
+
+    /// 让服务器重新读账号、ACL、TLS证书，连接都不会断。服务器设了ACL的话要对所有key都能读写才行
+    pub fn reload(&mut self) -> Result<()> {
+        let response = self.request(Request::Reload)?;
+        match response {
+            Response::Done(_) => Ok(()),
+            Response::Failed(message, code) => Err(KvsError::Remote { message, code }),
+            v => Err(KvsError::UnexpectedResponse {
+                response: format!("{:?}", v),
+            }),
+        }
+    }
+
+    /// 服务器的版本、开了多久、engine的key数和磁盘占用、连接数。服务器设了ACL的话要对所有key都能读才行
+    pub fn stats(&mut self) -> Result<ServerStats> {
+        let response = self.request(Request::Stats)?;
+        match response {
+            Response::Stats(stats) => Ok(stats),
+            Response::Failed(message, code) => Err(KvsError::Remote { message, code }),
+            v => Err(KvsError::UnexpectedResponse {
+                response: format!("{:?}", v),
+            }),
+        }
+    }
+
+    /// 订阅服务器执行的每一个命令，这个连接会一直开着，直到服务器把它踢掉
+    pub fn monitor(&mut self, token: &str) -> Result<Monitor> {
+        let mut connection = self.dial(Encoding::Json)?; // 事件都是一行json
+        connection.send(&Request::Monitor {
+            token: token.to_string(),
+        })?;
+
+        // 和别的请求不一样，第一行是响应，后面每一行是一个事件
+        match connection.receive()? {
+            Response::Done(_) => Ok(Monitor::new(connection.reader.lines())),
+            Response::Failed(message, code) => Err(KvsError::Remote { message, code }),
+            v => Err(KvsError::UnexpectedResponse {
+                response: format!("{:?}", v),
+            }),
+        }
+    }
+
+    /// 在服务器那边筛好再传回来
+    pub fn scan(&mut self, filter: KeyFilter) -> Result<Vec<(String, String)>> {
+        let response = self.request(Request::Scan(filter))?;
+        match response {
+            Response::Pairs(pairs) => Ok(pairs),
+            Response::Failed(message, code) => Err(KvsError::Remote { message, code }),
+            v => Err(KvsError::UnexpectedResponse {
+                response: format!("{:?}", v),
+            }),
+        }
+    }
+
+    /// 在服务器那边找出来删掉，返回删了几个
+    pub fn remove_matching(&mut self, filter: KeyFilter) -> Result<u64> {
+        let response = self.request(Request::RemoveMatching(filter))?;
+        match response {
+            Response::Count(count) => Ok(count as u64),
+            Response::Failed(message, code) => Err(KvsError::Remote { message, code }),
+            v => Err(KvsError::UnexpectedResponse {
+                response: format!("{:?}", v),
+            }),
+        }
+    }
+
+    /// 一个请求就把这一堆都导进去，太大的话调用者自己分批
+    pub fn bulk_load(&mut self, pairs: Vec<(String, String)>) -> Result<usize> {
+        let response = self.request(Request::BulkLoad(pairs))?;
+        match response {
+            Response::Count(count) => Ok(count),
+            Response::Failed(message, code) => Err(KvsError::Remote { message, code }),
+            v => Err(KvsError::UnexpectedResponse {
+                response: format!("{:?}", v),
+            }),
+        }
+    }
+}
+
+/// 服务器和客户端说什么协议
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Protocol {
+    /// `KvsClient` 用的
+    Kvs,
+    /// Redis的RESP，redis-cli和Redis的客户端库能直接连，只支持PING、GET、SET、DEL、EXISTS，还有订阅用的 `MONITOR token`
+    Resp,
+}
+
+/// 服务器把空闲的连接关了，请求还没发到或者服务器还没开始处理
+fn closed(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        std::io::ErrorKind::UnexpectedEof
+            | std::io::ErrorKind::BrokenPipe
+            | std::io::ErrorKind::ConnectionReset
+            | std::io::ErrorKind::ConnectionAborted
+    )
+}
+
+/// 每个连接扔给线程池里的一个线程，每个连接拿engine的一个clone
+///
+/// 默认每个连接开一个新线程，用 `with_pool` 换成别的线程池
+#[derive(Clone)]
+pub struct KvsServer<T, P = NaiveThreadPool> {
+    engine: T,
+    protocol: Protocol,
+    subscribers: Option<Arc<Mutex<Subscribers>>>,
+    audit: Option<Arc<Mutex<AuditLog>>>,
+    pool: P,
+    /// TLS、账号、ACL，运行的时候能换
+    config: ReloadHandle,
+    shutdown: ShutdownHandle,
+    /// 同时最多处理这么多连接，`None` 是不限
+    max_connections: Option<usize>,
+    timeouts: Timeouts,
+    /// 比这慢的请求打一条warn，`None` 是不管
+    slow_request: Option<Duration>,
+    /// `KvsServer::new` 的时候，算uptime用
+    started: Instant,
+}
+
+/// 一个连接上的状态，连接关了就没了
+#[derive(Default)]
+struct Session {
+    /// 握手以后可能会换
+    encoding: Encoding,
+    /// 登录的用户，没登录是 `None`
+    user: Option<String>,
+}
+
+impl<T> KvsServer<T>
+where
+    T: KvsEngine + Clone,
+{
+    pub fn new(engine: T) -> Self {
+        Self {
+            engine,
+            protocol: Protocol::Kvs,
+            subscribers: None,
+            audit: None,
+            pool: NaiveThreadPool,
+            config: ReloadHandle::default(),
+            shutdown: ShutdownHandle::default(),
+            max_connections: None,
+            timeouts: Timeouts::default(),
+            slow_request: None,
+            started: Instant::now(),
+        }
+    }
+}
+
+impl<T, P> KvsServer<T, P>
+where
+    T: KvsEngine + Clone,
+    P: ThreadPool + Clone + Send + 'static,
+{
+    /// 连接在 `pool` 里跑。一个连接从连上到断开一直占着一个线程，不发请求的也占着，所以池子有几个线程最多就同时服务几个连接，多出来的要排队等。固定大小的池子最好配上 `Timeouts::idle`
+    pub fn with_pool<Q>(self, pool: Q) -> KvsServer<T, Q>
+    where
+        Q: ThreadPool + Clone + Send + 'static,
+    {
+        KvsServer {
+            engine: self.engine,
+            protocol: self.protocol,
+            subscribers: self.subscribers,
+            audit: self.audit,
+            pool,
+            config: self.config,
+            shutdown: self.shutdown,
+            max_connections: self.max_connections,
+            timeouts: self.timeouts,
+            slow_request: self.slow_request,
+            started: self.started,
+        }
+    }
+
+    /// 同时最多开着 `max` 个连接，再来的先不收，在系统的backlog里排队，等有连接关了再收。不然客户端多了文件描述符会被用完。0当成1
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max.max(1));
+        self
+    }
+
+    /// 客户端太慢或者干脆不发了的话，连接最多等多久
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// 一个请求最多 `bytes` 个字节（json不算换行，bincode不算长度），默认512MB。超了的话回一个 `RequestTooLarge` 然后关掉连接
+    ///
+    /// 流式set的value不算在里面，要限制value的大小用 `SizeLimits` 。运行的时候可以用 `ReloadHandle::set_max_request` 换
+    pub fn max_request(self, bytes: usize) -> Self {
+        self.config.set_max_request(bytes);
+        self
+    }
+
+    /// 从收到请求到回完超过 `threshold` 的请求打一条warn，带上命令、key和花了多久，用来找出value特别大或者特别冷的key
+    pub fn slow_requests(mut self, threshold: Duration) -> Self {
+        self.slow_request = Some(threshold);
+        self
+    }
+
+    fn check_slow(&self, stream: &Stream, op: &str, key: Option<&str>, started: Instant) {
+        if let Some(threshold) = self.slow_request {
+            let elapsed = started.elapsed();
+            if elapsed >= threshold {
+                warn!(
+                    "Slow request from {}: {} {} took {:?}",
+                    stream.peer(),
+                    op,
+                    key.unwrap_or_default(),
+                    elapsed
+                );
+            }
+        }
+    }
+
+    /// 连接太多的话先等着，不收新的
+    fn wait_for_room(&self) {
+        if let Some(max) = self.max_connections {
+            self.shutdown.wait_for_room(max);
+        }
+    }
+
+    /// 说什么协议，默认是 `KvsClient` 的
+    pub fn protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// 连接都走TLS，`cert` 是PEM格式的证书链，`key` 是PEM格式的私钥。只有 `run` 支持
+    #[cfg(feature = "tls")]
+    pub fn tls<C, K>(self, cert: C, key: K) -> Result<Self>
+    where
+        C: AsRef<Path>,
+        K: AsRef<Path>,
+    {
+        self.config
+            .enable_tls(tls::server_config(cert.as_ref(), key.as_ref())?);
+        Ok(self)
+    }
+
+    /// 连接要先用 `accounts` 里的用户登录，不然只能 `Hello` 和 `Ping`
+    pub fn authenticated(self, accounts: Accounts) -> Self {
+        self.config.set_accounts(accounts);
+        self
+    }
+
+    /// 登录了的用户只能碰 `acl` 允许的key，ACL里没有的用户什么都碰不了
+    pub fn acl(self, acl: Acl) -> Self {
+        self.config.set_acl(acl);
+        self
+    }
+
+    /// `ReloadHandle::reload` 或者客户端发来 `KvsClient::reload` 的时候调 `reloader` ，它去重新读配置，再用 `ReloadHandle` 的 `set_` 换上
+    pub fn on_reload<F>(self, reloader: F) -> Self
+    where
+        F: FnMut(&ReloadHandle) -> Result<()> + Send + 'static,
+    {
+        self.config.on_reload(Box::new(reloader));
+        self
+    }
+
+    /// 拿着它可以在运行的时候换配置。clone出来的server和原来的是同一份
+    pub fn reload_handle(&self) -> ReloadHandle {
+        self.config.clone()
+    }
+
+    /// 设了TLS的话不能明文地收连接
+    fn plaintext(&self) -> Result<()> {
+        #[cfg(feature = "tls")]
+        if self.config.tls().is_some() {
+            return Err(KvsError::InvalidArgument {
+                name: "tls".to_string(),
+                value: "only supported by KvsServer::run".to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// 允许带着正确token的客户端订阅所有命令
+    pub fn monitored(mut self, config: MonitorConfig) -> Self {
+        self.subscribers = Some(Arc::new(Mutex::new(Subscribers::new(config))));
+        self
+    }
+
+    /// 成功了的写都记到审计日志里，见 `AuditConfig` 。文件打不开的话报错
+    pub fn audited(mut self, config: AuditConfig) -> Result<Self> {
+        self.audit = Some(Arc::new(Mutex::new(AuditLog::open(config)?)));
+        Ok(self)
+    }
+
+    fn stats(&self) -> Result<ServerStats> {
+        let stats = self.engine.stats()?;
+        Ok(ServerStats {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            uptime_secs: self.started.elapsed().as_secs(),
+            engine: self.engine.engine_name().to_string(),
+            keys: stats.keys,
+            disk_bytes: stats.disk_bytes,
+            cache_hit_rate: stats.cache_hit_rate(),
+            connections: self.shutdown.connections(),
+        })
+    }
+
+    /// 写审计日志失败的时候数据已经改了，只能报个错接着跑
+    fn audit(&self, entry: &AuditEntry) {
+        if let Some(audit) = &self.audit {
+            if let Err(e) = audit.lock().unwrap().record(entry) {
+                error!("Failed to write the audit log: {}", e);
+            }
+        }
+    }
+
+    /// 要么把连接留下来当订阅者，要么告诉它为什么不行
+    ///
+    /// 回的这一条用连接上的编码，后面的事件都是一行json
+    fn subscribe(&mut self, stream: &mut Stream, token: &str, encoding: Encoding) -> Result<()> {
+        // 回完话、加进去之前一直拿着锁，不然客户端收到回复以后马上发的命令可能在加进去之前就发布了
+        let mut subscribers = self.subscribers.as_ref().map(|v| v.lock().unwrap());
+        let response = match &subscribers {
+            None => Response::Failed(
+                "MONITOR is not enabled on this server".to_string(),
+                ErrorKind::Unsupported.code(),
+            ),
+            Some(subscribers) if !subscribers.authorized(token) => Response::Failed(
+                "MONITOR permission denied".to_string(),
+                ErrorKind::PermissionDenied.code(),
+            ),
+            Some(_) => Response::Done(None),
+        };
+        encoding.write(stream, &response)?;
+
+        if let (Response::Done(_), Some(subscribers)) = (&response, &mut subscribers) {
+            subscribers.add(stream.try_clone()?)?; // run里面的stream会被drop，但是clone出来的还开着
+        }
+        Ok(())
+    }
+
+    /// 版本取两边小的那个，编码都支持，再告诉客户端这边还有什么
+    fn hello(&self, hello: &Hello) -> Hello {
+        let mut capabilities = vec![
+            "bincode".to_string(),
+            "stream".to_string(),
+            "stats".to_string(),
+        ];
+        if self.subscribers.is_some() {
+            capabilities.push("monitor".to_string());
+        }
+        if self.config.accounts().is_some() {
+            capabilities.push("auth".to_string());
+        }
+        if self.config.is_reloadable() {
+            capabilities.push("reload".to_string());
+        }
+        Hello {
+            version: hello.version.min(PROTOCOL_VERSION),
+            encoding: hello.encoding,
+            capabilities,
+        }
+    }
+
+    /// 一直处理这个连接上的请求，直到客户端关了上传通道
+    ///
+    /// 一开始都是一行json，握手以后换成定下来的编码，见 `Hello` 。
+    /// 老的客户端不握手，发完一个请求就关上传通道，不带换行，收到响应以后连接就关了，也还能用。
+    /// 流式的set在请求后面还跟着value，流式的get在响应后面跟着value
+    fn serve(&mut self, stream: &mut Stream) -> Result<()> {
+        if self.protocol == Protocol::Resp {
+            return resp::serve(
+                &self.engine,
+                &self.config,
+                self.audit.as_deref(),
+                self.subscribers.as_deref(),
+                self.slow_request,
+                self.timeouts,
+                stream,
+            );
+        }
+        self.timeouts.apply(stream)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut session = Session::default();
+        loop {
+            if !self.timeouts.wait_for_request(stream, &mut reader)? {
+                return Ok(()); // 客户端不发了，或者空闲太久了
+            }
+            let frame = match session
+                .encoding
+                .read_frame(&mut reader, self.config.max_request())
+            {
+                Ok(Some(frame)) => frame,
+                Ok(None) => return Ok(()), // 客户端不发了
+                Err(e @ KvsError::RequestTooLarge { .. }) => {
+                    session.encoding.write(stream, &Response::failed(&e))?;
+                    return Ok(()); // 剩下的没读，下一个请求从哪开始已经不知道了
+                }
+                Err(e) => return Err(e),
+            };
+            if !self.respond(stream, &mut reader, &mut session, &frame[..])? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// 请求已经收好了，`reader` 接着读的是请求后面的东西。返回这个连接还能不能接着收请求
+    ///
+    /// 握手的话 `session` 的编码会换成定下来的，登录的话记下用户
+    fn respond(
+        &mut self,
+        stream: &mut Stream,
+        reader: &mut dyn Read,
+        session: &mut Session,
+        frame: &[u8],
+    ) -> Result<bool> {
+        let started = Instant::now();
+        let request: Request = session.encoding.decode(frame)?;
+        if let Request::Hello(hello) = &request {
+            let agreed = self.hello(hello);
+            session
+                .encoding
+                .write(stream, &Response::Hello(agreed.clone()))?; // 回的这一条还是用原来的编码
+            session.encoding = agreed.encoding;
+            return Ok(true);
+        }
+        let encoding = session.encoding;
+        if let Request::Auth(credentials) = &request {
+            let response = self.login(session, credentials);
+            encoding.write(stream, &response)?;
+            return Ok(true);
+        }
+        if session.user.is_none()
+            && self.config.accounts().is_some()
+            && !matches!(request, Request::Ping)
+        {
+            let response = Response::Failed(
+                "authentication required".to_string(),
+                ErrorKind::PermissionDenied.code(),
+            );
+            encoding.write(stream, &response)?;
+            // 流式的set后面还跟着value，没登录的人发多大都有可能，不读了直接断开
+            return Ok(!matches!(request, Request::SetStream(..)));
+        }
+        if !request.permitted(|key, access| self.allowed(session, key, access)) {
+            let response = Response::Failed(
+                "permission denied".to_string(),
+                ErrorKind::PermissionDenied.code(),
+            );
+            encoding.write(stream, &response)?;
+            return Ok(!matches!(request, Request::SetStream(..)));
+        }
+        if let Request::Monitor { token } = &request {
+            self.subscribe(stream, &token[..], encoding)?;
+            return Ok(false); // 订阅者的连接以后只用来发事件了
+        }
+
+        let (op, key) = request.describe();
+        if log::log_enabled!(log::Level::Debug) {
+            // 拿peer要一次系统调用，没开debug就省了
+            debug!(
+                "{} {} {}",
+                stream.peer(),
+                op,
+                key.as_deref().unwrap_or_default()
+            );
+        }
+        let audit = match &self.audit {
+            Some(audit) if request.writes() => Some(AuditEntry {
+                timestamp: now_millis(),
+                peer: stream.peer(),
+                user: session.user.clone(),
+                op: op.to_string(),
+                key: key.clone(),
+                value: if audit.lock().unwrap().values() {
+                    request.value()
+                } else {
+                    None
+                },
+            }),
+            _ => None,
+        };
+        let mut changed = true; // 有的写什么都没改，比如getdel不存在的key、条件不满足的set_nx，这些不进审计日志
+        let response = match request {
+            Request::Get(key) => match self.engine.get_bytes(&key.0) {
+                Ok(value) => Response::Done(value.map(Bytes)),
+                Err(e) => Response::failed(&e),
+            },
+            Request::MultiGet(keys) => {
+                let keys: Vec<&str> = keys.iter().map(|v| &v[..]).collect();
+                match self.engine.multi_get(&keys[..]) {
+                    Ok(values) => Response::Values(values),
+                    Err(e) => Response::failed(&e),
+                }
+            }
+            Request::Set(key, value) => match self.engine.set_bytes(key.0, value.0) {
+                Ok(_) => Response::Done(None),
+                Err(e) => Response::failed(&e),
+            },
+            Request::Remove(key) => match self.engine.remove_bytes(&key.0) {
+                Ok(_) => Response::Done(None),
+                Err(e) => Response::failed(&e),
+            },
+            Request::BulkLoad(pairs) => match self.engine.bulk_load(pairs) {
+                Ok(count) => {
+                    changed = count > 0;
+                    Response::Count(count)
+                }
+                Err(e) => Response::failed(&e),
+            },
+            Request::GetDel(key) => match self.engine.getdel(&key[..]) {
+                Ok(value) => {
+                    changed = value.is_some();
+                    Response::Done(value.map(Bytes::from))
+                }
+                Err(e) => Response::failed(&e),
+            },
+            Request::GetSet(key, value) => match self.engine.getset(key, value) {
+                Ok(previous) => Response::Done(previous.map(Bytes::from)),
+                Err(e) => Response::failed(&e),
+            },
+            Request::SetNx(key, value) => match self.engine.set_nx(key, value) {
+                Ok(written) => {
+                    changed = written;
+                    Response::Flag(written)
+                }
+                Err(e) => Response::failed(&e),
+            },
+            Request::Append(key, suffix) => match self.engine.append(&key[..], &suffix[..]) {
+                Ok(len) => Response::Count(len),
+                Err(e) => Response::failed(&e),
+            },
+            Request::ContainsKey(key) => match self.engine.contains_key(&key[..]) {
+                Ok(exists) => Response::Flag(exists),
+                Err(e) => Response::failed(&e),
+            },
+            Request::GetOrInsert(key, value) => {
+                changed = false;
+                match self.engine.get_or_insert_with(&key[..], || {
+                    changed = true;
+                    value
+                }) {
+                    Ok(value) => Response::Done(Some(Bytes::from(value))),
+                    Err(e) => Response::failed(&e),
+                }
+            }
+            Request::CompareAndSwap { key, expected, new } => {
+                match self
+                    .engine
+                    .compare_and_swap(&key[..], expected.as_deref(), new)
+                {
+                    Ok(swapped) => {
+                        changed = swapped;
+                        Response::Flag(swapped)
+                    }
+                    Err(e) => Response::failed(&e),
+                }
+            }
+            Request::Rename {
+                from,
+                to,
+                overwrite: true,
+            } => match self.engine.rename(&from[..], &to[..]) {
+                Ok(_) => Response::Flag(true),
+                Err(e) => Response::failed(&e),
+            },
+            Request::Rename {
+                from,
+                to,
+                overwrite: false,
+            } => match self.engine.rename_nx(&from[..], &to[..]) {
+                Ok(renamed) => {
+                    changed = renamed;
+                    Response::Flag(renamed)
+                }
+                Err(e) => Response::failed(&e),
+            },
+            Request::Copy { from, to } => match self.engine.copy(&from[..], &to[..]) {
+                Ok(_) => Response::Done(None),
+                Err(e) => Response::failed(&e),
+            },
+            Request::Metadata(key) => match self.engine.metadata(&key[..]) {
+                Ok(meta) => Response::Metadata(meta),
+                Err(e) => Response::failed(&e),
+            },
+            Request::Dump(key) => match self.engine.dump(&key[..]).and_then(|dump| match dump {
+                Some(dump) => dump.to_blob().map(Some),
+                None => Ok(None),
+            }) {
+                Ok(blob) => Response::Done(blob.map(Bytes::from)),
+                Err(e) => Response::failed(&e),
+            },
+            Request::Restore { blob, replace } => {
+                match Dump::from_blob(&blob[..]).and_then(|dump| self.engine.restore(dump, replace))
+                {
+                    Ok(_) => Response::Done(None),
+                    Err(e) => Response::failed(&e),
+                }
+            }
+            Request::Scan(filter) => match self.engine.scan_matching(&filter) {
+                Ok(mut pairs) => {
+                    pairs.retain(|(key, _)| {
+                        self.allowed(session, Some(key.as_bytes()), Access::Read)
+                    });
+                    Response::Pairs(pairs)
+                }
+                Err(e) => Response::failed(&e),
+            },
+            Request::RemoveMatching(filter) => match self.engine.remove_matching(&filter) {
+                Ok(count) => {
+                    changed = count > 0;
+                    Response::Count(count as usize)
+                }
+                Err(e) => Response::failed(&e),
+            },
+            // 对value和写下去都在engine的 `write_batch_if` 里，和别的连接的普通写之间也不会漏掉冲突
+            Request::Commit { reads, writes } => {
+                changed = !writes.is_empty();
+                match txn::commit(&self.engine, Staged::from_wire(reads, writes)) {
+                    Ok(_) => Response::Done(None),
+                    Err(KvsError::Conflict { key }) => Response::Conflict(key),
+                    Err(e) => Response::failed(&e),
+                }
+            }
+            Request::Ping => Response::Done(None),
+            Request::Reload if !self.config.is_reloadable() => Response::Failed(
+                "reload is not configured on this server".to_string(),
+                ErrorKind::Unsupported.code(),
+            ),
+            Request::Reload => match self.config.reload() {
+                Ok(()) => Response::Done(None),
+                Err(e) => Response::failed(&e),
+            },
+            Request::Stats => match self.stats() {
+                Ok(stats) => Response::Stats(stats),
+                Err(e) => Response::failed(&e),
+            },
+            Request::GetStream(name) => {
+                if self.send_stream(stream, &name.0, encoding)? {
+                    self.publish(stream, op, key.clone())?;
+                }
+                self.check_slow(stream, op, key.as_deref(), started);
+                return Ok(true);
+            }
+            Request::SetStream(key, len) => {
+                let mut value = stream::exact(reader, len);
+                let result = self.engine.set_from_reader(key.0, &mut value);
+                std::io::copy(&mut value, &mut std::io::sink())?; // 写失败了也要把value读完，不然连接里剩下的会被当成下一个请求
+                match result {
+                    Ok(_) => Response::Done(None),
+                    Err(e) => Response::failed(&e),
+                }
+            }
+            Request::Monitor { .. } | Request::Hello(_) | Request::Auth(_) => unreachable!(), // 上面已经处理过了
+        };
+        if let Some(entry) = audit {
+            if changed && !matches!(response, Response::Failed(..) | Response::Conflict(_)) {
+                self.audit(&entry); // 记下来了再告诉客户端成功了
+            }
+        }
+        if !matches!(response, Response::Failed(..)) {
+            self.publish(stream, op, key.clone())?;
+        }
+        encoding.write(stream, &response)?; // 发响应
+        self.check_slow(stream, op, key.as_deref(), started);
+        Ok(true)
+    }
+
+    /// 没设ACL的话什么都能做。`key` 是 `None` 的话要对所有key都有这个权限
+    fn allowed(&self, session: &Session, key: Option<&[u8]>, access: Access) -> bool {
+        let acl = match self.config.acl() {
+            Some(acl) => acl,
+            None => return true,
+        };
+        match (&session.user, key) {
+            (Some(user), Some(key)) => acl.access(user, key) >= access,
+            (Some(user), None) => acl.everywhere(user, access),
+            (None, _) => false,
+        }
+    }
+
+    /// 密码对的话这个连接以后就是这个用户了。不对的话之前登录的还算
+    fn login(&self, session: &mut Session, credentials: &Credentials) -> Response {
+        let accounts = match self.config.accounts() {
+            Some(accounts) => accounts,
+            None => {
+                return Response::Failed(
+                    "authentication is not enabled on this server".to_string(),
+                    ErrorKind::Unsupported.code(),
+                )
+            }
+        };
+        if accounts.verify(credentials) {
+            session.user = Some(credentials.user.clone());
+            Response::Done(None)
+        } else {
+            Response::Failed(
+                "invalid username or password".to_string(),
+                ErrorKind::PermissionDenied.code(),
+            )
+        }
+    }
+
+    /// 先回一个 `Stream` ，然后把value原样发过去。返回engine读成功了没有
+    fn send_stream(&mut self, stream: &mut Stream, key: &[u8], encoding: Encoding) -> Result<bool> {
+        let (response, reader) = match self.engine.get_reader(key) {
+            Ok(Some(reader)) => (Response::Stream(Some(reader.len())), Some(reader)),
+            Ok(None) => (Response::Stream(None), None),
+            Err(e) => (Response::failed(&e), None),
+        };
+        encoding.write(stream, &response)?;
+        if let Some(mut reader) = reader {
+            std::io::copy(&mut reader, stream)?;
+        }
+        Ok(!matches!(response, Response::Failed(..)))
+    }
+
+    /// 执行完了才发给订阅者，被拒绝了的、失败了的看不到
+    fn publish(&self, stream: &Stream, op: &str, key: Option<String>) -> Result<()> {
+        monitor::publish(self.subscribers.as_deref(), || MonitorEvent {
+            timestamp: now_millis(),
+            peer: stream.peer(),
+            op: op.to_string(),
+            key,
+        })
+    }
+
+    /// 设了TLS的话套上，握手在serve第一次读的时候做
+    fn accept(&self, stream: TcpStream) -> Result<Stream> {
+        #[cfg(feature = "tls")]
+        if let Some(config) = self.config.tls() {
+            return Ok(Stream::Tls(Arc::new(Mutex::new(tls::accept(
+                &config, stream,
+            )?))));
+        }
+        Ok(Stream::Tcp(stream))
+    }
+
+    /// 在某个ip:port上一直处理请求，直到 `shutdown_handle` 叫它停
+    pub fn run<U>(&mut self, address: U) -> Result<()>
+    where
+        U: ToSocketAddrs,
+    {
+        let listener = TcpListener::bind(address)?;
+        self.shutdown.listen(Listener::Tcp(listener.local_addr()?));
+        while !self.shutdown.is_shutting_down() {
+            self.wait_for_room();
+            match listener.accept() {
+                Ok(_) if self.shutdown.is_shutting_down() => break, // 是来叫醒的
+                Ok((stream, peer)) => match self.accept(stream) {
+                    Ok(stream) => self.dispatch(stream),
+                    Err(e) => warn!("{}: {}", peer, e),
+                },
+                Err(e) => error!("Failed to accept a connection: {}", e),
+            }
+        }
+        self.finish()
+    }
+
+    /// 和 `run` 一样，不过是在Unix socket上。客户端连的时候地址写 `unix:` 加上 `path` 。停了以后socket文件会删掉
+    #[cfg(unix)]
+    pub fn run_unix<U: AsRef<Path>>(&mut self, path: U) -> Result<()> {
+        self.plaintext()?;
+        let path = path.as_ref();
+        let listener = net::bind_unix(path)?;
+        self.shutdown.listen(Listener::Unix(path.to_path_buf()));
+        while !self.shutdown.is_shutting_down() {
+            self.wait_for_room();
+            match listener.accept() {
+                Ok(_) if self.shutdown.is_shutting_down() => break,
+                Ok((stream, _)) => self.dispatch(Stream::Unix(stream)),
+                Err(e) => error!("Failed to accept a connection: {}", e),
+            }
+        }
+        drop(listener);
+        let _ = std::fs::remove_file(path);
+        self.finish()
+    }
+
+    /// 交给线程池，连接关了以后 `finish` 才不用等它
+    fn dispatch(&self, mut stream: Stream) {
+        let peer = stream.peer();
+        let connection = match stream.socket() {
+            Ok(socket) => self.shutdown.register(socket),
+            Err(e) => {
+                warn!("{}: {}", peer, e);
+                return;
+            }
+        };
+        debug!("{} connected", peer);
+        let mut server = self.clone();
+        self.pool.spawn(move || {
+            let _connection = connection;
+            match server.serve(&mut stream) {
+                Ok(_) => debug!("{} disconnected", peer),
+                Err(e) => warn!("{} disconnected: {}", peer, e),
+            }
+        });
+    }
+
+    /// 不收连接了以后：等连接上正在做的请求做完，踢掉订阅者，落盘
+    fn finish(&self) -> Result<()> {
+        self.shutdown.wait();
+        if let Some(subscribers) = &self.subscribers {
+            subscribers.lock().unwrap().clear();
+        }
+        self.engine.flush()
+    }
+
+    /// 拿着它的线程可以让 `run` 停下来。clone出来的server和原来的是同一个
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
 }
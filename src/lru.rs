@@ -0,0 +1,110 @@
+use std::borrow::Borrow;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// 最简单的LRU，每个entry带一个递增的tick，用BTreeMap按tick排序，最小的tick就是最久没用过的
+///
+/// 不是O(1)的，是O(log n)，但是够用了
+#[derive(Debug)]
+pub(crate) struct Lru<K, V> {
+    capacity: usize,
+    tick: u64,
+    map: HashMap<K, (V, u64)>,
+    order: BTreeMap<u64, K>,
+}
+
+impl<K, V> Lru<K, V>
+where
+    K: Hash + Eq + Clone,
+{
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tick: 0,
+            map: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub(crate) fn contains<Q>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.map.contains_key(key)
+    }
+
+    /// 读的同时把entry挪到最新
+    pub(crate) fn get<Q>(&mut self, key: &Q) -> Option<&mut V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.tick += 1;
+        let tick = self.tick;
+        match self.map.get_mut(key) {
+            Some((value, old)) => {
+                let key = self.order.remove(old).unwrap(); // map和order永远是一致的
+                *old = tick;
+                self.order.insert(tick, key);
+                Some(value)
+            }
+            None => None,
+        }
+    }
+
+    /// 只看不动，不影响淘汰顺序
+    pub(crate) fn peek<Q>(&self, key: &Q) -> Option<&V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        self.map.get(key).map(|(value, _)| value)
+    }
+
+    /// 放进去，如果超过容量了就把最久没用过的踢出来还给调用者
+    pub(crate) fn insert(&mut self, key: K, value: V) -> Option<(K, V)> {
+        self.tick += 1;
+        if let Some((_, old)) = self.map.insert(key.clone(), (value, self.tick)) {
+            self.order.remove(&old);
+        }
+        self.order.insert(self.tick, key);
+
+        if self.map.len() > self.capacity {
+            self.pop_oldest()
+        } else {
+            None
+        }
+    }
+
+    pub(crate) fn remove<Q>(&mut self, key: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Hash + Eq + ?Sized,
+    {
+        match self.map.remove(key) {
+            Some((value, tick)) => {
+                self.order.remove(&tick);
+                Some(value)
+            }
+            None => None,
+        }
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.map.clear();
+        self.order.clear();
+    }
+
+    pub(crate) fn pop_oldest(&mut self) -> Option<(K, V)> {
+        let tick = *self.order.keys().next()?;
+        let key = self.order.remove(&tick).unwrap();
+        let (value, _) = self.map.remove(&key).unwrap();
+        Some((key, value))
+    }
+}
@@ -0,0 +1,304 @@
+use crate::batch;
+use crate::bytes;
+use crate::namespace;
+use crate::scan;
+use crate::txn;
+use crate::watch::Watchers;
+use crate::BatchOp;
+use crate::KeyFilter;
+use crate::KeyMeta;
+use crate::Keys;
+use crate::KvsEngine;
+use crate::KvsError;
+use crate::MergeOperator;
+use crate::Result;
+use crate::Scan;
+use crate::WatchEvent;
+use crate::Watcher;
+
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::time::Duration;
+
+/// 全放在内存里的engine，不碰磁盘，进程退出就全没了。测试、跑benchmark的基准、临时的缓存用
+///
+/// 本来一个HashMap就够了，但是范围扫描要按key排好序，所以还是用BTreeMap。所有操作都拿同一把锁，clone出来的是同一个map
+#[derive(Clone, Debug, Default)]
+pub struct MemEngine {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    map: BTreeMap<Vec<u8>, (Vec<u8>, KeyMeta)>,
+    watchers: Watchers,
+    merge: Option<MergeOperator>,
+}
+
+impl State {
+    /// 过期了的key碰到的时候再删
+    fn expire(&mut self, key: &[u8]) {
+        if self.map.get(key).is_some_and(|(_, meta)| meta.is_expired()) {
+            self.map.remove(key);
+            self.notify(key, None);
+        }
+    }
+
+    /// 和KvStore一样，有人在看才发
+    fn notify(&mut self, key: &[u8], value: Option<&[u8]>) {
+        if !self.watchers.watching(key) {
+            return;
+        }
+        self.watchers.publish(match value {
+            Some(value) => WatchEvent::Set {
+                key: key.to_vec(),
+                value: value.to_vec(),
+            },
+            None => WatchEvent::Remove { key: key.to_vec() },
+        });
+    }
+
+    fn put(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Option<Duration>) {
+        self.expire(&key[..]);
+        let mut meta = KeyMeta::next(self.map.get(&key[..]).map(|v| &v.1), value.len());
+        if let Some(ttl) = ttl {
+            meta = meta.with_ttl(ttl);
+        }
+        self.notify(&key[..], Some(&value[..]));
+        self.map.insert(key, (value, meta));
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Result<()> {
+        self.expire(key);
+        match self.map.remove(key) {
+            Some(_) => {
+                self.notify(key, None);
+                Ok(())
+            }
+            None => Err(KvsError::NotFound {
+                key: bytes::lossy(key),
+            }),
+        }
+    }
+}
+
+impl MemEngine {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_merge_operator(&mut self, operator: MergeOperator) {
+        self.state().merge = Some(operator);
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl KvsEngine for MemEngine {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        self.get_bytes(key.as_bytes())?
+            .map(|v| bytes::into_string(key.as_bytes(), v))
+            .transpose()
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let mut state = self.state();
+        state.expire(key);
+        Ok(state.map.get(key).map(|(value, _)| value.clone()))
+    }
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.state().put(key, value, None);
+        Ok(())
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.state().put(key, value, Some(ttl));
+        Ok(())
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        self.state().remove(key)
+    }
+
+    /// 本来就在内存里，直接叠上去
+    fn merge(&self, key: &[u8], operand: Vec<u8>) -> Result<()> {
+        let mut state = self.state();
+        let operator = state.merge.clone().ok_or(KvsError::NoMergeOperator)?;
+        state.expire(key);
+        let (existing, previous) = match state.map.get(key) {
+            Some((value, meta)) => (Some(&value[..]), Some(*meta)),
+            None => (None, None),
+        };
+        let value = operator.apply(key, existing, &operand[..]);
+        let meta = KeyMeta {
+            expires: previous.map_or(0, |v| v.expires),
+            ..KeyMeta::next(previous.as_ref(), value.len())
+        };
+        state.notify(key, Some(&value[..]));
+        state.map.insert(key.to_vec(), (value, meta));
+        Ok(())
+    }
+
+    /// 整个挪过去，元数据原样保留
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let mut state = self.state();
+        state.expire(from.as_bytes());
+        match state.map.remove(from.as_bytes()) {
+            Some(entry) => {
+                state.notify(from.as_bytes(), None);
+                state.notify(to.as_bytes(), Some(&entry.0[..]));
+                state.map.insert(to.as_bytes().to_vec(), entry);
+                Ok(())
+            }
+            None => Err(KvsError::NotFound {
+                key: from.to_string(),
+            }),
+        }
+    }
+
+    /// 拿着锁比较再写
+    fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: Option<&str>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let mut state = self.state();
+        state.expire(key.as_bytes());
+        let current = state.map.get(key.as_bytes()).map(|(value, _)| &value[..]);
+        if current != expected.map(|v| v.as_bytes()) {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => state.put(key.as_bytes().to_vec(), value.into_bytes(), None),
+            None if expected.is_some() => state.remove(key.as_bytes())?,
+            None => {}
+        }
+        Ok(true)
+    }
+
+    /// 拿着锁对value再一个一个写，中间插不进别人的写，也不会写到一半
+    fn write_batch_if(
+        &self,
+        expected: &BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+        ops: Vec<BatchOp>,
+    ) -> Result<()> {
+        let mut state = self.state();
+        txn::check(expected, |key| {
+            state.expire(key);
+            Ok(state.map.get(key).map(|(value, _)| value.clone()))
+        })?;
+        batch::validate(&ops[..], |key| {
+            state.expire(key);
+            Ok(state.map.contains_key(key))
+        })?;
+        for op in ops {
+            match op {
+                BatchOp::Set(key, value) => state.put(key, value, None),
+                BatchOp::Remove(key) => state.remove(&key[..])?,
+            }
+        }
+        Ok(())
+    }
+
+    fn append(&self, key: &str, suffix: &str) -> Result<usize> {
+        let mut state = self.state();
+        state.expire(key.as_bytes());
+        let mut value = match state.map.get(key.as_bytes()) {
+            Some((value, _)) => value.clone(),
+            None => vec![],
+        };
+        value.extend_from_slice(suffix.as_bytes());
+        let len = value.len();
+        state.put(key.as_bytes().to_vec(), value, None);
+        Ok(len)
+    }
+
+    /// namespace里的留下
+    fn clear(&self) -> Result<()> {
+        let mut state = self.state();
+        let (namespaced, removed): (BTreeMap<_, _>, BTreeMap<_, _>) =
+            std::mem::take(&mut state.map)
+                .into_iter()
+                .partition(|(key, _)| namespace::is_namespaced(key));
+        state.map = namespaced;
+        for (key, _) in removed {
+            state.notify(&key[..], None);
+        }
+        Ok(())
+    }
+
+    fn metadata(&self, key: &str) -> Result<Option<KeyMeta>> {
+        let mut state = self.state();
+        state.expire(key.as_bytes());
+        Ok(state.map.get(key.as_bytes()).map(|(_, meta)| *meta))
+    }
+
+    fn scan_matching(&self, filter: &KeyFilter) -> Result<Vec<(String, String)>> {
+        let matcher = filter.compile()?;
+        let mut pairs = vec![];
+        for (key, (value, meta)) in &self.state().map {
+            let key = match std::str::from_utf8(key) {
+                Ok(key) => key,
+                Err(_) => continue, // 不是utf8的key没法匹配
+            };
+            if matcher.matches(key)
+                && !namespace::is_namespaced(key.as_bytes())
+                && !meta.is_expired()
+            {
+                let value = bytes::as_str(key.as_bytes(), value)?;
+                pairs.push((key.to_string(), value.to_string()));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// 锁不能借给迭代器，只好一次全拷出来
+    fn scan_bounds(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Result<Scan<'_>> {
+        if scan::is_empty(start, end) {
+            return Ok(Box::new(std::iter::empty()));
+        }
+        let pairs: Vec<_> = self
+            .state()
+            .map
+            .range::<[u8], _>((start, end))
+            .filter(|(_, (_, meta))| !meta.is_expired())
+            .map(|(key, (value, _))| Ok((key.clone(), value.clone())))
+            .collect();
+        Ok(Box::new(pairs.into_iter()))
+    }
+
+    fn watch(&self, prefix: &[u8]) -> Result<Watcher> {
+        Ok(self.state().watchers.add(prefix))
+    }
+
+    fn keys(&self) -> Result<Keys<'_>> {
+        let keys: Vec<_> = self
+            .state()
+            .map
+            .iter()
+            .filter(|(key, (_, meta))| !namespace::is_namespaced(key) && !meta.is_expired())
+            .map(|(key, _)| Ok(key.clone()))
+            .collect();
+        Ok(Box::new(keys.into_iter()))
+    }
+
+    fn len(&self) -> Result<usize> {
+        Ok(self
+            .state()
+            .map
+            .iter()
+            .filter(|(key, (_, meta))| !namespace::is_namespaced(key) && !meta.is_expired())
+            .count())
+    }
+
+    fn engine_name(&self) -> &'static str {
+        "memory"
+    }
+}
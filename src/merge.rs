@@ -0,0 +1,68 @@
+//! merge：写的时候只记一个操作数，读或者compact的时候再用用户给的函数叠到value上，和RocksDB的merge operator一样
+//!
+//! 计数器、集合这种value，每次改一点点，用不着先读出来改完再整个写回去
+
+use crate::KvsError;
+use crate::Result;
+
+use std::fmt::Debug;
+use std::sync::Arc;
+
+type MergeFn = dyn Fn(&[u8], Option<&[u8]>, &[u8]) -> Vec<u8> + Send + Sync;
+
+/// 怎么把一个操作数叠到value上。参数是key、现在的value（没有的话是 `None` ）、操作数，返回叠完的value
+///
+/// 叠好几个操作数的时候是一个一个叠的，叠的时机不固定（可能是merge的时候、读的时候、compact的时候），所以函数不能有别的副作用
+#[derive(Clone)]
+pub struct MergeOperator(Arc<MergeFn>);
+
+impl MergeOperator {
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(&[u8], Option<&[u8]>, &[u8]) -> Vec<u8> + Send + Sync + 'static,
+    {
+        Self(Arc::new(f))
+    }
+
+    /// 十进制整数的计数器，操作数是要加上去的数（可以是负的）。value或者操作数不是整数的话当成0
+    pub fn counter() -> Self {
+        Self::new(|_, existing, operand| {
+            let parse = |v: &[u8]| {
+                std::str::from_utf8(v)
+                    .ok()
+                    .and_then(|v| v.trim().parse::<i64>().ok())
+                    .unwrap_or(0)
+            };
+            let sum = existing.map_or(0, parse).wrapping_add(parse(operand));
+            sum.to_string().into_bytes()
+        })
+    }
+
+    pub(crate) fn apply(&self, key: &[u8], existing: Option<&[u8]>, operand: &[u8]) -> Vec<u8> {
+        (self.0)(key, existing, operand)
+    }
+}
+
+impl Debug for MergeOperator {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "MergeOperator")
+    }
+}
+
+/// 把 `operands` 按顺序叠到 `base` 上。没有操作数的话原样返回，有操作数但是没设置operator的话报错
+pub(crate) fn fold(
+    operator: Option<&MergeOperator>,
+    key: &[u8],
+    base: Option<Vec<u8>>,
+    operands: &[Vec<u8>],
+) -> Result<Option<Vec<u8>>> {
+    if operands.is_empty() {
+        return Ok(base);
+    }
+    let operator = operator.ok_or(KvsError::NoMergeOperator)?;
+    let mut value = base;
+    for operand in operands {
+        value = Some(operator.apply(key, value.as_deref(), &operand[..]));
+    }
+    Ok(value)
+}
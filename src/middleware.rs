@@ -0,0 +1,1832 @@
+use crate::bytes;
+use crate::AsyncAdapter;
+use crate::BatchOp;
+use crate::CacheConfig;
+use crate::CachedEngine;
+use crate::Dump;
+use crate::IndexedEngine;
+use crate::Iter;
+use crate::KeyFilter;
+use crate::KeyMeta;
+use crate::Keys;
+use crate::KvsEngine;
+use crate::KvsError;
+use crate::Op;
+use crate::OpResult;
+use crate::Result;
+use crate::Scan;
+use crate::StoreStats;
+use crate::ValueReader;
+use crate::Watcher;
+use crate::BULK_BATCH;
+
+use log::info;
+use log::warn;
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::ops::Bound;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::time::Duration;
+use std::time::Instant;
+
+/// 一层一层往engine外面套，比如 `store.read_only().metered()`
+///
+/// 本来想叫Layer的，但是tower里面Layer是另一个意思
+pub trait EngineExt: KvsEngine + Clone {
+    fn cached(self, config: CacheConfig) -> CachedEngine<Self> {
+        CachedEngine::new(self, config)
+    }
+
+    fn metered(self) -> MeteredEngine<Self> {
+        MeteredEngine::new(self)
+    }
+
+    fn logged(self, name: &str) -> LoggingEngine<Self> {
+        LoggingEngine::new(self, name)
+    }
+
+    fn read_only(self) -> ReadOnlyEngine<Self> {
+        ReadOnlyEngine::new(self)
+    }
+
+    fn size_limited(self, limits: SizeLimits) -> SizeLimitedEngine<Self> {
+        SizeLimitedEngine::new(self, limits)
+    }
+
+    fn with_faults(self, faults: Faults) -> FaultInjectingEngine<Self> {
+        FaultInjectingEngine::new(self, faults)
+    }
+
+    /// 索引要自己 `add_index`
+    fn indexed(self) -> IndexedEngine<Self> {
+        IndexedEngine::new(self)
+    }
+
+    /// 变成 `AsyncKvsEngine` ，同步的操作在 `threads` 个线程上跑
+    fn into_async(self, threads: usize) -> AsyncAdapter<Self> {
+        AsyncAdapter::new(self, threads)
+    }
+
+    /// 擦掉具体类型，套了不同几层的engine都变成同一个类型
+    fn boxed(self) -> Box<dyn KvsEngine> {
+        Box::new(self)
+    }
+}
+
+impl<E: KvsEngine + Clone> EngineExt for E {}
+
+/// 各种操作的计数和累计耗时
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Metrics {
+    pub gets: u64,
+    pub sets: u64,
+    pub removes: u64,
+    /// get到了value的次数
+    pub hits: u64,
+    /// get到了None的次数
+    pub misses: u64,
+    pub errors: u64,
+    pub elapsed: Duration,
+}
+
+/// clone出来的共用一份计数
+#[derive(Clone)]
+pub struct MeteredEngine<E> {
+    inner: E,
+    metrics: Arc<Mutex<Metrics>>,
+}
+
+impl<E: KvsEngine> MeteredEngine<E> {
+    pub fn new(inner: E) -> Self {
+        Self {
+            inner,
+            metrics: Arc::new(Mutex::new(Metrics::default())),
+        }
+    }
+
+    /// 到现在为止的计数，拷一份出来
+    pub fn metrics(&self) -> Metrics {
+        self.metrics.lock().unwrap().clone()
+    }
+
+    fn record<T>(&self, start: Instant, result: &Result<T>) {
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.elapsed += start.elapsed();
+        if result.is_err() {
+            metrics.errors += 1;
+        }
+    }
+
+    pub fn inner(&self) -> &E {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> E {
+        self.inner
+    }
+}
+
+impl<E: KvsEngine + Clone> KvsEngine for MeteredEngine<E> {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        let start = Instant::now();
+        let result = self.inner.get(key);
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.gets += 1;
+        metrics.elapsed += start.elapsed();
+        match &result {
+            Ok(Some(_)) => metrics.hits += 1,
+            Ok(None) => metrics.misses += 1,
+            Err(_) => metrics.errors += 1,
+        }
+        result
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let start = Instant::now();
+        let result = self.inner.get_bytes(key);
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.gets += 1;
+        metrics.elapsed += start.elapsed();
+        match &result {
+            Ok(Some(_)) => metrics.hits += 1,
+            Ok(None) => metrics.misses += 1,
+            Err(_) => metrics.errors += 1,
+        }
+        result
+    }
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.set_bytes(key, value);
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.sets += 1;
+        metrics.elapsed += start.elapsed();
+        if result.is_err() {
+            metrics.errors += 1;
+        }
+        result
+    }
+
+    /// 只算拿到reader花的时间，之后读value花的时间算不进来
+    fn get_reader(&self, key: &[u8]) -> Result<Option<ValueReader<'_>>> {
+        let start = Instant::now();
+        let result = self.inner.get_reader(key);
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.gets += 1;
+        metrics.elapsed += start.elapsed();
+        match &result {
+            Ok(Some(_)) => metrics.hits += 1,
+            Ok(None) => metrics.misses += 1,
+            Err(_) => metrics.errors += 1,
+        }
+        result
+    }
+
+    fn set_from_reader(&self, key: Vec<u8>, reader: &mut dyn Read) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.set_from_reader(key, reader);
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.sets += 1;
+        metrics.elapsed += start.elapsed();
+        if result.is_err() {
+            metrics.errors += 1;
+        }
+        result
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.set_bytes_with_ttl(key, value, ttl);
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.sets += 1;
+        metrics.elapsed += start.elapsed();
+        if result.is_err() {
+            metrics.errors += 1;
+        }
+        result
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.remove_bytes(key);
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.removes += 1;
+        metrics.elapsed += start.elapsed();
+        if result.is_err() {
+            metrics.errors += 1;
+        }
+        result
+    }
+
+    fn write_batch(&self, ops: Vec<BatchOp>) -> Result<()> {
+        let start = Instant::now();
+        let sets = ops.iter().filter(|v| matches!(v, BatchOp::Set(..))).count() as u64;
+        let removes = ops.len() as u64 - sets;
+        let result = self.inner.write_batch(ops);
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.elapsed += start.elapsed();
+        match &result {
+            Ok(_) => {
+                metrics.sets += sets;
+                metrics.removes += removes;
+            }
+            Err(_) => metrics.errors += 1,
+        }
+        result
+    }
+
+    fn write_batch_if(
+        &self,
+        expected: &BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+        ops: Vec<BatchOp>,
+    ) -> Result<()> {
+        let start = Instant::now();
+        let sets = ops.iter().filter(|v| matches!(v, BatchOp::Set(..))).count() as u64;
+        let removes = ops.len() as u64 - sets;
+        let result = self.inner.write_batch_if(expected, ops);
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.elapsed += start.elapsed();
+        match &result {
+            Ok(_) => {
+                metrics.sets += sets;
+                metrics.removes += removes;
+            }
+            Err(_) => metrics.errors += 1,
+        }
+        result
+    }
+
+    fn clear(&self) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.clear();
+        self.record(start, &result);
+        result
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.rename(from, to);
+        self.record(start, &result);
+        result
+    }
+
+    fn rename_nx(&self, from: &str, to: &str) -> Result<bool> {
+        let start = Instant::now();
+        let result = self.inner.rename_nx(from, to);
+        self.record(start, &result);
+        result
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: Option<&str>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let start = Instant::now();
+        let result = self.inner.compare_and_swap(key, expected, new);
+        self.record(start, &result);
+        result
+    }
+
+    fn copy(&self, from: &str, to: &str) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.copy(from, to);
+        self.record(start, &result);
+        result
+    }
+
+    fn merge(&self, key: &[u8], operand: Vec<u8>) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.merge(key, operand);
+        self.record(start, &result);
+        result
+    }
+
+    fn apply(&self, ops: &[Op]) -> Result<Vec<OpResult>> {
+        let start = Instant::now();
+        let result = self.inner.apply(ops);
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.elapsed += start.elapsed();
+        match &result {
+            Ok(_) => {
+                for op in ops {
+                    match op {
+                        Op::Get(_) => metrics.gets += 1,
+                        Op::Set(..) => metrics.sets += 1,
+                        Op::Remove(_) => metrics.removes += 1,
+                    }
+                }
+            }
+            Err(_) => metrics.errors += 1,
+        }
+        result
+    }
+
+    fn getdel(&self, key: &str) -> Result<Option<String>> {
+        let start = Instant::now();
+        let result = self.inner.getdel(key);
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.removes += 1;
+        metrics.elapsed += start.elapsed();
+        if result.is_err() {
+            metrics.errors += 1;
+        }
+        result
+    }
+
+    fn getset(&self, key: String, value: String) -> Result<Option<String>> {
+        let start = Instant::now();
+        let result = self.inner.getset(key, value);
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.sets += 1;
+        metrics.elapsed += start.elapsed();
+        if result.is_err() {
+            metrics.errors += 1;
+        }
+        result
+    }
+
+    fn set_nx(&self, key: String, value: String) -> Result<bool> {
+        let start = Instant::now();
+        let result = self.inner.set_nx(key, value);
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.sets += 1;
+        metrics.elapsed += start.elapsed();
+        if result.is_err() {
+            metrics.errors += 1;
+        }
+        result
+    }
+
+    fn append(&self, key: &str, suffix: &str) -> Result<usize> {
+        let start = Instant::now();
+        let result = self.inner.append(key, suffix);
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.sets += 1;
+        metrics.elapsed += start.elapsed();
+        if result.is_err() {
+            metrics.errors += 1;
+        }
+        result
+    }
+
+    fn restore(&self, dump: Dump, replace: bool) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.restore(dump, replace);
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.sets += 1;
+        metrics.elapsed += start.elapsed();
+        if result.is_err() {
+            metrics.errors += 1;
+        }
+        result
+    }
+
+    fn remove_matching(&self, filter: &KeyFilter) -> Result<u64> {
+        let start = Instant::now();
+        let result = self.inner.remove_matching(filter);
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.elapsed += start.elapsed();
+        match &result {
+            Ok(count) => metrics.removes += count,
+            Err(_) => metrics.errors += 1,
+        }
+        result
+    }
+
+    fn flush(&self) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.flush();
+        self.record(start, &result);
+        result
+    }
+
+    fn engine_name(&self) -> &'static str {
+        self.inner.engine_name()
+    }
+
+    fn stats(&self) -> Result<StoreStats> {
+        self.inner.stats()
+    }
+
+    fn metadata(&self, key: &str) -> Result<Option<KeyMeta>> {
+        let start = Instant::now();
+        let result = self.inner.metadata(key);
+        self.record(start, &result);
+        result
+    }
+
+    fn multi_get(&self, keys: &[&str]) -> Result<Vec<Option<String>>> {
+        let start = Instant::now();
+        let result = self.inner.multi_get(keys);
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.gets += keys.len() as u64;
+        metrics.elapsed += start.elapsed();
+        match &result {
+            Ok(values) => {
+                let hits = values.iter().filter(|v| v.is_some()).count() as u64;
+                metrics.hits += hits;
+                metrics.misses += values.len() as u64 - hits;
+            }
+            Err(_) => metrics.errors += 1,
+        }
+        result
+    }
+
+    fn ttl(&self, key: &str) -> Result<Option<Duration>> {
+        let start = Instant::now();
+        let result = self.inner.ttl(key);
+        self.record(start, &result);
+        result
+    }
+
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        let start = Instant::now();
+        let result = self.inner.contains_key(key);
+        self.record(start, &result);
+        result
+    }
+
+    fn dump(&self, key: &str) -> Result<Option<Dump>> {
+        let start = Instant::now();
+        let result = self.inner.dump(key);
+        self.record(start, &result);
+        result
+    }
+
+    fn scan_matching(&self, filter: &KeyFilter) -> Result<Vec<(String, String)>> {
+        let start = Instant::now();
+        let result = self.inner.scan_matching(filter);
+        self.record(start, &result);
+        result
+    }
+
+    /// 只算上拿到迭代器的时间，之后一个一个读的时间算不到
+    fn scan_bounds(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Result<Scan<'_>> {
+        let now = Instant::now();
+        let result = self.inner.scan_bounds(start, end);
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.elapsed += now.elapsed();
+        if result.is_err() {
+            metrics.errors += 1;
+        }
+        result
+    }
+
+    fn watch(&self, prefix: &[u8]) -> Result<Watcher> {
+        let start = Instant::now();
+        let result = self.inner.watch(prefix);
+        self.record(start, &result);
+        result
+    }
+
+    fn keys(&self) -> Result<Keys<'_>> {
+        let now = Instant::now();
+        let result = self.inner.keys();
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.elapsed += now.elapsed();
+        if result.is_err() {
+            metrics.errors += 1;
+        }
+        result
+    }
+
+    fn len(&self) -> Result<usize> {
+        let start = Instant::now();
+        let result = self.inner.len();
+        self.record(start, &result);
+        result
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Scan<'_>> {
+        let now = Instant::now();
+        let result = self.inner.scan_prefix(prefix);
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.elapsed += now.elapsed();
+        if result.is_err() {
+            metrics.errors += 1;
+        }
+        result
+    }
+
+    fn iter(&self) -> Result<Iter<'_>> {
+        let now = Instant::now();
+        let result = self.inner.iter();
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.elapsed += now.elapsed();
+        if result.is_err() {
+            metrics.errors += 1;
+        }
+        result
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        let start = Instant::now();
+        let result = self.inner.is_empty();
+        self.record(start, &result);
+        result
+    }
+
+    fn bulk_load_iter(&self, pairs: &mut dyn Iterator<Item = (String, String)>) -> Result<usize> {
+        let start = Instant::now();
+        let result = self.inner.bulk_load(pairs);
+        let mut metrics = self.metrics.lock().unwrap();
+        metrics.elapsed += start.elapsed();
+        match &result {
+            Ok(count) => metrics.sets += *count as u64,
+            Err(_) => metrics.errors += 1,
+        }
+        result
+    }
+}
+
+/// 每个操作打一行日志，带上耗时和结果。成功的是info，失败的是warn
+#[derive(Clone)]
+pub struct LoggingEngine<E> {
+    inner: E,
+    name: String,
+}
+
+impl<E: KvsEngine> LoggingEngine<E> {
+    pub fn new(inner: E, name: &str) -> Self {
+        Self {
+            inner,
+            name: name.to_string(),
+        }
+    }
+
+    pub fn into_inner(self) -> E {
+        self.inner
+    }
+}
+
+impl<E: KvsEngine + Clone> KvsEngine for LoggingEngine<E> {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        let start = Instant::now();
+        let result = self.inner.get(key);
+        match &result {
+            Ok(Some(_)) => info!("[{}] get {} hit {:?}", self.name, key, start.elapsed()),
+            Ok(None) => info!("[{}] get {} miss {:?}", self.name, key, start.elapsed()),
+            Err(e) => warn!("[{}] get {} failed: {}", self.name, key, e),
+        }
+        result
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let start = Instant::now();
+        let result = self.inner.get_bytes(key);
+        let key = bytes::lossy(key);
+        match &result {
+            Ok(Some(_)) => info!("[{}] get {} hit {:?}", self.name, key, start.elapsed()),
+            Ok(None) => info!("[{}] get {} miss {:?}", self.name, key, start.elapsed()),
+            Err(e) => warn!("[{}] get {} failed: {}", self.name, key, e),
+        }
+        result
+    }
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let start = Instant::now();
+        let description = format!("set {} ({} bytes)", bytes::lossy(&key), value.len()); // key要被move走了，先记下来
+        let result = self.inner.set_bytes(key, value);
+        match &result {
+            Ok(_) => info!("[{}] {} {:?}", self.name, description, start.elapsed()),
+            Err(e) => warn!("[{}] {} failed: {}", self.name, description, e),
+        }
+        result
+    }
+
+    fn get_reader(&self, key: &[u8]) -> Result<Option<ValueReader<'_>>> {
+        let start = Instant::now();
+        let name = &self.name;
+        let result = self.inner.get_reader(key);
+        let key = bytes::lossy(key);
+        match &result {
+            Ok(Some(reader)) => info!(
+                "[{}] get {} streaming ({} bytes) {:?}",
+                name,
+                key,
+                reader.len(),
+                start.elapsed()
+            ),
+            Ok(None) => info!("[{}] get {} miss {:?}", name, key, start.elapsed()),
+            Err(e) => warn!("[{}] get {} failed: {}", name, key, e),
+        }
+        result
+    }
+
+    fn set_from_reader(&self, key: Vec<u8>, reader: &mut dyn Read) -> Result<()> {
+        let start = Instant::now();
+        let description = format!("set {} (streaming)", bytes::lossy(&key)); // 多长要读完才知道
+        let result = self.inner.set_from_reader(key, reader);
+        match &result {
+            Ok(_) => info!("[{}] {} {:?}", self.name, description, start.elapsed()),
+            Err(e) => warn!("[{}] {} failed: {}", self.name, description, e),
+        }
+        result
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let start = Instant::now();
+        let description = format!(
+            "set {} ({} bytes, ttl {:?})",
+            bytes::lossy(&key),
+            value.len(),
+            ttl
+        );
+        let result = self.inner.set_bytes_with_ttl(key, value, ttl);
+        match &result {
+            Ok(_) => info!("[{}] {} {:?}", self.name, description, start.elapsed()),
+            Err(e) => warn!("[{}] {} failed: {}", self.name, description, e),
+        }
+        result
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.remove_bytes(key);
+        let key = bytes::lossy(key);
+        match &result {
+            Ok(_) => info!("[{}] remove {} {:?}", self.name, key, start.elapsed()),
+            Err(e) => warn!("[{}] remove {} failed: {}", self.name, key, e),
+        }
+        result
+    }
+
+    fn write_batch(&self, ops: Vec<BatchOp>) -> Result<()> {
+        let start = Instant::now();
+        let count = ops.len();
+        let result = self.inner.write_batch(ops);
+        match &result {
+            Ok(_) => info!("[{}] batch {} {:?}", self.name, count, start.elapsed()),
+            Err(e) => warn!("[{}] batch {} failed: {}", self.name, count, e),
+        }
+        result
+    }
+
+    fn write_batch_if(
+        &self,
+        expected: &BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+        ops: Vec<BatchOp>,
+    ) -> Result<()> {
+        let start = Instant::now();
+        let count = ops.len();
+        let result = self.inner.write_batch_if(expected, ops);
+        match &result {
+            Ok(_) => info!(
+                "[{}] batch {} if {} unchanged {:?}",
+                self.name,
+                count,
+                expected.len(),
+                start.elapsed()
+            ),
+            Err(e) => warn!("[{}] batch {} failed: {}", self.name, count, e),
+        }
+        result
+    }
+
+    fn clear(&self) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.clear();
+        match &result {
+            Ok(_) => info!("[{}] clear {:?}", self.name, start.elapsed()),
+            Err(e) => warn!("[{}] clear failed: {}", self.name, e),
+        }
+        result
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.rename(from, to);
+        match &result {
+            Ok(_) => info!(
+                "[{}] rename {} {} {:?}",
+                self.name,
+                from,
+                to,
+                start.elapsed()
+            ),
+            Err(e) => warn!("[{}] rename {} {} failed: {}", self.name, from, to, e),
+        }
+        result
+    }
+
+    fn rename_nx(&self, from: &str, to: &str) -> Result<bool> {
+        let start = Instant::now();
+        let result = self.inner.rename_nx(from, to);
+        match &result {
+            Ok(renamed) => info!(
+                "[{}] rename_nx {} {} {} {:?}",
+                self.name,
+                from,
+                to,
+                renamed,
+                start.elapsed()
+            ),
+            Err(e) => warn!("[{}] rename_nx {} {} failed: {}", self.name, from, to, e),
+        }
+        result
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: Option<&str>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let start = Instant::now();
+        let result = self.inner.compare_and_swap(key, expected, new);
+        match &result {
+            Ok(swapped) => info!(
+                "[{}] compare_and_swap {} {} {:?}",
+                self.name,
+                key,
+                swapped,
+                start.elapsed()
+            ),
+            Err(e) => warn!("[{}] compare_and_swap {} failed: {}", self.name, key, e),
+        }
+        result
+    }
+
+    fn copy(&self, from: &str, to: &str) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.copy(from, to);
+        match &result {
+            Ok(_) => info!("[{}] copy {} {} {:?}", self.name, from, to, start.elapsed()),
+            Err(e) => warn!("[{}] copy {} {} failed: {}", self.name, from, to, e),
+        }
+        result
+    }
+
+    fn merge(&self, key: &[u8], operand: Vec<u8>) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.merge(key, operand);
+        let key = bytes::lossy(key);
+        match &result {
+            Ok(_) => info!("[{}] merge {} {:?}", self.name, key, start.elapsed()),
+            Err(e) => warn!("[{}] merge {} failed: {}", self.name, key, e),
+        }
+        result
+    }
+
+    fn apply(&self, ops: &[Op]) -> Result<Vec<OpResult>> {
+        let start = Instant::now();
+        let result = self.inner.apply(ops);
+        match &result {
+            Ok(_) => info!("[{}] apply {} {:?}", self.name, ops.len(), start.elapsed()),
+            Err(e) => warn!("[{}] apply {} failed: {}", self.name, ops.len(), e),
+        }
+        result
+    }
+
+    fn getdel(&self, key: &str) -> Result<Option<String>> {
+        let start = Instant::now();
+        let result = self.inner.getdel(key);
+        match &result {
+            Ok(Some(_)) => info!("[{}] getdel {} hit {:?}", self.name, key, start.elapsed()),
+            Ok(None) => info!("[{}] getdel {} miss {:?}", self.name, key, start.elapsed()),
+            Err(e) => warn!("[{}] getdel {} failed: {}", self.name, key, e),
+        }
+        result
+    }
+
+    fn getset(&self, key: String, value: String) -> Result<Option<String>> {
+        let start = Instant::now();
+        let description = format!("getset {} ({} bytes)", key, value.len());
+        let result = self.inner.getset(key, value);
+        match &result {
+            Ok(_) => info!("[{}] {} {:?}", self.name, description, start.elapsed()),
+            Err(e) => warn!("[{}] {} failed: {}", self.name, description, e),
+        }
+        result
+    }
+
+    fn set_nx(&self, key: String, value: String) -> Result<bool> {
+        let start = Instant::now();
+        let description = format!("set_nx {} ({} bytes)", key, value.len());
+        let result = self.inner.set_nx(key, value);
+        match &result {
+            Ok(set) => info!(
+                "[{}] {} {} {:?}",
+                self.name,
+                description,
+                set,
+                start.elapsed()
+            ),
+            Err(e) => warn!("[{}] {} failed: {}", self.name, description, e),
+        }
+        result
+    }
+
+    fn append(&self, key: &str, suffix: &str) -> Result<usize> {
+        let start = Instant::now();
+        let result = self.inner.append(key, suffix);
+        match &result {
+            Ok(len) => info!(
+                "[{}] append {} ({} bytes) {} {:?}",
+                self.name,
+                key,
+                suffix.len(),
+                len,
+                start.elapsed()
+            ),
+            Err(e) => warn!("[{}] append {} failed: {}", self.name, key, e),
+        }
+        result
+    }
+
+    fn restore(&self, dump: Dump, replace: bool) -> Result<()> {
+        let start = Instant::now();
+        let key = dump.key.clone();
+        let result = self.inner.restore(dump, replace);
+        match &result {
+            Ok(_) => info!("[{}] restore {} {:?}", self.name, key, start.elapsed()),
+            Err(e) => warn!("[{}] restore {} failed: {}", self.name, key, e),
+        }
+        result
+    }
+
+    fn remove_matching(&self, filter: &KeyFilter) -> Result<u64> {
+        let start = Instant::now();
+        let result = self.inner.remove_matching(filter);
+        match &result {
+            Ok(count) => info!(
+                "[{}] remove {:?} {} {:?}",
+                self.name,
+                filter,
+                count,
+                start.elapsed()
+            ),
+            Err(e) => warn!("[{}] remove {:?} failed: {}", self.name, filter, e),
+        }
+        result
+    }
+
+    fn flush(&self) -> Result<()> {
+        let start = Instant::now();
+        let result = self.inner.flush();
+        match &result {
+            Ok(_) => info!("[{}] flush {:?}", self.name, start.elapsed()),
+            Err(e) => warn!("[{}] flush failed: {}", self.name, e),
+        }
+        result
+    }
+
+    fn engine_name(&self) -> &'static str {
+        self.inner.engine_name()
+    }
+
+    fn stats(&self) -> Result<StoreStats> {
+        self.inner.stats()
+    }
+
+    fn metadata(&self, key: &str) -> Result<Option<KeyMeta>> {
+        let result = self.inner.metadata(key);
+        if let Err(e) = &result {
+            warn!("[{}] metadata {} failed: {}", self.name, key, e);
+        }
+        result
+    }
+
+    fn multi_get(&self, keys: &[&str]) -> Result<Vec<Option<String>>> {
+        let start = Instant::now();
+        let result = self.inner.multi_get(keys);
+        match &result {
+            Ok(values) => info!(
+                "[{}] multi_get {} hit {} {:?}",
+                self.name,
+                keys.len(),
+                values.iter().filter(|v| v.is_some()).count(),
+                start.elapsed()
+            ),
+            Err(e) => warn!("[{}] multi_get {} failed: {}", self.name, keys.len(), e),
+        }
+        result
+    }
+
+    fn ttl(&self, key: &str) -> Result<Option<Duration>> {
+        let result = self.inner.ttl(key);
+        if let Err(e) = &result {
+            warn!("[{}] ttl {} failed: {}", self.name, key, e);
+        }
+        result
+    }
+
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        let start = Instant::now();
+        let result = self.inner.contains_key(key);
+        match &result {
+            Ok(found) => info!(
+                "[{}] contains {} {} {:?}",
+                self.name,
+                key,
+                found,
+                start.elapsed()
+            ),
+            Err(e) => warn!("[{}] contains {} failed: {}", self.name, key, e),
+        }
+        result
+    }
+
+    fn dump(&self, key: &str) -> Result<Option<Dump>> {
+        let start = Instant::now();
+        let result = self.inner.dump(key);
+        match &result {
+            Ok(Some(_)) => info!("[{}] dump {} hit {:?}", self.name, key, start.elapsed()),
+            Ok(None) => info!("[{}] dump {} miss {:?}", self.name, key, start.elapsed()),
+            Err(e) => warn!("[{}] dump {} failed: {}", self.name, key, e),
+        }
+        result
+    }
+
+    fn scan_matching(&self, filter: &KeyFilter) -> Result<Vec<(String, String)>> {
+        let start = Instant::now();
+        let result = self.inner.scan_matching(filter);
+        match &result {
+            Ok(pairs) => info!(
+                "[{}] scan {:?} {} {:?}",
+                self.name,
+                filter,
+                pairs.len(),
+                start.elapsed()
+            ),
+            Err(e) => warn!("[{}] scan {:?} failed: {}", self.name, filter, e),
+        }
+        result
+    }
+
+    fn scan_bounds(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Result<Scan<'_>> {
+        let description = format!(
+            "scan {:?}..{:?}",
+            start.map(bytes::lossy),
+            end.map(bytes::lossy)
+        );
+        let result = self.inner.scan_bounds(start, end);
+        match &result {
+            Ok(_) => info!("[{}] {}", self.name, description),
+            Err(e) => warn!("[{}] {} failed: {}", self.name, description, e),
+        }
+        result
+    }
+
+    fn watch(&self, prefix: &[u8]) -> Result<Watcher> {
+        let result = self.inner.watch(prefix);
+        match &result {
+            Ok(_) => info!("[{}] watch {}", self.name, bytes::lossy(prefix)),
+            Err(e) => warn!(
+                "[{}] watch {} failed: {}",
+                self.name,
+                bytes::lossy(prefix),
+                e
+            ),
+        }
+        result
+    }
+
+    fn keys(&self) -> Result<Keys<'_>> {
+        let result = self.inner.keys();
+        match &result {
+            Ok(_) => info!("[{}] keys", self.name),
+            Err(e) => warn!("[{}] keys failed: {}", self.name, e),
+        }
+        result
+    }
+
+    fn len(&self) -> Result<usize> {
+        let result = self.inner.len();
+        match &result {
+            Ok(len) => info!("[{}] len {}", self.name, len),
+            Err(e) => warn!("[{}] len failed: {}", self.name, e),
+        }
+        result
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Scan<'_>> {
+        let result = self.inner.scan_prefix(prefix);
+        let prefix = bytes::lossy(prefix);
+        match &result {
+            Ok(_) => info!("[{}] scan {}*", self.name, prefix),
+            Err(e) => warn!("[{}] scan {}* failed: {}", self.name, prefix, e),
+        }
+        result
+    }
+
+    fn iter(&self) -> Result<Iter<'_>> {
+        let result = self.inner.iter();
+        match &result {
+            Ok(_) => info!("[{}] iter", self.name),
+            Err(e) => warn!("[{}] iter failed: {}", self.name, e),
+        }
+        result
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        let result = self.inner.is_empty();
+        match &result {
+            Ok(empty) => info!("[{}] is_empty {}", self.name, empty),
+            Err(e) => warn!("[{}] is_empty failed: {}", self.name, e),
+        }
+        result
+    }
+
+    fn bulk_load_iter(&self, pairs: &mut dyn Iterator<Item = (String, String)>) -> Result<usize> {
+        let start = Instant::now();
+        let result = self.inner.bulk_load(pairs);
+        match &result {
+            Ok(count) => info!("[{}] bulk load {} {:?}", self.name, count, start.elapsed()),
+            Err(e) => warn!("[{}] bulk load failed: {}", self.name, e),
+        }
+        result
+    }
+}
+
+/// 所有写操作都返回 `KvsError::ReadOnly`
+#[derive(Clone)]
+pub struct ReadOnlyEngine<E> {
+    inner: E,
+}
+
+impl<E: KvsEngine> ReadOnlyEngine<E> {
+    pub fn new(inner: E) -> Self {
+        Self { inner }
+    }
+
+    pub fn into_inner(self) -> E {
+        self.inner
+    }
+}
+
+impl<E: KvsEngine + Clone> KvsEngine for ReadOnlyEngine<E> {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        self.inner.get(key)
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get_bytes(key)
+    }
+
+    fn set_bytes(&self, _key: Vec<u8>, _value: Vec<u8>) -> Result<()> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn get_reader(&self, key: &[u8]) -> Result<Option<ValueReader<'_>>> {
+        self.inner.get_reader(key)
+    }
+
+    /// 不用先把value读完再报错
+    fn set_from_reader(&self, _key: Vec<u8>, _reader: &mut dyn Read) -> Result<()> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn set_bytes_with_ttl(&self, _key: Vec<u8>, _value: Vec<u8>, _ttl: Duration) -> Result<()> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn remove_bytes(&self, _key: &[u8]) -> Result<()> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn write_batch(&self, _ops: Vec<BatchOp>) -> Result<()> {
+        Err(KvsError::ReadOnly)
+    }
+
+    /// 只对value不写的话让它过去
+    fn write_batch_if(
+        &self,
+        expected: &BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+        ops: Vec<BatchOp>,
+    ) -> Result<()> {
+        if ops.is_empty() {
+            self.inner.write_batch_if(expected, ops)
+        } else {
+            Err(KvsError::ReadOnly)
+        }
+    }
+
+    fn clear(&self) -> Result<()> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn rename(&self, _from: &str, _to: &str) -> Result<()> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn rename_nx(&self, _from: &str, _to: &str) -> Result<bool> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn compare_and_swap(
+        &self,
+        _key: &str,
+        _expected: Option<&str>,
+        _new: Option<String>,
+    ) -> Result<bool> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn copy(&self, _from: &str, _to: &str) -> Result<()> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn merge(&self, _key: &[u8], _operand: Vec<u8>) -> Result<()> {
+        Err(KvsError::ReadOnly)
+    }
+
+    /// 全是 `Get` 的话就是读，让它过去
+    fn apply(&self, ops: &[Op]) -> Result<Vec<OpResult>> {
+        if ops.iter().all(|op| matches!(op, Op::Get(_))) {
+            self.inner.apply(ops)
+        } else {
+            Err(KvsError::ReadOnly)
+        }
+    }
+
+    fn getdel(&self, _key: &str) -> Result<Option<String>> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn getset(&self, _key: String, _value: String) -> Result<Option<String>> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn set_nx(&self, _key: String, _value: String) -> Result<bool> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn append(&self, _key: &str, _suffix: &str) -> Result<usize> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn restore(&self, _dump: Dump, _replace: bool) -> Result<()> {
+        Err(KvsError::ReadOnly)
+    }
+
+    fn remove_matching(&self, _filter: &KeyFilter) -> Result<u64> {
+        Err(KvsError::ReadOnly)
+    }
+
+    /// 不是写，让别的地方写的东西落盘也没什么不可以
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn engine_name(&self) -> &'static str {
+        self.inner.engine_name()
+    }
+
+    fn stats(&self) -> Result<StoreStats> {
+        self.inner.stats()
+    }
+
+    fn metadata(&self, key: &str) -> Result<Option<KeyMeta>> {
+        self.inner.metadata(key)
+    }
+
+    fn multi_get(&self, keys: &[&str]) -> Result<Vec<Option<String>>> {
+        self.inner.multi_get(keys)
+    }
+
+    fn ttl(&self, key: &str) -> Result<Option<Duration>> {
+        self.inner.ttl(key)
+    }
+
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        self.inner.contains_key(key)
+    }
+
+    fn dump(&self, key: &str) -> Result<Option<Dump>> {
+        self.inner.dump(key)
+    }
+
+    fn scan_matching(&self, filter: &KeyFilter) -> Result<Vec<(String, String)>> {
+        self.inner.scan_matching(filter)
+    }
+
+    fn scan_bounds(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Result<Scan<'_>> {
+        self.inner.scan_bounds(start, end)
+    }
+
+    fn watch(&self, prefix: &[u8]) -> Result<Watcher> {
+        self.inner.watch(prefix)
+    }
+
+    fn keys(&self) -> Result<Keys<'_>> {
+        self.inner.keys()
+    }
+
+    fn len(&self) -> Result<usize> {
+        self.inner.len()
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Scan<'_>> {
+        self.inner.scan_prefix(prefix)
+    }
+
+    fn iter(&self) -> Result<Iter<'_>> {
+        self.inner.iter()
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        self.inner.is_empty()
+    }
+
+    fn bulk_load_iter(&self, _pairs: &mut dyn Iterator<Item = (String, String)>) -> Result<usize> {
+        Err(KvsError::ReadOnly)
+    }
+}
+
+/// key和value最多多大，`None` 是不限
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SizeLimits {
+    pub max_key: Option<usize>,
+    pub max_value: Option<usize>,
+}
+
+impl SizeLimits {
+    fn check_key(&self, key: &[u8]) -> Result<()> {
+        match self.max_key {
+            Some(limit) if key.len() > limit => Err(KvsError::KeyTooLarge {
+                size: key.len() as u64,
+                limit: limit as u64,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_value(&self, value: &[u8]) -> Result<()> {
+        match self.max_value {
+            Some(limit) if value.len() > limit => Err(KvsError::ValueTooLarge {
+                size: value.len() as u64,
+                limit: limit as u64,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    fn check(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.check_key(key)?;
+        self.check_value(value)
+    }
+}
+
+/// 太长的key、太大的value直接报 `KeyTooLarge` 、 `ValueTooLarge` ，碰都不碰底下的engine。不然一个客户端写一个2GB的value，之后谁读它谁就得把2GB读进内存
+///
+/// merge只看得到操作数，叠出来的value多大是管不了的
+#[derive(Clone)]
+pub struct SizeLimitedEngine<E> {
+    inner: E,
+    /// clone出来的共用一份，`set_limits` 了大家都变
+    limits: Arc<RwLock<SizeLimits>>,
+}
+
+impl<E: KvsEngine> SizeLimitedEngine<E> {
+    pub fn new(inner: E, limits: SizeLimits) -> Self {
+        Self {
+            inner,
+            limits: Arc::new(RwLock::new(limits)),
+        }
+    }
+
+    /// 运行的时候改限制，已经写进去的不管。所有的clone都会用新的限制
+    pub fn set_limits(&self, limits: SizeLimits) {
+        *self.limits.write().unwrap() = limits;
+    }
+
+    pub fn limits(&self) -> SizeLimits {
+        *self.limits.read().unwrap()
+    }
+
+    pub fn into_inner(self) -> E {
+        self.inner
+    }
+
+    /// `ReloadHandle::manage_limits` 拿走一份，以后从那边换
+    pub(crate) fn shared_limits(&self) -> Arc<RwLock<SizeLimits>> {
+        self.limits.clone()
+    }
+}
+
+/// 读的时候数着，超过了就让底下的engine读失败，它什么都不会写
+struct LimitedReader<'a> {
+    inner: &'a mut dyn Read,
+    left: u64,
+    exceeded: bool,
+}
+
+impl Read for LimitedReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n as u64 > self.left {
+            self.exceeded = true;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "value too large",
+            ));
+        }
+        self.left -= n as u64;
+        Ok(n)
+    }
+}
+
+impl<E: KvsEngine + Clone> KvsEngine for SizeLimitedEngine<E> {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        self.inner.get(key)
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get_bytes(key)
+    }
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.limits().check(&key[..], &value[..])?;
+        self.inner.set_bytes(key, value)
+    }
+
+    fn get_reader(&self, key: &[u8]) -> Result<Option<ValueReader<'_>>> {
+        self.inner.get_reader(key)
+    }
+
+    /// 事先不知道有多大，只能边读边数
+    fn set_from_reader(&self, key: Vec<u8>, reader: &mut dyn Read) -> Result<()> {
+        let limits = self.limits();
+        limits.check_key(&key[..])?;
+        let limit = match limits.max_value {
+            Some(limit) => limit as u64,
+            None => return self.inner.set_from_reader(key, reader),
+        };
+        let mut limited = LimitedReader {
+            inner: reader,
+            left: limit,
+            exceeded: false,
+        };
+        match self.inner.set_from_reader(key, &mut limited) {
+            Err(_) if limited.exceeded => Err(KvsError::ValueTooLarge {
+                size: limit + 1, // 具体多大没读完不知道，至少比限制大
+                limit,
+            }),
+            result => result,
+        }
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.limits().check(&key[..], &value[..])?;
+        self.inner.set_bytes_with_ttl(key, value, ttl)
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        self.inner.remove_bytes(key)
+    }
+
+    fn write_batch(&self, ops: Vec<BatchOp>) -> Result<()> {
+        let limits = self.limits();
+        for op in &ops {
+            if let BatchOp::Set(key, value) = op {
+                limits.check(&key[..], &value[..])?;
+            }
+        }
+        self.inner.write_batch(ops)
+    }
+
+    fn write_batch_if(
+        &self,
+        expected: &BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+        ops: Vec<BatchOp>,
+    ) -> Result<()> {
+        let limits = self.limits();
+        for op in &ops {
+            if let BatchOp::Set(key, value) = op {
+                limits.check(&key[..], &value[..])?;
+            }
+        }
+        self.inner.write_batch_if(expected, ops)
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.inner.clear()
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.limits().check_key(to.as_bytes())?;
+        self.inner.rename(from, to)
+    }
+
+    fn rename_nx(&self, from: &str, to: &str) -> Result<bool> {
+        self.limits().check_key(to.as_bytes())?;
+        self.inner.rename_nx(from, to)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: Option<&str>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        match &new {
+            Some(value) => self.limits().check(key.as_bytes(), value.as_bytes())?,
+            None => self.limits().check_key(key.as_bytes())?,
+        }
+        self.inner.compare_and_swap(key, expected, new)
+    }
+
+    fn copy(&self, from: &str, to: &str) -> Result<()> {
+        self.limits().check_key(to.as_bytes())?;
+        self.inner.copy(from, to)
+    }
+
+    fn merge(&self, key: &[u8], operand: Vec<u8>) -> Result<()> {
+        self.limits().check(key, &operand[..])?;
+        self.inner.merge(key, operand)
+    }
+
+    /// 和merge一样只看得到加上去的那一段
+    fn append(&self, key: &str, suffix: &str) -> Result<usize> {
+        self.limits().check(key.as_bytes(), suffix.as_bytes())?;
+        self.inner.append(key, suffix)
+    }
+
+    fn getset(&self, key: String, value: String) -> Result<Option<String>> {
+        self.limits().check(key.as_bytes(), value.as_bytes())?;
+        self.inner.getset(key, value)
+    }
+
+    fn set_nx(&self, key: String, value: String) -> Result<bool> {
+        self.limits().check(key.as_bytes(), value.as_bytes())?;
+        self.inner.set_nx(key, value)
+    }
+
+    fn getdel(&self, key: &str) -> Result<Option<String>> {
+        self.inner.getdel(key)
+    }
+
+    fn apply(&self, ops: &[Op]) -> Result<Vec<OpResult>> {
+        let limits = self.limits();
+        for op in ops {
+            if let Op::Set(key, value) = op {
+                limits.check(key.as_bytes(), value.as_bytes())?;
+            }
+        }
+        self.inner.apply(ops)
+    }
+
+    fn remove_matching(&self, filter: &KeyFilter) -> Result<u64> {
+        self.inner.remove_matching(filter)
+    }
+
+    fn dump(&self, key: &str) -> Result<Option<Dump>> {
+        self.inner.dump(key)
+    }
+
+    fn restore(&self, dump: Dump, replace: bool) -> Result<()> {
+        self.limits()
+            .check(dump.key.as_bytes(), dump.value.as_bytes())?;
+        self.inner.restore(dump, replace)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn engine_name(&self) -> &'static str {
+        self.inner.engine_name()
+    }
+
+    fn stats(&self) -> Result<StoreStats> {
+        self.inner.stats()
+    }
+
+    fn metadata(&self, key: &str) -> Result<Option<KeyMeta>> {
+        self.inner.metadata(key)
+    }
+
+    fn multi_get(&self, keys: &[&str]) -> Result<Vec<Option<String>>> {
+        self.inner.multi_get(keys)
+    }
+
+    fn ttl(&self, key: &str) -> Result<Option<Duration>> {
+        self.inner.ttl(key)
+    }
+
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        self.inner.contains_key(key)
+    }
+
+    fn scan_matching(&self, filter: &KeyFilter) -> Result<Vec<(String, String)>> {
+        self.inner.scan_matching(filter)
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Scan<'_>> {
+        self.inner.scan_prefix(prefix)
+    }
+
+    fn scan_bounds(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Result<Scan<'_>> {
+        self.inner.scan_bounds(start, end)
+    }
+
+    fn watch(&self, prefix: &[u8]) -> Result<Watcher> {
+        self.inner.watch(prefix)
+    }
+
+    fn keys(&self) -> Result<Keys<'_>> {
+        self.inner.keys()
+    }
+
+    fn iter(&self) -> Result<Iter<'_>> {
+        self.inner.iter()
+    }
+
+    fn len(&self) -> Result<usize> {
+        self.inner.len()
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        self.inner.is_empty()
+    }
+
+    /// 一批一批检查完了再交给底下的engine，前面的批已经写进去了的话就留着
+    fn bulk_load_iter(&self, pairs: &mut dyn Iterator<Item = (String, String)>) -> Result<usize> {
+        let mut count = 0;
+        let mut chunk = vec![];
+        let limits = self.limits();
+        for (key, value) in pairs {
+            limits.check(key.as_bytes(), value.as_bytes())?;
+            chunk.push((key, value));
+            if chunk.len() >= BULK_BATCH {
+                count += self.inner.bulk_load(std::mem::take(&mut chunk))?;
+            }
+        }
+        count += self.inner.bulk_load(chunk)?;
+        Ok(count)
+    }
+}
+
+/// 什么时候该出错
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Faults {
+    /// 接下来的这么多个操作全部失败
+    pub fail_next: usize,
+    /// 每隔这么多个操作失败一次，比如 `Some(3)` 表示第3、6、9……个操作失败
+    pub fail_every: Option<usize>,
+    /// 只让写操作失败，读操作不受影响
+    pub writes_only: bool,
+}
+
+/// 给测试用的，按照 `Faults` 的配置让操作失败，失败的时候根本不会碰到底下的engine，就像磁盘突然坏了一样
+///
+/// clone出来的共用一份配置和计数
+#[derive(Clone)]
+pub struct FaultInjectingEngine<E> {
+    inner: E,
+    /// 后面是数到第几个操作了
+    faults: Arc<Mutex<(Faults, usize)>>,
+}
+
+impl<E: KvsEngine> FaultInjectingEngine<E> {
+    pub fn new(inner: E, faults: Faults) -> Self {
+        Self {
+            inner,
+            faults: Arc::new(Mutex::new((faults, 0))),
+        }
+    }
+
+    /// 测试跑到一半可以换配置，所有clone一起换。已经数到第几个操作了不会清零
+    pub fn set_faults(&self, faults: Faults) {
+        self.faults.lock().unwrap().0 = faults;
+    }
+
+    pub fn into_inner(self) -> E {
+        self.inner
+    }
+
+    fn check(&self, write: bool) -> Result<()> {
+        let (faults, count) = &mut *self.faults.lock().unwrap();
+        if faults.writes_only && !write {
+            return Ok(());
+        }
+
+        *count += 1;
+        let fail = if faults.fail_next > 0 {
+            faults.fail_next -= 1;
+            true
+        } else {
+            match faults.fail_every {
+                Some(n) if n > 0 => count.is_multiple_of(n),
+                _ => false,
+            }
+        };
+
+        if fail {
+            Err(KvsError::Io(std::io::Error::other("injected fault")))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<E: KvsEngine + Clone> KvsEngine for FaultInjectingEngine<E> {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        self.check(false)?;
+        self.inner.get(key)
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.check(false)?;
+        self.inner.get_bytes(key)
+    }
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.check(true)?;
+        self.inner.set_bytes(key, value)
+    }
+
+    fn get_reader(&self, key: &[u8]) -> Result<Option<ValueReader<'_>>> {
+        self.check(false)?;
+        self.inner.get_reader(key)
+    }
+
+    fn set_from_reader(&self, key: Vec<u8>, reader: &mut dyn Read) -> Result<()> {
+        self.check(true)?;
+        self.inner.set_from_reader(key, reader)
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.check(true)?;
+        self.inner.set_bytes_with_ttl(key, value, ttl)
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        self.check(true)?;
+        self.inner.remove_bytes(key)
+    }
+
+    fn write_batch(&self, ops: Vec<BatchOp>) -> Result<()> {
+        self.check(true)?; // 一整个batch算一个操作
+        self.inner.write_batch(ops)
+    }
+
+    fn write_batch_if(
+        &self,
+        expected: &BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+        ops: Vec<BatchOp>,
+    ) -> Result<()> {
+        self.check(true)?;
+        self.inner.write_batch_if(expected, ops)
+    }
+
+    fn clear(&self) -> Result<()> {
+        self.check(true)?;
+        self.inner.clear()
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.check(true)?;
+        self.inner.rename(from, to)
+    }
+
+    fn rename_nx(&self, from: &str, to: &str) -> Result<bool> {
+        self.check(true)?;
+        self.inner.rename_nx(from, to)
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: Option<&str>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.check(true)?;
+        self.inner.compare_and_swap(key, expected, new)
+    }
+
+    fn copy(&self, from: &str, to: &str) -> Result<()> {
+        self.check(true)?;
+        self.inner.copy(from, to)
+    }
+
+    fn merge(&self, key: &[u8], operand: Vec<u8>) -> Result<()> {
+        self.check(true)?;
+        self.inner.merge(key, operand)
+    }
+
+    fn apply(&self, ops: &[Op]) -> Result<Vec<OpResult>> {
+        self.check(ops.iter().any(|op| !matches!(op, Op::Get(_))))?; // 和batch一样算一个操作
+        self.inner.apply(ops)
+    }
+
+    fn getdel(&self, key: &str) -> Result<Option<String>> {
+        self.check(true)?;
+        self.inner.getdel(key)
+    }
+
+    fn getset(&self, key: String, value: String) -> Result<Option<String>> {
+        self.check(true)?;
+        self.inner.getset(key, value)
+    }
+
+    fn set_nx(&self, key: String, value: String) -> Result<bool> {
+        self.check(true)?;
+        self.inner.set_nx(key, value)
+    }
+
+    fn append(&self, key: &str, suffix: &str) -> Result<usize> {
+        self.check(true)?;
+        self.inner.append(key, suffix)
+    }
+
+    fn restore(&self, dump: Dump, replace: bool) -> Result<()> {
+        self.check(true)?;
+        self.inner.restore(dump, replace)
+    }
+
+    fn remove_matching(&self, filter: &KeyFilter) -> Result<u64> {
+        self.check(true)?;
+        self.inner.remove_matching(filter)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.check(true)?;
+        self.inner.flush()
+    }
+
+    fn engine_name(&self) -> &'static str {
+        self.inner.engine_name()
+    }
+
+    fn stats(&self) -> Result<StoreStats> {
+        self.check(false)?;
+        self.inner.stats()
+    }
+
+    fn metadata(&self, key: &str) -> Result<Option<KeyMeta>> {
+        self.check(false)?;
+        self.inner.metadata(key)
+    }
+
+    fn multi_get(&self, keys: &[&str]) -> Result<Vec<Option<String>>> {
+        self.check(false)?;
+        self.inner.multi_get(keys)
+    }
+
+    fn ttl(&self, key: &str) -> Result<Option<Duration>> {
+        self.check(false)?;
+        self.inner.ttl(key)
+    }
+
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        self.check(false)?;
+        self.inner.contains_key(key)
+    }
+
+    fn dump(&self, key: &str) -> Result<Option<Dump>> {
+        self.check(false)?;
+        self.inner.dump(key)
+    }
+
+    fn scan_matching(&self, filter: &KeyFilter) -> Result<Vec<(String, String)>> {
+        self.check(false)?;
+        self.inner.scan_matching(filter)
+    }
+
+    fn scan_bounds(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Result<Scan<'_>> {
+        self.check(false)?;
+        self.inner.scan_bounds(start, end)
+    }
+
+    fn watch(&self, prefix: &[u8]) -> Result<Watcher> {
+        self.check(false)?;
+        self.inner.watch(prefix)
+    }
+
+    fn keys(&self) -> Result<Keys<'_>> {
+        self.check(false)?;
+        self.inner.keys()
+    }
+
+    fn len(&self) -> Result<usize> {
+        self.check(false)?;
+        self.inner.len()
+    }
+
+    fn scan_prefix(&self, prefix: &[u8]) -> Result<Scan<'_>> {
+        self.check(false)?;
+        self.inner.scan_prefix(prefix)
+    }
+
+    fn iter(&self) -> Result<Iter<'_>> {
+        self.check(false)?;
+        self.inner.iter()
+    }
+
+    fn is_empty(&self) -> Result<bool> {
+        self.check(false)?;
+        self.inner.is_empty()
+    }
+
+    fn bulk_load_iter(&self, pairs: &mut dyn Iterator<Item = (String, String)>) -> Result<usize> {
+        self.check(true)?;
+        self.inner.bulk_load(pairs)
+    }
+}
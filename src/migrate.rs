@@ -0,0 +1,115 @@
+//! 把一个目录里的数据整个搬到另一个engine。kvs、sled、redb的磁盘格式完全不一样，换engine只能一个key一个key地倒过去
+
+use crate::archive;
+use crate::now_millis;
+use crate::BatchOp;
+use crate::KvStore;
+use crate::KvsEngine;
+use crate::KvsError;
+#[cfg(feature = "redb")]
+use crate::RedbKvsEngine;
+use crate::Result;
+#[cfg(feature = "sled")]
+use crate::SledKvsEngine;
+
+use std::path::Path;
+use std::time::Duration;
+
+/// 能搬过去的engine
+const TARGETS: &[&str] = &[
+    "kvs",
+    #[cfg(feature = "sled")]
+    "sled",
+    #[cfg(feature = "redb")]
+    "redb",
+];
+
+/// 攒够这么多个再一起写，sled每次写都要flush，一个一个写太慢了
+const BATCH: usize = 10000;
+
+/// 把 `src` 里所有还活着的key搬到 `dst` ，`dst` 用 `target` engine（`kvs` 、 `sled` 或者 `redb`）打开，返回搬了多少个key
+///
+/// `src` 是什么engine看它的 `.kvs` 。`dst` 要么不存在，要么是空的。带TTL的key搬过去还是在原来的时间过期，元数据里的时间和版本号会重新算
+pub fn migrate<P, Q>(src: P, dst: Q, target: &str) -> Result<usize>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let (src, dst) = (src.as_ref(), dst.as_ref());
+    if !TARGETS.contains(&target) {
+        return Err(KvsError::UnsupportedEngine {
+            name: target.to_string(),
+        });
+    }
+    let engine = archive::engine(src)?.ok_or_else(|| {
+        std::io::Error::new(
+            std::io::ErrorKind::NotFound,
+            format!("{:?} is not a kvs directory", src),
+        )
+    })?;
+    std::fs::create_dir_all(dst)?;
+    if std::fs::read_dir(dst)?.next().is_some() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("migration destination {:?} is not empty", dst),
+        )
+        .into());
+    }
+
+    match &engine[..] {
+        "kvs" => into(&mut KvStore::open(src)?, dst, target),
+        #[cfg(feature = "sled")]
+        "sled" => into(&mut SledKvsEngine::open(src)?, dst, target),
+        #[cfg(feature = "redb")]
+        "redb" => into(&mut RedbKvsEngine::open(src)?, dst, target),
+        _ => Err(KvsError::UnsupportedEngine { name: engine }),
+    }
+}
+
+fn into<S: KvsEngine>(src: &mut S, dst: &Path, target: &str) -> Result<usize> {
+    match target {
+        #[cfg(feature = "sled")]
+        "sled" => copy(src, &mut SledKvsEngine::open(dst)?),
+        #[cfg(feature = "redb")]
+        "redb" => copy(src, &mut RedbKvsEngine::open(dst)?),
+        _ => copy(src, &mut KvStore::open(dst)?),
+    }
+}
+
+fn copy<S: KvsEngine, D: KvsEngine>(src: &mut S, dst: &mut D) -> Result<usize> {
+    // key都不大，value一个一个读，不会一下子全读进内存。 `keys` 看不见namespace里的，要单独扫出来
+    let mut keys = src
+        .scan_prefix(&[0])?
+        .map(|entry| entry.map(|(key, _)| key))
+        .collect::<Result<Vec<_>>>()?;
+    keys.extend(src.keys()?.collect::<Result<Vec<_>>>()?);
+    let mut batch = Vec::with_capacity(BATCH);
+    let mut count = 0;
+    for key in keys {
+        let value = match src.get_bytes(&key[..])? {
+            Some(value) => value,
+            None => continue, // 刚好过期了
+        };
+        let expires = match std::str::from_utf8(&key[..]) {
+            Ok(k) => src.metadata(k)?.map_or(0, |meta| meta.expires),
+            Err(_) => 0, // metadata只能用String查，二进制的key就当没有TTL
+        };
+        if expires == 0 {
+            batch.push(BatchOp::Set(key, value));
+            if batch.len() >= BATCH {
+                dst.write_batch(std::mem::take(&mut batch))?;
+            }
+        } else {
+            let now = now_millis();
+            if expires <= now {
+                continue;
+            }
+            dst.set_bytes_with_ttl(key, value, Duration::from_millis(expires - now))?;
+        }
+        count += 1;
+    }
+    if !batch.is_empty() {
+        dst.write_batch(batch)?;
+    }
+    Ok(count)
+}
@@ -0,0 +1,134 @@
+use crate::net::Stream;
+use crate::rate::TokenBucket;
+use crate::KvsError;
+use crate::RateLimit;
+use crate::Result;
+use crate::Throttle;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use std::io::BufReader;
+use std::io::Lines;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// 服务器端MONITOR的配置。不配置的话MONITOR请求一律拒绝
+#[derive(Clone, Debug, PartialEq)]
+pub struct MonitorConfig {
+    /// 订阅的时候必须带上这个token才行，生产环境的流量可不是谁都能看的
+    pub token: String,
+    /// 每个订阅者每秒最多收到多少个事件，超出的直接丢掉，不会让服务器等
+    pub events_per_sec: f64,
+}
+
+/// 服务器执行完了的一个命令。没登录、ACL不让做、执行失败了的都不算
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MonitorEvent {
+    /// 从UNIX epoch开始的毫秒数
+    pub timestamp: u64,
+    /// 发请求的客户端
+    pub peer: String,
+    pub op: String,
+    /// 有的命令没有单个key，比如bulk load
+    pub key: Option<String>,
+}
+
+/// 订阅者慢成这样就不要了，免得把整个服务器拖住
+const WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+
+struct Subscriber {
+    stream: Stream,
+    limiter: TokenBucket,
+}
+
+/// 所有连着的订阅者。每个连接的线程执行完命令以后拿着锁挨个发一遍
+pub(crate) struct Subscribers {
+    config: MonitorConfig,
+    subscribers: Vec<Subscriber>,
+}
+
+impl Subscribers {
+    pub(crate) fn new(config: MonitorConfig) -> Self {
+        Self {
+            config,
+            subscribers: vec![],
+        }
+    }
+
+    pub(crate) fn authorized(&self, token: &str) -> bool {
+        self.config.token == token
+    }
+
+    pub(crate) fn add(&mut self, stream: Stream) -> Result<()> {
+        stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+        self.subscribers.push(Subscriber {
+            stream,
+            limiter: TokenBucket::new(RateLimit {
+                ops_per_sec: self.config.events_per_sec,
+                burst: self.config.events_per_sec.ceil().max(1.0) as u32, // 允许一秒钟的突发
+                throttle: Throttle::Fail,
+            })?,
+        });
+        Ok(())
+    }
+
+    /// 服务器停的时候，订阅者的连接都关掉
+    pub(crate) fn clear(&mut self) {
+        self.subscribers.clear();
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.subscribers.is_empty()
+    }
+
+    /// 写不进去的订阅者（断开了或者太慢）直接踢掉
+    pub(crate) fn publish(&mut self, event: &MonitorEvent) -> Result<()> {
+        let mut line = serde_json::to_string(event)?;
+        line.push('\n');
+        self.subscribers.retain_mut(|subscriber| {
+            if !subscriber.limiter.try_acquire() {
+                return true; // 超速了，这个事件就不给它了
+            }
+            subscriber.stream.write_all(line.as_bytes()).is_ok()
+        });
+        Ok(())
+    }
+}
+
+/// 有人订阅的话才拼 `event` ，拿peer要一次系统调用
+pub(crate) fn publish<F>(subscribers: Option<&Mutex<Subscribers>>, event: F) -> Result<()>
+where
+    F: FnOnce() -> MonitorEvent,
+{
+    if let Some(subscribers) = subscribers {
+        let mut subscribers = subscribers.lock().unwrap();
+        if !subscribers.is_empty() {
+            subscribers.publish(&event())?;
+        }
+    }
+    Ok(())
+}
+
+/// 客户端这边收到的事件流，一直读到服务器断开为止
+pub struct Monitor {
+    lines: Lines<BufReader<Stream>>,
+}
+
+impl Monitor {
+    pub(crate) fn new(lines: Lines<BufReader<Stream>>) -> Self {
+        Self { lines }
+    }
+}
+
+impl Iterator for Monitor {
+    type Item = Result<MonitorEvent>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.lines.next()? {
+            Ok(line) => Some(serde_json::from_str(&line[..]).map_err(KvsError::from)),
+            Err(e) => Some(Err(KvsError::from(e))),
+        }
+    }
+}
@@ -0,0 +1,252 @@
+//! 同一个目录里的几张“表”，每个namespace的key前面偷偷加上 `\0名字\0` ，互相看不见
+//!
+//! 所以 `\0` 开头的key留给namespace用了，直接在最外面写 `\0` 开头的key会和namespace撞上。
+//! 最外面的 `keys` 、 `iter` 、 `len` 、 `clear` 、 `scan_matching` 都跳过它们，要连namespace一起扫的话用 `scan`
+
+use crate::BatchOp;
+use crate::KeyFilter;
+use crate::KeyMeta;
+use crate::KvsEngine;
+use crate::KvsError;
+use crate::Result;
+use crate::Scan;
+use crate::StoreStats;
+use crate::ValueReader;
+use crate::Watcher;
+
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::ops::Bound;
+use std::time::Duration;
+
+/// 是不是哪个namespace（或者二级索引）里的key，最外面看不见
+pub(crate) fn is_namespaced(key: &[u8]) -> bool {
+    key.first() == Some(&0)
+}
+
+/// `engine.namespace("sessions")` 拿到的，用起来和一个单独的engine一样
+///
+/// 拿着engine的一个clone，所以可以和engine分开放到别的线程去用
+#[derive(Clone)]
+pub struct Namespace<E: KvsEngine> {
+    engine: E,
+    prefix: Vec<u8>,
+}
+
+impl<E: KvsEngine> Namespace<E> {
+    pub(crate) fn new(engine: E, name: &str) -> Result<Self> {
+        if name.is_empty() || name.contains('\0') {
+            return Err(KvsError::InvalidArgument {
+                name: "namespace".to_string(),
+                value: name.to_string(),
+            });
+        }
+        let mut prefix = vec![0];
+        prefix.extend(name.as_bytes());
+        prefix.push(0);
+        Ok(Self { engine, prefix })
+    }
+
+    /// 把这个namespace里的key全删了，一个batch删，返回删了几个
+    pub fn clear(&self) -> Result<usize> {
+        let keys: Vec<Vec<u8>> = self
+            .engine
+            .scan_prefix(&self.prefix[..])?
+            .map(|v| v.map(|(key, _)| key))
+            .collect::<Result<_>>()?;
+        let count = keys.len();
+        if count > 0 {
+            self.engine
+                .write_batch(keys.into_iter().map(BatchOp::Remove).collect())?;
+        }
+        Ok(count)
+    }
+
+    pub fn into_inner(self) -> E {
+        self.engine
+    }
+
+    fn key(&self, key: &[u8]) -> Vec<u8> {
+        let mut prefixed = self.prefix.clone();
+        prefixed.extend(key);
+        prefixed
+    }
+
+    /// 名字是utf8，拼出来的key还是utf8，String的API可以直接往下传
+    fn str_key(&self, key: &str) -> String {
+        String::from_utf8(self.key(key.as_bytes())).unwrap()
+    }
+}
+
+impl<E: KvsEngine + Clone> KvsEngine for Namespace<E> {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        let key = self.str_key(key);
+        self.engine.get(&key[..])
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let key = self.key(key);
+        self.engine.get_bytes(&key[..])
+    }
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let key = self.key(&key[..]);
+        self.engine.set_bytes(key, value)
+    }
+
+    fn get_reader(&self, key: &[u8]) -> Result<Option<ValueReader<'_>>> {
+        let key = self.key(key);
+        self.engine.get_reader(&key[..])
+    }
+
+    fn set_from_reader(&self, key: Vec<u8>, reader: &mut dyn Read) -> Result<()> {
+        let key = self.key(&key[..]);
+        self.engine.set_from_reader(key, reader)
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let key = self.key(&key[..]);
+        self.engine.set_bytes_with_ttl(key, value, ttl)
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        let prefixed = self.key(key);
+        match self.engine.remove_bytes(&prefixed[..]) {
+            Err(KvsError::NotFound { .. }) => Err(KvsError::NotFound {
+                key: crate::bytes::lossy(key),
+            }), // 报错的时候别把前缀露出去
+            result => result,
+        }
+    }
+
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let (prefixed_from, prefixed_to) = (self.str_key(from), self.str_key(to));
+        match self.engine.rename(&prefixed_from[..], &prefixed_to[..]) {
+            Err(KvsError::NotFound { .. }) => Err(KvsError::NotFound {
+                key: from.to_string(),
+            }),
+            result => result,
+        }
+    }
+
+    fn copy(&self, from: &str, to: &str) -> Result<()> {
+        let (prefixed_from, prefixed_to) = (self.str_key(from), self.str_key(to));
+        match self.engine.copy(&prefixed_from[..], &prefixed_to[..]) {
+            Err(KvsError::NotFound { .. }) => Err(KvsError::NotFound {
+                key: from.to_string(),
+            }),
+            result => result,
+        }
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: Option<&str>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let key = self.str_key(key);
+        self.engine.compare_and_swap(&key[..], expected, new)
+    }
+
+    fn merge(&self, key: &[u8], operand: Vec<u8>) -> Result<()> {
+        let prefixed = self.key(key);
+        self.engine.merge(&prefixed[..], operand)
+    }
+
+    /// 整个engine都flush了，别的namespace的写也一起落盘
+    fn flush(&self) -> Result<()> {
+        self.engine.flush()
+    }
+
+    fn engine_name(&self) -> &'static str {
+        self.engine.engine_name()
+    }
+
+    /// key只数这个namespace里的，别的都是整个engine的
+    fn stats(&self) -> Result<StoreStats> {
+        Ok(StoreStats {
+            keys: self.len()?,
+            ..self.engine.stats()?
+        })
+    }
+
+    fn write_batch(&self, ops: Vec<BatchOp>) -> Result<()> {
+        let ops = ops
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Set(key, value) => BatchOp::Set(self.key(&key[..]), value),
+                BatchOp::Remove(key) => BatchOp::Remove(self.key(&key[..])),
+            })
+            .collect();
+        self.engine.write_batch(ops)
+    }
+
+    fn write_batch_if(
+        &self,
+        expected: &BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+        ops: Vec<BatchOp>,
+    ) -> Result<()> {
+        let expected = expected
+            .iter()
+            .map(|(key, value)| (self.key(&key[..]), value.clone()))
+            .collect();
+        let ops = ops
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Set(key, value) => BatchOp::Set(self.key(&key[..]), value),
+                BatchOp::Remove(key) => BatchOp::Remove(self.key(&key[..])),
+            })
+            .collect();
+        self.engine.write_batch_if(&expected, ops)
+    }
+
+    fn metadata(&self, key: &str) -> Result<Option<KeyMeta>> {
+        let key = self.str_key(key);
+        self.engine.metadata(&key[..])
+    }
+
+    /// 在namespace的范围里扫一遍，去掉前缀再匹配
+    fn scan_matching(&self, filter: &KeyFilter) -> Result<Vec<(String, String)>> {
+        let matcher = filter.compile()?;
+        let len = self.prefix.len();
+        let mut pairs = vec![];
+        for entry in self.engine.scan_prefix(&self.prefix[..])? {
+            let (key, value) = entry?;
+            let key = match String::from_utf8(key[len..].to_vec()) {
+                Ok(key) => key,
+                Err(_) => continue,
+            };
+            if matcher.matches(&key[..]) {
+                let value = crate::bytes::into_string(key.as_bytes(), value)?;
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn scan_bounds(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Result<Scan<'_>> {
+        let start = match start {
+            Bound::Included(key) => Bound::Included(self.key(key)),
+            Bound::Excluded(key) => Bound::Excluded(self.key(key)),
+            Bound::Unbounded => Bound::Included(self.prefix.clone()),
+        };
+        let end = match end {
+            Bound::Included(key) => Bound::Included(self.key(key)),
+            Bound::Excluded(key) => Bound::Excluded(self.key(key)),
+            Bound::Unbounded => crate::scan::prefix_end(&self.prefix[..]),
+        };
+        let len = self.prefix.len();
+        let iter = self
+            .engine
+            .scan_bounds(start.as_ref().map(|v| &v[..]), end.as_ref().map(|v| &v[..]))?
+            .map(move |entry| entry.map(|(key, value)| (key[len..].to_vec(), value)));
+        Ok(Box::new(iter))
+    }
+
+    /// 收到的key是去掉前缀的
+    fn watch(&self, prefix: &[u8]) -> Result<Watcher> {
+        let prefixed = self.key(prefix);
+        Ok(self.engine.watch(&prefixed[..])?.strip(self.prefix.len()))
+    }
+}
@@ -0,0 +1,263 @@
+//! TCP和Unix domain socket的连接，服务器和客户端只认 `Stream`
+//!
+//! 地址以 `unix:` 开头的是socket文件的路径，比如 `unix:/run/kvs.sock` ，别的都是 `ip:port`
+
+#[cfg(feature = "tls")]
+pub(crate) use crate::tls::ClientTls;
+#[cfg(feature = "tls")]
+use crate::tls::TlsStream;
+use crate::Result;
+
+use std::fmt;
+use std::io::BufRead;
+use std::io::Read;
+use std::io::Write;
+use std::net::Shutdown;
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::Path;
+#[cfg(feature = "tls")]
+use std::sync::Arc;
+#[cfg(feature = "tls")]
+use std::sync::Mutex;
+use std::time::Duration;
+
+const UNIX_PREFIX: &str = "unix:";
+
+/// `unix:` 后面的路径，不是的话是 `None`
+pub(crate) fn unix_path(address: &str) -> Option<&str> {
+    address.strip_prefix(UNIX_PREFIX)
+}
+
+/// 没有tls这个feature的时候客户端只能是 `None`
+#[cfg(not(feature = "tls"))]
+pub(crate) enum ClientTls {}
+
+pub(crate) enum Stream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    /// TLS的连接没法像socket那样dup一份，clone出来的都是同一个。一个连接上的读写本来就是一个线程轮流做的，锁不会抢
+    #[cfg(feature = "tls")]
+    Tls(Arc<Mutex<TlsStream>>),
+}
+
+impl Stream {
+    /// `tls` 不是 `None` 的话在TCP上面套一层TLS
+    pub(crate) fn connect(address: &str, tls: Option<&ClientTls>) -> Result<Self> {
+        if let Some(tls) = tls {
+            #[cfg(feature = "tls")]
+            return Ok(Stream::Tls(Arc::new(Mutex::new(tls.connect(address)?))));
+            #[cfg(not(feature = "tls"))]
+            match *tls {}
+        }
+        match unix_path(address) {
+            #[cfg(unix)]
+            Some(path) => Ok(Stream::Unix(UnixStream::connect(path)?)),
+            #[cfg(not(unix))]
+            Some(_) => Err(crate::KvsError::Io(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "unix sockets are not supported on this platform",
+            ))),
+            None => Ok(Stream::Tcp(TcpStream::connect(address)?)),
+        }
+    }
+
+    pub(crate) fn try_clone(&self) -> std::io::Result<Self> {
+        match self {
+            Stream::Tcp(stream) => stream.try_clone().map(Stream::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.try_clone().map(Stream::Unix),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => Ok(Stream::Tls(stream.clone())),
+        }
+    }
+
+    /// MONITOR里显示的客户端地址。Unix socket的客户端一般没有名字
+    pub(crate) fn peer(&self) -> String {
+        match self {
+            Stream::Tcp(stream) => match stream.peer_addr() {
+                Ok(address) => address.to_string(),
+                Err(_) => "unknown".to_string(),
+            },
+            #[cfg(unix)]
+            Stream::Unix(stream) => match stream.peer_addr() {
+                Ok(address) => match address.as_pathname() {
+                    Some(path) => format!("{}{}", UNIX_PREFIX, path.display()),
+                    None => UNIX_PREFIX.to_string(),
+                },
+                Err(_) => "unknown".to_string(),
+            },
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => match stream.lock().unwrap().get_ref().peer_addr() {
+                Ok(address) => address.to_string(),
+                Err(_) => "unknown".to_string(),
+            },
+        }
+    }
+
+    /// 下面的socket，只用来从别的线程关连接。TLS的连接在读的时候锁着，只能这样
+    pub(crate) fn socket(&self) -> std::io::Result<Socket> {
+        match self {
+            Stream::Tcp(stream) => stream.try_clone().map(Socket::Tcp),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.try_clone().map(Socket::Unix),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream
+                .lock()
+                .unwrap()
+                .get_ref()
+                .try_clone()
+                .map(Socket::Tcp),
+        }
+    }
+
+    pub(crate) fn set_read_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_read_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_read_timeout(timeout),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.lock().unwrap().get_ref().set_read_timeout(timeout),
+        }
+    }
+
+    pub(crate) fn set_write_timeout(&self, timeout: Option<Duration>) -> std::io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.set_write_timeout(timeout),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.set_write_timeout(timeout),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.lock().unwrap().get_ref().set_write_timeout(timeout),
+        }
+    }
+}
+
+/// 服务器这边一个连接上最多等多久，`None` 是一直等，不能是0。不设的话一个连上了什么都不发的客户端能一直占着一个线程
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Timeouts {
+    /// 等下一个请求开始。等不到的话连接直接关掉，客户端下次发请求的时候会重新连
+    pub idle: Option<Duration>,
+    /// 请求开始了以后等剩下的部分，比如只发了半个请求、流式set的value
+    pub read: Option<Duration>,
+    /// 客户端不读的话往它写会堵住
+    pub write: Option<Duration>,
+}
+
+impl Timeouts {
+    /// 连接刚开始的时候设一下
+    pub(crate) fn apply(&self, stream: &Stream) -> std::io::Result<()> {
+        stream.set_read_timeout(self.read)?;
+        stream.set_write_timeout(self.write)
+    }
+
+    /// 等下一个请求的第一个字节，等到了以后接着读用 `read` 。返回 `false` 是客户端不发了或者空闲太久了
+    pub(crate) fn wait_for_request(
+        &self,
+        stream: &Stream,
+        reader: &mut dyn BufRead,
+    ) -> std::io::Result<bool> {
+        if self.idle == self.read {
+            return ready(reader);
+        }
+        stream.set_read_timeout(self.idle)?;
+        let ready = ready(reader);
+        stream.set_read_timeout(self.read)?;
+        ready
+    }
+}
+
+fn ready(reader: &mut dyn BufRead) -> std::io::Result<bool> {
+    match reader.fill_buf() {
+        Ok(buf) => Ok(!buf.is_empty()),
+        Err(e) if timed_out(&e) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// 设了超时的socket读写超时了，不同的系统报的不一样
+pub(crate) fn timed_out(error: &std::io::Error) -> bool {
+    matches!(
+        error.kind(),
+        std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+    )
+}
+
+pub(crate) enum Socket {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Socket {
+    pub(crate) fn shutdown(&self, how: Shutdown) -> std::io::Result<()> {
+        match self {
+            Socket::Tcp(socket) => socket.shutdown(how),
+            #[cfg(unix)]
+            Socket::Unix(socket) => socket.shutdown(how),
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.read(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.lock().unwrap().read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.write(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.lock().unwrap().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.flush(),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.lock().unwrap().flush(),
+        }
+    }
+}
+
+impl fmt::Debug for Stream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Stream::Tcp(stream) => stream.fmt(f),
+            #[cfg(unix)]
+            Stream::Unix(stream) => stream.fmt(f),
+            #[cfg(feature = "tls")]
+            Stream::Tls(stream) => stream.lock().unwrap().get_ref().fmt(f),
+        }
+    }
+}
+
+/// 在 `path` 上开一个Unix socket。上次没删掉的socket文件先删掉，不是socket的文件不碰
+#[cfg(unix)]
+pub(crate) fn bind_unix(path: &Path) -> Result<UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() && UnixStream::connect(path).is_err() {
+            std::fs::remove_file(path)?; // 没人在听，是上次留下的
+        }
+    }
+    Ok(UnixListener::bind(path)?)
+}
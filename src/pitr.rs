@@ -0,0 +1,59 @@
+//! 按时间点恢复：开了归档的话，segment在被compact删掉之前都会先硬链接到归档目录里，从头重放归档就能回到以前任何一个时刻
+//!
+//! 删除的记录里没有时间，只能跟着前后的写走：恢复到的是“时间点之后第一个写之前”的样子，这中间的删除都算进去
+
+use crate::fs;
+use crate::segment;
+use crate::segment::Writer;
+use crate::snapshot;
+use crate::Command;
+use crate::Compression;
+use crate::Result;
+
+use std::path::Path;
+
+/// 这条记录是什么时候写的，删除和batch的开头没有时间
+fn written_at(command: &Command) -> Option<u64> {
+    match command {
+        Command::Set(_, _, meta)
+        | Command::SetBlob(_, _, meta)
+        | Command::SetCompressed(_, _, meta)
+        | Command::Merge(_, _, meta) => Some(meta.modified),
+        Command::Remove(_) | Command::Batch(_) => None,
+    }
+}
+
+/// 把 `archive` 里 `timestamp` （从UNIX epoch开始的毫秒数）和之前写的记录重放到 `dest` 里， `dest` 必须是空的或者不存在
+///
+/// 记录原样写成一个segment，用到的blob链接过去，之后直接 `KvStore::open(dest)` 。一个batch要么全要，要么全不要
+pub(crate) fn restore(archive: &Path, dest: &Path, timestamp: u64) -> Result<()> {
+    snapshot::prepare(dest)?;
+    let mut writer = Writer::open(dest, 0)?;
+    'segments: for id in segment::list(archive)? {
+        let commands = segment::read(archive, id, false)?;
+        let mut i = 0;
+        while i < commands.len() {
+            let n = match commands[i].0 {
+                Command::Batch(n) => n as usize + 1,
+                _ => 1,
+            };
+            let group = &commands[i..(i + n).min(commands.len())];
+            if group
+                .iter()
+                .any(|(command, _)| written_at(command).is_some_and(|v| v > timestamp))
+            {
+                break 'segments; // 记录是按时间顺序追加的，后面的只会更晚
+            }
+            for (command, _) in group {
+                if let Command::SetBlob(_, blob, _) = command {
+                    snapshot::link_blob(archive, dest, *blob)?;
+                }
+                let (bytes, _) = segment::encode(command, Compression::None)?; // 压缩过的还是原样写
+                writer.append(&bytes[..])?;
+            }
+            i += n;
+        }
+    }
+    writer.sync()?;
+    fs::sync_dir(dest)
+}
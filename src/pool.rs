@@ -0,0 +1,101 @@
+//! 线程池，服务器拿它来跑每个连接，`AsyncAdapter` 拿它来跑同步的engine
+//!
+//! 三种实现可以换着用，跑benchmark的时候比一比
+
+use crate::KvsError;
+use crate::Result;
+
+use std::panic::AssertUnwindSafe;
+use std::sync::mpsc::channel;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+
+/// 活扔进去就不管了，拿不到返回值，要结果的话自己在活里面想办法传出来
+pub trait ThreadPool: Sized {
+    /// 开 `threads` 个线程，0个当1个。有的实现不会真的先开好
+    fn new(threads: usize) -> Result<Self>;
+
+    /// 活里面panic了不会把池子弄坏，后面的活照样有线程跑
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+}
+
+/// 其实不是池子，每个活新开一个线程，`threads` 没用。以前服务器就是这么干的
+#[derive(Clone)]
+pub struct NaiveThreadPool;
+
+impl ThreadPool for NaiveThreadPool {
+    fn new(_threads: usize) -> Result<Self> {
+        Ok(NaiveThreadPool)
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        thread::spawn(job); // panic了也只是这个线程没了
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// 固定几个线程抢一个channel里的活。clone出来的共用一组线程，最后一个clone没了线程才退出
+#[derive(Clone)]
+pub struct SharedQueueThreadPool {
+    sender: Sender<Job>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: usize) -> Result<Self> {
+        let (sender, receiver) = channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads.max(1) {
+            let receiver = receiver.clone();
+            thread::Builder::new().spawn(move || loop {
+                let job = receiver.lock().unwrap().recv(); // 拿到活就放开锁，别的线程才能接着拿
+                match job {
+                    // 活panic了的话把它接住，线程接着干活，不用再开一个
+                    Ok(job) => drop(std::panic::catch_unwind(AssertUnwindSafe(job))),
+                    Err(_) => return, // pool没了
+                }
+            })?;
+        }
+        Ok(Self { sender })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let _ = self.sender.send(Box::new(job)); // 线程都还在，发不出去是不可能的
+    }
+}
+
+/// 用rayon的线程池
+#[derive(Clone)]
+pub struct RayonThreadPool {
+    pool: Arc<rayon::ThreadPool>,
+}
+
+impl ThreadPool for RayonThreadPool {
+    fn new(threads: usize) -> Result<Self> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads.max(1))
+            .panic_handler(|_| {}) // 默认是直接abort整个进程
+            .build()
+            .map_err(|e| KvsError::Io(std::io::Error::other(e)))?;
+        Ok(Self {
+            pool: Arc::new(pool),
+        })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.pool.spawn(job);
+    }
+}
@@ -0,0 +1,90 @@
+use crate::KvsError;
+use crate::Result;
+
+use std::thread::sleep;
+use std::time::Duration;
+use std::time::Instant;
+
+/// 令牌用完了以后怎么办
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Throttle {
+    /// 睡到有令牌为止
+    Block,
+    /// 直接返回 `KvsError::RateLimited`
+    Fail,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    /// 每秒补充多少个令牌
+    pub ops_per_sec: f64,
+    /// 桶里最多攒多少个令牌，也就是允许多大的突发
+    pub burst: u32,
+    pub throttle: Throttle,
+}
+
+/// 经典令牌桶，不用后台线程补充令牌，每次取的时候按流逝的时间算一下补了多少
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// `ops_per_sec` 不是正数的话令牌永远补不上，`Block` 的时候算不出要睡多久
+    pub(crate) fn new(limit: RateLimit) -> Result<Self> {
+        if !(limit.ops_per_sec > 0.0 && limit.ops_per_sec.is_finite()) {
+            return Err(KvsError::InvalidArgument {
+                name: "ops_per_sec".to_string(),
+                value: limit.ops_per_sec.to_string(),
+            });
+        }
+        if limit.burst == 0 {
+            return Err(KvsError::InvalidArgument {
+                name: "burst".to_string(),
+                value: "0".to_string(),
+            });
+        }
+        Ok(Self {
+            limit,
+            tokens: limit.burst as f64, // 一开始桶是满的
+            last: Instant::now(),
+        })
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.limit.ops_per_sec).min(self.limit.burst as f64);
+        self.last = now;
+    }
+
+    /// 不管配置的是哪种throttle，拿不到就返回false
+    pub(crate) fn try_acquire(&mut self) -> bool {
+        self.refill();
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// 按照配置的throttle拿一个令牌
+    pub(crate) fn acquire(&mut self) -> Result<()> {
+        if self.try_acquire() {
+            return Ok(());
+        }
+        match self.limit.throttle {
+            Throttle::Fail => Err(KvsError::RateLimited),
+            Throttle::Block => {
+                let wait = (1.0 - self.tokens) / self.limit.ops_per_sec;
+                sleep(Duration::from_secs_f64(wait));
+                self.refill();
+                self.tokens -= 1.0; // 睡够了，肯定有了。浮点误差的话允许稍微欠一点
+                Ok(())
+            }
+        }
+    }
+}
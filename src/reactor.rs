@@ -0,0 +1,199 @@
+//! 用tokio收连接。空着不发请求的连接只是一个等着读的task，不占线程，上千个也没关系
+//!
+//! 请求那一行收齐了才把连接交给tokio的blocking线程，和 `run` 一样同步地处理，engine不用是异步的
+
+use crate::net::Socket;
+use crate::net::Stream;
+use crate::shutdown::Listener;
+use crate::wire;
+use crate::wire::Encoding;
+use crate::KvsEngine;
+use crate::KvsError;
+use crate::KvsServer;
+use crate::Protocol;
+use crate::Response;
+use crate::Result;
+use crate::Session;
+use crate::ThreadPool;
+
+use log::debug;
+use log::error;
+use log::warn;
+
+use std::future::Future;
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::net::ToSocketAddrs;
+use std::time::Duration;
+
+use tokio::io::AsyncBufRead;
+use tokio::io::AsyncBufReadExt;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tokio::io::BufReader;
+use tokio::net::TcpListener;
+use tokio::net::TcpStream;
+
+impl<T, P> KvsServer<T, P>
+where
+    T: KvsEngine + Clone,
+    P: ThreadPool + Clone + Send + 'static,
+{
+    /// 和 `run` 一样一直处理请求，不过是在自己开的tokio runtime上收连接。 `with_pool` 设的线程池不用，也不支持TLS
+    pub fn run_async<U>(&mut self, address: U) -> Result<()>
+    where
+        U: ToSocketAddrs,
+    {
+        self.plaintext()?;
+        let runtime = tokio::runtime::Builder::new_multi_thread()
+            .enable_all()
+            .build()?;
+        let addresses: Vec<SocketAddr> = address.to_socket_addrs()?.collect();
+        runtime.block_on(async {
+            let listener = TcpListener::bind(&addresses[..]).await?;
+            self.shutdown.listen(Listener::Tcp(listener.local_addr()?));
+            while !self.shutdown.is_shutting_down() {
+                self.wait_for_room(); // 连接都在别的线程上跑，这里堵一会儿没关系
+                match listener.accept().await {
+                    Ok(_) if self.shutdown.is_shutting_down() => break,
+                    Ok((stream, peer)) => {
+                        // 关连接要用std的socket，先拿一份
+                        let stream = stream.into_std()?;
+                        let socket = stream.try_clone()?;
+                        let stream = TcpStream::from_std(stream)?;
+                        let connection = self.shutdown.register(Socket::Tcp(socket));
+                        let server = self.clone();
+                        debug!("{} connected", peer);
+                        tokio::spawn(async move {
+                            let _connection = connection;
+                            match server.serve_async(stream).await {
+                                Ok(_) => debug!("{} disconnected", peer),
+                                Err(e) => warn!("{} disconnected: {}", peer, e),
+                            }
+                        });
+                    }
+                    Err(e) => error!("Failed to accept a connection: {}", e),
+                }
+            }
+            Ok::<_, KvsError>(())
+        })?;
+        self.finish() // runtime还在，连接还能接着处理
+    }
+
+    /// 等下一个请求的时候不占线程，请求收齐了再交给blocking线程处理，处理完了连接再拿回来
+    async fn serve_async(mut self, stream: TcpStream) -> Result<()> {
+        if self.protocol == Protocol::Resp {
+            // RESP的连接从头到尾占着一个blocking线程
+            let stream = stream.into_std()?;
+            stream.set_nonblocking(false)?;
+            let mut stream = Stream::Tcp(stream);
+            tokio::task::spawn_blocking(move || self.serve(&mut stream))
+                .await
+                .map_err(|e| KvsError::Io(std::io::Error::other(e)))??;
+            return Ok(());
+        }
+        let mut stream = stream;
+        let mut buffered = vec![]; // 已经收到了、还没处理的，比如流式的set读多了的value、连着发的下一个请求
+        let mut session = Session::default();
+        loop {
+            let mut reader = BufReader::new(Cursor::new(buffered).chain(stream));
+            match within(self.timeouts.idle, reader.fill_buf()).await {
+                Ok(Ok(buf)) if !buf.is_empty() => {}
+                Ok(Ok(_)) | Err(_) => break, // 客户端不发了，或者空闲太久了
+                Ok(Err(e)) => return Err(e.into()),
+            }
+            let frame = match within(
+                self.timeouts.read,
+                read_frame(&mut reader, session.encoding, self.config.max_request()),
+            )
+            .await?
+            {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(e @ KvsError::RequestTooLarge { .. }) => {
+                    let (_, mut tcp) = reader.into_inner().into_inner();
+                    tcp.write_all(&session.encoding.encode(&Response::failed(&e))?[..])
+                        .await?;
+                    break; // 和 `serve` 一样，接不上了
+                }
+                Err(e) => return Err(e),
+            };
+            let mut rest = reader.buffer().to_vec();
+            let (cursor, tcp) = reader.into_inner().into_inner();
+            rest.extend_from_slice(&cursor.get_ref()[cursor.position() as usize..]);
+            let tcp = tcp.into_std()?;
+            tcp.set_nonblocking(false)?;
+            tcp.set_read_timeout(self.timeouts.read)?; // 流式set的value
+            tcp.set_write_timeout(self.timeouts.write)?;
+
+            let (server, tcp, rest, state, open) =
+                tokio::task::spawn_blocking(move || -> Result<_> {
+                    let mut stream = Stream::Tcp(tcp.try_clone()?); // 原来的留着，处理完了还要还给tokio
+                    let mut reader = std::io::Read::chain(Cursor::new(rest), tcp.try_clone()?); // 和tokio的chain重名了
+                    let open = self.respond(&mut stream, &mut reader, &mut session, &frame[..])?;
+                    let (cursor, _) = reader.into_inner();
+                    let position = cursor.position() as usize;
+                    let rest = cursor.into_inner().split_off(position);
+                    Ok((self, tcp, rest, session, open))
+                })
+                .await
+                .map_err(|e| KvsError::Io(std::io::Error::other(e)))??; // panic了
+            if !open {
+                break;
+            }
+            self = server;
+            buffered = rest;
+            session = state;
+            tcp.set_nonblocking(true)?;
+            stream = TcpStream::from_std(tcp)?;
+        }
+        Ok(())
+    }
+}
+
+/// `timeout` 之内没做完的话是 `TimedOut` ，`None` 是一直等
+async fn within<F: Future>(timeout: Option<Duration>, future: F) -> std::io::Result<F::Output> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .map_err(|_| std::io::ErrorKind::TimedOut.into()),
+        None => Ok(future.await),
+    }
+}
+
+/// `Encoding::read_frame` 的异步版本
+async fn read_frame<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    encoding: Encoding,
+    limit: usize,
+) -> Result<Option<Vec<u8>>> {
+    match encoding {
+        Encoding::Json => {
+            let mut line = vec![];
+            let mut limited = reader.take((limit as u64).saturating_add(1));
+            match limited.read_until(b'\n', &mut line).await? {
+                0 => Ok(None),
+                n if n > limit && line.last() != Some(&b'\n') => Err(KvsError::RequestTooLarge {
+                    limit: limit as u64,
+                }),
+                _ => Ok(Some(line)),
+            }
+        }
+        Encoding::Bincode => {
+            let mut len = [0; 4];
+            if reader.read(&mut len[..1]).await? == 0 {
+                return Ok(None);
+            }
+            reader.read_exact(&mut len[1..]).await?;
+            let len = wire::frame_len(len)?;
+            if len > limit {
+                return Err(KvsError::RequestTooLarge {
+                    limit: limit as u64,
+                });
+            }
+            let mut payload = vec![0; len];
+            reader.read_exact(&mut payload[..]).await?;
+            Ok(Some(payload))
+        }
+    }
+}
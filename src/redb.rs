@@ -0,0 +1,644 @@
+//! 用redb存的engine。redb是一个B树，读不用拿锁，写事务同一时间只有一个，每个写事务可以单独选要不要fsync
+//!
+//! 和sled一样，value和元数据分开放在两张表里，元数据是json。每个写操作都是一个redb的写事务，改value和改元数据要么一起生效要么都没生效
+
+use crate::archive;
+use crate::batch;
+use crate::bytes;
+use crate::namespace;
+use crate::scan;
+use crate::txn;
+use crate::watch::Watchers;
+use crate::BatchOp;
+use crate::Durability;
+use crate::KeyFilter;
+use crate::KeyMeta;
+use crate::Keys;
+use crate::KvsEngine;
+use crate::KvsError;
+use crate::MergeOperator;
+use crate::Op;
+use crate::OpResult;
+use crate::Result;
+use crate::Scan;
+use crate::StoreStats;
+use crate::WatchEvent;
+use crate::Watcher;
+use crate::BULK_BATCH;
+
+use redb::CommitError;
+use redb::Database;
+use redb::DatabaseError;
+use redb::ReadableTable;
+use redb::ReadableTableMetadata;
+use redb::StorageError;
+use redb::Table;
+use redb::TableDefinition;
+use redb::TableError;
+use redb::TransactionError;
+use serde::Serialize;
+
+use std::collections::BTreeMap;
+use std::fs::create_dir_all;
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::MutexGuard;
+use std::time::Duration;
+use std::time::Instant;
+
+/// RedbKvsEngine的磁盘格式版本
+///
+/// - 1： `.kvs` 是json，数据在 `data.redb` 里， `data` 表放value， `meta` 表放json的元数据
+const REDB_FORMAT: u32 = 1;
+
+/// 目录里redb的数据库文件
+const FILE: &str = "data.redb";
+
+const DATA: TableDefinition<&[u8], &[u8]> = TableDefinition::new("data");
+const META: TableDefinition<&[u8], &[u8]> = TableDefinition::new("meta");
+
+/// 打开RedbKvsEngine时候的选项
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct RedbOptions {
+    /// 默认是 `Always` ，每个写事务commit的时候都fsync。 `Every` 和 `Never` 的写事务只交给操作系统，到时间了的那个写或者 `KvsEngine::flush` 才fsync
+    pub durability: Durability,
+    /// redb自己的page cache有多少字节， `None` 的话用redb的默认值
+    pub cache_size: Option<usize>,
+}
+
+impl Default for RedbOptions {
+    fn default() -> Self {
+        Self {
+            durability: Durability::Always,
+            cache_size: None,
+        }
+    }
+}
+
+/// redb的 `Database` 不能clone，clone出来的是同一个数据库、同一套订阅
+#[derive(Clone)]
+pub struct RedbKvsEngine {
+    db: Arc<Database>,
+    shared: Arc<Mutex<Shared>>,
+    path: PathBuf,
+    durability: Durability,
+    merge: Option<MergeOperator>,
+}
+
+/// 写的时候才要拿的锁。redb本来就一次只让一个写事务进去，拿着这把锁commit，发事件的顺序就和commit的顺序一样了
+struct Shared {
+    watchers: Watchers,
+    /// 上一次fsync的时间， `Every` 用的
+    synced: Instant,
+}
+
+impl RedbKvsEngine {
+    pub fn open<T>(root: T) -> Result<Self>
+    where
+        T: Into<PathBuf>,
+    {
+        Self::open_with(root, RedbOptions::default())
+    }
+
+    pub fn open_with<T>(root: T, options: RedbOptions) -> Result<Self>
+    where
+        T: Into<PathBuf>,
+    {
+        let root = root.into();
+        create_dir_all(&root)?;
+
+        archive::check(&root, "redb", REDB_FORMAT)?;
+
+        let path = root.join(FILE);
+        let mut builder = Database::builder();
+        if let Some(size) = options.cache_size {
+            builder.set_cache_size(size);
+        }
+        let db = match builder.create(&path) {
+            Ok(db) => db,
+            Err(DatabaseError::DatabaseAlreadyOpen) => return Err(KvsError::Locked { path: root }), // redb自己会给文件加锁
+            Err(e) => return Err(e.into()),
+        };
+
+        // 读事务打开不存在的表会报错，一开始就把两张表建好
+        let txn = db.begin_write()?;
+        txn.open_table(DATA)?;
+        txn.open_table(META)?;
+        txn.commit()?;
+        archive::write(&root, "redb", REDB_FORMAT, &options)?; // 拿到了文件锁再写，别的进程正开着的话不会把它的选项盖掉
+
+        Ok(Self {
+            db: Arc::new(db),
+            shared: Arc::new(Mutex::new(Shared {
+                watchers: Watchers::default(),
+                synced: Instant::now(),
+            })),
+            path,
+            durability: options.durability,
+            merge: None,
+        })
+    }
+
+    /// 和sled一样，每次打开都要重新设置。只管这一个handle，要在clone之前设置
+    pub fn set_merge_operator(&mut self, operator: MergeOperator) {
+        self.merge = Some(operator);
+    }
+
+    /// key数和数据库文件的大小，redb不告诉我们有多少是空着的
+    pub fn stats(&self) -> Result<StoreStats> {
+        Ok(StoreStats {
+            keys: self.len()?,
+            disk_bytes: std::fs::metadata(&self.path)?.len(),
+            ..Default::default()
+        })
+    }
+
+    fn shared(&self) -> MutexGuard<'_, Shared> {
+        self.shared.lock().unwrap()
+    }
+
+    /// 用打开时候的 `durability` 开一个写事务
+    fn write<T>(&self, f: impl FnOnce(&mut Tables<'_>) -> Result<T>) -> Result<T> {
+        self.write_as(self.durability, f)
+    }
+
+    /// `f` 返回 `Err` 的话事务直接扔掉，什么都没写。什么都没改的事务也扔掉，不用为它fsync
+    fn write_as<T>(
+        &self,
+        durability: Durability,
+        f: impl FnOnce(&mut Tables<'_>) -> Result<T>,
+    ) -> Result<T> {
+        let mut shared = self.shared();
+        let sync = match durability {
+            Durability::Always => true,
+            Durability::Every(interval) => shared.synced.elapsed() >= interval,
+            Durability::Never => false,
+        };
+        let mut txn = self.db.begin_write()?;
+        txn.set_durability(match sync {
+            true => redb::Durability::Immediate,
+            false => redb::Durability::Eventual, // 写进文件，但是不fsync
+        });
+        let (value, events, changed) = {
+            let mut tables = Tables {
+                data: txn.open_table(DATA)?,
+                meta: txn.open_table(META)?,
+                watchers: &shared.watchers,
+                events: vec![],
+                changed: false,
+            };
+            let value = f(&mut tables)?;
+            (value, tables.events, tables.changed)
+        };
+        if !changed {
+            txn.abort()?;
+            return Ok(value);
+        }
+        txn.commit()?;
+        if sync {
+            shared.synced = Instant::now();
+        }
+        for event in events {
+            shared.watchers.publish(event);
+        }
+        Ok(value)
+    }
+
+    /// 读事务里读value和元数据。过期了的话开一个写事务删掉，当不存在
+    fn read(&self, key: &[u8]) -> Result<Option<(Vec<u8>, KeyMeta)>> {
+        let txn = self.db.begin_read()?;
+        let value = match txn.open_table(DATA)?.get(key)? {
+            Some(value) => value.value().to_vec(),
+            None => return Ok(None),
+        };
+        let meta = match txn.open_table(META)?.get(key)? {
+            Some(meta) => serde_json::from_slice(meta.value())?,
+            None => KeyMeta {
+                size: value.len() as u64,
+                ..Default::default()
+            },
+        };
+        if meta.is_expired() {
+            self.write(|tables| tables.expire(key))?;
+            return Ok(None);
+        }
+        Ok(Some((value, meta)))
+    }
+}
+
+/// 一个写事务里打开的两张表，改了什么顺便记下要发的事件，commit了以后才发
+struct Tables<'txn> {
+    data: Table<'txn, &'static [u8], &'static [u8]>,
+    meta: Table<'txn, &'static [u8], &'static [u8]>,
+    watchers: &'txn Watchers,
+    events: Vec<WatchEvent>,
+    changed: bool,
+}
+
+impl Tables<'_> {
+    fn meta(&self, key: &[u8]) -> Result<Option<KeyMeta>> {
+        match self.meta.get(key)? {
+            Some(meta) => Ok(Some(serde_json::from_slice(meta.value())?)),
+            None => Ok(None),
+        }
+    }
+
+    /// 过期了的key碰到的时候再删
+    fn expire(&mut self, key: &[u8]) -> Result<()> {
+        if self.meta(key)?.is_some_and(|meta| meta.is_expired()) {
+            self.data.remove(key)?;
+            self.meta.remove(key)?;
+            self.changed = true;
+            self.notify(key, None);
+        }
+        Ok(())
+    }
+
+    fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.expire(key)?;
+        Ok(self.data.get(key)?.map(|value| value.value().to_vec()))
+    }
+
+    /// 普通的set，版本号接着上一次的算
+    fn set(&mut self, key: &[u8], value: &[u8], ttl: Option<Duration>) -> Result<()> {
+        self.expire(key)?;
+        let mut meta = KeyMeta::next(self.meta(key)?.as_ref(), value.len());
+        if let Some(ttl) = ttl {
+            meta = meta.with_ttl(ttl);
+        }
+        self.put(key, value, &meta)
+    }
+
+    fn put(&mut self, key: &[u8], value: &[u8], meta: &KeyMeta) -> Result<()> {
+        self.data.insert(key, value)?;
+        self.meta.insert(key, &serde_json::to_vec(meta)?[..])?;
+        self.changed = true;
+        self.notify(key, Some(value));
+        Ok(())
+    }
+
+    /// 删掉，返回删之前的value和元数据
+    fn take(&mut self, key: &[u8]) -> Result<Option<(Vec<u8>, Option<KeyMeta>)>> {
+        self.expire(key)?;
+        let meta = self.meta(key)?;
+        let value = match self.data.remove(key)? {
+            Some(value) => value.value().to_vec(),
+            None => return Ok(None),
+        };
+        self.meta.remove(key)?;
+        self.changed = true;
+        self.notify(key, None);
+        Ok(Some((value, meta)))
+    }
+
+    fn remove(&mut self, key: &[u8]) -> Result<()> {
+        match self.take(key)? {
+            Some(_) => Ok(()),
+            None => Err(KvsError::NotFound {
+                key: bytes::lossy(key),
+            }),
+        }
+    }
+
+    /// 和MemEngine一样，有人在看才拷一份
+    fn notify(&mut self, key: &[u8], value: Option<&[u8]>) {
+        if !self.watchers.watching(key) {
+            return;
+        }
+        self.events.push(match value {
+            Some(value) => WatchEvent::Set {
+                key: key.to_vec(),
+                value: value.to_vec(),
+            },
+            None => WatchEvent::Remove { key: key.to_vec() },
+        });
+    }
+}
+
+/// 读事务里的元数据表，scan的时候要跟着迭代器走
+fn is_expired(
+    meta: &redb::ReadOnlyTable<&'static [u8], &'static [u8]>,
+    key: &[u8],
+) -> Result<bool> {
+    match meta.get(key)? {
+        Some(meta) => Ok(serde_json::from_slice::<KeyMeta>(meta.value())?.is_expired()),
+        None => Ok(false),
+    }
+}
+
+impl KvsEngine for RedbKvsEngine {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        self.get_bytes(key.as_bytes())?
+            .map(|v| bytes::into_string(key.as_bytes(), v))
+            .transpose()
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self.read(key)?.map(|(value, _)| value))
+    }
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write(|tables| tables.set(&key[..], &value[..], None))
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.write(|tables| tables.set(&key[..], &value[..], Some(ttl)))
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        self.write(|tables| tables.remove(key))
+    }
+
+    /// 读、接、写在一个写事务里，别人的写插不进来
+    fn append(&self, key: &str, suffix: &str) -> Result<usize> {
+        self.write(|tables| {
+            let mut value = tables.get(key.as_bytes())?.unwrap_or_default();
+            value.extend_from_slice(suffix.as_bytes());
+            tables.set(key.as_bytes(), &value[..], None)?;
+            Ok(value.len())
+        })
+    }
+
+    fn merge(&self, key: &[u8], operand: Vec<u8>) -> Result<()> {
+        let operator = self.merge.clone().ok_or(KvsError::NoMergeOperator)?;
+        self.write(|tables| {
+            let existing = tables.get(key)?;
+            let value = operator.apply(key, existing.as_deref(), &operand[..]);
+            let previous = tables.meta(key)?;
+            let meta = KeyMeta {
+                expires: previous.map_or(0, |v| v.expires),
+                ..KeyMeta::next(previous.as_ref(), value.len())
+            };
+            tables.put(key, &value[..], &meta)
+        })
+    }
+
+    /// 开一个空的写事务，commit的时候fsync，前面没fsync的写也一起落到磁盘上了
+    fn flush(&self) -> Result<()> {
+        let mut shared = self.shared();
+        let mut txn = self.db.begin_write()?;
+        txn.set_durability(redb::Durability::Immediate);
+        txn.commit()?;
+        shared.synced = Instant::now();
+        Ok(())
+    }
+
+    fn engine_name(&self) -> &'static str {
+        "redb"
+    }
+
+    fn stats(&self) -> Result<StoreStats> {
+        RedbKvsEngine::stats(self)
+    }
+
+    fn getdel(&self, key: &str) -> Result<Option<String>> {
+        self.write(|tables| match tables.get(key.as_bytes())? {
+            Some(value) => {
+                let value = bytes::into_string(key.as_bytes(), value)?; // 不是utf8的话不删
+                tables.remove(key.as_bytes())?;
+                Ok(Some(value))
+            }
+            None => Ok(None),
+        })
+    }
+
+    fn getset(&self, key: String, value: String) -> Result<Option<String>> {
+        self.write(|tables| {
+            let previous = tables
+                .get(key.as_bytes())?
+                .map(|v| bytes::into_string(key.as_bytes(), v))
+                .transpose()?;
+            tables.set(key.as_bytes(), value.as_bytes(), None)?;
+            Ok(previous)
+        })
+    }
+
+    /// 整个挪过去，元数据原样保留
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        self.write(|tables| {
+            let (value, meta) =
+                tables
+                    .take(from.as_bytes())?
+                    .ok_or_else(|| KvsError::NotFound {
+                        key: from.to_string(),
+                    })?;
+            let meta = meta.unwrap_or_else(|| KeyMeta::next(None, value.len()));
+            tables.put(to.as_bytes(), &value[..], &meta)
+        })
+    }
+
+    fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: Option<&str>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.write(|tables| {
+            if tables.get(key.as_bytes())?.as_deref() != expected.map(|v| v.as_bytes()) {
+                return Ok(false);
+            }
+            match &new {
+                Some(value) => tables.set(key.as_bytes(), value.as_bytes(), None)?,
+                None => {
+                    tables.take(key.as_bytes())?;
+                }
+            }
+            Ok(true)
+        })
+    }
+
+    /// 一个写事务里一个一个写，remove了不存在的key的话整个事务扔掉
+    fn write_batch(&self, ops: Vec<BatchOp>) -> Result<()> {
+        self.write(|tables| {
+            for op in &ops {
+                match op {
+                    BatchOp::Set(key, value) => tables.set(&key[..], &value[..], None)?,
+                    BatchOp::Remove(key) => tables.remove(&key[..])?,
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// 对value和写在同一个写事务里，对完到写下去之间插不进别人的写
+    fn write_batch_if(
+        &self,
+        expected: &BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+        ops: Vec<BatchOp>,
+    ) -> Result<()> {
+        self.write(|tables| {
+            txn::check(expected, |key| tables.get(key))?;
+            for op in &ops {
+                match op {
+                    BatchOp::Set(key, value) => tables.set(&key[..], &value[..], None)?,
+                    BatchOp::Remove(key) => tables.remove(&key[..])?,
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn apply(&self, ops: &[Op]) -> Result<Vec<OpResult>> {
+        batch::apply_as_batch(self, ops)
+    }
+
+    /// namespace里的留下
+    fn clear(&self) -> Result<()> {
+        self.write(|tables| {
+            let keys = tables
+                .data
+                .iter()?
+                .map(|entry| Ok(entry?.0.value().to_vec()))
+                .filter(|key| {
+                    key.as_ref()
+                        .map_or(true, |key| !namespace::is_namespaced(key))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            for key in keys {
+                tables.take(&key[..])?;
+            }
+            Ok(())
+        })
+    }
+
+    fn metadata(&self, key: &str) -> Result<Option<KeyMeta>> {
+        Ok(self.read(key.as_bytes())?.map(|(_, meta)| meta))
+    }
+
+    /// glob开头没有通配符的那一段拿来当前缀，只扫B树上的一段
+    fn scan_matching(&self, filter: &KeyFilter) -> Result<Vec<(String, String)>> {
+        let matcher = filter.compile()?;
+        let mut pairs = vec![];
+        for entry in self.scan_prefix(filter.literal_prefix().as_bytes())? {
+            let (key, value) = entry?;
+            let key = match String::from_utf8(key) {
+                Ok(key) => key,
+                Err(_) => continue, // 不是utf8的key没法匹配
+            };
+            if matcher.matches(&key[..]) && !namespace::is_namespaced(key.as_bytes()) {
+                let value = bytes::into_string(key.as_bytes(), value)?;
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    /// 读事务里的 `Range` 自己拿着事务，可以一边扫一边给，不用一次全拷出来
+    fn scan_bounds(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Result<Scan<'_>> {
+        if scan::is_empty(start, end) {
+            return Ok(Box::new(std::iter::empty()));
+        }
+        let txn = self.db.begin_read()?;
+        let meta = txn.open_table(META)?;
+        let iter = txn
+            .open_table(DATA)?
+            .range::<&[u8]>((start, end))?
+            .filter_map(move |entry| {
+                let (key, value) = match entry {
+                    Ok(entry) => entry,
+                    Err(e) => return Some(Err(e.into())),
+                };
+                match is_expired(&meta, key.value()) {
+                    Ok(true) => None, // 扫的时候只跳过，不删
+                    Ok(false) => Some(Ok((key.value().to_vec(), value.value().to_vec()))),
+                    Err(e) => Some(Err(e)),
+                }
+            });
+        Ok(Box::new(iter))
+    }
+
+    fn watch(&self, prefix: &[u8]) -> Result<Watcher> {
+        Ok(self.shared().watchers.add(prefix))
+    }
+
+    fn keys(&self) -> Result<Keys<'_>> {
+        let txn = self.db.begin_read()?;
+        let meta = txn.open_table(META)?;
+        let iter = txn
+            .open_table(DATA)?
+            .range::<&[u8]>(..)?
+            .filter_map(move |entry| {
+                let key = match entry {
+                    Ok((key, _)) => key.value().to_vec(),
+                    Err(e) => return Some(Err(e.into())),
+                };
+                if namespace::is_namespaced(&key[..]) {
+                    return None;
+                }
+                match is_expired(&meta, &key[..]) {
+                    Ok(true) => None,
+                    Ok(false) => Some(Ok(key)),
+                    Err(e) => Some(Err(e)),
+                }
+            });
+        Ok(Box::new(iter))
+    }
+
+    /// redb自己记着表里有多少个key，减掉过期了还没来得及删的，再减掉namespace里的
+    fn len(&self) -> Result<usize> {
+        let txn = self.db.begin_read()?;
+        let mut expired = 0;
+        for entry in txn.open_table(META)?.range::<&[u8]>(..)? {
+            let (key, meta) = entry?;
+            let meta: KeyMeta = serde_json::from_slice(meta.value())?;
+            if meta.is_expired() && !namespace::is_namespaced(key.value()) {
+                expired += 1;
+            }
+        }
+        let data = txn.open_table(DATA)?;
+        let namespaced = data.range::<&[u8]>(&[0][..]..&[1][..])?.count();
+        Ok(data.len()? as usize - expired - namespaced)
+    }
+
+    /// 每一批一个写事务，中间的都不fsync，最后按 `durability` 来
+    fn bulk_load_iter(&self, pairs: &mut dyn Iterator<Item = (String, String)>) -> Result<usize> {
+        let mut count = 0;
+        let mut pairs = pairs.peekable();
+        while pairs.peek().is_some() {
+            self.write_as(Durability::Never, |tables| {
+                for (key, value) in pairs.by_ref().take(BULK_BATCH) {
+                    tables.set(key.as_bytes(), value.as_bytes(), None)?;
+                    count += 1;
+                }
+                Ok(())
+            })?;
+        }
+        if self.durability == Durability::Always {
+            self.flush()?;
+        }
+        Ok(count)
+    }
+}
+
+// redb每一步的错误类型都不一样，全都转成 `redb::Error` 放进 `KvsError::Redb`
+impl From<DatabaseError> for KvsError {
+    fn from(error: DatabaseError) -> Self {
+        KvsError::Redb(Box::new(error.into()))
+    }
+}
+
+impl From<TransactionError> for KvsError {
+    fn from(error: TransactionError) -> Self {
+        KvsError::Redb(Box::new(error.into()))
+    }
+}
+
+impl From<TableError> for KvsError {
+    fn from(error: TableError) -> Self {
+        KvsError::Redb(Box::new(error.into()))
+    }
+}
+
+impl From<StorageError> for KvsError {
+    fn from(error: StorageError) -> Self {
+        KvsError::Redb(Box::new(error.into()))
+    }
+}
+
+impl From<CommitError> for KvsError {
+    fn from(error: CommitError) -> Self {
+        KvsError::Redb(Box::new(error.into()))
+    }
+}
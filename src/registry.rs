@@ -0,0 +1,75 @@
+//! 按名字打开engine，比如 `kvs::open_engine("sled", dir)` 。别的crate可以用 `register_engine` 加自己的engine进来
+//!
+//! 一开始就有 `kvs` 、 `sled` 、 `redb` （开了对应的feature的话）、 `memory` ，都是用默认选项打开的
+
+use crate::EngineExt;
+use crate::KvStore;
+use crate::KvsEngine;
+use crate::KvsError;
+use crate::MemEngine;
+#[cfg(feature = "redb")]
+use crate::RedbKvsEngine;
+use crate::Result;
+#[cfg(feature = "sled")]
+use crate::SledKvsEngine;
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::OnceLock;
+
+type Factory = Arc<dyn Fn(&Path) -> Result<Box<dyn KvsEngine>> + Send + Sync>;
+
+fn registry() -> &'static Mutex<BTreeMap<String, Factory>> {
+    static REGISTRY: OnceLock<Mutex<BTreeMap<String, Factory>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        let mut factories: BTreeMap<String, Factory> = BTreeMap::new();
+        factories.insert(
+            "kvs".to_string(),
+            Arc::new(|path| Ok(KvStore::open(path)?.boxed())),
+        );
+        #[cfg(feature = "sled")]
+        factories.insert(
+            "sled".to_string(),
+            Arc::new(|path| Ok(SledKvsEngine::open(path)?.boxed())),
+        );
+        #[cfg(feature = "redb")]
+        factories.insert(
+            "redb".to_string(),
+            Arc::new(|path| Ok(RedbKvsEngine::open(path)?.boxed())),
+        );
+        factories.insert(
+            "memory".to_string(),
+            Arc::new(|_| Ok(MemEngine::new().boxed())), // 用不着目录
+        );
+        Mutex::new(factories)
+    })
+}
+
+/// 以后 `open_engine(name, ..)` 就用 `factory` 打开。已经有叫这个名字的话会被换掉，自带的也一样
+pub fn register_engine<F>(name: &str, factory: F)
+where
+    F: Fn(&Path) -> Result<Box<dyn KvsEngine>> + Send + Sync + 'static,
+{
+    registry()
+        .lock()
+        .unwrap()
+        .insert(name.to_string(), Arc::new(factory));
+}
+
+/// 在 `path` 上打开叫 `name` 的engine，没有这个名字的话报 `UnsupportedEngine`
+pub fn open_engine<P: AsRef<Path>>(name: &str, path: P) -> Result<Box<dyn KvsEngine>> {
+    let factory = registry().lock().unwrap().get(name).cloned();
+    match factory {
+        Some(factory) => factory(path.as_ref()), // 打开可能很慢，别拿着锁
+        None => Err(KvsError::UnsupportedEngine {
+            name: name.to_string(),
+        }),
+    }
+}
+
+/// 现在能打开的所有engine的名字，排好序的
+pub fn engine_names() -> Vec<String> {
+    registry().lock().unwrap().keys().cloned().collect()
+}
@@ -0,0 +1,149 @@
+//! 不重启、不断连接地换掉一部分配置：账号、ACL、TLS证书、key和value的大小限制、一个请求最多多大
+//!
+//! 换的时候已经在处理的请求用的还是旧的，下一个请求就用新的了。TLS只影响新的连接，已经握过手的连接不受影响
+//!
+//! 日志的级别是整个进程的，不归server管，`kvs-server` 在自己的reloader里换
+
+use crate::wire;
+use crate::Accounts;
+use crate::Acl;
+use crate::KvsEngine;
+use crate::KvsError;
+use crate::Result;
+use crate::SizeLimitedEngine;
+use crate::SizeLimits;
+
+#[cfg(feature = "tls")]
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::RwLock;
+
+type Reloader = Box<dyn FnMut(&ReloadHandle) -> Result<()> + Send>;
+
+/// `KvsServer::reload_handle` 拿到的，clone出来的和server用的都是同一份配置
+#[derive(Clone, Default)]
+pub struct ReloadHandle {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    accounts: RwLock<Option<Arc<Accounts>>>,
+    acl: RwLock<Option<Arc<Acl>>>,
+    #[cfg(feature = "tls")]
+    tls: RwLock<Option<Arc<rustls::ServerConfig>>>,
+    /// `manage_limits` 给的engine上的限制，和engine共用一份
+    limits: RwLock<Option<Arc<RwLock<SizeLimits>>>>,
+    /// `None` 是默认的 `wire::MAX_REQUEST`
+    max_request: RwLock<Option<usize>>,
+    /// `reload` 的时候调的，从哪里重新读配置只有它知道
+    reloader: Mutex<Option<Reloader>>,
+}
+
+impl ReloadHandle {
+    /// 换掉登录用的账号。已经登录了的连接不用重新登录
+    pub fn set_accounts(&self, accounts: Accounts) {
+        *self.inner.accounts.write().unwrap() = Some(Arc::new(accounts));
+    }
+
+    pub fn set_acl(&self, acl: Acl) {
+        *self.inner.acl.write().unwrap() = Some(Arc::new(acl));
+    }
+
+    /// 换证书，一般是证书快过期了。本来没开TLS的server不能这样开
+    #[cfg(feature = "tls")]
+    pub fn set_tls<C, K>(&self, cert: C, key: K) -> Result<()>
+    where
+        C: AsRef<Path>,
+        K: AsRef<Path>,
+    {
+        let config = crate::tls::server_config(cert.as_ref(), key.as_ref())?; // 读不出来的话还用旧的
+        let mut tls = self.inner.tls.write().unwrap();
+        if tls.is_none() {
+            return Err(KvsError::InvalidArgument {
+                name: "tls".to_string(),
+                value: "the server was not started with TLS".to_string(),
+            });
+        }
+        *tls = Some(config);
+        Ok(())
+    }
+
+    /// 以后 `set_limits` 换的是 `engine` 的限制。server拿到的engine外面一般还套着别的几层，找不到它，只好一开始交给这里
+    pub fn manage_limits<E: KvsEngine>(&self, engine: &SizeLimitedEngine<E>) {
+        *self.inner.limits.write().unwrap() = Some(engine.shared_limits());
+    }
+
+    /// 换key和value的大小限制，下一个写就按新的来，已经写进去的不管。没有 `manage_limits` 过的话报 `InvalidArgument`
+    pub fn set_limits(&self, limits: SizeLimits) -> Result<()> {
+        match &*self.inner.limits.read().unwrap() {
+            Some(shared) => {
+                *shared.write().unwrap() = limits;
+                Ok(())
+            }
+            None => Err(KvsError::InvalidArgument {
+                name: "limits".to_string(),
+                value: "the engine is not size limited".to_string(),
+            }),
+        }
+    }
+
+    /// 现在的大小限制，没有 `manage_limits` 过的话是 `None`
+    pub fn limits(&self) -> Option<SizeLimits> {
+        self.inner
+            .limits
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|shared| *shared.read().unwrap())
+    }
+
+    /// 一个请求最多 `bytes` 个字节，见 `KvsServer::max_request` 。已经连上的连接下一个请求就按新的读
+    pub fn set_max_request(&self, bytes: usize) {
+        *self.inner.max_request.write().unwrap() = Some(bytes);
+    }
+
+    pub fn max_request(&self) -> usize {
+        self.inner
+            .max_request
+            .read()
+            .unwrap()
+            .unwrap_or(wire::MAX_REQUEST)
+    }
+
+    /// 调 `KvsServer::on_reload` 给的函数，没给的话什么都不做。客户端发来的 `KvsClient::reload` 也是走这里
+    pub fn reload(&self) -> Result<()> {
+        match &mut *self.inner.reloader.lock().unwrap() {
+            Some(reloader) => reloader(self),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn is_reloadable(&self) -> bool {
+        self.inner.reloader.lock().unwrap().is_some()
+    }
+
+    pub(crate) fn on_reload(&self, reloader: Reloader) {
+        *self.inner.reloader.lock().unwrap() = Some(reloader);
+    }
+
+    pub(crate) fn accounts(&self) -> Option<Arc<Accounts>> {
+        self.inner.accounts.read().unwrap().clone()
+    }
+
+    pub(crate) fn acl(&self) -> Option<Arc<Acl>> {
+        self.inner.acl.read().unwrap().clone()
+    }
+
+    #[cfg(feature = "tls")]
+    pub(crate) fn tls(&self) -> Option<Arc<rustls::ServerConfig>> {
+        self.inner.tls.read().unwrap().clone()
+    }
+
+    /// 只有 `KvsServer::tls` 用，别的地方只能换不能开
+    #[cfg(feature = "tls")]
+    pub(crate) fn enable_tls(&self, config: Arc<rustls::ServerConfig>) {
+        *self.inner.tls.write().unwrap() = Some(config);
+    }
+}
@@ -0,0 +1,516 @@
+//! Redis的RESP协议，够redis-cli和一般的Redis客户端库做最基本的事：PING、GET、SET、DEL、EXISTS
+//!
+//! 一个连接上一条命令一条回复，和Redis一样。redis-cli连上会先发 `COMMAND DOCS` ，回个空数组它就不问了
+//!
+//! 服务器要求认证的话先 `AUTH password` （ `DEFAULT_USER` ）或者 `AUTH user password` ，之前只能PING。
+//! 设了ACL的话碰不了的key回 `NOPERM`
+//!
+//! 开了审计日志的话成功了的SET和DEL也会记下来
+//!
+//! 开了MONITOR的话执行成功了的命令也会发给订阅者。 `MONITOR token` 订阅，回 `+OK` 以后收到的是和 `KvsClient::monitor` 一样的一行一行json，不是Redis的格式
+
+use crate::audit::AuditLog;
+use crate::monitor;
+use crate::monitor::Subscribers;
+use crate::net::Stream;
+use crate::now_millis;
+use crate::Access;
+use crate::Accounts;
+use crate::Acl;
+use crate::AuditEntry;
+use crate::Credentials;
+use crate::KvsEngine;
+use crate::KvsError;
+use crate::MonitorEvent;
+use crate::ReloadHandle;
+use crate::Result;
+use crate::Timeouts;
+use crate::DEFAULT_USER;
+
+use log::error;
+use log::warn;
+
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::Read;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+/// 一个bulk string最多这么长，和Redis的 `proto-max-bulk-len` 一样
+const MAX_BULK: usize = 512 << 20;
+
+/// 一直处理命令，直到客户端断开或者发 `QUIT`
+///
+/// 账号和ACL每条命令都重新拿一次，`ReloadHandle` 换了的话下一条命令就用新的
+pub(crate) fn serve<E: KvsEngine>(
+    engine: &E,
+    config: &ReloadHandle,
+    audit: Option<&Mutex<AuditLog>>,
+    subscribers: Option<&Mutex<Subscribers>>,
+    slow_request: Option<Duration>,
+    timeouts: Timeouts,
+    stream: &mut Stream,
+) -> Result<()> {
+    timeouts.apply(stream)?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut user = None;
+    loop {
+        if !timeouts.wait_for_request(stream, &mut reader)? {
+            return Ok(());
+        }
+        // 多给一个字节，读到了就是超了
+        let max_request = config.max_request();
+        let mut limited = (&mut reader).take((max_request as u64).saturating_add(1));
+        let command = read_command(&mut limited, max_request);
+        if limited.limit() == 0 || matches!(command, Err(KvsError::RequestTooLarge { .. })) {
+            let mut reply = vec![];
+            error(&mut reply, "Protocol error: request too large");
+            stream.write_all(&reply[..])?;
+            return Ok(()); // 和Redis一样，协议错了就断开
+        }
+        let command = match command? {
+            Some(command) => command,
+            None => return Ok(()),
+        };
+        if command.is_empty() {
+            continue; // 空行
+        }
+        let started = Instant::now();
+        let mut reply = vec![];
+        let accounts = config.accounts();
+        let acl = config.acl();
+        let name = command[0].to_ascii_uppercase();
+        let quit = match &name[..] {
+            b"AUTH" => {
+                if let Some(v) = auth(accounts.as_deref(), &command[1..], &mut reply) {
+                    user = Some(v); // 输错了之前登录的还算，和Redis一样
+                }
+                false
+            }
+            b"PING" | b"QUIT" => {
+                let quit = execute(engine, &command[..], &mut reply, &mut vec![]);
+                publish(subscribers, stream, &command[..], &reply[..])?;
+                quit
+            }
+            _ if accounts.is_some() && user.is_none() => {
+                fail(&mut reply, "NOAUTH", "Authentication required.");
+                false
+            }
+            b"MONITOR" => subscribe(subscribers, stream, &command[1..])?, // 订阅上了的话这个连接以后只用来发事件了
+            _ if !permitted(acl.as_deref(), user.as_deref(), &command[..]) => {
+                fail(
+                    &mut reply,
+                    "NOPERM",
+                    "this user has no permissions to access one of the keys used as arguments",
+                );
+                false
+            }
+            _ => {
+                let mut written = vec![];
+                let quit = execute(engine, &command[..], &mut reply, &mut written);
+                if let Some(audit) = audit {
+                    record(audit, stream, user.as_deref(), &command[..], &written[..]);
+                }
+                publish(subscribers, stream, &command[..], &reply[..])?;
+                quit
+            }
+        };
+        stream.write_all(&reply[..])?;
+        if let Some(threshold) = slow_request {
+            let elapsed = started.elapsed();
+            if elapsed >= threshold {
+                warn!(
+                    "Slow request from {}: {} {} took {:?}",
+                    stream.peer(),
+                    String::from_utf8_lossy(&name).to_ascii_lowercase(),
+                    command
+                        .get(1)
+                        .map_or("".into(), |v| String::from_utf8_lossy(v)),
+                    elapsed
+                );
+            }
+        }
+        if quit {
+            return Ok(());
+        }
+    }
+}
+
+/// 返回要不要断开。真的写了或者删了的key放进 `written` ，审计日志只记这些
+fn execute<E: KvsEngine>(
+    engine: &E,
+    command: &[Vec<u8>],
+    reply: &mut Vec<u8>,
+    written: &mut Vec<Vec<u8>>,
+) -> bool {
+    let name = String::from_utf8_lossy(&command[0][..]).to_ascii_uppercase();
+    let args = &command[1..];
+    let result = match (&name[..], args.len()) {
+        ("PING", 0) => {
+            simple(reply, "PONG");
+            Ok(())
+        }
+        ("PING", 1) => {
+            bulk(reply, Some(&args[0][..]));
+            Ok(())
+        }
+        ("GET", 1) => engine.get_bytes(&args[0][..]).map(|value| {
+            bulk(reply, value.as_deref());
+        }),
+        ("SET", n) if n >= 2 => set(engine, args).map(|_| {
+            written.push(args[0].clone());
+            simple(reply, "OK")
+        }),
+        ("DEL", n) if n >= 1 => count(args, |key| match engine.remove_bytes(key) {
+            Ok(_) => {
+                written.push(key.to_vec());
+                Ok(true)
+            }
+            Err(KvsError::NotFound { .. }) => Ok(false),
+            Err(e) => Err(e),
+        })
+        .map(|n| integer(reply, n)),
+        ("EXISTS", n) if n >= 1 => {
+            count(args, |key| Ok(engine.get_bytes(key)?.is_some())).map(|n| integer(reply, n))
+        }
+        ("COMMAND", _) => {
+            reply.extend_from_slice(b"*0\r\n");
+            Ok(())
+        }
+        ("QUIT", _) => {
+            simple(reply, "OK");
+            return true;
+        }
+        ("PING", _) | ("GET", _) | ("SET", _) | ("DEL", _) | ("EXISTS", _) => {
+            error(
+                reply,
+                &format!(
+                    "wrong number of arguments for '{}' command",
+                    name.to_ascii_lowercase()
+                ),
+            );
+            Ok(())
+        }
+        _ => {
+            error(
+                reply,
+                &format!(
+                    "unknown command '{}'",
+                    String::from_utf8_lossy(&command[0][..])
+                ),
+            );
+            Ok(())
+        }
+    };
+    match result {
+        Ok(_) => {}
+        Err(KvsError::InvalidArgument { value, .. }) => error(reply, &value[..]), // 命令写错了，`value` 就是要回的话
+        Err(e) => error(reply, &format!("{}", e)),
+    }
+    false
+}
+
+/// 成功了的SET和DEL记到审计日志里，DEL只记真的删掉了的key
+fn record(
+    audit: &Mutex<AuditLog>,
+    stream: &Stream,
+    user: Option<&str>,
+    command: &[Vec<u8>],
+    written: &[Vec<u8>],
+) {
+    if written.is_empty() {
+        return; // 出错了，或者DEL的key都不存在
+    }
+    let mut audit = audit.lock().unwrap();
+    let (op, value) = match &command[0].to_ascii_uppercase()[..] {
+        b"SET" => ("set", audit.values().then(|| &command[2])),
+        b"DEL" => ("remove", None),
+        _ => return,
+    };
+    let peer = stream.peer();
+    for key in written {
+        let entry = AuditEntry {
+            timestamp: now_millis(),
+            peer: peer.clone(),
+            user: user.map(str::to_string),
+            op: op.to_string(),
+            key: Some(String::from_utf8_lossy(key).into_owned()),
+            value: value.map(|v| String::from_utf8_lossy(v).into_owned()),
+        };
+        if let Err(e) = audit.record(&entry) {
+            error!("Failed to write the audit log: {}", e);
+        }
+    }
+}
+
+/// 命令名小写了当op，GET、SET、DEL、EXISTS带上第一个key。回的是错误的话不发
+fn publish(
+    subscribers: Option<&Mutex<Subscribers>>,
+    stream: &Stream,
+    command: &[Vec<u8>],
+    reply: &[u8],
+) -> Result<()> {
+    if reply.starts_with(b"-") {
+        return Ok(());
+    }
+    monitor::publish(subscribers, || {
+        let op = String::from_utf8_lossy(&command[0][..]).to_ascii_lowercase();
+        let key = match &op[..] {
+            "get" | "set" | "del" | "exists" => command
+                .get(1)
+                .map(|v| String::from_utf8_lossy(v).into_owned()),
+            _ => None,
+        };
+        MonitorEvent {
+            timestamp: now_millis(),
+            peer: stream.peer(),
+            op,
+            key,
+        }
+    })
+}
+
+/// `MONITOR token` ，回话直接写到 `stream` 里。返回订阅上了没有
+fn subscribe(
+    subscribers: Option<&Mutex<Subscribers>>,
+    stream: &mut Stream,
+    args: &[Vec<u8>],
+) -> Result<bool> {
+    // 回完话、加进去之前一直拿着锁，和 `KvsServer` 那边一样
+    let mut subscribers = subscribers.map(|v| v.lock().unwrap());
+    let mut reply = vec![];
+    let subscribed = match (&subscribers, args) {
+        (None, [_]) => {
+            error(&mut reply, "MONITOR is not enabled on this server");
+            false
+        }
+        (Some(v), [token]) if !v.authorized(&String::from_utf8_lossy(token)) => {
+            fail(&mut reply, "NOPERM", "MONITOR permission denied");
+            false
+        }
+        (Some(_), [_]) => {
+            simple(&mut reply, "OK");
+            true
+        }
+        _ => {
+            error(
+                &mut reply,
+                "wrong number of arguments for 'monitor' command",
+            );
+            false
+        }
+    };
+    stream.write_all(&reply[..])?;
+    if let (true, Some(subscribers)) = (subscribed, &mut subscribers) {
+        subscribers.add(stream.try_clone()?)?;
+    }
+    Ok(subscribed)
+}
+
+/// `AUTH [user] password` ，登录成功了的话返回用户名
+fn auth(accounts: Option<&Accounts>, args: &[Vec<u8>], reply: &mut Vec<u8>) -> Option<String> {
+    let credentials = match args {
+        [password] => Credentials::new(DEFAULT_USER, &String::from_utf8_lossy(password)),
+        [user, password] => Credentials::new(
+            &String::from_utf8_lossy(user),
+            &String::from_utf8_lossy(password),
+        ),
+        _ => {
+            error(reply, "wrong number of arguments for 'auth' command");
+            return None;
+        }
+    };
+    match accounts {
+        None => {
+            error(
+                reply,
+                "AUTH called without any password configured for the default user",
+            );
+            None
+        }
+        Some(accounts) if accounts.verify(&credentials) => {
+            simple(reply, "OK");
+            Some(credentials.user)
+        }
+        Some(_) => {
+            fail(
+                reply,
+                "WRONGPASS",
+                "invalid username-password pair or user is disabled.",
+            );
+            None
+        }
+    }
+}
+
+/// 和 `Request::permitted` 一样，参数不对的命令让 `execute` 去报错
+fn permitted(acl: Option<&Acl>, user: Option<&str>, command: &[Vec<u8>]) -> bool {
+    let (acl, user) = match (acl, user) {
+        (None, _) => return true,
+        (Some(_), None) => return false,
+        (Some(acl), Some(user)) => (acl, user),
+    };
+    let args = &command[1..];
+    let (keys, access) = match &command[0].to_ascii_uppercase()[..] {
+        b"GET" | b"EXISTS" => (args, Access::Read),
+        b"SET" => (&args[..args.len().min(1)], Access::ReadWrite),
+        b"DEL" => (args, Access::ReadWrite),
+        _ => return true,
+    };
+    keys.iter().all(|key| acl.access(user, &key[..]) >= access)
+}
+
+/// `SET key value [EX seconds | PX milliseconds]`
+fn set<E: KvsEngine>(engine: &E, args: &[Vec<u8>]) -> Result<()> {
+    let key = args[0].clone();
+    let value = args[1].clone();
+    let ttl = match &args[2..] {
+        [] => None,
+        [option, amount] => {
+            let amount = std::str::from_utf8(&amount[..])
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .filter(|v| *v > 0);
+            match (&option.to_ascii_uppercase()[..], amount) {
+                (b"EX", Some(seconds)) => Some(Duration::from_secs(seconds)),
+                (b"PX", Some(ms)) => Some(Duration::from_millis(ms)),
+                (b"EX", None) | (b"PX", None) => {
+                    return Err(invalid("invalid expire time in 'set' command"))
+                }
+                _ => return Err(invalid("syntax error")),
+            }
+        }
+        _ => return Err(invalid("syntax error")),
+    };
+    match ttl {
+        Some(ttl) => engine.set_bytes_with_ttl(key, value, ttl),
+        None => engine.set_bytes(key, value),
+    }
+}
+
+/// 有几个key让 `f` 返回 `true`
+fn count<F>(keys: &[Vec<u8>], mut f: F) -> Result<i64>
+where
+    F: FnMut(&[u8]) -> Result<bool>,
+{
+    let mut n = 0;
+    for key in keys {
+        if f(&key[..])? {
+            n += 1;
+        }
+    }
+    Ok(n)
+}
+
+fn invalid(message: &str) -> KvsError {
+    KvsError::InvalidArgument {
+        name: "command".to_string(),
+        value: message.to_string(),
+    }
+}
+
+fn simple(reply: &mut Vec<u8>, message: &str) {
+    reply.extend_from_slice(format!("+{}\r\n", message).as_bytes());
+}
+
+fn error(reply: &mut Vec<u8>, message: &str) {
+    fail(reply, "ERR", message);
+}
+
+/// `prefix` 是Redis的错误类型，比如 `ERR` 、`NOAUTH` ，客户端库靠它分情况
+fn fail(reply: &mut Vec<u8>, prefix: &str, message: &str) {
+    let message = message.replace(['\r', '\n'], " "); // 错误信息只能有一行
+    reply.extend_from_slice(format!("-{} {}\r\n", prefix, message).as_bytes());
+}
+
+fn integer(reply: &mut Vec<u8>, n: i64) {
+    reply.extend_from_slice(format!(":{}\r\n", n).as_bytes());
+}
+
+/// `None` 是Redis的null，key不存在的时候回这个
+fn bulk(reply: &mut Vec<u8>, value: Option<&[u8]>) {
+    match value {
+        Some(value) => {
+            reply.extend_from_slice(format!("${}\r\n", value.len()).as_bytes());
+            reply.extend_from_slice(value);
+            reply.extend_from_slice(b"\r\n");
+        }
+        None => reply.extend_from_slice(b"$-1\r\n"),
+    }
+}
+
+/// 客户端库发的是bulk string的数组，telnet里手敲的是一行用空格分开的（inline command）。对面关了的话是 `None`
+///
+/// bulk string的长度是对面说了算的，比 `max_request` 还长的话直接报 `RequestTooLarge` ，不会先照着它分配内存
+fn read_command(reader: &mut dyn BufRead, max_request: usize) -> Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        let command = line
+            .split(|v| v.is_ascii_whitespace())
+            .filter(|v| !v.is_empty())
+            .map(|v| v.to_vec())
+            .collect();
+        return Ok(Some(command));
+    }
+
+    let n = parse_len(&line[1..])?;
+    let mut command = Vec::with_capacity(n.min(1024));
+    for _ in 0..n {
+        let header = read_line(reader)?.ok_or_else(eof)?;
+        if header.first() != Some(&b'$') {
+            return Err(protocol("expected '$'"));
+        }
+        let len = parse_len(&header[1..])?;
+        if len > MAX_BULK {
+            return Err(protocol("invalid bulk length"));
+        }
+        if len > max_request {
+            return Err(KvsError::RequestTooLarge {
+                limit: max_request as u64,
+            });
+        }
+        let mut value = vec![0; len + 2];
+        reader.read_exact(&mut value[..])?;
+        if !value.ends_with(b"\r\n") {
+            return Err(protocol("expected CRLF after bulk string"));
+        }
+        value.truncate(len);
+        command.push(value);
+    }
+    Ok(Some(command))
+}
+
+/// 去掉结尾的 `\r\n`
+fn read_line(reader: &mut dyn BufRead) -> Result<Option<Vec<u8>>> {
+    let mut line = vec![];
+    if reader.read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    while line.last().is_some_and(|v| *v == b'\n' || *v == b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(digits: &[u8]) -> Result<usize> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| protocol("invalid length"))
+}
+
+fn protocol(message: &str) -> KvsError {
+    KvsError::Io(std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("Protocol error: {}", message),
+    ))
+}
+
+fn eof() -> KvsError {
+    KvsError::Io(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))
+}
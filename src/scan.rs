@@ -0,0 +1,35 @@
+use crate::Result;
+
+use std::ops::Bound;
+
+/// `scan` 返回的迭代器，按key从小到大，一个一个读出来，不会一口气把整个范围都读进内存
+pub type Scan<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>;
+
+/// `iter` 返回的迭代器，`Scan` 的String版
+pub type Iter<'a> = Box<dyn Iterator<Item = Result<(String, String)>> + 'a>;
+
+/// `keys` 返回的迭代器，和 `Scan` 一样按key从小到大，只是不读value
+pub type Keys<'a> = Box<dyn Iterator<Item = Result<Vec<u8>>> + 'a>;
+
+/// 以 `prefix` 开头的key的范围的上界：最后一个不是0xff的字节加一，后面的扔掉。全是0xff的话就没有上界
+pub(crate) fn prefix_end(prefix: &[u8]) -> Bound<Vec<u8>> {
+    let mut end = prefix.to_vec();
+    while let Some(last) = end.pop() {
+        if last < 0xff {
+            end.push(last + 1);
+            return Bound::Excluded(end);
+        }
+    }
+    Bound::Unbounded
+}
+
+/// BTreeMap的range碰到start比end大的范围会panic，先自己挡掉
+pub(crate) fn is_empty(start: Bound<&[u8]>, end: Bound<&[u8]>) -> bool {
+    match (start, end) {
+        (Bound::Included(start), Bound::Included(end)) => start > end,
+        (Bound::Included(start), Bound::Excluded(end))
+        | (Bound::Excluded(start), Bound::Included(end))
+        | (Bound::Excluded(start), Bound::Excluded(end)) => start >= end,
+        _ => false,
+    }
+}
@@ -0,0 +1,457 @@
+//! 二级索引：从value里取出一个字段，按字段反查key
+//!
+//! 索引项和数据放在同一个engine里，key是 `\0\0索引名\0` + 字段长度（4个字节） + 字段 + 原来的key，value是空的。
+//! namespace的名字不能是空的，所以 `\0\0` 开头的key不会和namespace撞上。写数据和改索引放在同一个batch里，要么都写进去，要么都没写
+//!
+//! 索引怎么从value里取字段是个闭包，没法存到磁盘上，每次打开以后要重新 `add_index` 一遍
+
+use crate::txn;
+use crate::BatchOp;
+use crate::KeyFilter;
+use crate::KeyMeta;
+use crate::Keys;
+use crate::KvsEngine;
+use crate::KvsError;
+use crate::Result;
+use crate::Scan;
+use crate::StoreStats;
+use crate::Watcher;
+use crate::BULK_BATCH;
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::ops::Bound;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::RwLock;
+use std::time::Duration;
+
+/// 索引项都在这个前缀下面，对外是看不见的
+const PREFIX: &[u8] = b"\0\0";
+
+type Extract = Arc<dyn Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync>;
+
+/// 一个二级索引。value里取不出字段（闭包返回 `None` ）的key不进索引
+#[derive(Clone)]
+pub struct SecondaryIndex {
+    name: String,
+    extract: Extract,
+}
+
+impl SecondaryIndex {
+    /// 字段用闭包从value里算出来，比如 `SecondaryIndex::new("owner", |v| v.split(|b| *b == b',').next().map(|v| v.to_vec()))`
+    pub fn new<F>(name: &str, extract: F) -> Self
+    where
+        F: Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync + 'static,
+    {
+        Self {
+            name: name.to_string(),
+            extract: Arc::new(extract),
+        }
+    }
+
+    /// value是json的话按 [JSON Pointer](https://tools.ietf.org/html/rfc6901) 取字段，比如 `/user/email` 。
+    /// 字符串取里面的内容，别的（数字、布尔……）取json的写法，所以查数字42的时候用 `b"42"` 。不是json、没有这个字段、是 `null` 的不进索引
+    pub fn json_pointer(name: &str, pointer: &str) -> Self {
+        let pointer = pointer.to_string();
+        Self::new(name, move |value| {
+            let value: serde_json::Value = serde_json::from_slice(value).ok()?;
+            match value.pointer(&pointer[..])? {
+                serde_json::Value::Null => None,
+                serde_json::Value::String(field) => Some(field.clone().into_bytes()),
+                field => Some(field.to_string().into_bytes()),
+            }
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name[..]
+    }
+
+    fn field(&self, value: Option<&[u8]>) -> Option<Vec<u8>> {
+        value.and_then(|value| (self.extract)(value))
+    }
+}
+
+/// 某个索引下面、某个字段值的所有索引项的前缀
+fn field_prefix(name: &str, field: &[u8]) -> Vec<u8> {
+    let mut prefix = PREFIX.to_vec();
+    prefix.extend(name.as_bytes());
+    prefix.push(0);
+    prefix.extend(&(field.len() as u32).to_be_bytes());
+    prefix.extend(field);
+    prefix
+}
+
+fn entry_key(name: &str, field: &[u8], key: &[u8]) -> Vec<u8> {
+    let mut entry = field_prefix(name, field);
+    entry.extend(key);
+    entry
+}
+
+fn hidden(key: &[u8]) -> bool {
+    key.starts_with(PREFIX)
+}
+
+/// 带二级索引的engine，`store.indexed()` 拿到。写的时候顺便维护索引，`lookup` 按字段查
+///
+/// 带TTL的写没法和索引放进同一个batch，是分两次写的；key过期了索引项也还在。所以 `lookup` 查到以后会拿现在的value再对一遍，对不上的不返回
+///
+/// clone出来的共用一套索引，在哪个clone上 `add_index` 别的clone也能用
+#[derive(Clone)]
+pub struct IndexedEngine<E> {
+    inner: E,
+    indexes: Arc<RwLock<Vec<SecondaryIndex>>>,
+    /// 算索引项和写下去之间不能有别的写插进来，不然算出来的索引项就不对了，写都排队
+    writer: Arc<Mutex<()>>,
+}
+
+impl<E: KvsEngine + Clone> IndexedEngine<E> {
+    pub fn new(inner: E) -> Self {
+        Self {
+            inner,
+            indexes: Arc::new(RwLock::new(vec![])),
+            writer: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// 加一个索引，已经有的数据会全扫一遍把索引建起来。同名的索引会被换掉
+    pub fn add_index(&mut self, index: SecondaryIndex) -> Result<()> {
+        if index.name.is_empty() || index.name.contains('\0') {
+            return Err(KvsError::InvalidArgument {
+                name: "index".to_string(),
+                value: index.name,
+            });
+        }
+        let _writer = self.writer.lock().unwrap(); // 建索引的时候来的写会漏掉
+        self.drop_entries(&index.name.clone()[..])?;
+
+        let pairs: Vec<(Vec<u8>, Vec<u8>)> = self
+            .scan_bounds(Bound::Unbounded, Bound::Unbounded)?
+            .collect::<Result<_>>()?;
+        let mut ops = vec![];
+        for (key, value) in pairs {
+            if let Some(field) = index.field(Some(&value[..])) {
+                ops.push(BatchOp::Set(entry_key(&index.name, &field, &key), vec![]));
+            }
+            if ops.len() >= BULK_BATCH {
+                self.inner.write_batch(std::mem::take(&mut ops))?;
+            }
+        }
+        if !ops.is_empty() {
+            self.inner.write_batch(ops)?;
+        }
+        self.indexes.write().unwrap().push(index);
+        Ok(())
+    }
+
+    /// 不要这个索引了，索引项也删掉
+    pub fn drop_index(&mut self, name: &str) -> Result<()> {
+        let _writer = self.writer.lock().unwrap();
+        self.drop_entries(name)
+    }
+
+    /// 调用的人要拿着 `writer`
+    fn drop_entries(&self, name: &str) -> Result<()> {
+        self.indexes
+            .write()
+            .unwrap()
+            .retain(|index| index.name != name);
+        let mut prefix = PREFIX.to_vec();
+        prefix.extend(name.as_bytes());
+        prefix.push(0);
+        let entries: Vec<BatchOp> = self
+            .inner
+            .scan_prefix(&prefix[..])?
+            .map(|entry| entry.map(|(key, _)| BatchOp::Remove(key)))
+            .collect::<Result<_>>()?;
+        for chunk in entries.chunks(BULK_BATCH) {
+            self.inner.write_batch(chunk.to_vec())?;
+        }
+        Ok(())
+    }
+
+    /// `index` 字段是 `field` 的所有key和value，按key排好序
+    pub fn lookup(&self, index: &str, field: &[u8]) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let indexes = self.indexes.read().unwrap();
+        let position = match indexes.iter().position(|v| v.name == index) {
+            Some(position) => position,
+            None => {
+                return Err(KvsError::InvalidArgument {
+                    name: "index".to_string(),
+                    value: index.to_string(),
+                })
+            }
+        };
+        let prefix = field_prefix(index, field);
+        let keys: Vec<Vec<u8>> = self
+            .inner
+            .scan_prefix(&prefix[..])?
+            .map(|entry| entry.map(|(key, _)| key[prefix.len()..].to_vec()))
+            .collect::<Result<_>>()?;
+
+        let mut pairs = vec![];
+        for key in keys {
+            let value = match self.inner.get_bytes(&key[..])? {
+                Some(value) => value,
+                None => continue, // 过期了
+            };
+            if indexes[position].field(Some(&value[..])).as_deref() == Some(field) {
+                pairs.push((key, value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    pub fn inner(&self) -> &E {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> E {
+        self.inner
+    }
+
+    /// 在 `ops` 前面插上要改的索引项。同一个batch里同一个key可能改好几次，要按顺序算
+    fn plan(&self, ops: Vec<BatchOp>) -> Result<Vec<BatchOp>> {
+        let mut values: HashMap<Vec<u8>, Option<Vec<u8>>> = HashMap::new(); // batch里前面的写之后的value
+        let mut entries: HashMap<Vec<u8>, bool> = HashMap::new(); // batch里前面的写之后索引项在不在
+        let mut planned = vec![];
+        let indexes = self.indexes.read().unwrap();
+        for op in ops {
+            let key = op.key().to_vec();
+            let old = match values.get(&key) {
+                Some(value) => value.clone(),
+                None => self.inner.get_bytes(&key[..])?,
+            };
+            let new = match &op {
+                BatchOp::Set(_, value) => Some(value.clone()),
+                BatchOp::Remove(_) => None,
+            };
+            for index in indexes.iter() {
+                let (before, after) = (index.field(old.as_deref()), index.field(new.as_deref()));
+                if before == after {
+                    continue;
+                }
+                if let Some(field) = before {
+                    let entry = entry_key(&index.name, &field, &key);
+                    let exists = match entries.get(&entry) {
+                        Some(exists) => *exists,
+                        None => self.inner.get_bytes(&entry[..])?.is_some(),
+                    };
+                    if exists {
+                        planned.push(BatchOp::Remove(entry.clone()));
+                        entries.insert(entry, false);
+                    }
+                }
+                if let Some(field) = after {
+                    let entry = entry_key(&index.name, &field, &key);
+                    planned.push(BatchOp::Set(entry.clone(), vec![]));
+                    entries.insert(entry, true);
+                }
+            }
+            values.insert(key, new);
+            planned.push(op);
+        }
+        Ok(planned)
+    }
+
+    /// 调用的人要拿着 `writer`
+    fn write_planned(&self, ops: Vec<BatchOp>) -> Result<()> {
+        let ops = self.plan(ops)?;
+        self.inner.write_batch(ops)
+    }
+}
+
+impl<E: KvsEngine + Clone> KvsEngine for IndexedEngine<E> {
+    fn get(&self, key: &str) -> Result<Option<String>> {
+        self.inner.get(key)
+    }
+
+    fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.inner.get_bytes(key)
+    }
+
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.write_batch(vec![BatchOp::Set(key, value)])
+    }
+
+    /// 先改索引再写，中间崩了的话多出来的索引项 `lookup` 的时候会被过滤掉
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let _writer = self.writer.lock().unwrap();
+        let mut ops = self.plan(vec![BatchOp::Set(key, value)])?;
+        if let Some(BatchOp::Set(key, value)) = ops.pop() {
+            if !ops.is_empty() {
+                self.inner.write_batch(ops)?;
+            }
+            self.inner.set_bytes_with_ttl(key, value, ttl)?;
+        }
+        Ok(())
+    }
+
+    fn remove_bytes(&self, key: &[u8]) -> Result<()> {
+        self.write_batch(vec![BatchOp::Remove(key.to_vec())])
+    }
+
+    fn write_batch(&self, ops: Vec<BatchOp>) -> Result<()> {
+        let _writer = self.writer.lock().unwrap();
+        self.write_planned(ops)
+    }
+
+    /// 拿着 `writer` 对value，和 `compare_and_swap` 一样
+    fn write_batch_if(
+        &self,
+        expected: &BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+        ops: Vec<BatchOp>,
+    ) -> Result<()> {
+        let _writer = self.writer.lock().unwrap();
+        txn::check(expected, |key| self.inner.get_bytes(key))?;
+        if ops.is_empty() {
+            return Ok(());
+        }
+        self.write_planned(ops)
+    }
+
+    /// 拿着 `writer` 比较，写的时候和索引放在同一个batch里
+    fn compare_and_swap(
+        &self,
+        key: &str,
+        expected: Option<&str>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        let _writer = self.writer.lock().unwrap();
+        let current = self.inner.get_bytes(key.as_bytes())?;
+        if current.as_deref() != expected.map(|v| v.as_bytes()) {
+            return Ok(false);
+        }
+        let key = key.as_bytes().to_vec();
+        match new {
+            Some(value) => self.write_planned(vec![BatchOp::Set(key, value.into_bytes())])?,
+            None if expected.is_some() => self.write_planned(vec![BatchOp::Remove(key)])?,
+            None => {}
+        }
+        Ok(true)
+    }
+
+    /// 为了和索引放在一个batch里，改名是一个set加一个remove，元数据会重新开始算
+    fn rename(&self, from: &str, to: &str) -> Result<()> {
+        let _writer = self.writer.lock().unwrap();
+        let value = match self.inner.get_bytes(from.as_bytes())? {
+            Some(value) => value,
+            None => {
+                return Err(KvsError::NotFound {
+                    key: from.to_string(),
+                })
+            }
+        };
+        if from == to {
+            return Ok(());
+        }
+        self.write_planned(vec![
+            BatchOp::Set(to.as_bytes().to_vec(), value),
+            BatchOp::Remove(from.as_bytes().to_vec()),
+        ])
+    }
+
+    /// merge完才知道新的value，所以索引是merge完了再改的，不在同一个batch里
+    fn merge(&self, key: &[u8], operand: Vec<u8>) -> Result<()> {
+        let _writer = self.writer.lock().unwrap();
+        let old = self.inner.get_bytes(key)?;
+        self.inner.merge(key, operand)?;
+        let new = self.inner.get_bytes(key)?;
+        let mut ops = vec![];
+        for index in self.indexes.read().unwrap().iter() {
+            let (before, after) = (index.field(old.as_deref()), index.field(new.as_deref()));
+            if before == after {
+                continue;
+            }
+            if let Some(field) = before {
+                let entry = entry_key(&index.name, &field, key);
+                if self.inner.get_bytes(&entry[..])?.is_some() {
+                    ops.push(BatchOp::Remove(entry));
+                }
+            }
+            if let Some(field) = after {
+                ops.push(BatchOp::Set(entry_key(&index.name, &field, key), vec![]));
+            }
+        }
+        if ops.is_empty() {
+            return Ok(());
+        }
+        self.inner.write_batch(ops)
+    }
+
+    /// 外面的key和它们的索引项放在一个batch里删，namespace里的key和它们的索引项都留着
+    fn clear(&self) -> Result<()> {
+        let _writer = self.writer.lock().unwrap();
+        let ops = self
+            .keys()?
+            .map(|key| key.map(BatchOp::Remove))
+            .collect::<Result<Vec<_>>>()?;
+        if ops.is_empty() {
+            return Ok(());
+        }
+        self.write_planned(ops)
+    }
+
+    fn metadata(&self, key: &str) -> Result<Option<KeyMeta>> {
+        self.inner.metadata(key)
+    }
+
+    fn contains_key(&self, key: &str) -> Result<bool> {
+        self.inner.contains_key(key)
+    }
+
+    fn scan_matching(&self, filter: &KeyFilter) -> Result<Vec<(String, String)>> {
+        let mut pairs = self.inner.scan_matching(filter)?;
+        pairs.retain(|(key, _)| !hidden(key.as_bytes()));
+        Ok(pairs)
+    }
+
+    fn scan_bounds(&self, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Result<Scan<'_>> {
+        let iter = self
+            .inner
+            .scan_bounds(start, end)?
+            .filter(|entry| entry.as_ref().map_or(true, |(key, _)| !hidden(key)));
+        Ok(Box::new(iter))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.inner.flush()
+    }
+
+    fn engine_name(&self) -> &'static str {
+        self.inner.engine_name()
+    }
+
+    fn stats(&self) -> Result<StoreStats> {
+        self.inner.stats()
+    }
+
+    /// 用空前缀订阅的话索引项的变化也会收到
+    fn watch(&self, prefix: &[u8]) -> Result<Watcher> {
+        self.inner.watch(prefix)
+    }
+
+    fn keys(&self) -> Result<Keys<'_>> {
+        let iter = self
+            .inner
+            .keys()?
+            .filter(|key| key.as_ref().map_or(true, |key| !hidden(key)));
+        Ok(Box::new(iter))
+    }
+
+    fn bulk_load_iter(&self, pairs: &mut dyn Iterator<Item = (String, String)>) -> Result<usize> {
+        let mut count = 0;
+        let mut pairs = pairs.into_iter().peekable();
+        while pairs.peek().is_some() {
+            let ops: Vec<BatchOp> = pairs
+                .by_ref()
+                .take(BULK_BATCH)
+                .map(|(key, value)| BatchOp::Set(key.into_bytes(), value.into_bytes()))
+                .collect();
+            count += ops.len();
+            self.write_batch(ops)?;
+        }
+        Ok(count)
+    }
+}
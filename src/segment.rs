@@ -0,0 +1,445 @@
+//! KvStore的磁盘格式：一个一个的segment文件，名字是 `0.log` 、 `1.log` ……，每个文件里一行一个json的command，只会往后追加
+//!
+//! 每行前面是8个十六进制字符的CRC32和一个空格，后面才是json。以 `{` 开头的是加校验和之前写的，照样认
+//!
+//! 以前是一个command一个文件，几万个key就是几万个小文件，每次写都要open、rename，太浪费了
+
+use crate::checksum::crc32;
+use crate::compress::Compressed;
+use crate::compress::Compression;
+use crate::fs;
+use crate::fs::Context;
+use crate::handles::HandleCache;
+use crate::Command;
+use crate::KvsError;
+use crate::Result;
+
+use log::warn;
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::sync_channel;
+use std::sync::Arc;
+
+/// 一个command在哪个segment的哪个位置，`len` 不包括最后的换行
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Position {
+    pub(crate) segment: u64,
+    pub(crate) offset: u64,
+    pub(crate) len: u64,
+}
+
+pub(crate) fn path(root: &Path, segment: u64) -> PathBuf {
+    root.join(format!("{}.log", segment))
+}
+
+/// 目录下所有的segment，从老到新排好
+pub(crate) fn list(root: &Path) -> Result<Vec<u64>> {
+    let mut segments = vec![];
+    for entry in std::fs::read_dir(root).at(root)? {
+        let path = entry.at(root)?.path();
+        if path.extension().map(|v| v == "log").unwrap_or(false) {
+            if let Some(id) = path
+                .file_stem()
+                .and_then(|v| v.to_str())
+                .and_then(|v| v.parse().ok())
+            {
+                segments.push(id);
+            }
+        }
+    }
+    segments.sort_unstable();
+    Ok(segments)
+}
+
+/// 解析出来的一整个segment，不碰文件
+pub(crate) struct Parsed {
+    pub(crate) commands: Vec<(Command, Position)>,
+    /// 坏掉的记录的位置和原因，解析的时候跳过了
+    pub(crate) corrupt: Vec<(u64, String)>,
+    /// 里面有坏记录的batch，从 `Batch(n)` 开始到最后一个command结束
+    pub(crate) damaged: Vec<(u64, u64)>,
+    /// 前这么多字节是完整的，后面是没写完的
+    pub(crate) end: usize,
+}
+
+/// 最后一行要是没写完（写到一半崩了），就当它不存在。batch也一样，`Batch(n)` 后面不够n个command的话，连 `Batch(n)` 一起不算
+///
+/// 坏掉的记录后面还有东西的话就不是没写完，是真的坏了（比如磁盘位翻转），记在 `corrupt` 里接着往下解析
+pub(crate) fn parse(bytes: &[u8], segment: u64) -> Parsed {
+    let mut parsed = Parsed {
+        commands: vec![],
+        corrupt: vec![],
+        damaged: vec![],
+        end: 0,
+    };
+    let mut offset = 0;
+    // 正在读的batch从哪开始的，还差几个command，已经读到的command，有没有坏的
+    let mut batch_start = None;
+    let mut remaining = 0;
+    let mut pending = vec![];
+    let mut damaged = false;
+    while offset < bytes.len() {
+        let end = match bytes[offset..].iter().position(|v| *v == b'\n') {
+            Some(i) => offset + i,
+            None => break, // 没有换行，说明这一行没写完
+        };
+        let position = Position {
+            segment,
+            offset: offset as u64,
+            len: (end - offset) as u64,
+        };
+        let command = match decode(&bytes[offset..end]) {
+            Ok(command) => Some(command),
+            Err(_) if end + 1 == bytes.len() => break, // 最后一行，当成没写完
+            Err(reason) => {
+                parsed.corrupt.push((offset as u64, reason));
+                damaged |= batch_start.is_some();
+                None
+            }
+        };
+        match command {
+            Some(Command::Batch(_)) if batch_start.is_some() => break, // batch里套batch，不可能是正常写出来的
+            Some(Command::Batch(n)) => {
+                batch_start = Some(offset);
+                remaining = n;
+                pending.push((Command::Batch(n), position));
+            }
+            Some(command) if batch_start.is_some() => {
+                pending.push((command, position));
+                remaining -= 1;
+            }
+            Some(command) => parsed.commands.push((command, position)),
+            None if batch_start.is_some() => remaining -= 1, // 跳过的也占batch里的一个位置
+            None => {}
+        }
+        offset = end + 1;
+        if let (Some(start), 0) = (batch_start, remaining) {
+            parsed.commands.append(&mut pending); // 整个batch都读到了才算数
+            if damaged {
+                parsed.damaged.push((start as u64, offset as u64));
+            }
+            batch_start = None;
+            damaged = false;
+        }
+    }
+    if let Some(start) = batch_start {
+        offset = start; // batch没写全，整个扔掉
+        parsed.corrupt.retain(|(v, _)| *v < start as u64); // 扔掉的部分里坏的不算
+    }
+    parsed.end = offset;
+    parsed
+}
+
+/// 把整个segment读出来，没写完的部分从文件里截掉，截掉的部分存到 `quarantine_path` 里
+///
+/// 有坏掉的记录的话报 `Corrupt` ， `skip_corrupt` 的话跳过它接着读
+pub(crate) fn read(
+    root: &Path,
+    segment: u64,
+    skip_corrupt: bool,
+) -> Result<Vec<(Command, Position)>> {
+    let path = path(root, segment);
+    let mut bytes = vec![];
+    File::open(&path)
+        .and_then(|mut file| file.read_to_end(&mut bytes))
+        .at(&path)?;
+
+    let parsed = parse(&bytes[..], segment);
+    for (offset, reason) in parsed.corrupt {
+        if !skip_corrupt {
+            return Err(KvsError::Corrupt {
+                path,
+                offset,
+                reason,
+            });
+        }
+        warn!(
+            "Skipping corrupt record at offset {} of {:?}: {}",
+            offset, path, reason
+        );
+    }
+
+    let offset = parsed.end;
+    if offset < bytes.len() {
+        warn!(
+            "Truncating {} bytes of torn writes at the end of {:?}, saved to {:?}",
+            bytes.len() - offset,
+            path,
+            quarantine_path(&path)
+        );
+        quarantine(&path, &bytes[offset..])?;
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .and_then(|file| file.set_len(offset as u64))
+            .at_offset(&path, offset as u64)?;
+    }
+    Ok(parsed.commands)
+}
+
+/// 要扔掉的字节先存到 `quarantine_path` 里
+pub(crate) fn quarantine(path: &Path, bytes: &[u8]) -> Result<()> {
+    let path = quarantine_path(path);
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .at(&path)?; // 崩了好几次的话都攒在一起
+    file.write_all(bytes).at(&path)?;
+    file.sync_all().at(&path) // 截掉之前先保证存下来了
+}
+
+/// 用 `threads` 个线程一起 `read` 这些segment，读出来的按编号顺序交给 `apply`
+///
+/// 重放只能从老到新一个一个来，但是慢的是读文件、校验、解析json，这些可以一起做。线程按编号顺序领segment，所以先读完等着的一般不多
+pub(crate) fn read_parallel<F>(
+    root: &Path,
+    segments: &[u64],
+    skip_corrupt: bool,
+    threads: usize,
+    mut apply: F,
+) -> Result<()>
+where
+    F: FnMut(Vec<(Command, Position)>) -> Result<()>,
+{
+    let threads = threads.clamp(1, segments.len().max(1));
+    if threads == 1 {
+        for id in segments {
+            apply(read(root, *id, skip_corrupt)?)?;
+        }
+        return Ok(());
+    }
+
+    let next = AtomicUsize::new(0);
+    std::thread::scope(|scope| {
+        let (sender, receiver) = sync_channel(threads);
+        for _ in 0..threads {
+            let (sender, next) = (sender.clone(), &next);
+            scope.spawn(move || loop {
+                let i = next.fetch_add(1, Ordering::SeqCst);
+                if i >= segments.len() {
+                    break;
+                }
+                if sender
+                    .send((i, read(root, segments[i], skip_corrupt)))
+                    .is_err()
+                {
+                    break; // 前面出错了，不读了
+                }
+            });
+        }
+        drop(sender);
+
+        let mut ready = BTreeMap::new();
+        let mut expected = 0;
+        let mut result = Ok(());
+        for (i, commands) in receiver.iter() {
+            ready.insert(i, commands);
+            while let Some(commands) = ready.remove(&expected) {
+                result = commands.and_then(&mut apply);
+                if result.is_err() {
+                    break;
+                }
+                expected += 1;
+            }
+            if result.is_err() {
+                next.store(segments.len(), Ordering::SeqCst);
+                break;
+            }
+        }
+        drop(receiver); // 还在等着send的线程会拿到错误退出
+        result
+    })
+}
+
+/// 截掉的半截记录不直接扔，存到 `0.log.torn` 里，万一要找回来呢。不是 `.log` 结尾的，不会被当成segment
+fn quarantine_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".torn");
+    path.with_file_name(name)
+}
+
+/// 按 `position` 把一个command读出来，文件句柄用缓存里的
+pub(crate) fn read_at(handles: &HandleCache, root: &Path, position: Position) -> Result<Command> {
+    let path = path(root, position.segment);
+    handles
+        .read(
+            position.segment as usize,
+            &path,
+            position.offset,
+            position.len as usize,
+            |bytes| {
+                decode(bytes).map_err(|reason| KvsError::Corrupt {
+                    path: path.clone(),
+                    offset: position.offset,
+                    reason,
+                })
+            },
+        )
+        .at_offset(&path, position.offset)
+}
+
+/// 正在往里追加的那个segment
+#[derive(Debug)]
+pub(crate) struct Writer {
+    segment: u64,
+    path: PathBuf,
+    /// `Durability::Always` 的时候要放开store的锁去fsync，所以是共享的
+    file: Arc<File>,
+    len: u64,
+}
+
+impl Writer {
+    pub(crate) fn open(root: &Path, segment: u64) -> Result<Self> {
+        let path = path(root, segment);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .at(&path)?;
+        let len = file.metadata().at(&path)?.len();
+        Ok(Self {
+            segment,
+            path,
+            file: Arc::new(file),
+            len,
+        })
+    }
+
+    pub(crate) fn segment(&self) -> u64 {
+        self.segment
+    }
+
+    pub(crate) fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// 一次write把好几个command一起写下去，返回第一个字节的位置
+    pub(crate) fn append(&mut self, bytes: &[u8]) -> Result<u64> {
+        let offset = self.len;
+        (&*self.file)
+            .write_all(bytes)
+            .at_offset(&self.path, offset)?;
+        self.len += bytes.len() as u64;
+        Ok(offset)
+    }
+
+    pub(crate) fn sync(&self) -> Result<()> {
+        self.file.sync_all().at(&self.path)
+    }
+
+    /// 拿着它在锁外面fsync，这期间换了segment也没关系，换之前老的已经fsync过了
+    pub(crate) fn file(&self) -> Arc<File> {
+        self.file.clone()
+    }
+}
+
+/// 把command编码成一行，返回编码好的字节和不带换行的长度。Set的value按 `compression` 压缩
+pub(crate) fn encode(command: &Command, compression: Compression) -> Result<(Vec<u8>, u64)> {
+    let compressed = match command {
+        Command::Set(key, value, meta) => Compressed::pack(compression, &value.0[..])?
+            .map(|compressed| Command::SetCompressed(key.clone(), compressed, *meta)),
+        _ => None, // 压不小的、不是Set的都原样写
+    };
+    let json = serde_json::to_vec(compressed.as_ref().unwrap_or(command))?;
+    let mut bytes = format!("{:08x} ", crc32(&json[..])).into_bytes();
+    bytes.extend(json);
+    let len = bytes.len() as u64;
+    bytes.push(b'\n');
+    Ok((bytes, len))
+}
+
+/// 解码一行（不带换行），坏了的话返回哪里坏了
+fn decode(line: &[u8]) -> std::result::Result<Command, String> {
+    let json = if line.first() == Some(&b'{') {
+        line // 没有校验和的老记录
+    } else {
+        if line.len() < 9 || line[8] != b' ' {
+            return Err("missing checksum".to_string());
+        }
+        let expected = std::str::from_utf8(&line[..8])
+            .ok()
+            .and_then(|v| u32::from_str_radix(v, 16).ok())
+            .ok_or_else(|| "malformed checksum".to_string())?;
+        let json = &line[9..];
+        if crc32(json) != expected {
+            return Err("checksum mismatch".to_string());
+        }
+        json
+    };
+    serde_json::from_slice(json).map_err(|e| e.to_string())
+}
+
+/// 老版本一个command一个文件，文件名就是数字。按顺序重放一遍，返回最后还活着的key的Set和所有老文件
+///
+/// 解析不了的文件报 `Corrupt` ， `skip_corrupt` 的话跳过
+pub(crate) fn read_legacy(root: &Path, skip_corrupt: bool) -> Result<(Vec<Command>, Vec<PathBuf>)> {
+    let mut files = vec![];
+    for entry in std::fs::read_dir(root).at(root)? {
+        let path = entry.at(root)?.path();
+        if let Some(id) = path
+            .file_name()
+            .and_then(|v| v.to_str())
+            .and_then(|v| v.parse::<usize>().ok())
+        {
+            files.push((id, path));
+        }
+    }
+    files.sort_unstable();
+
+    let mut live = HashMap::new();
+    for (_, path) in &files {
+        let command = match serde_json::from_slice(&std::fs::read(path).at(path)?[..]) {
+            Ok(command) => command,
+            Err(e) if skip_corrupt => {
+                warn!("Skipping corrupt legacy file {:?}: {}", path, e);
+                continue;
+            }
+            Err(e) => {
+                return Err(KvsError::Corrupt {
+                    path: path.clone(),
+                    offset: 0,
+                    reason: e.to_string(),
+                })
+            }
+        };
+        match command {
+            Command::Set(key, value, mut meta) => {
+                if meta.version == 0 {
+                    meta.size = value.0.len() as u64; // 更老版本的记录，至少大小是知道的
+                }
+                live.insert(key, (value, meta));
+            }
+            Command::Remove(key) => {
+                live.remove(&key);
+            }
+            Command::Batch(_)
+            | Command::SetBlob(..)
+            | Command::SetCompressed(..)
+            | Command::Merge(..) => {} // 老版本没有batch，没有blob，不压缩，也没有merge
+        }
+    }
+    let live = live
+        .into_iter()
+        .map(|(key, (value, meta))| Command::Set(key, value, meta))
+        .collect();
+    let paths = files.into_iter().map(|(_, path)| path).collect();
+    Ok((live, paths))
+}
+
+/// 迁移完的老文件删掉
+pub(crate) fn remove_legacy(root: &Path, paths: &[PathBuf]) -> Result<()> {
+    for path in paths {
+        fs::remove(path)?;
+    }
+    fs::sync_dir(root)
+}
@@ -0,0 +1,138 @@
+//! 让 `run` 停下来：不再收新的连接，正在处理的请求做完、回完，连接都关了以后把engine落盘再返回
+//!
+//! 等下一个请求的连接是堵在读上的，关掉socket的读那一半它就读到EOF了，写的那一半还开着，正在做的请求照样能回
+
+use crate::net::Socket;
+
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::net::Ipv6Addr;
+use std::net::Shutdown;
+use std::net::SocketAddr;
+use std::net::TcpStream;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Condvar;
+use std::sync::Mutex;
+
+/// `KvsServer::shutdown_handle` 拿到的，可以clone了交给别的线程，比如处理信号的那个
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    stopping: AtomicBool,
+    state: Mutex<State>,
+    /// 连接少了一个或者要停了就叫一下
+    closed: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    /// 正在收连接的地址，停的时候连一下，把堵在accept上的叫醒
+    listeners: Vec<Listener>,
+    connections: HashMap<u64, Socket>,
+    next: u64,
+}
+
+#[derive(Clone)]
+pub(crate) enum Listener {
+    Tcp(SocketAddr),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl ShutdownHandle {
+    /// 让 `run` 停下来。不等它停完就返回，`run` 返回了才是真停了。调多少次都行
+    pub fn shutdown(&self) {
+        let state = self.inner.state.lock().unwrap();
+        if self.inner.stopping.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        for socket in state.connections.values() {
+            let _ = socket.shutdown(Shutdown::Read);
+        }
+        for listener in &state.listeners {
+            let _ = match listener {
+                Listener::Tcp(address) => TcpStream::connect(reachable(*address)).map(drop),
+                #[cfg(unix)]
+                Listener::Unix(path) => std::os::unix::net::UnixStream::connect(path).map(drop),
+            };
+        }
+        self.inner.closed.notify_all(); // 在 `wait_for_room` 的不用等了
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.inner.stopping.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn listen(&self, listener: Listener) {
+        self.inner.state.lock().unwrap().listeners.push(listener);
+    }
+
+    /// 连接关了（返回的 `Connection` drop了）才算处理完。已经在停了的话连接马上就读不到东西了
+    pub(crate) fn register(&self, socket: Socket) -> Connection {
+        let mut state = self.inner.state.lock().unwrap();
+        if self.is_shutting_down() {
+            let _ = socket.shutdown(Shutdown::Read);
+        }
+        let id = state.next;
+        state.next += 1;
+        state.connections.insert(id, socket);
+        Connection {
+            handle: self.clone(),
+            id,
+        }
+    }
+
+    /// 等到连接少于 `max` 个，或者要停了
+    pub(crate) fn wait_for_room(&self, max: usize) {
+        let mut state = self.inner.state.lock().unwrap();
+        while state.connections.len() >= max && !self.is_shutting_down() {
+            state = self.inner.closed.wait(state).unwrap();
+        }
+    }
+
+    /// 现在开着几个连接
+    pub(crate) fn connections(&self) -> usize {
+        self.inner.state.lock().unwrap().connections.len()
+    }
+
+    /// 等所有的连接都关了
+    pub(crate) fn wait(&self) {
+        let mut state = self.inner.state.lock().unwrap();
+        while !state.connections.is_empty() {
+            state = self.inner.closed.wait(state).unwrap();
+        }
+    }
+}
+
+/// 听的是 `0.0.0.0` 的话连本机
+fn reachable(mut address: SocketAddr) -> SocketAddr {
+    if address.ip().is_unspecified() {
+        address.set_ip(match address {
+            SocketAddr::V4(_) => Ipv4Addr::LOCALHOST.into(),
+            SocketAddr::V6(_) => Ipv6Addr::LOCALHOST.into(),
+        });
+    }
+    address
+}
+
+/// 跟着连接走，drop的时候从正在处理的连接里去掉
+pub(crate) struct Connection {
+    handle: ShutdownHandle,
+    id: u64,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let inner = &self.handle.inner;
+        inner.state.lock().unwrap().connections.remove(&self.id);
+        inner.closed.notify_all();
+    }
+}
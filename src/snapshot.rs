@@ -0,0 +1,95 @@
+//! KvStore的备份：老的segment只会被删不会被改，硬链接过去就行；正在写的那个只拷贝到当前的长度为止
+//!
+//! 拷完了最后写一个 `MANIFEST` ，有 `MANIFEST` 的才是完整的备份。备份出来的目录直接就能 `KvStore::open`
+
+use crate::archive;
+use crate::blob;
+use crate::fs;
+use crate::segment;
+use crate::Result;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+/// 备份里有哪些segment，每个多长
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Manifest {
+    /// 什么时候备份的，从UNIX epoch开始的毫秒数
+    pub created: u64,
+    pub segments: Vec<SegmentInfo>,
+    /// 还有key指着的blob。老的备份里没有这个
+    #[serde(default)]
+    pub blobs: Vec<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SegmentInfo {
+    pub id: u64,
+    pub len: u64,
+}
+
+pub(crate) const MANIFEST: &str = "MANIFEST";
+
+impl Manifest {
+    /// 读备份目录里的 `MANIFEST` ，没有的话说明备份没做完
+    pub fn read(dir: &Path) -> Result<Self> {
+        let content = std::fs::read(dir.join(MANIFEST))?;
+        Ok(serde_json::from_slice(&content[..])?)
+    }
+}
+
+/// 目标目录要么不存在，要么是空的，免得把别的东西覆盖了
+pub(crate) fn prepare(dest: &Path) -> Result<()> {
+    std::fs::create_dir_all(dest)?;
+    if std::fs::read_dir(dest)?.next().is_some() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("snapshot destination {:?} is not empty", dest),
+        )
+        .into());
+    }
+    Ok(())
+}
+
+/// 不会再变的segment，能硬链接就硬链接，跨文件系统之类的链接不了就拷贝
+pub(crate) fn link(root: &Path, dest: &Path, id: u64) -> Result<u64> {
+    let from = segment::path(root, id);
+    let to = segment::path(dest, id);
+    if std::fs::hard_link(&from, &to).is_err() {
+        std::fs::copy(&from, &to)?;
+    }
+    Ok(std::fs::metadata(&to)?.len())
+}
+
+/// blob写好了就不会变，和老的segment一样
+pub(crate) fn link_blob(root: &Path, dest: &Path, id: u64) -> Result<()> {
+    let from = blob::path(root, id);
+    let to = blob::path(dest, id);
+    std::fs::create_dir_all(dest.join(blob::DIR))?;
+    if std::fs::hard_link(&from, &to).is_err() {
+        std::fs::copy(&from, &to)?;
+    }
+    Ok(())
+}
+
+/// 还在往里写的segment，只拷贝前 `len` 个字节，后面再写进来的不算
+pub(crate) fn copy_prefix(root: &Path, dest: &Path, id: u64, len: u64) -> Result<()> {
+    let mut from = File::open(segment::path(root, id))?.take(len);
+    let mut to = File::create(segment::path(dest, id))?;
+    std::io::copy(&mut from, &mut to)?;
+    to.sync_all()?;
+    Ok(())
+}
+
+/// 所有segment都就位了以后再写 `.kvs` 和 `MANIFEST` ， `.kvs` 照抄原来的
+pub(crate) fn finish(root: &Path, dest: &Path, manifest: &Manifest) -> Result<()> {
+    let archive = std::fs::read(root.join(archive::FILE))?;
+    fs::write_atomic(&dest.join(archive::FILE), &archive[..])?;
+    fs::sync_dir(dest)?; // segment先落盘，MANIFEST才能写
+    fs::write_atomic(&dest.join(MANIFEST), &serde_json::to_vec(manifest)?[..])?;
+    fs::sync_dir(dest)
+}
@@ -0,0 +1,58 @@
+use serde::Deserialize;
+use serde::Serialize;
+
+/// `KvStore::stats()` 和 `SledKvsEngine::stats()` 拿到的，决定要不要compact、导出监控的时候看
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StoreStats {
+    /// 还活着（没过期）的key
+    pub keys: usize,
+    /// 数据在磁盘上一共占了多少字节
+    pub disk_bytes: u64,
+    /// 被覆盖、被删掉、过期了的记录占的字节，也就是compact能省下来多少。sled不告诉我们，是 `None`
+    pub garbage_bytes: Option<u64>,
+    /// sled没有segment，是 `None`
+    pub segments: Option<usize>,
+    /// 按现在的compaction策略，攒够了、还没来得及合的segment一共多少字节。sled是 `None`
+    pub compaction_debt: Option<u64>,
+    /// 读value的时候value已经在内存里了。sled自己的cache不告诉我们，一直是0
+    pub cache_hits: u64,
+    /// 读value的时候要去磁盘上读
+    pub cache_misses: u64,
+}
+
+impl StoreStats {
+    pub fn cache_hit_rate(&self) -> f64 {
+        if self.cache_hits + self.cache_misses == 0 {
+            0.0
+        } else {
+            self.cache_hits as f64 / (self.cache_hits + self.cache_misses) as f64
+        }
+    }
+
+    /// 磁盘上有多少比例是没用的，sled的话是 `None`
+    pub fn garbage_ratio(&self) -> Option<f64> {
+        match self.garbage_bytes {
+            Some(_) if self.disk_bytes == 0 => Some(0.0),
+            Some(garbage) => Some(garbage as f64 / self.disk_bytes as f64),
+            None => None,
+        }
+    }
+}
+
+/// `KvsClient::stats` 拿到的，服务器整个的情况
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ServerStats {
+    /// 服务器的版本，`CARGO_PKG_VERSION`
+    pub version: String,
+    /// 从 `KvsServer::new` 到现在过了多少秒
+    pub uptime_secs: u64,
+    /// `KvsEngine::engine_name`
+    pub engine: String,
+    pub keys: usize,
+    /// 不知道的engine（比如memory）是0
+    pub disk_bytes: u64,
+    /// 0到1，一次都没读过是0
+    pub cache_hit_rate: f64,
+    /// 现在开着的连接，问的这一个也算
+    pub connections: usize,
+}
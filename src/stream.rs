@@ -0,0 +1,64 @@
+use std::io::Cursor;
+use std::io::Read;
+use std::io::Take;
+
+/// `get_reader` 返回的，一点一点读value，不用一次读进内存
+///
+/// 事先知道一共多长，网络上传的时候先告诉对方长度
+pub struct ValueReader<'a> {
+    len: u64,
+    inner: Box<dyn Read + 'a>,
+}
+
+impl<'a> ValueReader<'a> {
+    pub(crate) fn new(len: u64, inner: Box<dyn Read + 'a>) -> Self {
+        Self { len, inner }
+    }
+
+    /// 已经在内存里了的value
+    pub(crate) fn from_bytes(bytes: Vec<u8>) -> Self {
+        Self::new(bytes.len() as u64, Box::new(Cursor::new(bytes)))
+    }
+
+    /// value一共多少字节，不管已经读了多少
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl Read for ValueReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+/// 刚好读 `len` 个字节。还没读够就EOF了（比如对面断了）的话报错，免得把半截value当成完整的存下去
+pub(crate) struct Exact<R> {
+    inner: Take<R>,
+}
+
+pub(crate) fn exact<R: Read>(reader: R, len: u64) -> Exact<R> {
+    Exact {
+        inner: reader.take(len),
+    }
+}
+
+impl<R: Read> Read for Exact<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let n = self.inner.read(buf)?;
+        if n == 0 && self.inner.limit() > 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("value ended {} bytes early", self.inner.limit()),
+            ));
+        }
+        Ok(n)
+    }
+}
@@ -0,0 +1,243 @@
+//! TLS，key和value不用明文在网上跑
+//!
+//! 服务器拿PEM格式的证书链和私钥，客户端按 `TlsOptions` 验证服务器的证书。只有TCP的 `run` 支持
+
+use crate::KvsError;
+use crate::Result;
+
+use rustls::client::danger::HandshakeSignatureValid;
+use rustls::client::danger::ServerCertVerified;
+use rustls::client::danger::ServerCertVerifier;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::CertificateDer;
+use rustls::pki_types::PrivateKeyDer;
+use rustls::pki_types::ServerName;
+use rustls::pki_types::UnixTime;
+use rustls::ClientConfig;
+use rustls::ClientConnection;
+use rustls::DigitallySignedStruct;
+use rustls::RootCertStore;
+use rustls::ServerConfig;
+use rustls::ServerConnection;
+use rustls::SignatureScheme;
+use rustls::StreamOwned;
+
+use std::convert::TryFrom;
+use std::net::TcpStream;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// 客户端怎么验证服务器的证书
+#[derive(Clone, Debug, Default)]
+pub struct TlsOptions {
+    /// 只信任这个PEM文件里的CA，自签名的证书就把证书自己放进来。不设的话信任常见的公共CA
+    pub ca_file: Option<PathBuf>,
+    /// 证书上应该写着的名字。不设的话用地址里端口前面的那部分
+    pub server_name: Option<String>,
+    /// 完全不验证证书，谁都能冒充服务器。只在测试的时候用
+    pub insecure: bool,
+}
+
+/// 连接上跑的TLS，服务器和客户端两边的类型不一样
+#[derive(Debug)]
+pub(crate) enum TlsStream {
+    Client(StreamOwned<ClientConnection, TcpStream>),
+    Server(StreamOwned<ServerConnection, TcpStream>),
+}
+
+impl TlsStream {
+    pub(crate) fn get_ref(&self) -> &TcpStream {
+        match self {
+            TlsStream::Client(stream) => stream.get_ref(),
+            TlsStream::Server(stream) => stream.get_ref(),
+        }
+    }
+}
+
+impl std::io::Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            TlsStream::Client(stream) => stream.read(buf),
+            TlsStream::Server(stream) => stream.read(buf),
+        }
+    }
+}
+
+impl std::io::Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            TlsStream::Client(stream) => stream.write(buf),
+            TlsStream::Server(stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            TlsStream::Client(stream) => stream.flush(),
+            TlsStream::Server(stream) => stream.flush(),
+        }
+    }
+}
+
+/// 客户端的配置，`KvsClient::tls` 的时候做好，每次连接都用
+pub(crate) struct ClientTls {
+    config: Arc<ClientConfig>,
+    server_name: Option<ServerName<'static>>,
+}
+
+impl ClientTls {
+    pub(crate) fn new(options: &TlsOptions) -> Result<Self> {
+        let provider = provider();
+        let builder = ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()?;
+        let config = if options.insecure {
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(NoVerifier(provider)))
+                .with_no_client_auth()
+        } else {
+            let mut roots = RootCertStore::empty();
+            match &options.ca_file {
+                Some(path) => {
+                    for cert in certificates(path)? {
+                        roots.add(cert)?;
+                    }
+                }
+                None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+            }
+            builder.with_root_certificates(roots).with_no_client_auth()
+        };
+        let server_name = match &options.server_name {
+            Some(name) => Some(server_name(name)?),
+            None => None,
+        };
+        Ok(Self {
+            config: Arc::new(config),
+            server_name,
+        })
+    }
+
+    /// 握手是在第一次读写的时候做的，证书不对的话那时候报错
+    pub(crate) fn connect(&self, address: &str) -> Result<TlsStream> {
+        let name = match &self.server_name {
+            Some(name) => name.clone(),
+            None => server_name(host(address))?,
+        };
+        let connection = ClientConnection::new(self.config.clone(), name)?;
+        let stream = TcpStream::connect(address)?;
+        Ok(TlsStream::Client(StreamOwned::new(connection, stream)))
+    }
+}
+
+/// 服务器的配置，证书和私钥都是PEM格式的文件
+pub(crate) fn server_config(cert: &Path, key: &Path) -> Result<Arc<ServerConfig>> {
+    let chain = certificates(cert)?;
+    let key = PrivateKeyDer::from_pem_file(key).map_err(|e| pem_error(key, e))?;
+    let config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(chain, key)?;
+    Ok(Arc::new(config))
+}
+
+pub(crate) fn accept(config: &Arc<ServerConfig>, stream: TcpStream) -> Result<TlsStream> {
+    let connection = ServerConnection::new(config.clone())?;
+    Ok(TlsStream::Server(StreamOwned::new(connection, stream)))
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(rustls::crypto::ring::default_provider())
+}
+
+fn certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let certs = CertificateDer::pem_file_iter(path)
+        .and_then(|v| v.collect::<std::result::Result<Vec<_>, _>>())
+        .map_err(|e| pem_error(path, e))?;
+    if certs.is_empty() {
+        return Err(KvsError::File {
+            path: path.to_path_buf(),
+            offset: None,
+            error: std::io::Error::new(std::io::ErrorKind::InvalidData, "no certificates found"),
+        });
+    }
+    Ok(certs)
+}
+
+fn pem_error(path: &Path, error: rustls::pki_types::pem::Error) -> KvsError {
+    let error = match error {
+        rustls::pki_types::pem::Error::Io(e) => e,
+        e => std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{:?}", e)),
+    };
+    KvsError::File {
+        path: path.to_path_buf(),
+        offset: None,
+        error,
+    }
+}
+
+fn server_name(name: &str) -> Result<ServerName<'static>> {
+    ServerName::try_from(name.to_string()).map_err(|_| KvsError::InvalidArgument {
+        name: "server_name".to_string(),
+        value: name.to_string(),
+    })
+}
+
+/// `127.0.0.1:4000` 里的 `127.0.0.1` ，`[::1]:4000` 里的 `::1`
+fn host(address: &str) -> &str {
+    let host = match address.rsplit_once(':') {
+        Some((host, _)) => host,
+        None => address,
+    };
+    host.trim_start_matches('[').trim_end_matches(']')
+}
+
+/// `TlsOptions::insecure` ，什么证书都接受，但是签名还是要对
+#[derive(Debug)]
+struct NoVerifier(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _: &CertificateDer<'_>,
+        _: &[CertificateDer<'_>],
+        _: &ServerName<'_>,
+        _: &[u8],
+        _: UnixTime,
+    ) -> std::result::Result<ServerCertVerified, rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> std::result::Result<HandshakeSignatureValid, rustls::Error> {
+        rustls::crypto::verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
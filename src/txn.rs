@@ -0,0 +1,245 @@
+//! 乐观事务：读的时候记下读到了什么，写先攒着，commit的时候再看一遍读过的key有没有被别人改过，没改过才一起写下去
+//!
+//! 比较的是value本身而不是版本号，key被删掉又set回同一个value的话不算冲突，反正结果是一样的
+
+use crate::bytes;
+use crate::BatchOp;
+use crate::Bytes;
+use crate::KvsClient;
+use crate::KvsEngine;
+use crate::KvsError;
+use crate::Request;
+use crate::Response;
+use crate::Result;
+
+use std::collections::BTreeMap;
+
+/// 事务里读过什么、要写什么。本地的事务和远端的事务都用这个
+#[derive(Debug, Default)]
+pub(crate) struct Staged {
+    /// 第一次读到的value，`None` 是读的时候不存在
+    pub(crate) reads: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    /// `None` 是删掉
+    pub(crate) writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl Staged {
+    /// 事务里已经知道的value：自己写过的优先，其次是读过的。都没有的话要去engine里读
+    pub(crate) fn lookup(&self, key: &[u8]) -> Option<Option<Vec<u8>>> {
+        match self.writes.get(key) {
+            Some(value) => Some(value.clone()),
+            None => self.reads.get(key).cloned(),
+        }
+    }
+
+    /// 删之前要先读过事务开始之前的value。本来就不存在、是事务里自己set出来的话，把那个set撤掉就行了，不然batch里会remove一个不存在的key
+    pub(crate) fn remove(&mut self, key: &[u8]) {
+        match self.reads.get(key) {
+            Some(Some(_)) => {
+                self.writes.insert(key.to_vec(), None);
+            }
+            _ => {
+                self.writes.remove(key);
+            }
+        }
+    }
+
+    /// 发给服务器的时候，两个map都拍平
+    pub(crate) fn into_wire(self) -> Request {
+        let flatten = |map: BTreeMap<Vec<u8>, Option<Vec<u8>>>| {
+            map.into_iter()
+                .map(|(key, value)| (Bytes(key), value.map(Bytes)))
+                .collect()
+        };
+        Request::Commit {
+            reads: flatten(self.reads),
+            writes: flatten(self.writes),
+        }
+    }
+
+    pub(crate) fn from_wire(
+        reads: Vec<(Bytes, Option<Bytes>)>,
+        writes: Vec<(Bytes, Option<Bytes>)>,
+    ) -> Self {
+        let collect = |pairs: Vec<(Bytes, Option<Bytes>)>| {
+            pairs
+                .into_iter()
+                .map(|(key, value)| (key.0, value.map(|v| v.0)))
+                .collect()
+        };
+        Self {
+            reads: collect(reads),
+            writes: collect(writes),
+        }
+    }
+
+    pub(crate) fn ops(&self) -> Vec<BatchOp> {
+        self.writes
+            .iter()
+            .map(|(key, value)| match value {
+                Some(value) => BatchOp::Set(key.clone(), value.clone()),
+                None => BatchOp::Remove(key.clone()),
+            })
+            .collect()
+    }
+}
+
+/// 读过的key现在还是当时读到的那样的话，把写一个batch写下去，不是的话报 `Conflict`
+///
+/// 对value和写下去原子不原子看engine的 `write_batch_if` ，KvStore、sled、MemEngine是原子的
+pub(crate) fn commit<E: KvsEngine>(engine: &E, staged: Staged) -> Result<()> {
+    engine.write_batch_if(&staged.reads, staged.ops())
+}
+
+/// `expected` 里的每个key用 `current` 读一下现在的value，有一个对不上就报 `Conflict`
+pub(crate) fn check<F>(expected: &BTreeMap<Vec<u8>, Option<Vec<u8>>>, mut current: F) -> Result<()>
+where
+    F: FnMut(&[u8]) -> Result<Option<Vec<u8>>>,
+{
+    for (key, value) in expected {
+        if current(key)? != *value {
+            return Err(KvsError::Conflict {
+                key: bytes::lossy(key),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// `engine.begin()` 或者 `engine.transaction(..)` 拿到的
+///
+/// 读能看到事务里自己前面的写。没commit就drop掉相当于rollback，什么都不会发生
+pub struct Transaction<'a, E: KvsEngine> {
+    engine: &'a E,
+    staged: Staged,
+}
+
+impl<'a, E: KvsEngine> Transaction<'a, E> {
+    pub(crate) fn new(engine: &'a E) -> Self {
+        Self {
+            engine,
+            staged: Staged::default(),
+        }
+    }
+
+    pub fn get(&mut self, key: &str) -> Result<Option<String>> {
+        self.get_bytes(key.as_bytes())?
+            .map(|v| bytes::into_string(key.as_bytes(), v))
+            .transpose()
+    }
+
+    pub fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.staged.lookup(key) {
+            return Ok(value);
+        }
+        let value = self.engine.get_bytes(key)?;
+        self.staged.reads.insert(key.to_vec(), value.clone());
+        Ok(value)
+    }
+
+    pub fn set(&mut self, key: String, value: String) {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.staged.writes.insert(key, Some(value));
+    }
+
+    /// 和engine的remove一样，key不存在的话报 `NotFound`
+    pub fn remove(&mut self, key: &str) -> Result<()> {
+        self.remove_bytes(key.as_bytes())
+    }
+
+    pub fn remove_bytes(&mut self, key: &[u8]) -> Result<()> {
+        if self.get_bytes(key)?.is_none() {
+            return Err(KvsError::NotFound {
+                key: bytes::lossy(key),
+            });
+        }
+        if !self.staged.reads.contains_key(key) {
+            let value = self.engine.get_bytes(key)?; // 事务里set过但是没读过，还不知道原来在不在
+            self.staged.reads.insert(key.to_vec(), value);
+        }
+        self.staged.remove(key);
+        Ok(())
+    }
+
+    pub fn commit(self) -> Result<()> {
+        commit(self.engine, self.staged)
+    }
+
+    /// 其实就是drop，写出来好看一点
+    pub fn rollback(self) {}
+}
+
+/// `client.begin()` 拿到的，和 `Transaction` 一样，只是读是一个一个发请求，写攒到commit的时候一个请求发过去
+///
+/// 服务器那边不保存事务的状态，commit的时候把读到过的value也一起发过去，服务器对一遍
+pub struct RemoteTransaction<'a> {
+    client: &'a mut KvsClient,
+    staged: Staged,
+}
+
+impl<'a> RemoteTransaction<'a> {
+    pub(crate) fn new(client: &'a mut KvsClient) -> Self {
+        Self {
+            client,
+            staged: Staged::default(),
+        }
+    }
+
+    pub fn get(&mut self, key: &str) -> Result<Option<String>> {
+        self.get_bytes(key.as_bytes())?
+            .map(|v| bytes::into_string(key.as_bytes(), v))
+            .transpose()
+    }
+
+    pub fn get_bytes(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.staged.lookup(key) {
+            return Ok(value);
+        }
+        let value = self.client.get_bytes(key)?;
+        self.staged.reads.insert(key.to_vec(), value.clone());
+        Ok(value)
+    }
+
+    pub fn set(&mut self, key: String, value: String) {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.staged.writes.insert(key, Some(value));
+    }
+
+    pub fn remove(&mut self, key: &str) -> Result<()> {
+        self.remove_bytes(key.as_bytes())
+    }
+
+    pub fn remove_bytes(&mut self, key: &[u8]) -> Result<()> {
+        if self.get_bytes(key)?.is_none() {
+            return Err(KvsError::NotFound {
+                key: bytes::lossy(key),
+            });
+        }
+        if !self.staged.reads.contains_key(key) {
+            let value = self.client.get_bytes(key)?;
+            self.staged.reads.insert(key.to_vec(), value);
+        }
+        self.staged.remove(key);
+        Ok(())
+    }
+
+    /// 别的客户端在这期间改了读过的key的话报 `Conflict`
+    pub fn commit(self) -> Result<()> {
+        match self.client.request(self.staged.into_wire())? {
+            Response::Done(_) => Ok(()),
+            Response::Conflict(key) => Err(KvsError::Conflict { key }),
+            Response::Failed(message, code) => Err(KvsError::Remote { message, code }),
+            v => Err(KvsError::UnexpectedResponse {
+                response: format!("{:?}", v),
+            }),
+        }
+    }
+
+    pub fn rollback(self) {}
+}
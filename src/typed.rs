@@ -0,0 +1,95 @@
+//! 直接存结构体：key和value都用serde编码成JSON再交给底下的engine，不用每个地方都自己 `to_string` 、 `parse` 了
+//!
+//! 本来想再加一个bincode的，但是为了这个多一个依赖不值得，JSON也够用了
+
+use crate::KvsEngine;
+use crate::Result;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use std::marker::PhantomData;
+use std::ops::Bound;
+use std::time::Duration;
+
+/// `TypedStore::<_, u64, User>::new(store)` ，之后 `get` 、 `set` 的都是 `u64` 和 `User`
+///
+/// key也是编码过的，比如 `String` 的key在底下是带引号的 `"name"` ，所以别和直接用字符串的写法混着用。
+/// 数字的key编码成十进制的字符串，扫出来的顺序是按字符串排的，不是按大小
+pub struct TypedStore<E, K, V> {
+    inner: E,
+    _marker: PhantomData<fn() -> (K, V)>, // 不真的拿着K和V，不影响Send
+}
+
+impl<E, K, V> TypedStore<E, K, V>
+where
+    E: KvsEngine,
+    K: Serialize,
+    V: Serialize + DeserializeOwned,
+{
+    pub fn new(inner: E) -> Self {
+        Self {
+            inner,
+            _marker: PhantomData,
+        }
+    }
+
+    /// value解不出来（比如是别人用别的类型写的）的话报 `Serde`
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        let key = serde_json::to_vec(key)?;
+        match self.inner.get_bytes(&key[..])? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value[..])?)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn set(&self, key: &K, value: &V) -> Result<()> {
+        self.inner
+            .set_bytes(serde_json::to_vec(key)?, serde_json::to_vec(value)?)
+    }
+
+    pub fn set_with_ttl(&self, key: &K, value: &V, ttl: Duration) -> Result<()> {
+        self.inner
+            .set_bytes_with_ttl(serde_json::to_vec(key)?, serde_json::to_vec(value)?, ttl)
+    }
+
+    /// 和 `KvsEngine::remove` 一样，key不存在的话报 `NotFound`
+    pub fn remove(&self, key: &K) -> Result<()> {
+        self.inner.remove_bytes(&serde_json::to_vec(key)?[..])
+    }
+
+    /// JSON肯定是utf8，可以直接用 `&str` 的版本，不用读value
+    pub fn contains_key(&self, key: &K) -> Result<bool> {
+        self.inner.contains_key(&serde_json::to_string(key)?)
+    }
+
+    pub fn inner_mut(&mut self) -> &mut E {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> E {
+        self.inner
+    }
+}
+
+impl<E, K, V> TypedStore<E, K, V>
+where
+    E: KvsEngine,
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+{
+    /// 所有的key和value，按编码过的key排。有一个解不出来就报错
+    pub fn iter(&self) -> Result<impl Iterator<Item = Result<(K, V)>> + '_> {
+        let iter = self
+            .inner
+            .scan_bounds(Bound::Unbounded, Bound::Unbounded)?
+            .map(|entry| {
+                let (key, value) = entry?;
+                Ok((
+                    serde_json::from_slice(&key[..])?,
+                    serde_json::from_slice(&value[..])?,
+                ))
+            });
+        Ok(iter)
+    }
+}
@@ -0,0 +1,199 @@
+//! KvStore的只读快照：把某一时刻的索引钉住，之后store再怎么写，从这里读到的都是那一刻的样子
+//!
+//! 索引是 `Arc` 共享的，拿快照只是多一个引用，不用拷贝。有快照的时候store第一次写会把索引复制一份再改（copy-on-write），之后就是改自己那份了。
+//! segment只追加不修改，快照里记的位置一直有效；只有compact会删segment，有快照钉着的时候删除会推迟到快照都没了以后
+
+use crate::blob;
+use crate::bytes;
+use crate::merge;
+use crate::scan;
+use crate::segment;
+use crate::Command;
+use crate::Entry;
+use crate::Keys;
+use crate::MergeOperator;
+use crate::Result;
+use crate::Scan;
+
+use crate::handles::HandleCache;
+
+use std::collections::BTreeMap;
+use std::ops::Bound;
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+pub(crate) type Index = BTreeMap<Vec<u8>, Entry>;
+
+/// `store.read_view()` 拿到的，不借用store，可以一边读它一边继续写store
+#[derive(Debug)]
+pub struct ReadView {
+    pub(crate) map: Arc<Index>,
+    pub(crate) handles: HandleCache,
+    pub(crate) root: PathBuf,
+    pub(crate) version: u64,
+    /// 有还没叠上去的merge的话，读的时候要用
+    pub(crate) merge: Option<MergeOperator>,
+    /// 还有快照活着的话compact不能删segment
+    pub(crate) _pin: Arc<()>,
+}
+
+impl ReadView {
+    /// 拿快照的时候store的版本号，也就是那时候store已经写了几次
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    pub fn get(&self, key: &str) -> Result<Option<String>> {
+        self.get_bytes(key.as_bytes())?
+            .map(|v| bytes::into_string(key.as_bytes(), v))
+            .transpose()
+    }
+
+    pub fn get_bytes(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.map.get(key) {
+            Some(entry) if !entry.meta.is_expired() => {
+                read_value(&self.handles, &self.root, self.merge.as_ref(), key, entry)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    pub fn scan<R>(&self, range: R) -> Result<Scan<'_>>
+    where
+        R: RangeBounds<Vec<u8>>,
+    {
+        Ok(scan_index(
+            &self.map,
+            &self.handles,
+            &self.root,
+            self.merge.as_ref(),
+            range.start_bound().map(|v| &v[..]),
+            range.end_bound().map(|v| &v[..]),
+        ))
+    }
+
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Result<Scan<'_>> {
+        let end = scan::prefix_end(prefix);
+        Ok(scan_index(
+            &self.map,
+            &self.handles,
+            &self.root,
+            self.merge.as_ref(),
+            Bound::Included(prefix),
+            end.as_ref().map(|v| &v[..]),
+        ))
+    }
+
+    pub fn keys(&self) -> Keys<'_> {
+        let iter = self
+            .map
+            .iter()
+            .filter(|(_, entry)| !entry.meta.is_expired())
+            .map(|(key, _)| Ok(key.clone()));
+        Box::new(iter)
+    }
+
+    pub fn len(&self) -> usize {
+        self.map
+            .values()
+            .filter(|entry| !entry.meta.is_expired())
+            .count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// 在内存里的话直接拿，不在的话去segment里读，有merge的话叠上去，读出来的不放回索引里
+pub(crate) fn read_value(
+    handles: &HandleCache,
+    root: &Path,
+    operator: Option<&MergeOperator>,
+    key: &[u8],
+    entry: &Entry,
+) -> Result<Option<Vec<u8>>> {
+    if let Some(value) = &entry.value {
+        return Ok(Some(value.to_vec())); // 内存里的是已经叠好了的
+    }
+    let base = match segment::read_at(handles, root, entry.position)? {
+        Command::Set(_, value, _) => Some(value.0),
+        Command::SetBlob(_, id, _) => Some(blob::read(root, id)?),
+        Command::SetCompressed(_, compressed, _) => Some(compressed.unpack()?),
+        Command::Merge(..) if !entry.operands.is_empty() => None, // 第一条就是merge，叠在空的value上
+        _ => return Ok(None),                                     // 和get一样，按理说不会发生
+    };
+    merge::fold(operator, key, base, &entry.operands[..])
+}
+
+/// 拿着快照在索引上走，不借用store，走的时候store照样能读能写。每次从上一个key后面接着找
+pub(crate) fn scan_view(view: ReadView, start: Bound<&[u8]>, end: Bound<&[u8]>) -> Scan<'static> {
+    if scan::is_empty(start, end) {
+        return Box::new(std::iter::empty());
+    }
+    Box::new(ViewScan {
+        view,
+        next: start.map(|v| v.to_vec()),
+        end: end.map(|v| v.to_vec()),
+    })
+}
+
+struct ViewScan {
+    view: ReadView,
+    next: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+}
+
+impl Iterator for ViewScan {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let bounds = (
+                self.next.as_ref().map(|v| &v[..]),
+                self.end.as_ref().map(|v| &v[..]),
+            );
+            if scan::is_empty(bounds.0, bounds.1) {
+                return None;
+            }
+            let view = &self.view;
+            let (key, entry) = view.map.range::<[u8], _>(bounds).next()?;
+            self.next = Bound::Excluded(key.clone());
+            if entry.meta.is_expired() {
+                continue;
+            }
+            match read_value(&view.handles, &view.root, view.merge.as_ref(), key, entry) {
+                Ok(Some(value)) => return Some(Ok((key.clone(), value))),
+                Ok(None) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// 在有序的索引上走，value是一个一个按需读的
+pub(crate) fn scan_index<'a>(
+    map: &'a Index,
+    handles: &'a HandleCache,
+    root: &'a Path,
+    operator: Option<&'a MergeOperator>,
+    start: Bound<&[u8]>,
+    end: Bound<&[u8]>,
+) -> Scan<'a> {
+    if scan::is_empty(start, end) {
+        return Box::new(std::iter::empty());
+    }
+    let iter = map
+        .range::<[u8], _>((start, end))
+        .filter(|(_, entry)| !entry.meta.is_expired())
+        .filter_map(
+            move |(key, entry)| match read_value(handles, root, operator, key, entry) {
+                Ok(Some(value)) => Some(Ok((key.clone(), value))),
+                Ok(None) => None,
+                Err(e) => Some(Err(e)),
+            },
+        );
+    Box::new(iter)
+}
@@ -0,0 +1,139 @@
+//! 订阅某个前缀下面的key的变化，不用一直轮询
+//!
+//! sled自己就有订阅，KvStore和MemEngine是写的时候顺手往channel里发一份
+
+use std::sync::mpsc::channel;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+
+/// 一个key被改了
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum WatchEvent {
+    /// set、改名改过来的、batch里的set都是这个
+    Set { key: Vec<u8>, value: Vec<u8> },
+    /// remove、改名改走的、发现过期了的都是这个
+    Remove { key: Vec<u8> },
+}
+
+impl WatchEvent {
+    pub fn key(&self) -> &[u8] {
+        match self {
+            WatchEvent::Set { key, .. } | WatchEvent::Remove { key } => &key[..],
+        }
+    }
+
+    fn strip(self, len: usize) -> Self {
+        match self {
+            WatchEvent::Set { key, value } => WatchEvent::Set {
+                key: key[len..].to_vec(),
+                value,
+            },
+            WatchEvent::Remove { key } => WatchEvent::Remove {
+                key: key[len..].to_vec(),
+            },
+        }
+    }
+}
+
+enum Source {
+    Channel(Receiver<WatchEvent>),
+    #[cfg(feature = "sled")]
+    Sled(sled::Subscriber),
+}
+
+/// `engine.watch(prefix)` 拿到的，没有事件的时候 `next` 会一直等。engine被drop了以后迭代结束
+///
+/// 事件是写成功了以后才发的，同一个engine上的写是什么顺序，收到的就是什么顺序
+pub struct Watcher {
+    source: Source,
+    /// key前面要去掉几个字节，namespace用的
+    strip: usize,
+}
+
+impl Watcher {
+    #[cfg(feature = "sled")]
+    pub(crate) fn sled(subscriber: sled::Subscriber) -> Self {
+        Self {
+            source: Source::Sled(subscriber),
+            strip: 0,
+        }
+    }
+
+    /// key前面再多去掉 `len` 个字节
+    pub(crate) fn strip(mut self, len: usize) -> Self {
+        self.strip += len;
+        self
+    }
+
+    /// 最多等 `timeout` ，等不到或者engine没了返回 `None`
+    pub fn next_timeout(&mut self, timeout: Duration) -> Option<WatchEvent> {
+        let event = match &mut self.source {
+            Source::Channel(receiver) => match receiver.recv_timeout(timeout) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) | Err(RecvTimeoutError::Disconnected) => {
+                    return None
+                }
+            },
+            #[cfg(feature = "sled")]
+            Source::Sled(subscriber) => from_sled(subscriber.next_timeout(timeout).ok()?),
+        };
+        Some(event.strip(self.strip))
+    }
+}
+
+impl Iterator for Watcher {
+    type Item = WatchEvent;
+
+    fn next(&mut self) -> Option<WatchEvent> {
+        let event = match &mut self.source {
+            Source::Channel(receiver) => receiver.recv().ok()?,
+            #[cfg(feature = "sled")]
+            Source::Sled(subscriber) => from_sled(subscriber.next()?),
+        };
+        Some(event.strip(self.strip))
+    }
+}
+
+#[cfg(feature = "sled")]
+fn from_sled(event: sled::Event) -> WatchEvent {
+    match event {
+        sled::Event::Insert { key, value } => WatchEvent::Set {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        },
+        sled::Event::Remove { key } => WatchEvent::Remove { key: key.to_vec() },
+    }
+}
+
+/// KvStore和MemEngine里所有的订阅
+#[derive(Debug, Default)]
+pub(crate) struct Watchers {
+    senders: Vec<(Vec<u8>, Sender<WatchEvent>)>,
+}
+
+impl Watchers {
+    pub(crate) fn add(&mut self, prefix: &[u8]) -> Watcher {
+        let (sender, receiver) = channel();
+        self.senders.push((prefix.to_vec(), sender));
+        Watcher {
+            source: Source::Channel(receiver),
+            strip: 0,
+        }
+    }
+
+    /// 有没有人在看这个key。没有的话就不用为了发事件去拷value了
+    pub(crate) fn watching(&self, key: &[u8]) -> bool {
+        self.senders
+            .iter()
+            .any(|(prefix, _)| key.starts_with(prefix))
+    }
+
+    /// `Watcher` 已经drop了的顺便删掉
+    pub(crate) fn publish(&mut self, event: WatchEvent) {
+        self.senders.retain(|(prefix, sender)| {
+            !event.key().starts_with(prefix) || sender.send(event.clone()).is_ok()
+        });
+    }
+}
@@ -0,0 +1,150 @@
+//! 网络上一条请求、一条响应怎么编码，连上以后怎么握手
+//!
+//! 默认是一行json，拿telnet都能调。客户端一连上先发一个 `Hello` ，说自己是哪个版本的协议、想用什么编码，
+//! 服务器回一个 `Hello` 说定下来用什么、自己支持什么，之后两边都换成定下来的编码。bincode的消息前面是4个字节大端的长度
+//!
+//! 老的客户端不握手，一直是json；老的服务器不认识 `Hello` ，会直接把连接关掉，客户端就当它是0版本
+
+use crate::KvsError;
+use crate::Result;
+
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde::Serialize;
+
+use std::io::BufRead;
+use std::io::Read;
+use std::io::Write;
+
+/// 协议的版本。加了老的一方不认识的请求、改了消息的格式就加一
+pub const PROTOCOL_VERSION: u32 = 2;
+
+/// 一个bincode的消息最多这么长，长度坏了的时候别一下子分配几个G
+const MAX_FRAME: u32 = 1 << 30;
+
+/// 服务器默认收的一个请求最多这么长，和Redis的 `proto-max-bulk-len` 一样
+pub(crate) const MAX_REQUEST: usize = 512 << 20;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Encoding {
+    #[default]
+    Json,
+    /// 比json省CPU和字节，二进制的key和value也不用变成数字数组
+    Bincode,
+}
+
+impl Encoding {
+    pub(crate) fn encode<T: Serialize>(self, message: &T) -> Result<Vec<u8>> {
+        match self {
+            Encoding::Json => {
+                let mut frame = serde_json::to_vec(message)?;
+                frame.push(b'\n');
+                Ok(frame)
+            }
+            Encoding::Bincode => {
+                let payload = bincode::serialize(message)?;
+                let mut frame = Vec::with_capacity(4 + payload.len());
+                frame.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+                frame.extend_from_slice(&payload[..]);
+                Ok(frame)
+            }
+        }
+    }
+
+    /// `frame` 是 `read_frame` 读出来的，bincode的话不带长度
+    pub(crate) fn decode<T: DeserializeOwned>(self, frame: &[u8]) -> Result<T> {
+        match self {
+            Encoding::Json => Ok(serde_json::from_slice(frame)?),
+            Encoding::Bincode => Ok(bincode::deserialize(frame)?),
+        }
+    }
+
+    pub(crate) fn write<T: Serialize>(self, writer: &mut dyn Write, message: &T) -> Result<()> {
+        writer.write_all(&self.encode(message)?[..])?;
+        Ok(())
+    }
+
+    /// 对面关了的话是 `None`
+    pub(crate) fn read<T: DeserializeOwned>(self, reader: &mut dyn BufRead) -> Result<Option<T>> {
+        match self.read_frame(reader, usize::MAX)? {
+            Some(frame) => Ok(Some(self.decode(&frame[..])?)),
+            None => Ok(None),
+        }
+    }
+
+    /// 读一条消息原始的字节。json是一行（老的客户端不带换行，读到EOF为止），bincode是长度后面那些
+    ///
+    /// 比 `limit` 长（不算换行和长度）的话是 `RequestTooLarge` ，不会先把它整个读进内存。这时候连接上剩下的东西没读完，只能关掉
+    pub(crate) fn read_frame(
+        self,
+        reader: &mut dyn BufRead,
+        limit: usize,
+    ) -> Result<Option<Vec<u8>>> {
+        match self {
+            Encoding::Json => {
+                let mut line = vec![];
+                let mut limited = reader.take((limit as u64).saturating_add(1)); // 多读一个字节才知道是不是超了
+                match limited.read_until(b'\n', &mut line)? {
+                    0 => Ok(None),
+                    n if n > limit && line.last() != Some(&b'\n') => {
+                        Err(KvsError::RequestTooLarge {
+                            limit: limit as u64,
+                        })
+                    }
+                    _ => Ok(Some(line)),
+                }
+            }
+            Encoding::Bincode => {
+                let mut len = [0; 4];
+                match reader.read(&mut len[..1])? {
+                    0 => return Ok(None),
+                    _ => reader.read_exact(&mut len[1..])?,
+                }
+                let len = frame_len(len)?;
+                if len > limit {
+                    return Err(KvsError::RequestTooLarge {
+                        limit: limit as u64,
+                    });
+                }
+                let mut payload = vec![0; len];
+                reader.read_exact(&mut payload[..])?;
+                Ok(Some(payload))
+            }
+        }
+    }
+}
+
+/// 握手的时候两边互相发的，都是json
+///
+/// 客户端发的是它想要的，服务器回的是定下来的：版本取两边小的那个，编码服务器不支持的话是 `Json`
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u32,
+    pub encoding: Encoding,
+    /// 服务器支持的功能，比如 `"bincode"` 、 `"monitor"` 。以后加的压缩、认证之类的也在这里说，不认识的忽略就行
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+}
+
+impl Hello {
+    /// 没握手的服务器（老版本）就当是这样
+    pub(crate) fn legacy() -> Self {
+        Self {
+            version: 0,
+            encoding: Encoding::Json,
+            capabilities: vec![],
+        }
+    }
+}
+
+/// bincode的消息前面那4个字节
+pub(crate) fn frame_len(prefix: [u8; 4]) -> Result<usize> {
+    let len = u32::from_be_bytes(prefix);
+    if len > MAX_FRAME {
+        return Err(KvsError::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("frame of {} bytes is too large", len),
+        )));
+    }
+    Ok(len as usize)
+}
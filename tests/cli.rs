@@ -1,4 +1,6 @@
 use assert_cmd::prelude::*;
+use kvs::{KvStore, KvsEngine, SledKvsEngine};
+use predicates::prelude::*;
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
 use std::process::Command;
@@ -20,28 +22,28 @@ fn client_cli_invalid_get() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get"])
+        .args(["get"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "extra", "field"])
+        .args(["get", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--addr", "invalid-addr"])
+        .args(["get", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -52,35 +54,35 @@ fn client_cli_invalid_set() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set"])
+        .args(["set"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "missing_field"])
+        .args(["set", "missing_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "extra_field"])
+        .args(["set", "key", "value", "extra_field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key", "value", "--addr", "invalid-addr"])
+        .args(["set", "key", "value", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key", "--unknown-flag"])
+        .args(["get", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -91,28 +93,28 @@ fn client_cli_invalid_rm() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm"])
+        .args(["rm"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "extra", "field"])
+        .args(["rm", "extra", "field"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--addr", "invalid-addr"])
+        .args(["rm", "key", "--addr", "invalid-addr"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key", "--unknown-flag"])
+        .args(["rm", "key", "--unknown-flag"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -123,7 +125,7 @@ fn client_cli_invalid_subcommand() {
     let temp_dir = TempDir::new().unwrap();
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["unknown"])
+        .args(["unknown"])
         .current_dir(&temp_dir)
         .assert()
         .failure();
@@ -134,7 +136,7 @@ fn client_cli_invalid_subcommand() {
 fn client_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-client").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -145,7 +147,7 @@ fn client_cli_version() {
 fn server_cli_version() {
     let temp_dir = TempDir::new().unwrap();
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-    cmd.args(&["-V"])
+    cmd.args(["-V"])
         .current_dir(&temp_dir)
         .assert()
         .stdout(contains(env!("CARGO_PKG_VERSION")));
@@ -157,13 +159,14 @@ fn cli_log_configuration() {
     let stderr_path = temp_dir.path().join("stderr");
     let mut cmd = Command::cargo_bin("kvs-server").unwrap();
     let mut child = cmd
-        .args(&["--engine", "kvs", "--addr", "127.0.0.1:4001"])
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4001"])
         .current_dir(&temp_dir)
         .stderr(File::create(&stderr_path).unwrap())
         .spawn()
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait for server");
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -171,6 +174,84 @@ fn cli_log_configuration() {
     assert!(content.contains("127.0.0.1:4001"));
 }
 
+// `kvs-server --log-level debug` should log every connection and request, `off` should log nothing
+#[test]
+fn cli_log_level() {
+    for (level, port) in [("debug", 4012), ("off", 4013)] {
+        let temp_dir = TempDir::new().unwrap();
+        let stderr_path = temp_dir.path().join("stderr");
+        let addr = format!("127.0.0.1:{}", port);
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(["--engine", "kvs", "--addr", &addr, "--log-level", level])
+            .current_dir(&temp_dir)
+            .env_remove("RUST_LOG")
+            .stderr(File::create(&stderr_path).unwrap())
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", "key1", "value1", "--addr", &addr])
+            .assert()
+            .success();
+        child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait for server");
+
+        let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
+        if level == "debug" {
+            assert!(content.contains("connected"));
+            assert!(content.contains("set key1"));
+        } else {
+            assert!(content.is_empty());
+        }
+    }
+}
+
+// `kvs-server --slow-request-ms` should log requests that take longer than that
+#[test]
+fn cli_slow_request() {
+    let temp_dir = TempDir::new().unwrap();
+    let stderr_path = temp_dir.path().join("stderr");
+    let data_path = temp_dir.path().join("data.tsv");
+    let lines: String = (0..100_000)
+        .map(|i| format!("key{}\tvalue{}\n", i, i))
+        .collect();
+    fs::write(&data_path, lines).unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args([
+            "--engine",
+            "kvs",
+            "--addr",
+            "127.0.0.1:4014",
+            "--slow-request-ms",
+            "1",
+        ])
+        .current_dir(&temp_dir)
+        .env_remove("RUST_LOG")
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "load",
+            data_path.to_str().unwrap(),
+            "--addr",
+            "127.0.0.1:4014",
+        ])
+        .assert()
+        .success();
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait for server");
+
+    let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
+    assert!(content.contains("Slow request"));
+    assert!(content.contains("bulk_load"));
+}
+
 #[test]
 fn cli_wrong_engine() {
     // sled first, kvs second
@@ -178,15 +259,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "sled", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "sled", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait for server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "kvs", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -197,15 +279,16 @@ fn cli_wrong_engine() {
         let temp_dir = TempDir::new().unwrap();
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         let mut child = cmd
-            .args(&["--engine", "kvs", "--addr", "127.0.0.1:4002"])
+            .args(["--engine", "kvs", "--addr", "127.0.0.1:4002"])
             .current_dir(&temp_dir)
             .spawn()
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait for server");
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
-        cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
+        cmd.args(["--engine", "sled", "--addr", "127.0.0.1:4003"])
             .current_dir(&temp_dir)
             .assert()
             .failure();
@@ -217,19 +300,20 @@ fn cli_access_server(engine: &str, addr: &str) {
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait for server");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value1", "--addr", addr])
+        .args(["set", "key1", "value1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -237,7 +321,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -245,7 +329,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key1", "value2", "--addr", addr])
+        .args(["set", "key1", "value2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -253,7 +337,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -261,7 +345,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -269,7 +353,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key2", "--addr", addr])
+        .args(["rm", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .failure()
@@ -277,7 +361,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["set", "key2", "value3", "--addr", addr])
+        .args(["set", "key2", "value3", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -285,7 +369,7 @@ fn cli_access_server(engine: &str, addr: &str) {
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["rm", "key1", "--addr", addr])
+        .args(["rm", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -298,26 +382,27 @@ fn cli_access_server(engine: &str, addr: &str) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
-        .args(&["--engine", engine, "--addr", addr])
+        .args(["--engine", engine, "--addr", addr])
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait for server");
     });
     thread::sleep(Duration::from_secs(1));
 
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key2", "--addr", addr])
+        .args(["get", "key2", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
         .stdout(contains("value3"));
     Command::cargo_bin("kvs-client")
         .unwrap()
-        .args(&["get", "key1", "--addr", addr])
+        .args(["get", "key1", "--addr", addr])
         .current_dir(&temp_dir)
         .assert()
         .success()
@@ -335,3 +420,413 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_stat() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "sled", "--addr", "127.0.0.1:4006"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4006"])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value22", "--addr", "127.0.0.1:4006"])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["stat", "key1", "--addr", "127.0.0.1:4006"])
+        .assert()
+        .success()
+        .stdout(contains("size: 7").and(contains("version: 2")));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["stat", "key2", "--addr", "127.0.0.1:4006"])
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait for server");
+}
+
+// Idle connections should not lock clients out of a server started with the default thread pool
+#[test]
+fn cli_idle_connections() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args([
+            "--engine",
+            "memory",
+            "--threads",
+            "2",
+            "--addr",
+            "127.0.0.1:4016",
+        ])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let _idle = [
+        std::net::TcpStream::connect("127.0.0.1:4016").unwrap(),
+        std::net::TcpStream::connect("127.0.0.1:4016").unwrap(),
+    ];
+
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let output = Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(["set", "key1", "value1", "--addr", "127.0.0.1:4016"])
+            .output()
+            .unwrap();
+        sender.send(output.status.success()).unwrap();
+    });
+    let served = receiver.recv_timeout(Duration::from_secs(5));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait for server");
+    assert_eq!(
+        served,
+        Ok(true),
+        "client was locked out by idle connections"
+    );
+}
+
+// SIGHUP should reread --config and apply new size limits and log level without a restart
+#[cfg(unix)]
+#[test]
+fn cli_reload_config() {
+    let temp_dir = TempDir::new().unwrap();
+    let config = temp_dir.path().join("kvs.conf");
+    let stderr_path = temp_dir.path().join("stderr");
+    fs::write(
+        &config,
+        "# reloaded on SIGHUP\nmax-value-bytes 4\nlog-level warn\n",
+    )
+    .unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "memory", "--addr", "127.0.0.1:4017"])
+        .args(["--max-key-bytes", "8", "--config", "kvs.conf"])
+        .current_dir(&temp_dir)
+        .env_remove("RUST_LOG")
+        .stderr(File::create(&stderr_path).unwrap())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let client = |args: &[&str]| {
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(args)
+            .args(["--addr", "127.0.0.1:4017"])
+            .assert()
+    };
+    client(&["set", "early", "value1"])
+        .failure()
+        .stderr(contains("ValueTooLarge"));
+    client(&["set", "early", "v1"]).success();
+
+    // Settings dropped from the file fall back to the command line, the key limit stays
+    fs::write(&config, "max-value-bytes 100\nlog-level debug\n").unwrap();
+    let status = Command::new("kill")
+        .args(["-HUP", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    thread::sleep(Duration::from_millis(500));
+    client(&["set", "key1", "value1"]).success();
+    client(&["set", "longer_key", "value1"])
+        .failure()
+        .stderr(contains("KeyTooLarge"));
+
+    // A broken file keeps the old settings
+    fs::write(&config, "max-value-bytes lots\n").unwrap();
+    Command::new("kill")
+        .args(["-HUP", &child.id().to_string()])
+        .status()
+        .unwrap();
+    thread::sleep(Duration::from_millis(500));
+    client(&["set", "key1", "value12"]).success();
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait for server");
+    let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
+    assert!(!content.contains("set early")); // only logged at debug
+    assert!(content.contains("Configuration reloaded"));
+    assert!(content.contains("set longer_key"));
+    assert!(content.contains("Reload failed"));
+}
+
+// `kvs-client dump` and `kvs-client restore` should copy a key between servers
+#[test]
+fn cli_dump_restore() {
+    let source_dir = TempDir::new().unwrap();
+    let mut source = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4007"])
+        .current_dir(&source_dir)
+        .spawn()
+        .unwrap();
+    let target_dir = TempDir::new().unwrap();
+    let mut target = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "sled", "--addr", "127.0.0.1:4008"])
+        .current_dir(&target_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4007"])
+        .assert()
+        .success();
+    let output = Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["dump", "key1", "--addr", "127.0.0.1:4007"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let blob = String::from_utf8(output.stdout).unwrap();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["restore", blob.trim(), "--addr", "127.0.0.1:4008"])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4008"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["restore", blob.trim(), "--addr", "127.0.0.1:4008"])
+        .assert()
+        .failure();
+
+    source.kill().expect("server exited before killed");
+    source.wait().expect("failed to wait for server");
+    target.kill().expect("server exited before killed");
+    target.wait().expect("failed to wait for server");
+}
+
+#[test]
+fn cli_latency() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4009"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args([
+            "latency",
+            "--duration",
+            "300ms",
+            "--interval",
+            "50ms",
+            "--addr",
+            "127.0.0.1:4009",
+        ])
+        .assert()
+        .success()
+        .stdout(contains("ping").and(contains("set")).and(contains("get")));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["latency", "--duration", "3h", "--addr", "127.0.0.1:4009"])
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "kvs-latency-probe", "--addr", "127.0.0.1:4009"])
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait for server");
+}
+
+// `kvs-workload` should run against an embedded engine and a server and print CSV
+#[test]
+fn cli_workload() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-workload")
+        .unwrap()
+        .args([
+            "--engine",
+            "sled",
+            "--records",
+            "50",
+            "--operations",
+            "200",
+            "--read-ratio",
+            "0.5",
+            "--value-size",
+            "10-20",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(
+            contains("operation,count,throughput")
+                .and(contains("\nread,"))
+                .and(contains("\nupdate,")),
+        );
+
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4010"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    let output = temp_dir.path().join("result.csv");
+    Command::cargo_bin("kvs-workload")
+        .unwrap()
+        .args([
+            "--addr",
+            "127.0.0.1:4010",
+            "--records",
+            "20",
+            "--operations",
+            "50",
+            "--distribution",
+            "uniform",
+            "--target-qps",
+            "500",
+            "--output",
+        ])
+        .arg(&output)
+        .assert()
+        .success();
+    assert!(std::fs::read_to_string(&output)
+        .unwrap()
+        .starts_with("operation,"));
+    Command::cargo_bin("kvs-workload")
+        .unwrap()
+        .args(["--read-ratio", "2", "--addr", "127.0.0.1:4010"])
+        .assert()
+        .failure();
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait for server");
+}
+
+// `kvs-client stats` should print what the server reports about itself
+#[test]
+fn cli_stats() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut child = Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(["--engine", "memory", "--addr", "127.0.0.1:4015"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4015"])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["stats", "--addr", "127.0.0.1:4015"])
+        .assert()
+        .success()
+        .stdout(
+            contains(format!("version: {}", env!("CARGO_PKG_VERSION")))
+                .and(contains("engine: memory"))
+                .and(contains("keys: 1"))
+                .and(contains("connections: ")),
+        );
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait for server");
+}
+
+// `kvs-server --engine memory` should serve requests without writing anything to the working directory
+#[test]
+fn cli_memory_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", "memory", "--addr", "127.0.0.1:4011"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["set", "key1", "value1", "--addr", "127.0.0.1:4011"])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4011"])
+        .assert()
+        .success()
+        .stdout("value1\n");
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["rm", "key1", "--addr", "127.0.0.1:4011"])
+        .assert()
+        .success();
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4011"])
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait for server");
+    assert_eq!(fs::read_dir(temp_dir.path()).unwrap().count(), 0);
+}
+
+// kvs-migrate copies a store into another engine and reports how many keys it moved
+#[test]
+fn cli_migrate() {
+    let temp_dir = TempDir::new().unwrap();
+    {
+        let store = KvStore::open(temp_dir.path().join("src")).unwrap();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        store.set("key2".to_owned(), "value2".to_owned()).unwrap();
+    }
+
+    Command::cargo_bin("kvs-migrate")
+        .unwrap()
+        .args(["src", "dst", "--engine", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("2 keys migrated"));
+    let engine = SledKvsEngine::open(temp_dir.path().join("dst")).unwrap();
+    assert_eq!(engine.get("key2").unwrap(), Some("value2".to_owned()));
+
+    Command::cargo_bin("kvs-migrate")
+        .unwrap()
+        .args(["src", "dst", "--engine", "sled"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
@@ -0,0 +1,1937 @@
+use kvs::{
+    AsyncKvsEngine, BatchOp, CacheConfig, CachedEngine, Dump, Durability, EngineExt, Faults,
+    KeyFilter, KvStore, KvStoreOptions, KvsEngine, KvsError, MemEngine, MergeOperator, Op,
+    OpResult, Result, SecondaryIndex, SizeLimits, SledKvsEngine, SledOptions, TypedStore,
+    WatchEvent, Watcher, WriteBehind,
+};
+#[cfg(feature = "redb")]
+use kvs::{RedbKvsEngine, RedbOptions};
+use serde::{Deserialize, Serialize};
+use std::future::Future;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+use std::thread::{self, Thread};
+use std::time::Duration;
+use tempfile::TempDir;
+
+// Runs `check` on a fresh KvStore, sled engine, redb engine, MemEngine and write-behind cached KvStore in turn.
+// Returns the KvStore's directory so a test can reopen it and see what was persisted
+fn each_engine<F>(check: F) -> Result<TempDir>
+where
+    F: FnMut(Box<dyn KvsEngine>) -> Result<()>,
+{
+    each_engine_with(
+        CacheConfig {
+            capacity: 16,
+            write_behind: Some(WriteBehind {
+                max_pending: 16,
+                max_staleness: Duration::from_secs(3600),
+            }),
+        },
+        check,
+    )
+}
+
+// Same as `each_engine`, but the cached KvStore uses `cache` instead
+fn each_engine_with<F>(cache: CacheConfig, mut check: F) -> Result<TempDir>
+where
+    F: FnMut(Box<dyn KvsEngine>) -> Result<()>,
+{
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(KvStore::open(kvs_dir.path())?.boxed())?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(SledKvsEngine::open(temp_dir.path())?.boxed())?;
+    #[cfg(feature = "redb")]
+    {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        check(RedbKvsEngine::open(temp_dir.path())?.boxed())?;
+    }
+    check(MemEngine::new().boxed())?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check(KvStore::open(temp_dir.path())?.cached(cache).boxed())?;
+    Ok(kvs_dir)
+}
+
+// Cached engine should read through and keep values consistent with writes
+#[test]
+fn cached_read_through() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let engine = CachedEngine::new(
+        store,
+        CacheConfig {
+            capacity: 1,
+            write_behind: None,
+        },
+    );
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2")?, Some("value2".to_owned()));
+    engine.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(engine.get("key1")?, Some("value3".to_owned()));
+    engine.remove("key1")?;
+    assert_eq!(engine.get("key1")?, None);
+    assert!(engine.remove("key1").is_err());
+
+    drop(engine);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+// Write-behind should buffer writes and write them back on flush and drop
+#[test]
+fn cached_write_behind() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let engine = CachedEngine::new(
+        store,
+        CacheConfig {
+            capacity: 16,
+            write_behind: Some(WriteBehind {
+                max_pending: 16,
+                max_staleness: Duration::from_secs(3600),
+            }),
+        },
+    );
+
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key2")?, Some("value2".to_owned()));
+    engine.remove("key1")?;
+    assert_eq!(engine.get("key1")?, None);
+    assert!(engine.remove("key1").is_err());
+    engine.set("key3".to_owned(), "value3".to_owned())?;
+    engine.remove("key3")?;
+    engine.flush()?;
+
+    engine.set("key4".to_owned(), "value4".to_owned())?;
+    drop(engine);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3")?, None);
+    assert_eq!(store.get("key4")?, Some("value4".to_owned()));
+
+    Ok(())
+}
+
+// Write-behind should write back stale writes in the background even when nobody touches the cache again
+#[test]
+fn cached_write_behind_background() -> Result<()> {
+    let store = MemEngine::new();
+    let engine = CachedEngine::new(
+        store.clone(),
+        CacheConfig {
+            capacity: 16,
+            write_behind: Some(WriteBehind {
+                max_pending: 16,
+                max_staleness: Duration::from_millis(50),
+            }),
+        },
+    );
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(store.get("key1")?, None);
+    drop(engine.clone()); // dropping a clone should leave the flusher running
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    drop(engine);
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Middleware should be stackable and count/reject/fail operations as configured
+#[test]
+fn middleware_layers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let engine = store
+        .with_faults(Faults {
+            fail_next: 1,
+            fail_every: None,
+            writes_only: true,
+        })
+        .metered();
+
+    assert!(engine.set("key1".to_owned(), "value1".to_owned()).is_err());
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2")?, None);
+
+    let metrics = engine.metrics().clone();
+    assert_eq!(metrics.sets, 2);
+    assert_eq!(metrics.gets, 2);
+    assert_eq!(metrics.hits, 1);
+    assert_eq!(metrics.misses, 1);
+    assert_eq!(metrics.errors, 1);
+
+    let engine = engine.into_inner().into_inner().read_only();
+    assert_eq!(engine.get("key1")?, Some("value1".to_owned()));
+    assert!(matches!(
+        engine.set("key1".to_owned(), "value2".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert!(matches!(engine.remove("key1"), Err(KvsError::ReadOnly)));
+
+    Ok(())
+}
+
+// 8 threads append to the same key and race set_nx on another one, so append must not lose suffixes and set_nx must let exactly one through
+fn check_concurrent_writes<E: KvsEngine + Clone>(engine: &E) -> Result<()> {
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<bool> {
+                for _ in 0..50 {
+                    engine.append("log", "x")?;
+                }
+                engine.set_nx("winner".to_owned(), "me".to_owned())
+            })
+        })
+        .collect();
+    let mut winners = 0;
+    for handle in handles {
+        if handle.join().unwrap()? {
+            winners += 1;
+        }
+    }
+    assert_eq!(winners, 1);
+    assert_eq!(engine.get("log")?.map(|v| v.len()), Some(400));
+    Ok(())
+}
+
+// Every middleware layer should keep the atomic append and set_nx of the KvStore underneath
+#[test]
+fn middleware_concurrent_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check_concurrent_writes(&store.clone().metered())?;
+    store.clear()?;
+    check_concurrent_writes(&store.clone().logged("test"))?;
+    store.clear()?;
+    check_concurrent_writes(&store.clone().with_faults(Faults::default()))?;
+    store.clear()?;
+    check_concurrent_writes(&store.clone().size_limited(SizeLimits {
+        max_key: Some(8),
+        max_value: Some(1000),
+    }))?;
+
+    let engine = store.clone().read_only();
+    assert!(matches!(engine.append("log", "x"), Err(KvsError::ReadOnly)));
+    assert!(matches!(
+        engine.set_nx("other".to_owned(), "me".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    assert_eq!(engine.apply(&[Op::Get("winner".to_owned())])?.len(), 1);
+    assert!(matches!(engine.getdel("winner"), Err(KvsError::ReadOnly)));
+    assert_eq!(engine.get("winner")?, Some("me".to_owned()));
+    Ok(())
+}
+
+// 8 threads pass a token around with getdel and set, and swap one shared key with getset, so no token may be taken twice and every value getset wrote must come back once
+fn check_getdel_getset_concurrent<E: KvsEngine + Clone>(engine: &E) -> Result<()> {
+    engine.set("token".to_owned(), "0".to_owned())?;
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<(Vec<u64>, Vec<Option<String>>)> {
+                let mut taken = Vec::new();
+                for _ in 0..500 {
+                    if let Some(token) = engine.getdel("token")? {
+                        let token: u64 = token.parse().unwrap();
+                        taken.push(token);
+                        engine.set("token".to_owned(), (token + 1).to_string())?;
+                    }
+                }
+                let mut previous = Vec::new();
+                for i in 0..300 {
+                    previous.push(engine.getset("shared".to_owned(), format!("{}-{}", t, i))?);
+                }
+                Ok((taken, previous))
+            })
+        })
+        .collect();
+    let mut taken = Vec::new();
+    let mut seen = Vec::new();
+    for handle in handles {
+        let (tokens, previous) = handle.join().unwrap()?;
+        taken.extend(tokens);
+        seen.extend(previous);
+    }
+    taken.sort_unstable();
+    let next = taken.len() as u64;
+    assert!(taken.iter().copied().eq(0..next), "a token was taken twice");
+    assert_eq!(engine.get("token")?, Some(next.to_string()));
+
+    seen.push(engine.get("shared")?);
+    seen.sort();
+    let mut written: Vec<_> = (0..8)
+        .flat_map(|t| (0..300).map(move |i| Some(format!("{}-{}", t, i))))
+        .collect();
+    written.push(None);
+    written.sort();
+    assert!(seen == written, "getset lost or repeated a value");
+    Ok(())
+}
+
+// getdel, getset, append and set_nx should each be atomic on every engine, even with many threads on the same keys
+#[test]
+fn concurrent_read_modify_write() -> Result<()> {
+    each_engine(|engine| {
+        check_concurrent_writes(&engine)?;
+        engine.clear()?;
+        check_getdel_getset_concurrent(&engine)
+    })?;
+    Ok(())
+}
+
+// Oversized keys and values should be rejected before they reach the engine, including streamed ones
+#[test]
+fn size_limits() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let engine = store.size_limited(SizeLimits {
+        max_key: Some(8),
+        max_value: Some(100),
+    });
+
+    engine.set("key1".to_owned(), "x".repeat(100))?;
+    assert!(matches!(
+        engine.set("key1".to_owned(), "x".repeat(101)),
+        Err(KvsError::ValueTooLarge {
+            size: 101,
+            limit: 100
+        })
+    ));
+    assert!(matches!(
+        engine.set("a".repeat(9), "value".to_owned()),
+        Err(KvsError::KeyTooLarge { size: 9, limit: 8 })
+    ));
+    assert!(matches!(
+        engine.write_batch(vec![
+            BatchOp::Set(b"key2".to_vec(), b"value".to_vec()),
+            BatchOp::Set(b"key3".to_vec(), vec![0; 1000]),
+        ]),
+        Err(KvsError::ValueTooLarge { .. })
+    ));
+    assert_eq!(engine.get("key2")?, None); // the whole batch was refused
+    assert!(matches!(
+        engine.rename("key1", "much too long"),
+        Err(KvsError::KeyTooLarge { .. })
+    ));
+    assert!(matches!(
+        engine.set_from_reader(b"key4".to_vec(), &mut &vec![0; 1 << 20][..]),
+        Err(KvsError::ValueTooLarge { .. })
+    ));
+    engine.set_from_reader(b"key4".to_vec(), &mut &b"small"[..])?;
+    assert_eq!(engine.get("key4")?, Some("small".to_owned()));
+    assert_eq!(engine.get("key1")?.map(|v| v.len()), Some(100));
+    assert!(matches!(
+        engine.getset("key4".to_owned(), "x".repeat(101)),
+        Err(KvsError::ValueTooLarge { .. })
+    ));
+    assert!(matches!(
+        engine.set_nx("key5".to_owned(), "x".repeat(101)),
+        Err(KvsError::ValueTooLarge { .. })
+    ));
+    assert!(matches!(
+        engine.append("key4", &"x".repeat(101)),
+        Err(KvsError::ValueTooLarge { .. })
+    ));
+    assert!(matches!(
+        engine.apply(&[Op::Set("much too long".to_owned(), "value".to_owned())]),
+        Err(KvsError::KeyTooLarge { .. })
+    ));
+    assert_eq!(engine.append("key4", "er")?, 7);
+    assert_eq!(engine.getdel("key4")?, Some("smaller".to_owned()));
+    engine.set("key4".to_owned(), "small".to_owned())?;
+
+    let clone = engine.clone();
+    clone.set_limits(SizeLimits {
+        max_key: None,
+        max_value: Some(200),
+    });
+    assert_eq!(engine.limits().max_value, Some(200));
+    engine.set("a".repeat(9), "x".repeat(200))?;
+    engine.remove(&"a".repeat(9))?;
+
+    let engine = engine.into_inner();
+    assert_eq!(engine.get("key4")?, Some("small".to_owned()));
+    assert_eq!(engine.keys()?.count(), 2);
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct User {
+    name: String,
+    tags: Vec<String>,
+}
+
+// A typed store should roundtrip structs under structured keys and refuse values of another type
+#[test]
+fn typed_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let users = TypedStore::<_, (String, u32), User>::new(store);
+    let alice = User {
+        name: "alice".to_owned(),
+        tags: vec!["admin".to_owned()],
+    };
+    let bob = User {
+        name: "bob".to_owned(),
+        tags: vec![],
+    };
+    users.set(&("eu".to_owned(), 1), &alice)?;
+    users.set(&("us".to_owned(), 2), &bob)?;
+    assert_eq!(users.get(&("eu".to_owned(), 1))?, Some(alice.clone()));
+    assert_eq!(users.get(&("eu".to_owned(), 2))?, None);
+    assert!(users.contains_key(&("us".to_owned(), 2))?);
+
+    let all = users.iter()?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        all,
+        vec![(("eu".to_owned(), 1), alice), (("us".to_owned(), 2), bob)]
+    );
+
+    users.remove(&("us".to_owned(), 2))?;
+    assert!(matches!(
+        users.remove(&("us".to_owned(), 2)),
+        Err(KvsError::NotFound { .. })
+    ));
+
+    let store = users.into_inner();
+    store.set("[\"eu\",1]".to_owned(), "42".to_owned())?; // same key, but not a User
+    let users = TypedStore::<_, (String, u32), User>::new(store);
+    assert!(matches!(
+        users.get(&("eu".to_owned(), 1)),
+        Err(KvsError::Serde(_))
+    ));
+    Ok(())
+}
+
+// Pending writes should survive a failed write-back and be retried
+#[test]
+fn cached_write_behind_retry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let mut engine = store.with_faults(Faults::default()).cached(CacheConfig {
+        capacity: 16,
+        write_behind: Some(WriteBehind {
+            max_pending: 16,
+            max_staleness: Duration::from_secs(3600),
+        }),
+    });
+
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.inner_mut().set_faults(Faults {
+        fail_next: 1,
+        ..Default::default()
+    });
+    assert!(engine.flush().is_err());
+    engine.flush()?;
+    drop(engine);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+
+    Ok(())
+}
+
+fn check_getdel<E: KvsEngine>(engine: &E) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.getdel("key1")?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key1")?, None);
+    assert_eq!(engine.getdel("key1")?, None);
+    Ok(())
+}
+
+// getdel should hand back the value and leave the key missing
+#[test]
+fn getdel() -> Result<()> {
+    each_engine(|engine| check_getdel(&engine))?;
+    Ok(())
+}
+
+fn check_multi_get<E: KvsEngine>(engine: &E) -> Result<()> {
+    assert_eq!(engine.multi_get(&[])?, vec![]);
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(
+        engine.multi_get(&["key2", "missing", "key1", "key2"])?,
+        vec![
+            Some("value2".to_owned()),
+            None,
+            Some("value1".to_owned()),
+            Some("value2".to_owned())
+        ]
+    );
+    Ok(())
+}
+
+// multi_get should return one slot per requested key, in order
+#[test]
+fn multi_get() -> Result<()> {
+    each_engine(|engine| check_multi_get(&engine))?;
+    let engine = MemEngine::new().metered();
+    check_multi_get(&engine)?;
+    assert_eq!(engine.metrics().gets, 4);
+    Ok(())
+}
+
+fn check_getset<E: KvsEngine>(engine: &E) -> Result<()> {
+    assert_eq!(engine.getset("key1".to_owned(), "value1".to_owned())?, None);
+    assert_eq!(
+        engine.getset("key1".to_owned(), "value2".to_owned())?,
+        Some("value1".to_owned())
+    );
+    assert_eq!(engine.get("key1")?, Some("value2".to_owned()));
+    assert_eq!(engine.metadata("key1")?.map(|m| m.version), Some(2));
+    Ok(())
+}
+
+// getset should return the previous value and bump the version like a set
+#[test]
+fn getset() -> Result<()> {
+    each_engine(|engine| check_getset(&engine))?;
+    Ok(())
+}
+
+fn check_compare_and_swap<E: KvsEngine>(engine: &E) -> Result<()> {
+    assert!(engine.compare_and_swap("key1", None, Some("value1".to_owned()))?);
+    assert!(!engine.compare_and_swap("key1", None, Some("value2".to_owned()))?);
+    assert!(!engine.compare_and_swap("key1", Some("value2"), Some("value3".to_owned()))?);
+    assert_eq!(engine.get("key1")?, Some("value1".to_owned()));
+    assert!(engine.compare_and_swap("key1", Some("value1"), Some("value2".to_owned()))?);
+    assert_eq!(engine.get("key1")?, Some("value2".to_owned()));
+    assert_eq!(engine.metadata("key1")?.map(|m| m.version), Some(2));
+    assert!(engine.compare_and_swap("key1", Some("value2"), None)?);
+    assert_eq!(engine.get("key1")?, None);
+    assert!(engine.compare_and_swap("key1", None, None)?);
+    assert!(!engine.compare_and_swap("key1", Some("value2"), None)?);
+    Ok(())
+}
+
+// 8 threads each bump a counter 25 times with compare_and_swap, so the value and the version in the metadata must both account for every swap
+fn check_compare_and_swap_concurrent<E: KvsEngine + Clone>(engine: &E) -> Result<()> {
+    assert!(engine.compare_and_swap("counter", None, Some("0".to_owned()))?);
+    let handles: Vec<_> = (0..8)
+        .map(|_| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                let mut done = 0;
+                while done < 25 {
+                    let current = engine.get("counter")?.unwrap();
+                    let next = (current.parse::<u64>().unwrap() + 1).to_string();
+                    if engine.compare_and_swap("counter", Some(&current), Some(next))? {
+                        done += 1;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(engine.get("counter")?, Some("200".to_owned()));
+    assert_eq!(engine.metadata("counter")?.map(|m| m.version), Some(201));
+    Ok(())
+}
+
+// compare_and_swap should only write when the current value is the expected one
+#[test]
+fn compare_and_swap() -> Result<()> {
+    each_engine(|engine| {
+        check_compare_and_swap(&engine)?;
+        engine.clear()?;
+        check_compare_and_swap_concurrent(&engine)
+    })?;
+    Ok(())
+}
+
+fn check_rename<E: KvsEngine>(engine: &E) -> Result<()> {
+    engine.set("a".to_owned(), "1".to_owned())?;
+    engine.set("b".to_owned(), "2".to_owned())?;
+    engine.set("c".to_owned(), "3".to_owned())?;
+    engine.set("a".to_owned(), "1".to_owned())?;
+
+    engine.rename("a", "d")?;
+    assert_eq!(engine.get("a")?, None);
+    assert_eq!(engine.get("d")?, Some("1".to_owned()));
+    assert_eq!(engine.metadata("d")?.map(|m| m.version), Some(2));
+
+    assert!(!engine.rename_nx("d", "b")?);
+    assert_eq!(engine.get("d")?, Some("1".to_owned()));
+    engine.rename("d", "b")?;
+    assert_eq!(engine.get("b")?, Some("1".to_owned()));
+    assert_eq!(engine.get("d")?, None);
+    assert_eq!(engine.get("c")?, Some("3".to_owned()));
+
+    assert!(engine.rename_nx("c", "e")?);
+    assert!(matches!(
+        engine.rename("missing", "x"),
+        Err(KvsError::NotFound { .. })
+    ));
+    Ok(())
+}
+
+// rename should move the value and its metadata, and rename_nx should not overwrite
+#[test]
+fn rename() -> Result<()> {
+    // write-behind would fold the two sets of "a" into one and the version would be off
+    let temp_dir = each_engine_with(CacheConfig::default(), |engine| check_rename(&engine))?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("b")?, Some("1".to_owned()));
+    assert_eq!(store.get("e")?, Some("3".to_owned()));
+    assert_eq!(store.get("c")?, None);
+    Ok(())
+}
+
+fn check_scan_matching<E: KvsEngine>(engine: &E) -> Result<()> {
+    for key in &[
+        "user:1:email",
+        "user:2:email",
+        "user:2:name",
+        "users",
+        "admin:1:email",
+    ] {
+        engine.set(key.to_string(), format!("{}-value", key))?;
+    }
+
+    let keys = |pairs: Vec<(String, String)>| -> Vec<String> {
+        pairs.into_iter().map(|(key, _)| key).collect()
+    };
+    assert_eq!(
+        keys(engine.scan_matching(&KeyFilter::Glob("user:*:email".to_owned()))?),
+        vec!["user:1:email", "user:2:email"]
+    );
+    assert_eq!(
+        keys(engine.scan_matching(&KeyFilter::Glob("user?".to_owned()))?),
+        vec!["users"]
+    );
+    assert_eq!(
+        keys(engine.scan_matching(&KeyFilter::Regex("^[a-z]+:1:".to_owned()))?),
+        vec!["admin:1:email", "user:1:email"]
+    );
+    assert_eq!(engine.scan_matching(&KeyFilter::All)?.len(), 5);
+    assert_eq!(
+        engine.scan_matching(&KeyFilter::Glob("users".to_owned()))?,
+        vec![("users".to_owned(), "users-value".to_owned())]
+    );
+    assert!(matches!(
+        engine.scan_matching(&KeyFilter::Regex("(".to_owned())),
+        Err(KvsError::InvalidArgument { .. })
+    ));
+    Ok(())
+}
+
+// scan_matching should filter keys with globs and regexes, and reject a bad regex
+#[test]
+fn scan_matching() -> Result<()> {
+    each_engine(|engine| check_scan_matching(&engine))?;
+    Ok(())
+}
+
+fn check_remove_matching<E: KvsEngine + Clone>(engine: &E) -> Result<()> {
+    engine.set("session:1".to_owned(), "a".to_owned())?;
+    engine.set("session:2".to_owned(), "b".to_owned())?;
+    engine.set("user:1".to_owned(), "c".to_owned())?;
+    engine
+        .namespace("sessions")?
+        .set("session:3".to_owned(), "d".to_owned())?;
+    assert_eq!(
+        engine.remove_matching(&KeyFilter::Glob("session:*".to_owned()))?,
+        2
+    );
+    assert_eq!(engine.get("session:1")?, None);
+    assert_eq!(engine.get("user:1")?, Some("c".to_owned()));
+    assert_eq!(
+        engine.remove_matching(&KeyFilter::Glob("session:*".to_owned()))?,
+        0
+    );
+
+    let sessions = engine.namespace("sessions")?;
+    assert_eq!(sessions.remove_matching(&KeyFilter::All)?, 1); // only this namespace
+    assert_eq!(engine.get("user:1")?, Some("c".to_owned()));
+    assert!(engine
+        .remove_matching(&KeyFilter::Regex("[".to_owned()))
+        .is_err());
+    Ok(())
+}
+
+// remove_matching should delete exactly the matching keys and report how many
+#[test]
+fn remove_matching() -> Result<()> {
+    each_engine(|engine| check_remove_matching(&engine))?;
+    Ok(())
+}
+
+// A key dumped from one engine should restore into another one, and damaged blobs should be rejected
+#[test]
+fn dump_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let source = KvStore::open(temp_dir.path())?;
+    source.set("key1".to_owned(), "value1".to_owned())?;
+    source.set("key1".to_owned(), "value2".to_owned())?;
+    assert_eq!(source.dump("key2")?, None);
+
+    let dump = source.dump("key1")?.unwrap();
+    assert_eq!(dump.meta.version, 2);
+    let blob = dump.to_blob()?;
+    assert_eq!(Dump::from_blob(&format!("{}\n", blob))?, dump);
+
+    let mut damaged = blob.clone().into_bytes();
+    damaged[10] = if damaged[10] == b'0' { b'1' } else { b'0' };
+    assert!(matches!(
+        Dump::from_blob(&String::from_utf8(damaged).unwrap()),
+        Err(KvsError::BadDump { .. })
+    ));
+    assert!(matches!(
+        Dump::from_blob("not a blob"),
+        Err(KvsError::BadDump { .. })
+    ));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let target = SledKvsEngine::open(temp_dir.path())?;
+    target.restore(Dump::from_blob(&blob)?, false)?;
+    assert_eq!(target.get("key1")?, Some("value2".to_owned()));
+    assert!(matches!(
+        target.restore(dump.clone(), false),
+        Err(KvsError::KeyExists { .. })
+    ));
+    target.restore(dump, true)?;
+    assert_eq!(target.get("key1")?, Some("value2".to_owned()));
+    Ok(())
+}
+
+fn check_binary<E: KvsEngine>(engine: &E) -> Result<()> {
+    let key = vec![0u8, 0xff, b'k', 0];
+    let value = vec![0xc3u8, 0x28, 0, 1, 2];
+    engine.set_bytes(key.clone(), value.clone())?;
+    engine.set_bytes(b"text".to_vec(), b"plain".to_vec())?;
+    assert_eq!(engine.get_bytes(&key)?, Some(value));
+    assert_eq!(engine.get("text")?, Some("plain".to_owned()));
+
+    engine.set_bytes(b"bad".to_vec(), vec![0xff, 0xfe])?;
+    assert!(matches!(engine.get("bad"), Err(KvsError::NotUtf8 { .. })));
+
+    engine.remove_bytes(&key)?;
+    assert_eq!(engine.get_bytes(&key)?, None);
+    assert!(matches!(
+        engine.remove_bytes(&key),
+        Err(KvsError::NotFound { .. })
+    ));
+    Ok(())
+}
+
+// Keys and values that are not utf8 (or contain NUL) should roundtrip through every engine
+#[test]
+fn binary_keys_values() -> Result<()> {
+    let temp_dir = each_engine(|engine| check_binary(&engine))?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_bytes(b"bad")?, Some(vec![0xff, 0xfe]));
+    assert_eq!(store.get_bytes(&[0, 0xff, b'k', 0])?, None);
+    Ok(())
+}
+
+fn check_batch<E: KvsEngine>(engine: &E) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+
+    let mut batch = engine.batch();
+    batch
+        .set("key2".to_owned(), "value2".to_owned())
+        .set("key3".to_owned(), "value3".to_owned())
+        .remove("key1")
+        .remove("key3");
+    assert_eq!(batch.len(), 4);
+    batch.commit()?;
+    assert_eq!(engine.get("key1")?, None);
+    assert_eq!(engine.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(engine.get("key3")?, None);
+
+    // removing a missing key rejects the whole batch
+    let mut batch = engine.batch();
+    batch
+        .set("key2".to_owned(), "changed".to_owned())
+        .remove("key1");
+    assert!(matches!(batch.commit(), Err(KvsError::NotFound { .. })));
+    assert_eq!(engine.get("key2")?, Some("value2".to_owned()));
+
+    let mut batch = engine.batch();
+    batch.set("key4".to_owned(), "value4".to_owned());
+    drop(batch);
+    assert_eq!(engine.get("key4")?, None);
+    Ok(())
+}
+
+// Writes in a batch should all apply together, or not at all
+#[test]
+fn write_batch() -> Result<()> {
+    let temp_dir = each_engine(|engine| check_batch(&engine))?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.metadata("key2")?.unwrap().version, 1);
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_batch(&KvStore::open(temp_dir.path())?.metered())?;
+    Ok(())
+}
+
+fn check_scan<E: KvsEngine>(engine: &E) -> Result<()> {
+    for key in &["user:2", "user:1", "group:1", "user;", "users", "a"] {
+        engine.set(key.to_string(), format!("{}-value", key))?;
+    }
+    engine.set_bytes(vec![0xff, 0xff], b"high".to_vec())?;
+    engine.set_bytes(vec![0xff, 0xff, 0], b"higher".to_vec())?;
+
+    let keys =
+        |scan: kvs::Scan| -> Result<Vec<Vec<u8>>> { scan.map(|v| v.map(|(key, _)| key)).collect() };
+    assert_eq!(
+        keys(engine.scan_prefix(b"user:")?)?,
+        vec![b"user:1".to_vec(), b"user:2".to_vec()]
+    );
+    let pairs: Vec<(Vec<u8>, Vec<u8>)> = engine.scan_prefix(b"user:")?.collect::<Result<_>>()?;
+    assert_eq!(pairs[0].1, b"user:1-value".to_vec());
+    assert_eq!(
+        keys(engine.scan_prefix(&[0xff, 0xff])?)?,
+        vec![vec![0xff, 0xff], vec![0xff, 0xff, 0]]
+    );
+    assert_eq!(keys(engine.scan_prefix(b"nothing")?)?.len(), 0);
+
+    assert_eq!(
+        keys(engine.scan(b"group:1".to_vec()..b"user:2".to_vec())?)?,
+        vec![b"group:1".to_vec(), b"user:1".to_vec()]
+    );
+    assert_eq!(
+        keys(engine.scan(b"user;".to_vec()..)?)?,
+        vec![
+            b"user;".to_vec(),
+            b"users".to_vec(),
+            vec![0xff, 0xff],
+            vec![0xff, 0xff, 0]
+        ]
+    );
+    assert_eq!(
+        keys(engine.scan(b"a".to_vec()..=b"group:1".to_vec())?)?,
+        vec![b"a".to_vec(), b"group:1".to_vec()]
+    );
+    assert_eq!(keys(engine.scan(..)?)?.len(), 8);
+    assert_eq!(keys(engine.scan(b"z".to_vec()..b"a".to_vec())?)?.len(), 0);
+    Ok(())
+}
+
+// Range and prefix scans should return keys in order, without reading anything outside the range
+#[test]
+fn range_scan() -> Result<()> {
+    let temp_dir = each_engine(|engine| check_scan(&engine))?;
+    let store = KvStore::open(temp_dir.path())?;
+    let pairs: Vec<_> = store.scan_prefix(b"group")?.collect::<Result<_>>()?;
+    assert_eq!(
+        pairs,
+        vec![(b"group:1".to_vec(), b"group:1-value".to_vec())]
+    );
+    Ok(())
+}
+
+fn check_ttl<E: KvsEngine + Clone>(engine: &E) -> Result<()> {
+    engine.set_with_ttl(
+        "short".to_owned(),
+        "value1".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    engine.set_with_ttl(
+        "long".to_owned(),
+        "value2".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    engine.set("forever".to_owned(), "value3".to_owned())?;
+    engine.set_with_ttl(
+        "cleared".to_owned(),
+        "value4".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    engine.set("cleared".to_owned(), "value5".to_owned())?;
+
+    assert_eq!(engine.get("short")?, Some("value1".to_owned()));
+    assert!(engine.ttl("long")?.unwrap() > Duration::from_secs(3500));
+    assert_eq!(engine.ttl("forever")?, None);
+    assert!(matches!(
+        engine.ttl("missing"),
+        Err(KvsError::NotFound { .. })
+    ));
+
+    std::thread::sleep(Duration::from_millis(200));
+    assert_eq!(engine.get("short")?, None);
+    assert_eq!(engine.get_bytes(b"short")?, None);
+    assert_eq!(engine.metadata("short")?, None);
+    assert!(matches!(
+        engine.ttl("short"),
+        Err(KvsError::NotFound { .. })
+    ));
+    assert!(matches!(
+        engine.remove("short"),
+        Err(KvsError::NotFound { .. })
+    ));
+    assert_eq!(engine.get("long")?, Some("value2".to_owned()));
+    assert_eq!(engine.get("cleared")?, Some("value5".to_owned()));
+    let keys: Vec<Vec<u8>> = engine
+        .scan(..)?
+        .map(|v| v.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(
+        keys,
+        vec![b"cleared".to_vec(), b"forever".to_vec(), b"long".to_vec()]
+    );
+
+    // setting an expired key starts it over
+    engine.set("short".to_owned(), "value6".to_owned())?;
+    assert_eq!(engine.metadata("short")?.unwrap().version, 1);
+    Ok(())
+}
+
+// Keys set with a TTL should disappear once it runs out
+#[test]
+fn ttl_expiration() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check_ttl(&store)?;
+    store.set_with_ttl(
+        "gone".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    drop(store);
+    std::thread::sleep(Duration::from_millis(200));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("gone")?, None);
+    assert!(store.ttl("long")?.is_some());
+
+    each_engine(|engine| check_ttl(&engine))?;
+    Ok(())
+}
+
+// Every durability policy should keep acknowledged writes across a clean reopen
+#[test]
+fn durability() -> Result<()> {
+    let policies = [
+        Durability::Always,
+        Durability::Every(Duration::from_millis(10)),
+        Durability::Never,
+    ];
+    for durability in policies.iter().copied() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            durability,
+            ..Default::default()
+        };
+        let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        std::thread::sleep(Duration::from_millis(20));
+        store.set("big".to_owned(), "x".repeat(4 << 20))?;
+        store.set("key2".to_owned(), "value2".to_owned())?; // goes into a new segment
+        drop(store);
+        let store = KvStore::open_with(temp_dir.path(), options)?;
+        assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+        assert_eq!(store.stats()?.segments, Some(2));
+
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = SledOptions { durability };
+        let engine = SledKvsEngine::open_with(temp_dir.path(), options.clone())?;
+        engine.set("key1".to_owned(), "value1".to_owned())?;
+        drop(engine);
+        let engine = SledKvsEngine::open_with(temp_dir.path(), options)?;
+        assert_eq!(engine.get("key1")?, Some("value1".to_owned()));
+
+        #[cfg(feature = "redb")]
+        {
+            let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+            let options = RedbOptions {
+                durability,
+                ..Default::default()
+            };
+            let engine = RedbKvsEngine::open_with(temp_dir.path(), options.clone())?;
+            engine.set("key1".to_owned(), "value1".to_owned())?;
+            std::thread::sleep(Duration::from_millis(20));
+            engine.set("key2".to_owned(), "value2".to_owned())?;
+            drop(engine);
+            let engine = RedbKvsEngine::open_with(temp_dir.path(), options)?;
+            assert_eq!(engine.get("key1")?, Some("value1".to_owned()));
+            assert_eq!(engine.get("key2")?, Some("value2".to_owned()));
+        }
+    }
+    Ok(())
+}
+
+// flush should write back everything buffered above the engine and keep it across a reopen
+#[test]
+fn flush() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with(temp_dir.path(), KvStoreOptions::default())?;
+    let config = CacheConfig {
+        capacity: 16,
+        write_behind: Some(WriteBehind {
+            max_pending: 100,
+            max_staleness: Duration::from_secs(60),
+        }),
+    };
+    let mut engine = CachedEngine::new(store, config);
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("big".to_owned(), "x".repeat(4 << 20))?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.inner_mut().get("key2")?, None); // still pending
+    engine.flush()?;
+    assert_eq!(engine.inner_mut().get("key2")?, Some("value2".to_owned()));
+    drop(engine);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = SledOptions {
+        durability: Durability::Never,
+    };
+    let engine = SledKvsEngine::open_with(temp_dir.path(), options.clone())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.flush()?;
+    drop(engine);
+    let engine = SledKvsEngine::open_with(temp_dir.path(), options)?;
+    assert_eq!(engine.get("key1")?, Some("value1".to_owned()));
+
+    let engine = MemEngine::new().with_faults(Faults {
+        fail_next: 1,
+        ..Default::default()
+    });
+    assert!(engine.flush().is_err());
+    engine.flush()?;
+    Ok(())
+}
+
+fn check_namespace<E: KvsEngine + Clone>(engine: &E) -> Result<()> {
+    engine.clear()?;
+    engine.set("key1".to_owned(), "outside".to_owned())?;
+    let sessions = engine.namespace("sessions")?;
+    sessions.set("key1".to_owned(), "session1".to_owned())?;
+    sessions.set("key2".to_owned(), "session2".to_owned())?;
+    assert_eq!(sessions.get("key1")?, Some("session1".to_owned()));
+    let users = engine.namespace("users")?;
+    assert_eq!(users.get("key1")?, None);
+    users.set("key1".to_owned(), "user1".to_owned())?;
+    assert!(matches!(
+        users.remove("key2"),
+        Err(KvsError::NotFound { key }) if key == "key2"
+    ));
+    assert_eq!(engine.get("key1")?, Some("outside".to_owned()));
+    assert_eq!(engine.len()?, 1);
+    assert_eq!(
+        engine.keys()?.collect::<Result<Vec<_>>>()?,
+        vec![b"key1".to_vec()]
+    );
+    assert_eq!(
+        engine.iter()?.collect::<Result<Vec<_>>>()?,
+        vec![("key1".to_owned(), "outside".to_owned())]
+    );
+    assert_eq!(
+        engine.scan_matching(&KeyFilter::All)?,
+        vec![("key1".to_owned(), "outside".to_owned())]
+    );
+
+    let sessions = engine.namespace("sessions")?;
+    let keys: Vec<Vec<u8>> = sessions
+        .scan(..)?
+        .map(|v| v.map(|(key, _)| key))
+        .collect::<Result<_>>()?;
+    assert_eq!(keys, vec![b"key1".to_vec(), b"key2".to_vec()]);
+    assert_eq!(
+        sessions.scan_matching(&KeyFilter::Glob("*2".to_owned()))?,
+        vec![("key2".to_owned(), "session2".to_owned())]
+    );
+    assert_eq!(sessions.clear()?, 2);
+    assert_eq!(sessions.get("key2")?, None);
+    assert_eq!(
+        engine.namespace("users")?.get("key1")?,
+        Some("user1".to_owned())
+    );
+    assert_eq!(engine.get("key1")?, Some("outside".to_owned()));
+    engine.clear()?;
+    assert!(engine.is_empty()?);
+    assert_eq!(
+        engine.namespace("users")?.get("key1")?,
+        Some("user1".to_owned())
+    );
+    assert!(matches!(
+        engine.namespace("bad\0name"),
+        Err(KvsError::InvalidArgument { .. })
+    ));
+    Ok(())
+}
+
+// Namespaces should keep their keys apart from each other and out of the top level's keys, len and clear, and drop them as a whole
+#[test]
+fn namespaces() -> Result<()> {
+    let temp_dir = each_engine(|engine| check_namespace(&engine))?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        store.namespace("users")?.get("key1")?,
+        Some("user1".to_owned())
+    );
+    assert_eq!(store.namespace("sessions")?.get("key1")?, None);
+    Ok(())
+}
+
+fn check_len_keys<E: KvsEngine + Clone>(engine: &E) -> Result<()> {
+    assert!(engine.is_empty()?);
+    assert_eq!(engine.len()?, 0);
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value3".to_owned())?;
+    engine.set_bytes(vec![0xff], b"binary".to_vec())?;
+    engine.set_with_ttl(
+        "short".to_owned(),
+        "value4".to_owned(),
+        Duration::from_millis(50),
+    )?;
+    assert_eq!(engine.len()?, 4);
+    std::thread::sleep(Duration::from_millis(100));
+    assert_eq!(engine.len()?, 3);
+    assert!(!engine.is_empty()?);
+    let keys: Vec<Vec<u8>> = engine.keys()?.collect::<Result<_>>()?;
+    assert_eq!(keys, vec![b"key1".to_vec(), b"key2".to_vec(), vec![0xff]]);
+    engine.remove("key1")?;
+    assert_eq!(engine.len()?, 2);
+    Ok(())
+}
+
+// len, is_empty and keys should only count live keys
+#[test]
+fn len_keys() -> Result<()> {
+    let temp_dir = each_engine(|engine| check_len_keys(&engine))?;
+    assert_eq!(KvStore::open(temp_dir.path())?.len()?, 2);
+    check_len_keys(&MemEngine::new().namespace("ns")?)?;
+    Ok(())
+}
+
+fn check_iter<E: KvsEngine + Clone>(engine: &E) -> Result<()> {
+    assert_eq!(engine.iter()?.count(), 0);
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set_bytes(b"key3".to_vec(), vec![0xff])?;
+    engine.set("key4".to_owned(), "value4".to_owned())?;
+    engine.remove("key2")?;
+
+    let mut iter = engine.iter()?;
+    assert_eq!(
+        iter.next().transpose()?,
+        Some(("key1".to_owned(), "value1".to_owned()))
+    );
+    assert!(matches!(iter.next(), Some(Err(KvsError::NotUtf8 { .. }))));
+    assert_eq!(
+        iter.next().transpose()?,
+        Some(("key4".to_owned(), "value4".to_owned()))
+    );
+    assert!(iter.next().is_none());
+    Ok(())
+}
+
+// iter should walk every live pair in key order and keep going past values that are not utf8
+#[test]
+fn iter() -> Result<()> {
+    each_engine(|engine| check_iter(&engine))?;
+    check_iter(&MemEngine::new().namespace("ns")?)?;
+    check_iter(&MemEngine::new().boxed())?;
+    Ok(())
+}
+
+fn check_apply<E: KvsEngine + Clone>(engine: &E) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    let results = engine.apply(&[
+        Op::Get("key1".to_owned()),
+        Op::Set("key2".to_owned(), "value2".to_owned()),
+        Op::Get("key2".to_owned()),
+        Op::Remove("key1".to_owned()),
+        Op::Get("key1".to_owned()),
+    ])?;
+    assert_eq!(
+        results,
+        vec![
+            OpResult::Value(Some("value1".to_owned())),
+            OpResult::Done,
+            OpResult::Value(Some("value2".to_owned())),
+            OpResult::Done,
+            OpResult::Value(None),
+        ]
+    );
+    assert_eq!(engine.apply(&[])?, vec![]);
+
+    let results = engine.apply(&[
+        Op::Set("key3".to_owned(), "value3".to_owned()),
+        Op::Remove("key2".to_owned()),
+    ])?;
+    assert_eq!(results, vec![OpResult::Done, OpResult::Done]);
+    assert_eq!(engine.get("key3")?, Some("value3".to_owned()));
+    assert_eq!(engine.get("key2")?, None);
+
+    assert!(matches!(
+        engine.apply(&[Op::Remove("key2".to_owned())]),
+        Err(KvsError::NotFound { .. })
+    ));
+    Ok(())
+}
+
+// a batch of only writes should go through write_batch on KvStore and sled, so nothing lands if one op fails
+fn check_apply_atomic<E: KvsEngine + Clone>(engine: &E) -> Result<()> {
+    assert!(matches!(
+        engine.apply(&[
+            Op::Set("key4".to_owned(), "value4".to_owned()),
+            Op::Remove("missing".to_owned()),
+        ]),
+        Err(KvsError::NotFound { .. })
+    ));
+    assert_eq!(engine.get("key4")?, None);
+    Ok(())
+}
+
+// apply should run gets, sets and removes in order and return one result per op
+#[test]
+fn apply() -> Result<()> {
+    each_engine(|engine| check_apply(&engine))?;
+    check_apply(&MemEngine::new().namespace("ns")?)?;
+
+    // Only KvStore, sled and redb promise an atomic batch, also when boxed
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_apply_atomic(&KvStore::open(temp_dir.path())?.boxed())?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    check_apply_atomic(&SledKvsEngine::open(temp_dir.path())?)?;
+    #[cfg(feature = "redb")]
+    {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        check_apply_atomic(&RedbKvsEngine::open(temp_dir.path())?.boxed())?;
+    }
+    Ok(())
+}
+
+fn check_transaction<E: KvsEngine + Clone>(engine: &E) -> Result<()> {
+    engine.set("alice".to_owned(), "10".to_owned())?;
+    engine.set("bob".to_owned(), "5".to_owned())?;
+    engine.transaction(|txn| {
+        let alice: u32 = txn.get("alice")?.unwrap().parse().unwrap();
+        let bob: u32 = txn.get("bob")?.unwrap().parse().unwrap();
+        txn.set("alice".to_owned(), (alice - 3).to_string());
+        txn.set("bob".to_owned(), (bob + 3).to_string());
+        assert_eq!(txn.get("alice")?, Some("7".to_owned()));
+        txn.set("temp".to_owned(), "value".to_owned());
+        txn.remove("temp")?;
+        assert!(matches!(txn.remove("temp"), Err(KvsError::NotFound { .. })));
+        Ok(())
+    })?;
+    assert_eq!(engine.get("alice")?, Some("7".to_owned()));
+    assert_eq!(engine.get("bob")?, Some("8".to_owned()));
+    assert_eq!(engine.get("temp")?, None);
+
+    let result: Result<()> = engine.transaction(|txn| {
+        txn.set("alice".to_owned(), "0".to_owned());
+        txn.remove("bob")?;
+        Err(KvsError::InvalidArgument {
+            name: "amount".to_owned(),
+            value: "too much".to_owned(),
+        })
+    });
+    assert!(result.is_err());
+    assert_eq!(engine.get("alice")?, Some("7".to_owned()));
+    assert_eq!(engine.get("bob")?, Some("8".to_owned()));
+
+    let mut txn = engine.begin();
+    txn.set("carol".to_owned(), "1".to_owned());
+    txn.rollback();
+    assert_eq!(engine.get("carol")?, None);
+    Ok(())
+}
+
+// 4 threads bump a counter in transactions while 4 more bump it with compare_and_swap, so a commit that lets a plain write in between would lose increments
+fn check_transaction_concurrent<E: KvsEngine + Clone>(engine: &E) -> Result<()> {
+    engine.set("counter".to_owned(), "0".to_owned())?;
+    let bump = |value: &str| (value.parse::<u64>().unwrap() + 1).to_string();
+    let handles: Vec<_> = (0..8)
+        .map(|i| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                let mut done = 0;
+                while done < 25 {
+                    let bumped = if i % 2 == 0 {
+                        match engine.transaction(|txn| {
+                            let next = bump(&txn.get("counter")?.unwrap());
+                            txn.set("counter".to_owned(), next);
+                            Ok(())
+                        }) {
+                            Ok(()) => true,
+                            Err(KvsError::Conflict { .. }) => false,
+                            Err(e) => return Err(e),
+                        }
+                    } else {
+                        let current = engine.get("counter")?.unwrap();
+                        engine.compare_and_swap("counter", Some(&current), Some(bump(&current)))?
+                    };
+                    if bumped {
+                        done += 1;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(engine.get("counter")?, Some("200".to_owned()));
+    Ok(())
+}
+
+// Transactions should see their own writes and apply them all at once on commit, or not at all
+#[test]
+fn transactions() -> Result<()> {
+    let temp_dir = each_engine(|engine| check_transaction(&engine))?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("bob")?, Some("8".to_owned()));
+    each_engine(|engine| check_transaction_concurrent(&engine))?;
+    Ok(())
+}
+
+fn check_stream<E: KvsEngine + Clone>(engine: &E) -> Result<()> {
+    engine.set_from_reader(b"key1".to_vec(), &mut &b"value1"[..])?;
+    assert_eq!(engine.get("key1")?, Some("value1".to_owned()));
+    let mut reader = engine.get_reader(b"key1")?.unwrap();
+    assert_eq!(reader.len(), 6);
+    let mut value = String::new();
+    std::io::Read::read_to_string(&mut reader, &mut value)?;
+    assert_eq!(value, "value1");
+    drop(reader);
+    assert!(engine.get_reader(b"key2")?.is_none());
+    Ok(())
+}
+
+// Every engine should support streaming values in and out, even if it buffers them
+#[test]
+fn stream_values() -> Result<()> {
+    each_engine(|engine| check_stream(&engine))?;
+    check_stream(&MemEngine::new().namespace("ns")?)?;
+    check_stream(&MemEngine::new().metered())?;
+    assert!(matches!(
+        MemEngine::new()
+            .read_only()
+            .set_from_reader(b"key1".to_vec(), &mut &b"value1"[..]),
+        Err(KvsError::ReadOnly)
+    ));
+    Ok(())
+}
+
+// Sled should report what it can of the store stats
+#[test]
+fn sled_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.remove("key2")?;
+    let stats = engine.stats()?;
+    assert_eq!(stats.keys, 1);
+    assert!(stats.disk_bytes > 0);
+    assert_eq!((stats.garbage_bytes, stats.segments), (None, None));
+    Ok(())
+}
+
+// Redb should record itself in `.kvs`, lock its directory and take data migrated from kvs
+#[cfg(feature = "redb")]
+#[test]
+fn redb_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = RedbOptions {
+        durability: Durability::Never,
+        cache_size: Some(1 << 20),
+    };
+    let engine = RedbKvsEngine::open_with(temp_dir.path(), options)?;
+    assert_eq!(engine.engine_name(), "redb");
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.remove("key2")?;
+    engine.flush()?;
+    let stats = engine.stats()?;
+    assert_eq!(stats.keys, 1);
+    assert!(stats.disk_bytes > 0);
+    assert_eq!((stats.garbage_bytes, stats.segments), (None, None));
+
+    // The directory stays locked while the engine is open, and belongs to redb afterwards
+    assert!(matches!(
+        RedbKvsEngine::open(temp_dir.path()),
+        Err(KvsError::Locked { .. })
+    ));
+    drop(engine);
+    let manifest = std::fs::read_to_string(temp_dir.path().join(".kvs"))?;
+    assert!(manifest.contains(r#""engine": "redb""#));
+    assert!(manifest.contains(r#""durability": "Never""#));
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::BadArchive { .. })
+    ));
+    assert!(matches!(
+        SledKvsEngine::open(temp_dir.path()),
+        Err(KvsError::BadArchive { .. })
+    ));
+    let engine = kvs::open_engine("redb", temp_dir.path())?;
+    assert_eq!(engine.get("key1")?, Some("value1".to_owned()));
+    drop(engine);
+
+    let (kvs_dir, redb_dir) = (temp_dir.path().join("kvs"), temp_dir.path().join("redb"));
+    {
+        let store = KvStore::open(&kvs_dir)?;
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        store.set_with_ttl("ttl".to_owned(), "soon".to_owned(), Duration::from_secs(60))?;
+    }
+    assert_eq!(kvs::migrate(&kvs_dir, &redb_dir, "redb")?, 101);
+    let engine = RedbKvsEngine::open(&redb_dir)?;
+    assert_eq!(engine.len()?, 101);
+    assert_eq!(engine.get("key99")?, Some("value99".to_owned()));
+    assert!(engine.ttl("ttl")?.is_some());
+    Ok(())
+}
+
+// Wrapped engines should report the name and stats of the engine underneath, caches their own hit rate
+#[test]
+fn engine_stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let engine = CachedEngine::new(store, CacheConfig::default())
+        .read_only()
+        .boxed();
+    assert_eq!(engine.engine_name(), "kvs");
+    drop(engine);
+    assert_eq!(
+        MemEngine::new()
+            .size_limited(SizeLimits::default())
+            .engine_name(),
+        "memory"
+    );
+
+    let store = KvStore::open(temp_dir.path())?;
+    let engine = CachedEngine::new(store, CacheConfig::default());
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(engine.get("key1")?, Some("value1".to_owned()));
+    engine.remove("key1")?;
+    assert_eq!(engine.get("key1")?, None);
+    let stats = KvsEngine::stats(&engine)?;
+    assert_eq!(
+        (stats.keys, stats.cache_hits, stats.cache_misses),
+        (0, 1, 1)
+    );
+    assert!(stats.disk_bytes > 0);
+    Ok(())
+}
+
+// Migrating kvs -> sled -> kvs should keep every live pair, binary keys and TTLs included
+#[test]
+fn migrate_between_engines() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (kvs_dir, sled_dir, back_dir) = (
+        temp_dir.path().join("kvs"),
+        temp_dir.path().join("sled"),
+        temp_dir.path().join("back"),
+    );
+    {
+        let store = KvStore::open(&kvs_dir)?;
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}", i))?;
+        }
+        store.remove("key0")?;
+        store.set_bytes(vec![0xff, 0x00], vec![1, 2, 3])?;
+        store.set_with_ttl("ttl".to_owned(), "soon".to_owned(), Duration::from_secs(60))?;
+        store
+            .namespace("users")?
+            .set("key1".to_owned(), "user1".to_owned())?;
+    }
+
+    assert_eq!(kvs::migrate(&kvs_dir, &sled_dir, "sled")?, 102);
+    assert!(matches!(
+        KvStore::open(&sled_dir),
+        Err(KvsError::BadArchive { .. })
+    ));
+    assert_eq!(kvs::migrate(&sled_dir, &back_dir, "kvs")?, 102);
+
+    let store = KvStore::open(&back_dir)?;
+    assert_eq!(store.get("key0")?, None);
+    assert_eq!(store.get("key99")?, Some("value99".to_owned()));
+    assert_eq!(store.get_bytes(&[0xff, 0x00])?, Some(vec![1, 2, 3]));
+    assert!(store.metadata("ttl")?.unwrap().expires > 0);
+    assert_eq!(
+        store.namespace("users")?.get("key1")?,
+        Some("user1".to_owned())
+    );
+
+    // A non-empty destination or an unknown engine is refused
+    assert!(kvs::migrate(&kvs_dir, &back_dir, "kvs").is_err());
+    assert!(matches!(
+        kvs::migrate(&kvs_dir, temp_dir.path().join("other"), "rocksdb"),
+        Err(KvsError::UnsupportedEngine { .. })
+    ));
+    Ok(())
+}
+
+fn check_clear<E: KvsEngine + Clone>(engine: &E) -> Result<()> {
+    engine.clear()?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set_bytes(vec![0xff], vec![0x00])?;
+    engine
+        .namespace("users")?
+        .set("key1".to_owned(), "user1".to_owned())?;
+    engine.namespace("sessions")?.clear()?;
+    assert_eq!(engine.len()?, 2);
+
+    engine.clear()?;
+    assert!(engine.is_empty()?);
+    assert_eq!(engine.get("key1")?, None);
+    assert_eq!(engine.namespace("users")?.clear()?, 1); // the top-level clear left it alone
+    engine.set("key1".to_owned(), "again".to_owned())?;
+    assert_eq!(engine.get("key1")?, Some("again".to_owned()));
+    Ok(())
+}
+
+// clear should remove every top-level key, and a namespace should only clear its own keys
+#[test]
+fn clear() -> Result<()> {
+    each_engine(|engine| check_clear(&engine))?;
+    assert!(matches!(
+        MemEngine::new().read_only().clear(),
+        Err(KvsError::ReadOnly)
+    ));
+    Ok(())
+}
+
+fn check_copy<E: KvsEngine + Clone>(engine: &E) -> Result<()> {
+    engine.set("a".to_owned(), "1".to_owned())?;
+    engine.set("b".to_owned(), "2".to_owned())?;
+    engine.copy("a", "b")?;
+    engine.copy("a", "c")?;
+    assert_eq!(engine.get("a")?, Some("1".to_owned()));
+    assert_eq!(engine.get("b")?, Some("1".to_owned()));
+    assert_eq!(engine.get("c")?, Some("1".to_owned()));
+    assert!(matches!(
+        engine.copy("missing", "d"),
+        Err(KvsError::NotFound { key }) if key == "missing"
+    ));
+    assert_eq!(engine.get("d")?, None);
+
+    let users = engine.namespace("users")?;
+    assert!(matches!(
+        users.copy("a", "d"),
+        Err(KvsError::NotFound { key }) if key == "a"
+    ));
+    users.set("a".to_owned(), "user".to_owned())?;
+    users.copy("a", "b")?;
+    assert_eq!(users.get("b")?, Some("user".to_owned()));
+    assert_eq!(engine.get("b")?, Some("1".to_owned()));
+    Ok(())
+}
+
+// copy should duplicate a value under a new key, overwriting whatever was there
+#[test]
+fn copy() -> Result<()> {
+    each_engine(|engine| check_copy(&engine))?;
+    Ok(())
+}
+
+fn check_set_nx<E: KvsEngine>(engine: &E) -> Result<()> {
+    assert!(engine.set_nx("lock".to_owned(), "owner1".to_owned())?);
+    assert!(!engine.set_nx("lock".to_owned(), "owner2".to_owned())?);
+    assert_eq!(engine.get("lock")?, Some("owner1".to_owned()));
+    engine.remove("lock")?;
+    assert!(engine.set_nx("lock".to_owned(), "owner2".to_owned())?);
+
+    let mut called = 0;
+    let value = engine.get_or_insert_with("counter", || {
+        called += 1;
+        "0".to_owned()
+    })?;
+    assert_eq!(value, "0");
+    let value = engine.get_or_insert_with("counter", || {
+        called += 1;
+        "1".to_owned()
+    })?;
+    assert_eq!(value, "0");
+    assert_eq!(called, 1);
+    Ok(())
+}
+
+// set_nx should only write missing keys and get_or_insert_with should only call the closure for missing keys
+#[test]
+fn set_nx() -> Result<()> {
+    each_engine(|engine| check_set_nx(&engine))?;
+    Ok(())
+}
+
+fn check_append<E: KvsEngine + Clone>(engine: &E) -> Result<()> {
+    assert_eq!(engine.append("log", "line1\n")?, 6);
+    assert_eq!(engine.append("log", "line2\n")?, 12);
+    assert_eq!(engine.get("log")?, Some("line1\nline2\n".to_owned()));
+    engine.set_bytes(b"binary".to_vec(), vec![0xff])?;
+    assert_eq!(engine.append("binary", "a")?, 2);
+    assert_eq!(engine.get_bytes(b"binary")?, Some(vec![0xff, b'a']));
+    Ok(())
+}
+
+// append should extend the value, creating the key if it is missing
+#[test]
+fn append() -> Result<()> {
+    let temp_dir = each_engine(|engine| check_append(&engine))?;
+    assert_eq!(
+        KvStore::open(temp_dir.path())?.get("log")?,
+        Some("line1\nline2\n".to_owned())
+    );
+    Ok(())
+}
+
+fn check_contains_key<E: KvsEngine + Clone>(engine: &E) -> Result<()> {
+    assert!(!engine.contains_key("key1")?);
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(engine.contains_key("key1")?);
+    engine.remove("key1")?;
+    assert!(!engine.contains_key("key1")?);
+    engine.set_with_ttl(
+        "ttl".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(200),
+    )?;
+    assert!(engine.contains_key("ttl")?);
+    std::thread::sleep(Duration::from_millis(300));
+    assert!(!engine.contains_key("ttl")?);
+    Ok(())
+}
+
+// contains_key should track sets, removes and expiry
+#[test]
+fn contains_key() -> Result<()> {
+    each_engine(|engine| check_contains_key(&engine))?;
+    Ok(())
+}
+
+fn check_watch<E: KvsEngine + Clone>(engine: &E) -> Result<()> {
+    let next = |watcher: &mut Watcher| watcher.next_timeout(Duration::from_secs(5));
+    let mut watcher = engine.watch(b"config:")?;
+    engine.set("config:a".to_owned(), "1".to_owned())?;
+    engine.set("other".to_owned(), "2".to_owned())?;
+    engine.remove("config:a")?;
+    let mut batch = engine.batch();
+    batch.set("config:b".to_owned(), "3".to_owned());
+    batch.commit()?;
+    assert_eq!(
+        next(&mut watcher),
+        Some(WatchEvent::Set {
+            key: b"config:a".to_vec(),
+            value: b"1".to_vec()
+        })
+    );
+    assert_eq!(
+        next(&mut watcher),
+        Some(WatchEvent::Remove {
+            key: b"config:a".to_vec()
+        })
+    );
+    assert_eq!(next(&mut watcher).unwrap().key(), b"config:b");
+    assert_eq!(watcher.next_timeout(Duration::from_millis(50)), None);
+
+    let mut watcher = engine.namespace("app")?.watch(b"")?;
+    engine
+        .namespace("app")?
+        .set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key1".to_owned(), "outside".to_owned())?;
+    assert_eq!(
+        next(&mut watcher),
+        Some(WatchEvent::Set {
+            key: b"key1".to_vec(),
+            value: b"value1".to_vec()
+        })
+    );
+    assert_eq!(watcher.next_timeout(Duration::from_millis(50)), None);
+    Ok(())
+}
+
+// watch should deliver changes under a prefix, in order, and nothing outside it
+#[test]
+fn watch() -> Result<()> {
+    // write-behind would fold the set and remove of "config:a" into one event
+    each_engine_with(CacheConfig::default(), |engine| check_watch(&engine))?;
+
+    // The stream ends once the engine is gone
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let watcher = store.watch(b"")?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    assert_eq!(watcher.count(), 1);
+    Ok(())
+}
+
+fn check_secondary_index<E: KvsEngine + Clone>(engine: E) -> Result<()> {
+    let mut engine = engine.indexed();
+    engine.set(
+        "user:1".to_owned(),
+        r#"{"city":"Paris","age":30}"#.to_owned(),
+    )?;
+    engine.add_index(SecondaryIndex::json_pointer("city", "/city"))?;
+    engine.add_index(SecondaryIndex::new("initial", |value| {
+        value.first().map(|b| vec![*b])
+    }))?;
+    engine.set(
+        "user:2".to_owned(),
+        r#"{"city":"Paris","age":41}"#.to_owned(),
+    )?;
+    engine.set(
+        "user:3".to_owned(),
+        r#"{"city":"Rome","age":30}"#.to_owned(),
+    )?;
+    engine.set("plain".to_owned(), "not json".to_owned())?;
+
+    let keys = |pairs: Vec<(Vec<u8>, Vec<u8>)>| -> Vec<Vec<u8>> {
+        pairs.into_iter().map(|(key, _)| key).collect()
+    };
+    assert_eq!(
+        keys(engine.lookup("city", b"Paris")?),
+        vec![b"user:1".to_vec(), b"user:2".to_vec()]
+    );
+    assert_eq!(
+        keys(engine.lookup("initial", b"n")?),
+        vec![b"plain".to_vec()]
+    );
+
+    // Updates move the entry and removes drop it
+    engine.set("user:1".to_owned(), r#"{"city":"Rome"}"#.to_owned())?;
+    engine.remove("user:3")?;
+    assert_eq!(
+        keys(engine.lookup("city", b"Paris")?),
+        vec![b"user:2".to_vec()]
+    );
+    assert_eq!(
+        keys(engine.lookup("city", b"Rome")?),
+        vec![b"user:1".to_vec()]
+    );
+
+    // A batch touching the same key twice only leaves the final entry
+    let mut batch = engine.batch();
+    batch.set("user:4".to_owned(), r#"{"city":"Oslo"}"#.to_owned());
+    batch.set("user:4".to_owned(), r#"{"city":"Lima"}"#.to_owned());
+    batch.commit()?;
+    assert!(engine.lookup("city", b"Oslo")?.is_empty());
+    assert_eq!(
+        keys(engine.lookup("city", b"Lima")?),
+        vec![b"user:4".to_vec()]
+    );
+
+    // Index entries stay hidden from normal reads
+    assert_eq!(engine.len()?, 4);
+    assert_eq!(engine.scan_prefix(b"")?.count(), 4);
+    assert!(engine.lookup("missing", b"x").is_err());
+    assert!(engine.add_index(SecondaryIndex::new("", |_| None)).is_err());
+
+    engine.drop_index("city")?;
+    let inner = engine.into_inner();
+    assert_eq!(inner.keys()?.count(), 4); // index entries are hidden like namespaces
+    assert_eq!(inner.scan_prefix(b"")?.count(), 8); // four keys plus one "initial" entry each
+    Ok(())
+}
+
+// Secondary indexes should follow writes and answer lookups by field
+#[test]
+fn secondary_index() -> Result<()> {
+    each_engine(check_secondary_index)?;
+    Ok(())
+}
+
+// Keeps the value as a sorted, comma separated set of members
+fn union() -> MergeOperator {
+    MergeOperator::new(|_, existing, operand| {
+        let mut members: Vec<&[u8]> = existing
+            .map(|v| v.split(|b| *b == b',').collect())
+            .unwrap_or_default();
+        if !members.contains(&operand) {
+            members.push(operand);
+        }
+        members.sort();
+        members.join(&b","[..])
+    })
+}
+
+fn check_merge<E: KvsEngine + Clone>(engine: &E) -> Result<()> {
+    engine.set("tags".to_owned(), "b".to_owned())?;
+    for member in &["c", "a", "b"] {
+        engine.merge(b"tags", member.as_bytes().to_vec())?;
+    }
+    engine.merge(b"fresh", b"x".to_vec())?;
+    assert_eq!(engine.get_bytes(b"tags")?, Some(b"a,b,c".to_vec()));
+    assert_eq!(engine.get_bytes(b"fresh")?, Some(b"x".to_vec()));
+    assert_eq!(engine.metadata("tags")?.unwrap().size, 5);
+
+    // Merging keeps the TTL, and works through a namespace
+    engine.set_with_ttl("temp".to_owned(), "a".to_owned(), Duration::from_secs(60))?;
+    engine.merge(b"temp", b"b".to_vec())?;
+    assert!(engine.ttl("temp")?.is_some());
+    engine.namespace("app")?.merge(b"tags", b"z".to_vec())?;
+    assert_eq!(
+        engine.namespace("app")?.get_bytes(b"tags")?,
+        Some(b"z".to_vec())
+    );
+    Ok(())
+}
+
+// merge should fold operands into the value with the operator set on the engine
+#[test]
+fn merge() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStore::open(temp_dir.path())?;
+    store.set_merge_operator(union());
+    check_merge(&store)?;
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = SledKvsEngine::open(temp_dir.path())?;
+    store.set_merge_operator(union());
+    check_merge(&store)?;
+    #[cfg(feature = "redb")]
+    {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut store = RedbKvsEngine::open(temp_dir.path())?;
+        store.set_merge_operator(union());
+        check_merge(&store)?;
+    }
+    let mut store = MemEngine::new();
+    store.set_merge_operator(union());
+    check_merge(&store.cached(CacheConfig {
+        capacity: 10,
+        write_behind: None,
+    }))?;
+
+    let store = MemEngine::new();
+    assert!(matches!(
+        store.merge(b"key1", b"1".to_vec()),
+        Err(KvsError::NoMergeOperator)
+    ));
+    assert!(matches!(
+        MemEngine::new().read_only().merge(b"key1", b"1".to_vec()),
+        Err(KvsError::ReadOnly)
+    ));
+    Ok(())
+}
+
+struct ThreadWaker(Thread);
+
+impl Wake for ThreadWaker {
+    fn wake(self: Arc<Self>) {
+        self.0.unpark();
+    }
+}
+
+// Just enough of an executor to drive a single future on the current thread
+fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        thread::park();
+    }
+}
+
+fn check_async<E: AsyncKvsEngine>(engine: &E) -> Result<()> {
+    // all the writes are already running before anything is awaited
+    let writes: Vec<_> = (0..20)
+        .map(|i| engine.set(format!("key{}", i), format!("value{}", i)))
+        .collect();
+    for write in writes {
+        block_on(write)?;
+    }
+    assert_eq!(
+        block_on(engine.get("key7".to_owned()))?,
+        Some("value7".to_owned())
+    );
+    block_on(engine.remove("key7".to_owned()))?;
+    assert_eq!(block_on(engine.get("key7".to_owned()))?, None);
+    assert!(matches!(
+        block_on(engine.remove("key7".to_owned())),
+        Err(KvsError::NotFound { .. })
+    ));
+    let pairs = block_on(engine.scan(KeyFilter::Glob("key1*".to_owned())))?;
+    assert_eq!(pairs.len(), 11);
+    Ok(())
+}
+
+// Sync engines wrapped for async callers should behave like the engine underneath
+#[test]
+fn async_adapter() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    check_async(&store.clone().into_async(4))?;
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+    check_async(&MemEngine::new().into_async(1))?;
+    Ok(())
+}
+
+// Engines picked at runtime behind a Box<dyn KvsEngine> should behave like the engine inside
+#[test]
+fn boxed_engine() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engines: Vec<Box<dyn KvsEngine>> = vec![
+        KvStore::open(temp_dir.path().join("kvs"))?.boxed(),
+        SledKvsEngine::open(temp_dir.path().join("sled"))?.boxed(),
+        MemEngine::new().read_only().boxed(),
+    ];
+    for engine in engines[..2].iter() {
+        check_scan(engine)?; // wants to see every key, so it goes first
+        check_getdel(engine)?;
+        check_batch(engine)?;
+        check_namespace(engine)?;
+        assert_eq!(
+            engine.bulk_load((0..100).map(|i| (format!("bulk{}", i), i.to_string())))?,
+            100
+        );
+        let clone = engine.clone();
+        assert_eq!(clone.get("bulk42")?, Some("42".to_owned())); // same data underneath
+    }
+    assert!(matches!(
+        engines[2].set("key".to_owned(), "value".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    Ok(())
+}
+
+// Engines should open by name, including ones registered from outside the crate
+#[test]
+fn engine_registry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = kvs::open_engine("kvs", temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    drop(engine);
+    assert_eq!(
+        KvStore::open(temp_dir.path())?.get("key1")?,
+        Some("value1".to_owned())
+    );
+    assert!(matches!(
+        kvs::open_engine("nonexistent", temp_dir.path()),
+        Err(KvsError::UnsupportedEngine { .. })
+    ));
+
+    kvs::register_engine("frozen", |_| Ok(MemEngine::new().read_only().boxed()));
+    assert!(kvs::engine_names().contains(&"frozen".to_owned()));
+    let engine = kvs::open_engine("frozen", temp_dir.path())?;
+    assert!(matches!(
+        engine.set("key1".to_owned(), "value1".to_owned()),
+        Err(KvsError::ReadOnly)
+    ));
+    Ok(())
+}
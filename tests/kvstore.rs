@@ -1,4 +1,7 @@
-use kvs::{KvStore, KvsEngine, Result};
+use kvs::{
+    CompactionPolicy, Compression, Durability, ErrorKind, KvStore, KvStoreOptions, KvsEngine,
+    KvsError, Manifest, MergeOperator, Result, WarmUp,
+};
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -6,19 +9,19 @@ use walkdir::WalkDir;
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
 
-    assert_eq!(store.get("key1")?, Some("value1"));
-    assert_eq!(store.get("key2")?, Some("value2"));
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1")?, Some("value1"));
-    assert_eq!(store.get("key2")?, Some("value2"));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
 
     Ok(())
 }
@@ -27,19 +30,19 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
-    assert_eq!(store.get("key1")?, Some("value1"));
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
     store.set("key1".to_owned(), "value2".to_owned())?;
-    assert_eq!(store.get("key1")?, Some("value2"));
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
-    assert_eq!(store.get("key1")?, Some("value2"));
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
-    assert_eq!(store.get("key1")?, Some("value3"));
+    assert_eq!(store.get("key1")?, Some("value3".to_owned()));
 
     Ok(())
 }
@@ -48,14 +51,14 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2")?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2")?, None);
 
     Ok(())
@@ -64,7 +67,7 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1").is_err());
     Ok(())
 }
@@ -72,19 +75,40 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1").is_ok());
     assert_eq!(store.get("key1")?, None);
     Ok(())
 }
 
+// Removing a key written before others should only drop that key, also after reopen
+#[test]
+fn remove_earlier_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key1")?;
+    assert_eq!(store.get("key1")?, None);
+    assert!(store.remove("key1").is_err());
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+    Ok(())
+}
+
 // Insert data until total size of the directory decreases.
 // Test data correctness after compaction.
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -114,13 +138,1461 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
-            assert_eq!(store.get(&key[..])?, Some(&format!("{}", iter)[..]));
+            assert_eq!(store.get(&key[..])?, Some(format!("{}", iter).clone()));
         }
         return Ok(());
     }
 
     panic!("No compaction detected");
 }
+
+// Removing keys should only append tombstones, and compaction should reclaim both them and the removed values
+#[test]
+fn tombstones_compacted() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = "x".repeat(1000);
+    for i in 0..1000 {
+        store.set(format!("key{}", i), value.clone())?;
+    }
+    let log_size = || -> u64 {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap())
+            .filter(|entry| entry.path().extension().is_some_and(|v| v == "log"))
+            .map(|entry| entry.metadata().unwrap().len())
+            .sum()
+    };
+    let full = log_size();
+    for i in 1..1000 {
+        store.remove(&format!("key{}", i))?;
+    }
+    assert!(
+        log_size() < full / 5,
+        "{} of {} bytes of log left",
+        log_size(),
+        full
+    );
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0")?, Some(value.clone()));
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key999")?, None);
+    Ok(())
+}
+
+// Bulk load should keep the last value of duplicated keys and persist everything
+#[test]
+fn bulk_load() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key0".to_owned(), "old".to_owned())?;
+    assert_eq!(store.get("key0")?, Some("old".to_owned()));
+
+    let pairs = (0..100)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .chain(vec![("key1".to_owned(), "latest".to_owned())]);
+    assert_eq!(store.bulk_load(pairs)?, 101);
+    assert_eq!(store.get("key0")?, Some("value0".to_owned()));
+    assert_eq!(store.get("key1")?, Some("latest".to_owned()));
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0")?, Some("value0".to_owned()));
+    assert_eq!(store.get("key1")?, Some("latest".to_owned()));
+    assert_eq!(store.get("key99")?, Some("value99".to_owned()));
+
+    Ok(())
+}
+
+// Bulk load under Durability::Always should sort each batch, keep versions of repeated keys in input order and still persist everything
+#[test]
+fn bulk_load_durable() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        durability: Durability::Always,
+        ..Default::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    let pairs = (0..25000)
+        .rev()
+        .map(|i| (format!("key{:05}", i), format!("value{}", i)))
+        .chain(vec![("key00001".to_owned(), "latest".to_owned())]);
+    assert_eq!(store.bulk_load(pairs)?, 25001);
+    assert_eq!(store.get("key00001")?, Some("latest".to_owned()));
+    assert_eq!(store.metadata("key00001")?.map(|m| m.version), Some(2));
+    store.set("after".to_owned(), "value".to_owned())?; // durability is back to Always
+
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key00001")?, Some("latest".to_owned()));
+    assert_eq!(store.get("key24999")?, Some("value24999".to_owned()));
+    assert_eq!(store.get("after")?, Some("value".to_owned()));
+    assert_eq!(store.len()?, 25001);
+    Ok(())
+}
+
+// Leftovers of an interrupted write should be ignored and cleaned up on open
+#[test]
+fn interrupted_write_leftovers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let leftover = temp_dir.path().join("0.tmp");
+    std::fs::write(&leftover, "{\"Set\":[\"key1\",\"trunc").expect("unable to write leftover");
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!leftover.exists());
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+
+    Ok(())
+}
+
+// Open file handles should be reused and stay correct across overwrites and removals
+#[test]
+fn file_handle_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        max_open_files: 2,
+        ..Default::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    for i in 0..4 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key0")?, Some("value0".to_owned()));
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    let stats = store.handle_stats();
+    assert_eq!(stats.misses, 1); // all in the same segment
+    assert_eq!(stats.hits, 2);
+    assert_eq!(stats.open, 1);
+
+    store.set("key3".to_owned(), "value3b".to_owned())?;
+    store.remove("key0")?;
+    drop(store);
+
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions {
+            max_open_files: 2,
+            ..Default::default()
+        },
+    )?;
+    assert_eq!(store.get("key0")?, None);
+    assert_eq!(store.get("key3")?, Some("value3b".to_owned()));
+    assert_eq!(store.get("key3")?, Some("value3b".to_owned()));
+    assert_eq!(store.handle_stats().misses, 1);
+
+    Ok(())
+}
+
+// Preloading should serve every value from memory, and refuse datasets over the limit
+#[test]
+fn preload() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key3")?;
+    drop(store);
+
+    let too_small = KvStoreOptions {
+        preload: Some(10),
+        ..Default::default()
+    };
+    assert!(matches!(
+        KvStore::open_with(temp_dir.path(), too_small),
+        Err(KvsError::PreloadTooLarge { .. })
+    ));
+
+    let options = KvStoreOptions {
+        preload: Some(1 << 20),
+        ..Default::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    for i in 0..10 {
+        let expected = format!("value{}", i);
+        let expected = if i == 3 { None } else { Some(expected) };
+        assert_eq!(store.get(&format!("key{}", i))?, expected);
+    }
+    assert_eq!(store.handle_stats().misses, 0);
+
+    Ok(())
+}
+
+// Metadata should track creation, modification, size and version across reopen
+#[test]
+fn key_metadata() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.metadata("key1")?, None);
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let first = store.metadata("key1")?.expect("metadata missing");
+    assert_eq!(first.size, 6);
+    assert_eq!(first.version, 1);
+    assert_eq!(first.created, first.modified);
+
+    std::thread::sleep(std::time::Duration::from_millis(5));
+    store.set("key1".to_owned(), "value22".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    let second = store.metadata("key1")?.expect("metadata missing");
+    assert_eq!(second.size, 7);
+    assert_eq!(second.version, 2);
+    assert_eq!(second.created, first.created);
+    assert!(second.modified > first.modified);
+
+    store.remove("key1")?;
+    assert_eq!(store.metadata("key1")?, None);
+
+    Ok(())
+}
+
+// Commands should be appended to a few segment files instead of one file per command
+#[test]
+fn segment_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key1")?;
+    store.rename("key2", "renamed")?;
+    drop(store);
+
+    let files = std::fs::read_dir(temp_dir.path())
+        .expect("unable to list directory")
+        .count();
+    assert!(files <= 3, "{} files on disk", files);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key0")?, Some("value0".to_owned()));
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, None);
+    assert_eq!(store.get("renamed")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key999")?, Some("value999".to_owned()));
+    Ok(())
+}
+
+// Expired keys should be dropped from disk when the log is compacted
+#[test]
+fn compaction_purges_expired() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..10 {
+        store.set_with_ttl(
+            format!("expiring{}", key_id),
+            "value".to_owned(),
+            std::time::Duration::from_millis(100),
+        )?;
+    }
+    std::thread::sleep(std::time::Duration::from_millis(200));
+
+    let contains_expired = || {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file())
+            .any(|entry| {
+                let content = std::fs::read(entry.path()).expect("unable to read segment");
+                content.windows(8).any(|v| v == b"expiring")
+            })
+    };
+    assert!(contains_expired());
+    let value = "x".repeat(10000);
+    for _ in 0..1000 {
+        store.set("filler".to_owned(), value.clone())?;
+        if !contains_expired() {
+            drop(store);
+            let store = KvStore::open(temp_dir.path())?;
+            assert_eq!(store.get("expiring0")?, None);
+            assert_eq!(store.get("filler")?, Some(value.clone()));
+            return Ok(());
+        }
+    }
+    panic!("Expired keys survived compaction");
+}
+
+// A command torn by a crash should be dropped and the segment truncated
+#[test]
+fn torn_segment_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let segment = temp_dir.path().join("0.log");
+    let len = std::fs::metadata(&segment).expect("segment missing").len();
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&segment)
+        .expect("unable to open segment");
+    std::io::Write::write_all(&mut file, b"{\"Set\":[\"key2\",\"trunc")
+        .expect("unable to write torn command");
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        std::fs::metadata(&segment).expect("segment missing").len(),
+        len
+    );
+    assert_eq!(
+        std::fs::read(temp_dir.path().join("0.log.torn")).expect("torn bytes not kept"),
+        b"{\"Set\":[\"key2\",\"trunc"
+    );
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// A batch cut short by a crash should be dropped as a whole
+#[test]
+fn torn_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = store.batch();
+    batch
+        .set("key2".to_owned(), "value2".to_owned())
+        .remove("key1");
+    batch.commit()?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    drop(store);
+
+    let segment = temp_dir.path().join("0.log");
+    let len = std::fs::metadata(&segment).expect("segment missing").len();
+    let mut file = std::fs::OpenOptions::new()
+        .append(true)
+        .open(&segment)
+        .expect("unable to open segment");
+    std::io::Write::write_all(
+        &mut file,
+        b"{\"Batch\":2}\n{\"Set\":[\"key3\",\"value3\",{\"created\":1,\"modified\":1,\"size\":6,\"version\":1}]}\n",
+    )
+    .expect("unable to write torn batch");
+    drop(file);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        std::fs::metadata(&segment).expect("segment missing").len(),
+        len
+    );
+    assert_eq!(store.get("key3")?, None);
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Stores written in the old one-file-per-command layout should be migrated on open
+#[test]
+fn legacy_migration() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let write = |name: &str, content: &str| {
+        std::fs::write(temp_dir.path().join(name), content).expect("unable to write legacy file")
+    };
+    write(".kvs", "kvs");
+    write("0", r#"{"Set":["key1","value1"]}"#);
+    write("1", r#"{"Set":["key2","value2"]}"#);
+    write("2", r#"{"Remove":"key1"}"#);
+    write(
+        "3",
+        r#"{"Set":["key3","value3",{"created":1,"modified":2,"size":6,"version":2}]}"#,
+    );
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, None);
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.metadata("key3")?.map(|m| m.version), Some(2));
+    for name in &["0", "1", "2", "3"] {
+        assert!(!temp_dir.path().join(name).exists());
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A snapshot should hold the data as of when it was taken, and open as a store of its own
+#[test]
+fn snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dest = backup_dir.path().join("backup");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let manifest = store.snapshot(&dest)?;
+    assert_eq!(Manifest::read(&dest)?, manifest);
+    assert_eq!(manifest.segments.len(), 1);
+    assert!(store.snapshot(&dest).is_err()); // destination is not empty
+
+    store.set("key1".to_owned(), "changed".to_owned())?;
+    store.remove("key2")?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.get("key1")?, Some("changed".to_owned()));
+
+    let backup = KvStore::open(&dest)?;
+    assert_eq!(backup.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(backup.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(backup.get("key3")?, None);
+    backup.set("key4".to_owned(), "value4".to_owned())?;
+    drop(backup);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key4")?, None);
+    assert_eq!(store.get("key1")?, Some("changed".to_owned()));
+    Ok(())
+}
+
+// A checkpoint should seal the active segment and hard link every segment holding data, so nothing is copied
+#[test]
+fn checkpoint() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let dest = backup_dir.path().join("backup");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    let manifest = store.checkpoint(&dest)?;
+    assert_eq!(manifest.segments.len(), 2);
+    assert!(manifest.segments[0].len > 0);
+    assert_eq!(manifest.segments[1].len, 0); // the fresh segment the store writes to now
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let path = dest.join(format!("{}.log", manifest.segments[0].id));
+        assert_eq!(std::fs::metadata(path)?.nlink(), 2);
+    }
+
+    store.set("key1".to_owned(), "changed".to_owned())?;
+    store.remove("key2")?;
+    let backup = KvStore::open(&dest)?;
+    assert_eq!(backup.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(backup.get("key2")?, Some("value2".to_owned()));
+    backup.set("key3".to_owned(), "value3".to_owned())?;
+    drop(backup);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("changed".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+    assert_eq!(store.get("key3")?, None);
+    let manifest = store.checkpoint(backup_dir.path().join("again"))?;
+    assert_eq!(manifest.segments.len(), 3);
+    Ok(())
+}
+
+// A record damaged in the middle of a segment should be reported, or skipped when asked to
+#[test]
+fn corrupt_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let segment = temp_dir.path().join("0.log");
+    let mut content = std::fs::read(&segment).expect("unable to read segment");
+    let at = content
+        .windows(6)
+        .position(|v| v == b"value2")
+        .expect("record missing");
+    content[at] = b'V';
+    std::fs::write(&segment, &content).expect("unable to write segment");
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corrupt { path, offset, .. }) => {
+            assert_eq!(path, segment);
+            assert_eq!(content[offset as usize - 1], b'\n'); // start of a record
+            assert!(!content[offset as usize..at].contains(&b'\n')); // the damaged record itself
+        }
+        _ => panic!("damaged record not reported"),
+    }
+
+    let options = KvStoreOptions {
+        skip_corrupt: true,
+        ..Default::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+    assert_eq!(
+        std::fs::read(&segment).expect("unable to read segment"),
+        content
+    );
+    Ok(())
+}
+
+// With a bounded cache, values that were not read lately should be dropped from memory and read back from disk
+#[test]
+fn bounded_value_cache() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        cache_capacity: Some(2),
+        ..Default::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    let reads = |store: &KvStore| {
+        let stats = store.handle_stats();
+        stats.hits + stats.misses
+    };
+
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(reads(&store), 0);
+    assert_eq!(store.get("key1")?, Some("value1".to_owned())); // evicted, read back from disk
+    assert_eq!(reads(&store), 1);
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(reads(&store), 1);
+    assert_eq!(store.get("key3")?, Some("value3".to_owned())); // evicted by key1
+    assert_eq!(reads(&store), 2);
+
+    store.remove("key2")?;
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+    assert_eq!(store.get("key4")?, Some("value4".to_owned()));
+    assert_eq!(reads(&store), 2);
+    drop(store);
+
+    let store = KvStore::open_with(
+        temp_dir.path(),
+        KvStoreOptions {
+            preload: Some(1 << 20),
+            ..options
+        },
+    )?;
+    assert_eq!(store.get("key4")?, Some("value4".to_owned()));
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A read view should keep seeing the data as of when it was taken, even across compaction
+#[test]
+fn read_view() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let view = store.read_view();
+    assert_eq!(view.version(), 2);
+
+    store.set("key1".to_owned(), "changed".to_owned())?;
+    store.remove("key2")?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(store.version(), 5);
+    assert_eq!(view.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(view.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(view.get("key3")?, None);
+    assert_eq!(view.len(), 2);
+
+    let segments = || {
+        std::fs::read_dir(temp_dir.path())
+            .expect("unable to list directory")
+            .filter(|entry| entry.as_ref().unwrap().file_name() != ".kvs")
+            .count()
+    };
+    let value = "x".repeat(1000);
+    for _ in 0..3 {
+        for i in 0..1000 {
+            store.set(format!("filler{}", i), value.clone())?; // enough garbage to compact a few times
+        }
+    }
+    let pinned = segments();
+    assert!(pinned > 2, "{} segments on disk", pinned);
+    let pairs: Vec<(Vec<u8>, Vec<u8>)> = view.scan(..)?.collect::<Result<_>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            (b"key1".to_vec(), b"value1".to_vec()),
+            (b"key2".to_vec(), b"value2".to_vec())
+        ]
+    );
+
+    drop(view);
+    store.set("key4".to_owned(), "value4".to_owned())?;
+    assert!(segments() < pinned);
+    assert_eq!(store.get("key1")?, Some("changed".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("changed".to_owned()));
+    assert_eq!(store.get("key4")?, Some("value4".to_owned()));
+    Ok(())
+}
+
+// An I/O error on a file should say which file it was
+#[test]
+fn io_error_path() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_from_reader(b"big".to_vec(), &mut &vec![7; 3 << 20][..])?; // goes into a blob
+    let blob = std::fs::read_dir(temp_dir.path().join("blobs"))
+        .expect("unable to list blobs")
+        .next()
+        .expect("blob missing")
+        .expect("unable to list blobs")
+        .path();
+    std::fs::remove_file(&blob).expect("unable to remove blob");
+
+    let error = store.get_bytes(b"big").expect_err("blob should be gone");
+    assert!(matches!(&error, KvsError::File { path, .. } if *path == blob));
+    assert_eq!(error.kind(), ErrorKind::Io);
+    assert!(error.to_string().contains(&format!("{:?}", blob)));
+    Ok(())
+}
+
+// Large values should be streamed to their own blob file and back, and the blob removed once it is overwritten
+#[test]
+fn stream_large_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let blobs = |dir: &std::path::Path| match std::fs::read_dir(dir.join("blobs")) {
+        Ok(entries) => entries.count(),
+        Err(_) => 0,
+    };
+    let value: Vec<u8> = (0..3 << 20).map(|i| (i % 251) as u8).collect();
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_from_reader(b"big".to_vec(), &mut &value[..])?;
+    store.set_from_reader(b"small".to_vec(), &mut &b"value1"[..])?;
+    assert_eq!(blobs(temp_dir.path()), 1);
+
+    let mut reader = store.get_reader(b"big")?.unwrap();
+    assert_eq!(reader.len(), value.len() as u64);
+    let mut read = vec![];
+    std::io::Read::read_to_end(&mut reader, &mut read)?;
+    assert!(read == value);
+    drop(reader);
+    assert_eq!(store.get("small")?, Some("value1".to_owned()));
+    assert!(store.get_reader(b"missing")?.is_none());
+    store.rename("big", "moved")?;
+    assert_eq!(blobs(temp_dir.path()), 1);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.get_bytes(b"moved")?.unwrap() == value);
+    assert_eq!(store.metadata("moved")?.unwrap().size, value.len() as u64);
+    let dest = backup_dir.path().join("backup");
+    assert_eq!(store.snapshot(&dest)?.blobs.len(), 1);
+
+    store.set("moved".to_owned(), "value2".to_owned())?; // enough garbage to compact right away
+    assert_eq!(blobs(temp_dir.path()), 0);
+    assert_eq!(store.get("moved")?, Some("value2".to_owned()));
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("moved")?, Some("value2".to_owned()));
+    assert!(KvStore::open(&dest)?.get_bytes(b"moved")?.unwrap() == value);
+    Ok(())
+}
+
+// Compressed values should take less space on disk and read back the same with any setting
+#[test]
+fn compression() -> Result<()> {
+    let disk_usage = |dir: &std::path::Path| -> u64 {
+        WalkDir::new(dir)
+            .into_iter()
+            .map(|entry| entry.unwrap().metadata().unwrap())
+            .filter(|metadata| metadata.is_file())
+            .map(|metadata| metadata.len())
+            .sum()
+    };
+    let document = |i: usize| {
+        format!(
+            r#"{{"id":{},"name":"user{}","tags":["alpha","beta","gamma"],"bio":"{}"}}"#,
+            i,
+            i,
+            "lorem ipsum dolor sit amet ".repeat(20)
+        )
+    };
+    let plain_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(plain_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), document(i))?;
+    }
+    drop(store);
+
+    for algorithm in [Compression::Lz4, Compression::Zstd] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions {
+            compression: algorithm,
+            ..Default::default()
+        };
+        let store = KvStore::open_with(temp_dir.path(), options)?;
+        for i in 0..100 {
+            store.set(format!("key{}", i), document(i))?;
+        }
+        store.set("short".to_owned(), "value1".to_owned())?;
+        assert_eq!(store.get("key7")?, Some(document(7).clone()));
+        drop(store);
+        assert!(
+            disk_usage(temp_dir.path()) * 2 < disk_usage(plain_dir.path()),
+            "{:?} did not shrink the log",
+            algorithm
+        );
+
+        let store = KvStore::open(temp_dir.path())?; // reading doesn't depend on the setting
+        assert_eq!(store.get("key42")?, Some(document(42).clone()));
+        assert_eq!(store.get("short")?, Some("value1".to_owned()));
+        assert_eq!(
+            store.metadata("key42")?.unwrap().size,
+            document(42).len() as u64
+        );
+        let view = store.read_view();
+        assert_eq!(view.get("key99")?, Some(document(99)));
+    }
+    Ok(())
+}
+
+// Lookups for absent keys should be answered from the in-memory index without opening any segment
+#[test]
+fn missing_keys_skip_disk() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..100 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..1000 {
+        assert_eq!(store.get(&format!("missing{}", i))?, None);
+        assert_eq!(store.get_bytes(format!("other{}", i).as_bytes())?, None);
+    }
+    let stats = store.handle_stats();
+    assert_eq!((stats.hits, stats.misses, stats.open), (0, 0, 0));
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.handle_stats().misses, 1);
+    Ok(())
+}
+
+// Stats should track live keys, disk usage, garbage and value cache hits
+#[test]
+fn stats() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let stats = store.stats()?;
+    assert_eq!(
+        (stats.keys, stats.disk_bytes, stats.segments),
+        (0, 0, Some(0))
+    );
+    assert_eq!(stats.garbage_ratio(), Some(0.0));
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2")?;
+    let stats = store.stats()?;
+    assert_eq!(stats.keys, 1);
+    assert_eq!(stats.segments, Some(1));
+    assert!(stats.garbage_bytes.unwrap() > 0);
+    assert!(stats.garbage_bytes.unwrap() < stats.disk_bytes);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.stats()?.garbage_bytes, stats.garbage_bytes);
+    assert_eq!(store.get("key1")?, Some("value3".to_owned()));
+    assert_eq!(store.get("key1")?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+    let stats = store.stats()?;
+    assert_eq!((stats.cache_hits, stats.cache_misses), (1, 1));
+    assert_eq!(stats.cache_hit_rate(), 0.5);
+    Ok(())
+}
+
+// A directory should only be open in one store at a time, and free again once that store is dropped or closed
+#[test]
+fn lock_directory() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::Locked { .. })
+    ));
+    // The lock is taken before the manifest is even read
+    let manifest = temp_dir.path().join(".kvs");
+    let content = std::fs::read(&manifest)?;
+    std::fs::write(&manifest, "sled")?;
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::Locked { .. })
+    ));
+    std::fs::write(&manifest, content)?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.close()?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2")?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// The .kvs manifest should be upgraded from the bare engine name, and newer formats refused
+#[test]
+fn archive_manifest() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let manifest = temp_dir.path().join(".kvs");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+    std::fs::write(&manifest, "kvs").expect("unable to write manifest");
+
+    let options = KvStoreOptions {
+        compression: Compression::Zstd,
+        ..Default::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    drop(store);
+    let content: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&manifest).expect("manifest missing")[..])?;
+    assert_eq!(content["engine"], "kvs");
+    assert_eq!(content["format"], 1);
+    assert_eq!(content["options"]["compression"], "Zstd");
+
+    std::fs::write(&manifest, r#"{"engine":"kvs","format":99}"#).expect("unable to write manifest");
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::UnsupportedFormat {
+            found: 99,
+            supported: 1,
+            ..
+        })
+    ));
+    std::fs::write(&manifest, r#"{"engine":"sled","format":1}"#).expect("unable to write manifest");
+    assert!(matches!(
+        KvStore::open(temp_dir.path()),
+        Err(KvsError::BadArchive { .. })
+    ));
+    Ok(())
+}
+
+// export_to writes every live key as JSON lines and import_from reads them back into another store
+#[test]
+fn export_and_import() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path().join("from"))?;
+    for i in 0..20 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.remove("key3")?;
+    store.set_bytes(vec![0xff, 0x00], vec![0xfe])?;
+    store.set_with_ttl(
+        "ttl".to_owned(),
+        "soon".to_owned(),
+        std::time::Duration::from_secs(60),
+    )?;
+
+    let mut exported = Vec::new();
+    assert_eq!(store.export_to(&mut exported)?, 21);
+    let text = String::from_utf8(exported.clone()).expect("export is not utf8");
+    assert!(text.starts_with("{\"version\":1}\n"));
+    assert!(text.contains("{\"key\":\"key7\",\"value\":\"value7\"}"));
+
+    let other = KvStore::open(temp_dir.path().join("to"))?;
+    assert_eq!(other.import_from(&exported[..])?, 21);
+    assert_eq!(other.get("key3")?, None);
+    assert_eq!(other.get("key19")?, Some("value19".to_owned()));
+    assert_eq!(other.get_bytes(&[0xff, 0x00])?, Some(vec![0xfe]));
+    let expires = store.metadata("ttl")?.unwrap().expires;
+    assert!(other.metadata("ttl")?.unwrap().expires.abs_diff(expires) < 1000);
+
+    assert!(matches!(
+        other.import_from(&b"{\"version\":2}\n"[..]),
+        Err(KvsError::BadDump { .. })
+    ));
+    Ok(())
+}
+
+// clear truncates the log on disk and nothing comes back after reopening
+#[test]
+fn clear_truncates_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let disk_usage = || -> u64 {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .map(|entry| entry.unwrap().metadata().unwrap())
+            .filter(|metadata| metadata.is_file())
+            .map(|metadata| metadata.len())
+            .sum()
+    };
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..1000 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set_bytes(b"big".to_vec(), vec![7; 2 << 20])?; // goes into a blob
+    let before = disk_usage();
+
+    store.clear()?;
+    assert!(store.is_empty()?);
+    assert!(disk_usage() < before / 100);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.is_empty()?);
+    assert_eq!(store.get("key1")?, None);
+    Ok(())
+}
+
+// contains_key answers from the index without reading or caching the value
+#[test]
+fn contains_key_skips_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_bytes(b"big".to_vec(), vec![7; 2 << 20])?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.contains_key("big")?);
+    assert!(store.contains_key("key1")?);
+    assert!(!store.contains_key("key2")?);
+    let stats = store.stats()?;
+    assert_eq!((stats.cache_hits, stats.cache_misses), (0, 0));
+    Ok(())
+}
+
+// Merge operands are folded on read, survive reopening, and compaction folds them into a plain value
+#[test]
+fn merge_operands() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let open = |operator: bool| -> Result<KvStore> {
+        let mut store = KvStore::open(temp_dir.path())?;
+        if operator {
+            store.set_merge_operator(MergeOperator::counter());
+        }
+        Ok(store)
+    };
+    let compact = |store: &KvStore| -> Result<()> {
+        store.set_bytes(b"pad".to_vec(), vec![0; 2 << 20])?;
+        store.set_bytes(b"pad".to_vec(), vec![0; 2 << 20])
+    };
+
+    let store = open(true)?;
+    store.set("hits".to_owned(), "10".to_owned())?;
+    drop(store);
+    let store = open(true)?;
+    for _ in 0..5 {
+        store.merge(b"hits", b"1".to_vec())?; // the value is not in memory yet
+    }
+    store.merge(b"fresh", b"-3".to_vec())?;
+    let view = store.read_view();
+    assert_eq!(store.get("hits")?, Some("15".to_owned()));
+    assert_eq!(view.get("fresh")?, Some("-3".to_owned()));
+    drop(view);
+    drop(store);
+
+    // Without the operator merged keys can't be read, but compaction keeps their operands
+    let store = open(false)?;
+    assert!(matches!(store.get("hits"), Err(KvsError::NoMergeOperator)));
+    assert!(matches!(
+        store.merge(b"hits", b"1".to_vec()),
+        Err(KvsError::NoMergeOperator)
+    ));
+    compact(&store)?;
+    drop(store);
+
+    let store = open(true)?;
+    assert_eq!(store.get("hits")?, Some("15".to_owned()));
+    assert_eq!(store.get("fresh")?, Some("-3".to_owned()));
+    store.merge(b"hits", b"5".to_vec())?;
+    compact(&store)?;
+    assert_eq!(store.metadata("hits")?.unwrap().size, 2);
+    drop(store);
+
+    // Once folded, no operator is needed anymore
+    let store = open(false)?;
+    assert_eq!(store.get("hits")?, Some("20".to_owned()));
+    assert_eq!(store.get("fresh")?, Some("-3".to_owned()));
+    Ok(())
+}
+
+// Size-tiered compaction merges only the segments of one tier, reports the pending work as debt, and keeps removed keys removed
+#[test]
+fn size_tiered_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let segment = |id: u64| temp_dir.path().join(format!("{}.log", id));
+    let pad = || "y".repeat(4 << 20); // fills a segment on its own
+
+    // Written with the default policy: segment 0 is twice as large as segments 1 to 4
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("gone".to_owned(), "v0".to_owned())?;
+    store.set("keep".to_owned(), "v0".to_owned())?;
+    store.set("big".to_owned(), "x".repeat(8 << 20))?;
+    store.remove("gone")?;
+    store.set("pad1".to_owned(), pad())?;
+    store.set("keep".to_owned(), "v1".to_owned())?;
+    store.set("pad2".to_owned(), pad())?;
+    store.set("pad3".to_owned(), pad())?;
+    store.set("pad4".to_owned(), pad())?;
+    drop(store);
+
+    let options = KvStoreOptions {
+        compaction: CompactionPolicy::SizeTiered {
+            min_merge: 3,
+            max_merge: 3,
+            ratio: 2,
+        },
+        ..Default::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    let stats = store.stats()?;
+    assert_eq!(stats.segments, Some(5));
+    assert!(stats.compaction_debt.unwrap() >= 3 * (4 << 20)); // segments 1 to 3, 4 is still being written
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let stats = store.stats()?;
+    assert_eq!(stats.compaction_debt, Some(0));
+    assert_eq!(stats.segments, Some(4)); // the write itself went into a new segment 5
+    assert!(segment(0).exists() && segment(4).exists());
+    assert!(!segment(1).exists() && !segment(3).exists());
+    assert_eq!(store.get("gone")?, None);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("gone")?, None);
+    assert_eq!(store.get("keep")?, Some("v1".to_owned()));
+    assert_eq!(store.get("pad2")?.map(|v| v.len()), Some(4 << 20));
+    assert_eq!(store.get("big")?.map(|v| v.len()), Some(8 << 20));
+    assert_eq!(store.len()?, 7);
+    Ok(())
+}
+
+// Reading segments on several threads at open should rebuild exactly the same index as reading them one by one
+#[test]
+fn parallel_recovery() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for round in 0..4 {
+        for i in 0..100 {
+            store.set(format!("key{}", i), format!("value{}-{}", i, round))?;
+        }
+        for i in (round..100).step_by(7) {
+            store.remove(&format!("key{}", i))?;
+        }
+        store.set(format!("pad{}", round), "x".repeat(4 << 20))?; // every round ends its own segment
+    }
+    drop(store);
+
+    let open = |threads: usize| -> Result<KvStore> {
+        let options = KvStoreOptions {
+            recovery_threads: Some(threads),
+            ..Default::default()
+        };
+        KvStore::open_with(temp_dir.path(), options)
+    };
+    let store = open(1)?;
+    assert!(store.stats()?.segments.unwrap() >= 4);
+    let pairs = store.scan_prefix(b"key")?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(pairs.len(), 100 - 14); // what the last round removed
+    let garbage = store.stats()?.garbage_bytes;
+    drop(store);
+
+    let store = open(4)?;
+    assert_eq!(
+        store.scan_prefix(b"key")?.collect::<Result<Vec<_>>>()?,
+        pairs
+    );
+    assert_eq!(store.stats()?.garbage_bytes, garbage);
+    Ok(())
+}
+
+// With a disk quota, overwrites compact instead of failing, new data is refused once full, and removes free space again
+#[test]
+fn disk_quota() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let limit = 64 * 1024;
+    let options = KvStoreOptions {
+        max_disk_bytes: Some(limit),
+        ..Default::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options.clone())?;
+    let value = "x".repeat(1000);
+    for _ in 0..200 {
+        store.set("key".to_owned(), value.clone())?; // far more than the quota in total
+    }
+    assert!(store.stats()?.disk_bytes <= limit);
+
+    let mut written = 0;
+    let error = loop {
+        match store.set(format!("key{}", written), value.clone()) {
+            Ok(()) => written += 1,
+            Err(e) => break e,
+        }
+    };
+    assert!(matches!(error, KvsError::QuotaExceeded { .. }));
+    assert!(written > 50);
+    assert!(store.stats()?.disk_bytes <= limit);
+
+    for i in 0..10 {
+        store.remove(&format!("key{}", i))?;
+    }
+    store.set("key0".to_owned(), value.clone())?;
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key0")?, Some(value.clone()));
+    assert!(matches!(
+        store.set("another".to_owned(), "x".repeat(20000)),
+        Err(KvsError::QuotaExceeded { .. })
+    ));
+    Ok(())
+}
+
+// With archiving on, compaction keeps old segments in the archive and the store can be rebuilt as of any earlier moment
+#[test]
+fn point_in_time_restore() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let archive_dir = TempDir::new().expect("unable to create temporary working directory");
+    let backup_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions {
+        archive: Some(archive_dir.path().to_owned()),
+        ..Default::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let good = store.metadata("key2")?.unwrap().modified;
+    std::thread::sleep(std::time::Duration::from_millis(5));
+
+    // bad writes, then enough overwrites to compact every segment away
+    store.set("key1".to_owned(), "oops".to_owned())?;
+    store.remove("key2")?;
+    for _ in 0..30 {
+        store.set("filler".to_owned(), "x".repeat(100_000))?;
+    }
+    assert!(store.stats()?.garbage_bytes < Some(1 << 20));
+
+    store.restore_to(backup_dir.path().join("good"), good)?;
+    let restored = KvStore::open(backup_dir.path().join("good"))?;
+    assert_eq!(restored.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(restored.get("key2")?, Some("value2".to_owned()));
+    assert_eq!(restored.get("filler")?, None);
+
+    let now = store.metadata("filler")?.unwrap().modified;
+    store.restore_to(backup_dir.path().join("now"), now)?;
+    let restored = KvStore::open(backup_dir.path().join("now"))?;
+    assert_eq!(restored.get("key1")?, Some("oops".to_owned()));
+    assert_eq!(restored.get("key2")?, None);
+    assert_eq!(restored.get("filler")?.map(|v| v.len()), Some(100_000));
+
+    // the archive alone is enough once the store itself is gone
+    drop(store);
+    std::fs::remove_dir_all(temp_dir.path())?;
+    KvStore::restore_archive(archive_dir.path(), backup_dir.path().join("gone"), good)?;
+    let restored = KvStore::open(backup_dir.path().join("gone"))?;
+    assert_eq!(restored.get("key1")?, Some("value1".to_owned()));
+
+    let plain = KvStore::open(temp_dir.path())?;
+    assert!(matches!(
+        plain.restore_to(backup_dir.path().join("none"), good),
+        Err(KvsError::InvalidArgument { .. })
+    ));
+    Ok(())
+}
+
+// A manual compaction should report what it reclaimed and call back with progress up to every live key
+#[test]
+fn manual_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..3000 {
+        store.set(format!("key{}", i), "value".to_owned())?;
+    }
+    for i in 0..1500 {
+        store.set(format!("key{}", i), "changed".to_owned())?;
+    }
+    let before = store.stats()?.disk_bytes;
+
+    let mut updates = vec![];
+    let report = store.compact_with_progress(|progress| updates.push(progress))?;
+    assert_eq!(report.segments_rewritten, 1);
+    assert_eq!(report.bytes_reclaimed, before - store.stats()?.disk_bytes);
+    assert!(report.bytes_reclaimed > 0);
+    assert_eq!(store.stats()?.garbage_bytes, Some(0));
+    let last = updates.last().unwrap();
+    assert_eq!((last.keys_done, last.keys_total), (3000, 3000));
+    assert!(updates.len() > 2);
+    assert!(updates
+        .windows(2)
+        .all(|pair| pair[0].keys_done <= pair[1].keys_done));
+
+    assert_eq!(store.compact()?.bytes_reclaimed, 0); // nothing left to throw away
+    assert_eq!(store.get("key0")?, Some("changed".to_owned()));
+    assert_eq!(store.get("key2999")?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Warming up on open should make the first reads of the chosen keys hit memory
+#[test]
+fn warm_up() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    store.set("key0".to_owned(), "rewritten".to_owned())?; // now the most recent write
+    drop(store);
+
+    let options = KvStoreOptions {
+        warm_up: Some(WarmUp::Recent(2)),
+        ..Default::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key0")?, Some("rewritten".to_owned()));
+    assert_eq!(store.get("key9")?, Some("value9".to_owned()));
+    assert_eq!(
+        (store.stats()?.cache_hits, store.stats()?.cache_misses),
+        (2, 0)
+    );
+    assert_eq!(store.get("key8")?, Some("value8".to_owned()));
+    assert_eq!(store.stats()?.cache_misses, 1);
+    drop(store);
+
+    let options = KvStoreOptions {
+        warm_up: Some(WarmUp::Keys(vec![b"key3".to_vec(), b"missing".to_vec()])),
+        ..Default::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options)?;
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+    assert_eq!(store.get("missing")?, None);
+    assert_eq!(store.stats()?.cache_hits, 1);
+    Ok(())
+}
+
+// verify should report damaged records, torn tails, missing and orphan blobs and leftovers without touching anything,
+// and repair should fix them so the store opens cleanly again
+#[test]
+fn verify_and_repair() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.set_from_reader(b"big".to_vec(), &mut &vec![7; 3 << 20][..])?; // goes into a blob
+    assert!(matches!(
+        KvStore::verify(temp_dir.path()),
+        Err(KvsError::Locked { .. })
+    ));
+    drop(store);
+    assert!(KvStore::verify(temp_dir.path())?.is_clean());
+
+    let segment = temp_dir.path().join("0.log");
+    let mut content = std::fs::read(&segment).expect("unable to read segment");
+    let at = content
+        .windows(6)
+        .position(|v| v == b"value2")
+        .expect("record missing");
+    content[at] = b'V';
+    content.extend_from_slice(b"{\"Set\":"); // torn tail
+    std::fs::write(&segment, &content).expect("unable to write segment");
+    let blobs = temp_dir.path().join("blobs");
+    let blob = std::fs::read_dir(&blobs)
+        .expect("unable to list blobs")
+        .next()
+        .expect("blob missing")
+        .expect("unable to list blobs")
+        .path();
+    std::fs::remove_file(&blob).expect("unable to remove blob");
+    std::fs::write(blobs.join("999.blob"), b"orphan").expect("unable to write blob");
+    std::fs::write(temp_dir.path().join("1.log.tmp"), b"leftover").expect("unable to write file");
+
+    let report = KvStore::verify(temp_dir.path())?;
+    assert!(!report.is_clean());
+    assert!(!report.repaired);
+    assert_eq!(report.corrupt.len(), 1);
+    assert_eq!(report.torn.len(), 1);
+    assert_eq!(report.missing_blobs.len(), 1);
+    assert_eq!(report.missing_blobs[0].key, "big");
+    assert_eq!(report.orphan_blobs, vec![999]);
+    assert_eq!(report.temporaries.len(), 1);
+    assert_eq!(
+        std::fs::read(&segment).expect("unable to read segment"),
+        content
+    );
+    let json = serde_json::to_string(&report).expect("unable to serialize report");
+    assert!(json.contains("\"missing_blobs\""));
+
+    let repaired = KvStore::repair(temp_dir.path())?;
+    assert!(repaired.repaired);
+    assert_eq!(repaired.corrupt, report.corrupt);
+    assert!(KvStore::verify(temp_dir.path())?.is_clean());
+    assert!(!blobs.join("999.blob").exists());
+    assert!(!temp_dir.path().join("1.log.tmp").exists());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1")?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2")?, None);
+    assert_eq!(store.get("key3")?, Some("value3".to_owned()));
+    assert_eq!(store.get_bytes(b"big")?, None);
+    Ok(())
+}
+
+// Overwrites should keep the configured number of old versions, across compaction and reopening
+#[test]
+fn version_history() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions {
+        keep_versions: 2,
+        ..Default::default()
+    };
+    let store = KvStore::open_with(temp_dir.path(), options())?;
+    for i in 1..=4 {
+        store.set("key1".to_owned(), format!("value{}", i))?;
+    }
+    let big = vec![7; 3 << 20];
+    store.set_from_reader(b"key2".to_vec(), &mut &big[..])?; // goes into a blob
+    store.set("key2".to_owned(), "small".to_owned())?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get_version(b"key1", 0)?, Some(b"value4".to_vec()));
+        assert_eq!(store.get_version(b"key1", 1)?, Some(b"value3".to_vec()));
+        assert_eq!(store.get_version(b"key1", 2)?, Some(b"value2".to_vec()));
+        assert_eq!(store.get_version(b"key1", 3)?, None);
+        let values: Vec<Vec<u8>> = store
+            .history(b"key1")?
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect();
+        assert_eq!(
+            values,
+            vec![b"value4".to_vec(), b"value3".to_vec(), b"value2".to_vec()]
+        );
+        assert_eq!(store.get_version(b"key2", 1)?, Some(big.clone()));
+        Ok(())
+    };
+    check(&store)?;
+    store.compact()?;
+    check(&store)?;
+    drop(store);
+
+    let store = KvStore::open_with(temp_dir.path(), options())?;
+    check(&store)?;
+    store.compact()?;
+    drop(store);
+    let store = KvStore::open_with(temp_dir.path(), options())?;
+    check(&store)?;
+
+    store.remove("key1")?;
+    assert!(store.history(b"key1")?.is_empty());
+    assert_eq!(store.get_version(b"key1", 1)?, None);
+    drop(store);
+
+    // without the option nothing is kept
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_version(b"key2", 0)?, Some(b"small".to_vec()));
+    assert_eq!(store.get_version(b"key2", 1)?, None);
+    Ok(())
+}
+
+// Several threads can share one store through an Arc; set_nx and compare_and_swap stay exclusive
+#[test]
+fn shared_between_threads() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = std::sync::Arc::new(KvStore::open(temp_dir.path())?);
+    store.set("counter".to_owned(), "0".to_owned())?;
+
+    let winners: Vec<bool> = std::thread::scope(|scope| {
+        let handles: Vec<_> = (0..8)
+            .map(|t| {
+                let store = store.clone();
+                scope.spawn(move || -> Result<bool> {
+                    for i in 0..50 {
+                        store.set(format!("key{}-{}", t, i), format!("value{}", i))?;
+                        assert_eq!(
+                            store.get(&format!("key{}-{}", t, i))?,
+                            Some(format!("value{}", i))
+                        );
+                        // retry until our increment wins
+                        loop {
+                            let current = store.get("counter")?.unwrap();
+                            let next = (current.parse::<u32>().unwrap() + 1).to_string();
+                            if store.compare_and_swap("counter", Some(&current[..]), Some(next))? {
+                                break;
+                            }
+                        }
+                    }
+                    store.set_nx("lock".to_owned(), t.to_string())
+                })
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect::<Result<_>>()
+    })?;
+
+    assert_eq!(winners.iter().filter(|won| **won).count(), 1);
+    assert_eq!(store.get("counter")?, Some("400".to_owned()));
+    assert_eq!(store.len()?, 8 * 50 + 2);
+    Ok(())
+}
+
+// Clones share the same store, so each thread can own one
+#[test]
+fn clones_share_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let handles: Vec<_> = (0..4)
+        .map(|t| {
+            let store = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for i in 0..100 {
+                    store.set(format!("key{}-{}", t, i), format!("value{}", i))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(store.len()?, 400);
+    assert_eq!(store.get("key3-99")?, Some("value99".to_owned()));
+
+    // the directory lock is only released once the last clone is gone
+    let other = store.clone();
+    store.close()?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    other.set("late".to_owned(), "value".to_owned())?;
+    drop(other);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("late")?, Some("value".to_owned()));
+    Ok(())
+}
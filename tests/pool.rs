@@ -0,0 +1,54 @@
+use kvs::{NaiveThreadPool, RayonThreadPool, Result, SharedQueueThreadPool, ThreadPool};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::time::Duration;
+
+const JOBS: usize = 64;
+
+fn check_spawn<P: ThreadPool>(pool: &P) -> Result<()> {
+    let counter = Arc::new(AtomicUsize::new(0));
+    let (sender, receiver) = channel();
+    for _ in 0..JOBS {
+        let counter = counter.clone();
+        let sender = sender.clone();
+        pool.spawn(move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            sender.send(()).unwrap();
+        });
+    }
+    for _ in 0..JOBS {
+        receiver
+            .recv_timeout(Duration::from_secs(10))
+            .expect("job did not finish");
+    }
+    assert_eq!(counter.load(Ordering::SeqCst), JOBS);
+    Ok(())
+}
+
+fn check_panic<P: ThreadPool>(pool: &P) -> Result<()> {
+    for _ in 0..JOBS {
+        pool.spawn(|| panic!("expected panic in thread pool test"));
+    }
+    check_spawn(pool)
+}
+
+// Every pool should run every job it is given
+#[test]
+fn spawn() -> Result<()> {
+    check_spawn(&NaiveThreadPool::new(4)?)?;
+    check_spawn(&SharedQueueThreadPool::new(4)?)?;
+    check_spawn(&SharedQueueThreadPool::new(0)?)?;
+    check_spawn(&RayonThreadPool::new(4)?)?;
+    Ok(())
+}
+
+// Jobs that panic shouldn't take the workers down with them
+#[test]
+fn panic_recovery() -> Result<()> {
+    std::panic::set_hook(Box::new(|_| {})); // keep the expected panics out of the output
+    check_panic(&NaiveThreadPool::new(4)?)?;
+    check_panic(&SharedQueueThreadPool::new(4)?)?;
+    check_panic(&RayonThreadPool::new(4)?)?;
+    Ok(())
+}
@@ -0,0 +1,1560 @@
+use kvs::{
+    Access, Accounts, Acl, AuditConfig, AuditEntry, Credentials, Encoding, EngineExt, ErrorKind,
+    Hello, KeyFilter, KvStore, KvsClient, KvsEngine, KvsError, KvsServer, MonitorConfig,
+    MonitorEvent, Protocol, RateLimit, RayonThreadPool, Result, SharedQueueThreadPool, SizeLimits,
+    ThreadPool, Throttle, Timeouts, DEFAULT_USER, PROTOCOL_VERSION,
+};
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// Run a kvs engine server in the background for the lifetime of the test process.
+fn spawn_server(addr: &'static str) -> TempDir {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path()).expect("unable to open store");
+    thread::spawn(move || {
+        let mut server = KvsServer::new(store);
+        server.run(addr).expect("server failed");
+    });
+    thread::sleep(Duration::from_millis(200));
+    temp_dir
+}
+
+#[test]
+fn client_rate_limit_fail() -> Result<()> {
+    let _dir = spawn_server("127.0.0.1:4100");
+    let mut client = KvsClient::connect("127.0.0.1:4100".to_owned())?.rate_limited(RateLimit {
+        ops_per_sec: 0.001,
+        burst: 2,
+        throttle: Throttle::Fail,
+    })?;
+
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1")?, Some("value1".to_owned()));
+    assert!(matches!(client.get("key1"), Err(KvsError::RateLimited)));
+    Ok(())
+}
+
+#[test]
+fn client_rate_limit_block() -> Result<()> {
+    let _dir = spawn_server("127.0.0.1:4101");
+    let mut client = KvsClient::connect("127.0.0.1:4101".to_owned())?.rate_limited(RateLimit {
+        ops_per_sec: 10.0,
+        burst: 1,
+        throttle: Throttle::Block,
+    })?;
+
+    let start = Instant::now();
+    for i in 0..4 {
+        client.set(format!("key{}", i), "value".to_owned())?;
+    }
+    // The first request uses the initial token, the other three wait ~100ms each
+    assert!(start.elapsed() >= Duration::from_millis(250));
+    Ok(())
+}
+
+// Rate limits that could never refill should be rejected instead of panicking later
+#[test]
+fn client_rate_limit_invalid() -> Result<()> {
+    for (ops_per_sec, burst) in [(0.0, 1), (-1.0, 1), (f64::NAN, 1), (10.0, 0)] {
+        let limit = RateLimit {
+            ops_per_sec,
+            burst,
+            throttle: Throttle::Block,
+        };
+        let result = KvsClient::connect("127.0.0.1:4100".to_owned())?.rate_limited(limit);
+        assert!(matches!(result, Err(KvsError::InvalidArgument { .. })));
+    }
+    Ok(())
+}
+
+#[test]
+fn client_bulk_load() -> Result<()> {
+    let _dir = spawn_server("127.0.0.1:4102");
+    let mut client = KvsClient::connect("127.0.0.1:4102".to_owned())?;
+    let pairs = (0..1000)
+        .map(|i| (format!("key{}", i), format!("value{}", i)))
+        .collect();
+    assert_eq!(client.bulk_load(pairs)?, 1000);
+    assert_eq!(client.get("key500")?, Some("value500".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn client_getdel() -> Result<()> {
+    let _dir = spawn_server("127.0.0.1:4103");
+    let mut client = KvsClient::connect("127.0.0.1:4103".to_owned())?;
+    client.set("token".to_owned(), "secret".to_owned())?;
+    assert_eq!(client.getdel("token")?, Some("secret".to_owned()));
+    assert_eq!(client.getdel("token")?, None);
+    Ok(())
+}
+
+#[test]
+fn client_getset() -> Result<()> {
+    let _dir = spawn_server("127.0.0.1:4104");
+    let mut client = KvsClient::connect("127.0.0.1:4104".to_owned())?;
+    assert_eq!(client.getset("lock".to_owned(), "a".to_owned())?, None);
+    assert_eq!(
+        client.getset("lock".to_owned(), "b".to_owned())?,
+        Some("a".to_owned())
+    );
+    assert_eq!(client.get("lock")?, Some("b".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn client_compare_and_swap() -> Result<()> {
+    let _dir = spawn_server("127.0.0.1:4110");
+    let mut client = KvsClient::connect("127.0.0.1:4110".to_owned())?;
+    assert!(client.compare_and_swap("counter", None, Some("1".to_owned()))?);
+    assert!(!client.compare_and_swap("counter", None, Some("1".to_owned()))?);
+    assert!(client.compare_and_swap("counter", Some("1"), Some("2".to_owned()))?);
+    assert_eq!(client.get("counter")?, Some("2".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn client_multi_get() -> Result<()> {
+    let _dir = spawn_server("127.0.0.1:4111");
+    let mut client = KvsClient::connect("127.0.0.1:4111".to_owned())?;
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key3".to_owned(), "value3".to_owned())?;
+    assert_eq!(
+        client.multi_get(&["key1", "key2", "key3"])?,
+        vec![Some("value1".to_owned()), None, Some("value3".to_owned())]
+    );
+    Ok(())
+}
+
+#[test]
+fn client_transaction() -> Result<()> {
+    let _dir = spawn_server("127.0.0.1:4112");
+    let mut client = KvsClient::connect("127.0.0.1:4112".to_owned())?;
+    let mut other = KvsClient::connect("127.0.0.1:4112".to_owned())?;
+    client.set("counter".to_owned(), "1".to_owned())?;
+    client.transaction(|txn| {
+        let counter: u32 = txn.get("counter")?.unwrap().parse().unwrap();
+        txn.set("counter".to_owned(), (counter + 1).to_string());
+        txn.set("log".to_owned(), "incremented".to_owned());
+        Ok(())
+    })?;
+    assert_eq!(client.get("counter")?, Some("2".to_owned()));
+    assert_eq!(client.get("log")?, Some("incremented".to_owned()));
+
+    let mut txn = client.begin();
+    assert_eq!(txn.get("counter")?, Some("2".to_owned()));
+    txn.set("counter".to_owned(), "3".to_owned());
+    other.set("counter".to_owned(), "10".to_owned())?;
+    assert!(matches!(
+        txn.commit(),
+        Err(KvsError::Conflict { key }) if key == "counter"
+    ));
+    assert_eq!(client.get("counter")?, Some("10".to_owned()));
+    Ok(())
+}
+
+// Remote transactions racing plain APPENDs from other connections should never drop an append
+#[test]
+fn client_transaction_concurrent() -> Result<()> {
+    let _dir = spawn_server("127.0.0.1:4151");
+    let handles: Vec<_> = (0..8)
+        .map(|i| {
+            thread::spawn(move || -> Result<()> {
+                let mut client = KvsClient::connect("127.0.0.1:4151".to_owned())?;
+                let mut done = 0;
+                while done < 20 {
+                    if i % 2 == 0 {
+                        let result = client.transaction(|txn| {
+                            let log = txn.get("log")?.unwrap_or_default();
+                            txn.set("log".to_owned(), log + "t");
+                            Ok(())
+                        });
+                        match result {
+                            Ok(()) => done += 1,
+                            Err(KvsError::Conflict { .. }) => {}
+                            Err(e) => return Err(e),
+                        }
+                    } else {
+                        client.append("log", "a")?;
+                        done += 1;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    let mut client = KvsClient::connect("127.0.0.1:4151".to_owned())?;
+    assert_eq!(client.get("log")?.map(|v| v.len()), Some(160));
+    Ok(())
+}
+
+#[test]
+fn client_stream() -> Result<()> {
+    let _dir = spawn_server("127.0.0.1:4113");
+    let mut client = KvsClient::connect("127.0.0.1:4113".to_owned())?;
+    let value: Vec<u8> = (0..3 << 20).map(|i| (i % 251) as u8).collect();
+    client.set_from_reader(b"big".to_vec(), &mut &value[..], value.len() as u64)?;
+    let mut reader = client.get_reader(b"big")?.unwrap();
+    assert_eq!(reader.len(), value.len() as u64);
+    let mut read = vec![];
+    std::io::Read::read_to_end(&mut reader, &mut read)?;
+    assert!(read == value);
+    assert!(client.get_reader(b"missing")?.is_none());
+    assert!(client
+        .set_from_reader(b"short".to_vec(), &mut &b"value1"[..], 10)
+        .is_err());
+    assert_eq!(client.get("short")?, None);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1")?, Some("value1".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn client_rename() -> Result<()> {
+    let _dir = spawn_server("127.0.0.1:4105");
+    let mut client = KvsClient::connect("127.0.0.1:4105".to_owned())?;
+    client.set("a".to_owned(), "1".to_owned())?;
+    client.set("b".to_owned(), "2".to_owned())?;
+    assert!(!client.rename_nx("a", "b")?);
+    client.rename("a", "b")?;
+    assert_eq!(client.get("b")?, Some("1".to_owned()));
+    assert!(client.rename("a", "c").is_err());
+    Ok(())
+}
+
+#[test]
+fn client_copy() -> Result<()> {
+    let _dir = spawn_server("127.0.0.1:4114");
+    let mut client = KvsClient::connect("127.0.0.1:4114".to_owned())?;
+    client.set("a".to_owned(), "1".to_owned())?;
+    client.set("b".to_owned(), "2".to_owned())?;
+    client.copy("a", "b")?;
+    assert_eq!(client.get("a")?, Some("1".to_owned()));
+    assert_eq!(client.get("b")?, Some("1".to_owned()));
+    assert!(client.copy("missing", "c").is_err());
+    assert_eq!(client.get("c")?, None);
+    Ok(())
+}
+
+#[test]
+fn client_append() -> Result<()> {
+    let _dir = spawn_server("127.0.0.1:4116");
+    let mut client = KvsClient::connect("127.0.0.1:4116".to_owned())?;
+    assert_eq!(client.append("log", "a")?, 1);
+    assert_eq!(client.append("log", "bc")?, 3);
+    assert_eq!(client.get("log")?, Some("abc".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn client_contains_key() -> Result<()> {
+    let _dir = spawn_server("127.0.0.1:4117");
+    let mut client = KvsClient::connect("127.0.0.1:4117".to_owned())?;
+    assert!(!client.contains_key("key1")?);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert!(client.contains_key("key1")?);
+    Ok(())
+}
+
+// Metadata over the wire should keep the creation time and move the update time on every set
+#[test]
+fn client_metadata() -> Result<()> {
+    let _dir = spawn_server("127.0.0.1:4118");
+    let mut client = KvsClient::connect("127.0.0.1:4118".to_owned())?;
+    assert_eq!(client.metadata("key1")?, None);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    let first = client.metadata("key1")?.unwrap();
+    assert!(first.created > 0);
+    assert_eq!(first.modified, first.created);
+    thread::sleep(Duration::from_millis(10));
+    client.set("key1".to_owned(), "longer value".to_owned())?;
+    let second = client.metadata("key1")?.unwrap();
+    assert_eq!(second.created, first.created);
+    assert!(second.modified > first.modified);
+    assert_eq!(second.size, 12);
+    assert_eq!(second.version, 2);
+    Ok(())
+}
+
+#[test]
+fn client_set_nx() -> Result<()> {
+    let _dir = spawn_server("127.0.0.1:4115");
+    let handles: Vec<_> = (0..8)
+        .map(|i| {
+            thread::spawn(move || -> Result<bool> {
+                let mut client = KvsClient::connect("127.0.0.1:4115".to_owned())?;
+                client.set_nx("lock".to_owned(), format!("owner{}", i))
+            })
+        })
+        .collect();
+    let claimed = handles
+        .into_iter()
+        .map(|handle| handle.join().unwrap())
+        .collect::<Result<Vec<bool>>>()?;
+    assert_eq!(claimed.iter().filter(|v| **v).count(), 1);
+
+    let mut client = KvsClient::connect("127.0.0.1:4115".to_owned())?;
+    let owner = client.get("lock")?.unwrap();
+    assert_eq!(
+        client.get_or_insert("lock".to_owned(), "late".to_owned())?,
+        owner
+    );
+    assert_eq!(
+        client.get_or_insert("fresh".to_owned(), "value".to_owned())?,
+        "value"
+    );
+    Ok(())
+}
+
+#[test]
+fn client_scan() -> Result<()> {
+    let _dir = spawn_server("127.0.0.1:4106");
+    let mut client = KvsClient::connect("127.0.0.1:4106".to_owned())?;
+    client.set("user:1:email".to_owned(), "a@example.com".to_owned())?;
+    client.set("user:1:name".to_owned(), "a".to_owned())?;
+    client.set("user:2:email".to_owned(), "b@example.com".to_owned())?;
+    assert_eq!(
+        client.scan(KeyFilter::Glob("user:*:email".to_owned()))?,
+        vec![
+            ("user:1:email".to_owned(), "a@example.com".to_owned()),
+            ("user:2:email".to_owned(), "b@example.com".to_owned()),
+        ]
+    );
+    assert!(client.scan(KeyFilter::Regex("[".to_owned())).is_err());
+    Ok(())
+}
+
+// Keys matching a pattern should be removed on the server, with the count sent back
+#[test]
+fn client_remove_matching() -> Result<()> {
+    let _dir = spawn_server("127.0.0.1:4119");
+    let mut client = KvsClient::connect("127.0.0.1:4119".to_owned())?;
+    client.set("session:1".to_owned(), "a".to_owned())?;
+    client.set("session:2".to_owned(), "b".to_owned())?;
+    client.set("user:1".to_owned(), "c".to_owned())?;
+    assert_eq!(
+        client.remove_matching(KeyFilter::Glob("session:*".to_owned()))?,
+        2
+    );
+    assert_eq!(
+        client.scan(KeyFilter::All)?,
+        vec![("user:1".to_owned(), "c".to_owned())]
+    );
+    assert!(client
+        .remove_matching(KeyFilter::Regex("[".to_owned()))
+        .is_err());
+    Ok(())
+}
+
+// Monitor subscribers should see commands once they ran, and not the ones that failed
+#[test]
+fn client_monitor() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?.size_limited(SizeLimits {
+        max_key: None,
+        max_value: Some(8),
+    });
+    thread::spawn(move || {
+        let mut server = KvsServer::new(store).monitored(MonitorConfig {
+            token: "secret".to_owned(),
+            events_per_sec: 100.0,
+        });
+        server.run("127.0.0.1:4107").expect("server failed");
+    });
+    thread::sleep(Duration::from_millis(200));
+
+    let mut client = KvsClient::connect("127.0.0.1:4107".to_owned())?;
+    assert!(client.monitor("wrong").is_err());
+    let mut monitor = client.monitor("secret")?;
+
+    let mut client = KvsClient::connect("127.0.0.1:4107".to_owned())?;
+    assert!(client
+        .set("big".to_owned(), "too large".to_owned())
+        .is_err());
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.get("key2")?;
+    let event = monitor.next().unwrap()?;
+    assert_eq!((&event.op[..], event.key.as_deref()), ("set", Some("key1")));
+    assert!(event.peer.starts_with("127.0.0.1:"));
+    let event = monitor.next().unwrap()?;
+    assert_eq!((&event.op[..], event.key.as_deref()), ("get", Some("key2")));
+    Ok(())
+}
+
+#[test]
+fn client_monitor_disabled() -> Result<()> {
+    let _dir = spawn_server("127.0.0.1:4108");
+    let mut client = KvsClient::connect("127.0.0.1:4108".to_owned())?;
+    let error = client
+        .monitor("secret")
+        .err()
+        .expect("monitor should be refused");
+    assert_eq!(
+        error.kind(),
+        ErrorKind::Remote {
+            code: ErrorKind::Unsupported.code()
+        }
+    );
+    Ok(())
+}
+
+#[test]
+fn client_binary() -> Result<()> {
+    let _dir = spawn_server("127.0.0.1:4109");
+    let mut client = KvsClient::connect("127.0.0.1:4109".to_owned())?;
+    client.set_bytes(vec![0, 0xff], vec![0xfe, 0, 1])?;
+    assert_eq!(client.get_bytes(&[0, 0xff])?, Some(vec![0xfe, 0, 1]));
+    assert!(matches!(
+        client.set("key1".to_owned(), "value1".to_owned()),
+        Ok(())
+    ));
+    assert_eq!(client.get("key1")?, Some("value1".to_owned()));
+    client.set_bytes(b"key2".to_vec(), vec![0xff])?;
+    assert!(matches!(client.get("key2"), Err(KvsError::NotUtf8 { .. })));
+    client.remove_bytes(&[0, 0xff])?;
+    assert_eq!(client.get_bytes(&[0, 0xff])?, None);
+    Ok(())
+}
+
+// A connection that never sends its request shouldn't hold up other clients
+#[test]
+fn client_not_blocked_by_idle_connection() -> Result<()> {
+    let _dir = spawn_server("127.0.0.1:4120");
+    let _idle = std::net::TcpStream::connect("127.0.0.1:4120")?;
+    thread::sleep(Duration::from_millis(50));
+
+    let (sender, receiver) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        let result = KvsClient::connect("127.0.0.1:4120".to_owned()).and_then(|mut client| {
+            client.set("key1".to_owned(), "value1".to_owned())?;
+            client.get("key1")
+        });
+        sender.send(result).unwrap();
+    });
+    let value = receiver
+        .recv_timeout(Duration::from_secs(5))
+        .expect("server is stuck on the idle connection")?;
+    assert_eq!(value, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Server-side errors should reach the client with a code it can branch on
+#[test]
+fn client_error_kinds() -> Result<()> {
+    let _dir = spawn_server("127.0.0.1:4121");
+    let mut client = KvsClient::connect("127.0.0.1:4121".to_owned())?;
+    let error = client.remove("missing").expect_err("key should not exist");
+    assert!(matches!(error, KvsError::Remote { .. }));
+    assert_eq!(ErrorKind::from_code(error.code()), ErrorKind::NotFound);
+
+    let error = client
+        .scan(KeyFilter::Regex("(".to_owned()))
+        .expect_err("regex should not compile");
+    assert_eq!(ErrorKind::from_code(error.code()), ErrorKind::InvalidInput);
+    Ok(())
+}
+
+fn check_concurrent_clients(addr: &'static str) -> Result<()> {
+    let handles: Vec<_> = (0..16)
+        .map(|i| {
+            thread::spawn(move || -> Result<()> {
+                let mut client = KvsClient::connect(addr.to_owned())?;
+                for j in 0..20 {
+                    client.set(format!("key{}-{}", i, j), format!("value{}", j))?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    let mut client = KvsClient::connect(addr.to_owned())?;
+    for i in 0..16 {
+        assert_eq!(
+            client.get(&format!("key{}-19", i))?,
+            Some("value19".to_owned())
+        );
+    }
+    Ok(())
+}
+
+// Servers on a fixed-size pool should serve many clients at once against the same engine
+#[test]
+fn client_thread_pools() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    thread::spawn(move || {
+        let mut server =
+            KvsServer::new(store).with_pool(SharedQueueThreadPool::new(4).expect("no pool"));
+        server.run("127.0.0.1:4122").expect("server failed");
+    });
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    thread::spawn(move || {
+        let mut server = KvsServer::new(store).with_pool(RayonThreadPool::new(4).expect("no pool"));
+        server.run("127.0.0.1:4123").expect("server failed");
+    });
+    thread::sleep(Duration::from_millis(200));
+
+    check_concurrent_clients("127.0.0.1:4122")?;
+    check_concurrent_clients("127.0.0.1:4123")?;
+    Ok(())
+}
+
+// The tokio server should keep serving while lots of connections sit idle, streamed values included
+#[cfg(feature = "tokio")]
+#[test]
+fn client_async_server() -> Result<()> {
+    use std::io::Read;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    thread::spawn(move || {
+        let mut server = KvsServer::new(store);
+        server.run_async("127.0.0.1:4124").expect("server failed");
+    });
+    thread::sleep(Duration::from_millis(200));
+
+    let _idle = (0..64)
+        .map(|_| std::net::TcpStream::connect("127.0.0.1:4124"))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    check_concurrent_clients("127.0.0.1:4124")?;
+
+    let mut client = KvsClient::connect("127.0.0.1:4124".to_owned())?;
+    let value = vec![7u8; 100_000];
+    client.set_from_reader(b"big".to_vec(), &mut &value[..], value.len() as u64)?;
+    let mut read = vec![];
+    client
+        .get_reader(b"big")?
+        .expect("value should exist")
+        .read_to_end(&mut read)?;
+    assert_eq!(read, value);
+    assert!(matches!(
+        client.remove("missing"),
+        Err(KvsError::Remote { .. })
+    ));
+    Ok(())
+}
+
+fn check_framing(addr: &str) -> Result<()> {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{Shutdown, TcpStream};
+
+    // Several newline-terminated requests on one connection, sent before reading any response
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"\"Ping\"\n\"Ping\"\n\"Ping\"\n")?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    for _ in 0..3 {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        assert_eq!(line, "{\"Done\":null}\n");
+    }
+
+    // Old clients send one request without a newline, close their side and read until EOF
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"\"Ping\"")?;
+    stream.shutdown(Shutdown::Write)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    assert_eq!(response.trim_end(), "{\"Done\":null}");
+
+    // A client keeps using its connection across requests, streamed values included
+    let mut client = KvsClient::connect(addr.to_owned())?;
+    for i in 0..100 {
+        client.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    let value = vec![1u8; 10_000];
+    client.set_from_reader(b"big".to_vec(), &mut &value[..], value.len() as u64)?;
+    assert_eq!(client.get("key99")?, Some("value99".to_owned()));
+    Ok(())
+}
+
+// A connection should carry many requests, while one-shot clients keep working
+#[test]
+fn client_persistent_connection() -> Result<()> {
+    let _dir = spawn_server("127.0.0.1:4125");
+    check_framing("127.0.0.1:4125")?;
+    #[cfg(feature = "tokio")]
+    {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        thread::spawn(move || {
+            let mut server = KvsServer::new(store);
+            server.run_async("127.0.0.1:4126").expect("server failed");
+        });
+        thread::sleep(Duration::from_millis(200));
+        check_framing("127.0.0.1:4126")?;
+    }
+    Ok(())
+}
+
+fn check_bincode(addr: &str) -> Result<()> {
+    let mut client = KvsClient::connect(addr.to_owned())?.encoding(Encoding::Bincode);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1")?, Some("value1".to_owned()));
+    client.set_bytes(vec![0xff, 0], vec![0, 0xfe])?;
+    assert_eq!(client.get_bytes(&[0xff, 0])?, Some(vec![0, 0xfe]));
+    assert_eq!(
+        client.scan(KeyFilter::Glob("key*".to_owned()))?,
+        vec![("key1".to_owned(), "value1".to_owned())]
+    );
+    let error = client.remove("missing").expect_err("key should not exist");
+    assert_eq!(ErrorKind::from_code(error.code()), ErrorKind::NotFound);
+
+    let value = vec![3u8; 10_000];
+    client.set_from_reader(b"big".to_vec(), &mut &value[..], value.len() as u64)?;
+    assert_eq!(client.get_bytes(b"big")?, Some(value));
+
+    // JSON clients on the same server are unaffected
+    let mut client = KvsClient::connect(addr.to_owned())?;
+    assert_eq!(client.get("key1")?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// A client asking for bincode should get every request and response in bincode
+#[test]
+fn client_bincode() -> Result<()> {
+    let _dir = spawn_server("127.0.0.1:4127");
+    check_bincode("127.0.0.1:4127")?;
+    #[cfg(feature = "tokio")]
+    {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open(temp_dir.path())?;
+        thread::spawn(move || {
+            let mut server = KvsServer::new(store);
+            server.run_async("127.0.0.1:4128").expect("server failed");
+        });
+        thread::sleep(Duration::from_millis(200));
+        check_bincode("127.0.0.1:4128")?;
+    }
+    Ok(())
+}
+
+// The handshake should settle the version and encoding, and clients should still talk to servers that predate it
+#[test]
+fn client_hello() -> Result<()> {
+    use std::io::{BufRead, BufReader, Write};
+
+    let _dir = spawn_server("127.0.0.1:4129");
+    let mut client = KvsClient::connect("127.0.0.1:4129".to_owned())?.encoding(Encoding::Bincode);
+    let hello = client.hello()?;
+    assert_eq!(hello.version, PROTOCOL_VERSION);
+    assert_eq!(hello.encoding, Encoding::Bincode);
+    assert!(hello.capabilities.contains(&"bincode".to_owned()));
+    assert!(!hello.capabilities.contains(&"monitor".to_owned()));
+    client.ping()?;
+
+    // An old server hangs up on requests it can't parse and answers everything else
+    let listener = std::net::TcpListener::bind("127.0.0.1:4130")?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut line = String::new();
+            BufReader::new(stream.try_clone().unwrap())
+                .read_line(&mut line)
+                .unwrap();
+            if !line.contains("Hello") {
+                stream.write_all(b"{\"Done\":null}").unwrap();
+            }
+        }
+    });
+    let mut client = KvsClient::connect("127.0.0.1:4130".to_owned())?.encoding(Encoding::Bincode);
+    client.ping()?;
+    assert_eq!(
+        client.hello()?,
+        Hello {
+            version: 0,
+            encoding: Encoding::Json,
+            capabilities: vec![],
+        }
+    );
+    Ok(())
+}
+
+// redis-cli style clients should be able to GET, SET, DEL and check keys over RESP
+#[test]
+fn resp_protocol() -> Result<()> {
+    use std::io::{Read, Write};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    thread::spawn(move || {
+        let mut server = KvsServer::new(store).protocol(Protocol::Resp);
+        server.run("127.0.0.1:4131").expect("server failed");
+    });
+    thread::sleep(Duration::from_millis(200));
+
+    let mut stream = std::net::TcpStream::connect("127.0.0.1:4131")?;
+    stream.write_all(
+        b"*1\r\n$4\r\nPING\r\n\
+          *3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$6\r\nvalue1\r\n\
+          *2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n\
+          *2\r\n$3\r\nGET\r\n$7\r\nmissing\r\n\
+          EXISTS key1 missing\r\n\
+          *3\r\n$3\r\nDEL\r\n$4\r\nkey1\r\n$7\r\nmissing\r\n\
+          *1\r\n$3\r\nGET\r\n\
+          FLUSHALL\r\n\
+          *5\r\n$3\r\nSET\r\n$4\r\nkey2\r\n$1\r\nv\r\n$2\r\nEX\r\n$1\r\n0\r\n\
+          QUIT\r\n",
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    assert_eq!(
+        response,
+        "+PONG\r\n\
+         +OK\r\n\
+         $6\r\nvalue1\r\n\
+         $-1\r\n\
+         :1\r\n\
+         :1\r\n\
+         -ERR wrong number of arguments for 'get' command\r\n\
+         -ERR unknown command 'FLUSHALL'\r\n\
+         -ERR invalid expire time in 'set' command\r\n\
+         +OK\r\n"
+    );
+    Ok(())
+}
+
+// Servers listening on a Unix socket should serve the same requests, and replace a stale socket file
+#[cfg(unix)]
+#[test]
+fn client_unix_socket() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("kvs.sock");
+    drop(std::os::unix::net::UnixListener::bind(&path)?); // left behind by a server that died
+    let store = KvStore::open(temp_dir.path())?;
+    let socket = path.clone();
+    thread::spawn(move || {
+        let mut server = KvsServer::new(store).monitored(MonitorConfig {
+            token: "secret".to_owned(),
+            events_per_sec: 1000.0,
+        });
+        server.run_unix(socket).expect("server failed");
+    });
+    thread::sleep(Duration::from_millis(200));
+
+    let addr = format!("unix:{}", path.display());
+    check_bincode(&addr)?;
+    let mut client = KvsClient::connect(addr.clone())?;
+    assert_eq!(client.hello()?.version, PROTOCOL_VERSION);
+    assert_eq!(client.get("key1")?, Some("value1".to_owned()));
+
+    let mut monitor = KvsClient::connect(addr.clone())?.monitor("secret")?;
+    KvsClient::connect(addr)?.set("key2".to_owned(), "value2".to_owned())?;
+    let event = monitor.next().expect("monitor closed")?;
+    assert_eq!(event.op, "set");
+    assert_eq!(event.key, Some("key2".to_owned()));
+    Ok(())
+}
+
+// TLS clients should reach the server only when they trust its certificate
+#[cfg(feature = "tls")]
+#[test]
+fn client_tls() -> Result<()> {
+    use kvs::TlsOptions;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let certified = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()])
+        .expect("unable to generate certificate");
+    let cert = temp_dir.path().join("cert.pem");
+    let key = temp_dir.path().join("key.pem");
+    std::fs::write(&cert, certified.cert.pem())?;
+    std::fs::write(&key, certified.key_pair.serialize_pem())?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    let server = KvsServer::new(store).tls(&cert, &key)?;
+    thread::spawn(move || {
+        let mut server = server;
+        server.run("127.0.0.1:4132").expect("server failed");
+    });
+    thread::sleep(Duration::from_millis(200));
+
+    let trusted = TlsOptions {
+        ca_file: Some(cert.clone()),
+        server_name: Some("localhost".to_owned()),
+        insecure: false,
+    };
+    let mut client = KvsClient::connect("127.0.0.1:4132".to_owned())?.tls(trusted.clone())?;
+    assert_eq!(client.hello()?.version, PROTOCOL_VERSION);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1")?, Some("value1".to_owned()));
+    let value = vec![5u8; 100_000];
+    client.set_from_reader(b"big".to_vec(), &mut &value[..], value.len() as u64)?;
+    assert_eq!(client.get_bytes(b"big")?, Some(value));
+
+    let mut client = KvsClient::connect("127.0.0.1:4132".to_owned())?
+        .encoding(Encoding::Bincode)
+        .tls(trusted.clone())?;
+    assert_eq!(client.get("key1")?, Some("value1".to_owned()));
+
+    // Skipping verification still encrypts
+    let mut client = KvsClient::connect("127.0.0.1:4132".to_owned())?.tls(TlsOptions {
+        insecure: true,
+        ..TlsOptions::default()
+    })?;
+    assert_eq!(client.get("key1")?, Some("value1".to_owned()));
+
+    // The certificate doesn't name 127.0.0.1
+    let mut client = KvsClient::connect("127.0.0.1:4132".to_owned())?.tls(TlsOptions {
+        server_name: None,
+        ..trusted
+    })?;
+    client
+        .get("key1")
+        .expect_err("certificate should not match");
+
+    // A self-signed certificate isn't trusted by default
+    let mut client = KvsClient::connect("127.0.0.1:4132".to_owned())?.tls(TlsOptions {
+        server_name: Some("localhost".to_owned()),
+        ..TlsOptions::default()
+    })?;
+    client
+        .get("key1")
+        .expect_err("certificate should not be trusted");
+
+    // Plaintext clients get nothing
+    let mut client = KvsClient::connect("127.0.0.1:4132".to_owned())?;
+    client
+        .get("key1")
+        .expect_err("server should only speak TLS");
+    Ok(())
+}
+
+// Servers that require authentication should only answer HELLO and PING until the client logs in
+#[test]
+fn client_auth() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let accounts_file = temp_dir.path().join("users");
+    std::fs::write(
+        &accounts_file,
+        "# user:password\nalice:open:sesame\n\nbob:hunter2\n",
+    )?;
+    let accounts = Accounts::load(&accounts_file)?;
+    let store = KvStore::open(temp_dir.path())?;
+    thread::spawn(move || {
+        let mut server = KvsServer::new(store)
+            .authenticated(accounts)
+            .monitored(MonitorConfig {
+                token: "secret".to_owned(),
+                events_per_sec: 1000.0,
+            });
+        server.run("127.0.0.1:4133").expect("server failed");
+    });
+    thread::sleep(Duration::from_millis(200));
+
+    let mut anonymous = KvsClient::connect("127.0.0.1:4133".to_owned())?;
+    assert!(anonymous.hello()?.capabilities.contains(&"auth".to_owned()));
+    anonymous.ping()?;
+    let error = anonymous.get("key1").expect_err("login should be required");
+    assert_eq!(
+        ErrorKind::from_code(error.code()),
+        ErrorKind::PermissionDenied
+    );
+    let value = vec![0u8; 10_000];
+    anonymous
+        .set_from_reader(b"big".to_vec(), &mut &value[..], value.len() as u64)
+        .expect_err("login should be required");
+    assert!(anonymous.monitor("secret").is_err());
+
+    let mut client = KvsClient::connect("127.0.0.1:4133".to_owned())?
+        .credentials(Credentials::new("alice", "open:sesame"));
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1")?, Some("value1".to_owned()));
+
+    let mut client = KvsClient::connect("127.0.0.1:4133".to_owned())?
+        .encoding(Encoding::Bincode)
+        .credentials(Credentials::new("bob", "hunter2"));
+    let mut monitor = client.monitor("secret")?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(
+        monitor.next().expect("monitor closed")?.key,
+        Some("key2".to_owned())
+    );
+
+    let mut client = KvsClient::connect("127.0.0.1:4133".to_owned())?
+        .credentials(Credentials::new("bob", "open:sesame"));
+    let error = client.get("key1").expect_err("password should be wrong");
+    assert_eq!(
+        ErrorKind::from_code(error.code()),
+        ErrorKind::PermissionDenied
+    );
+    Ok(())
+}
+
+// RESP clients should AUTH like they do against Redis
+#[test]
+fn resp_auth() -> Result<()> {
+    use std::io::{Read, Write};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    thread::spawn(move || {
+        let mut server = KvsServer::new(store)
+            .protocol(Protocol::Resp)
+            .authenticated(Accounts::with_password("hunter2"));
+        server.run("127.0.0.1:4134").expect("server failed");
+    });
+    thread::sleep(Duration::from_millis(200));
+
+    let mut stream = std::net::TcpStream::connect("127.0.0.1:4134")?;
+    stream.write_all(
+        b"PING\r\n\
+          GET key1\r\n\
+          AUTH wrong\r\n\
+          AUTH default wrong\r\n\
+          AUTH hunter2\r\n\
+          SET key1 value1\r\n\
+          AUTH wrong\r\n\
+          GET key1\r\n\
+          QUIT\r\n",
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    assert_eq!(
+        response,
+        "+PONG\r\n\
+         -NOAUTH Authentication required.\r\n\
+         -WRONGPASS invalid username-password pair or user is disabled.\r\n\
+         -WRONGPASS invalid username-password pair or user is disabled.\r\n\
+         +OK\r\n\
+         +OK\r\n\
+         -WRONGPASS invalid username-password pair or user is disabled.\r\n\
+         $6\r\nvalue1\r\n\
+         +OK\r\n"
+    );
+    Ok(())
+}
+
+fn check_denied<T: std::fmt::Debug>(result: Result<T>) {
+    let error = result.expect_err("request should be denied");
+    assert_eq!(
+        ErrorKind::from_code(error.code()),
+        ErrorKind::PermissionDenied
+    );
+}
+
+// Users should only touch keys under the prefixes their ACL grants, longest prefix first
+#[test]
+fn client_acl() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let acl_file = temp_dir.path().join("acl");
+    std::fs::write(
+        &acl_file,
+        "alice config:* read\nalice app1:* readwrite\nalice app1:secret:* none\n",
+    )?;
+    let mut acl = Acl::load(&acl_file)?;
+    acl.allow("admin", "*", Access::ReadWrite);
+    let mut accounts = Accounts::new();
+    accounts.add(Credentials::new("alice", "alice"));
+    accounts.add(Credentials::new("admin", "admin"));
+    accounts.add(Credentials::new("mallory", "mallory"));
+    let store = KvStore::open(temp_dir.path())?;
+    thread::spawn(move || {
+        let mut server = KvsServer::new(store).authenticated(accounts).acl(acl);
+        server.run("127.0.0.1:4135").expect("server failed");
+    });
+    thread::sleep(Duration::from_millis(200));
+
+    let mut admin = KvsClient::connect("127.0.0.1:4135".to_owned())?
+        .credentials(Credentials::new("admin", "admin"));
+    for key in &["config:a", "app1:x", "app1:secret:s", "other"] {
+        admin.set(key.to_string(), "value".to_owned())?;
+    }
+
+    let mut alice = KvsClient::connect("127.0.0.1:4135".to_owned())?
+        .credentials(Credentials::new("alice", "alice"));
+    assert_eq!(alice.get("config:a")?, Some("value".to_owned()));
+    check_denied(alice.set("config:a".to_owned(), "changed".to_owned()));
+    alice.set("app1:y".to_owned(), "value".to_owned())?;
+    check_denied(alice.get("app1:secret:s"));
+    check_denied(alice.get("other"));
+    check_denied(alice.multi_get(&["config:a", "other"]));
+    check_denied(alice.rename("app1:x", "config:b"));
+    alice.copy("config:a", "app1:c")?;
+    check_denied(alice.remove_matching(KeyFilter::All));
+    let mut keys: Vec<String> = alice
+        .scan(KeyFilter::All)?
+        .into_iter()
+        .map(|(key, _)| key)
+        .collect();
+    keys.sort();
+    assert_eq!(keys, vec!["app1:c", "app1:x", "app1:y", "config:a"]);
+    let value = vec![0u8; 10_000];
+    check_denied(alice.set_from_reader(b"other".to_vec(), &mut &value[..], value.len() as u64));
+    assert_eq!(alice.get("app1:y")?, Some("value".to_owned()));
+
+    // Users without rules can log in but touch nothing
+    let mut mallory = KvsClient::connect("127.0.0.1:4135".to_owned())?
+        .credentials(Credentials::new("mallory", "mallory"));
+    mallory.ping()?;
+    check_denied(mallory.get("config:a"));
+    assert_eq!(mallory.scan(KeyFilter::All)?, vec![]);
+
+    assert_eq!(admin.get("config:a")?, Some("value".to_owned()));
+    assert_eq!(
+        admin.remove_matching(KeyFilter::Glob("app1:*".to_owned()))?,
+        4
+    );
+    Ok(())
+}
+
+// RESP clients should get NOPERM for keys outside their ACL
+#[test]
+fn resp_acl() -> Result<()> {
+    use std::io::{Read, Write};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let mut acl = Acl::new();
+    acl.allow(kvs::DEFAULT_USER, "config:", Access::Read);
+    acl.allow(kvs::DEFAULT_USER, "app1:", Access::ReadWrite);
+    thread::spawn(move || {
+        let mut server = KvsServer::new(store)
+            .protocol(Protocol::Resp)
+            .authenticated(Accounts::with_password("hunter2"))
+            .acl(acl);
+        server.run("127.0.0.1:4136").expect("server failed");
+    });
+    thread::sleep(Duration::from_millis(200));
+
+    let mut stream = std::net::TcpStream::connect("127.0.0.1:4136")?;
+    stream.write_all(
+        b"AUTH hunter2\r\n\
+          SET app1:x 1\r\n\
+          SET config:x 1\r\n\
+          GET config:x\r\n\
+          EXISTS app1:x other\r\n\
+          DEL app1:x\r\n\
+          QUIT\r\n",
+    )?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let noperm =
+        "-NOPERM this user has no permissions to access one of the keys used as arguments\r\n";
+    assert_eq!(
+        response,
+        format!("+OK\r\n+OK\r\n{}$-1\r\n{}:1\r\n+OK\r\n", noperm, noperm)
+    );
+    Ok(())
+}
+
+fn check_shutdown<F>(addr: &'static str, run: F) -> Result<()>
+where
+    F: FnOnce(&mut KvsServer<KvStore, SharedQueueThreadPool>) -> Result<()> + Send + 'static,
+{
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let mut server = KvsServer::new(store).with_pool(SharedQueueThreadPool::new(4)?);
+    let handle = server.shutdown_handle();
+    let server = thread::spawn(move || run(&mut server));
+    thread::sleep(Duration::from_millis(200));
+
+    let mut client = KvsClient::connect(addr.to_owned())?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    let _idle = std::net::TcpStream::connect(addr)?;
+
+    handle.shutdown();
+    handle.shutdown();
+    assert!(handle.is_shutting_down());
+    server.join().expect("server panicked")?;
+    assert!(client.get("key").is_err());
+    assert!(std::net::TcpStream::connect(addr).is_err());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key")?, Some("value".to_owned()));
+    Ok(())
+}
+
+// run should return once shut down, after closing idle connections and flushing the engine
+#[test]
+fn server_shutdown() -> Result<()> {
+    check_shutdown("127.0.0.1:4137", |server| server.run("127.0.0.1:4137"))
+}
+
+// run_async should shut down the same way
+#[cfg(feature = "tokio")]
+#[test]
+fn server_shutdown_async() -> Result<()> {
+    check_shutdown("127.0.0.1:4138", |server| {
+        server.run_async("127.0.0.1:4138")
+    })
+}
+
+// Reloading should swap accounts and ACLs without dropping connections, and only admins may ask for it
+#[test]
+fn server_reload() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let mut accounts = Accounts::new();
+    accounts.add(Credentials::new("admin", "root"));
+    accounts.add(Credentials::new("alice", "old"));
+    let mut acl = Acl::new();
+    acl.allow("admin", "*", Access::ReadWrite);
+    acl.allow("alice", "app1:", Access::Read);
+    let server = KvsServer::new(store)
+        .authenticated(accounts)
+        .acl(acl.clone())
+        .on_reload(move |config| {
+            let mut accounts = Accounts::new();
+            accounts.add(Credentials::new("admin", "root"));
+            accounts.add(Credentials::new("alice", "new"));
+            config.set_accounts(accounts);
+            let mut acl = acl.clone();
+            acl.allow("alice", "app1:", Access::ReadWrite);
+            config.set_acl(acl);
+            Ok(())
+        });
+    let handle = server.reload_handle();
+    thread::spawn(move || {
+        let mut server = server;
+        server.run("127.0.0.1:4139").expect("server failed");
+    });
+    thread::sleep(Duration::from_millis(200));
+
+    let mut alice = KvsClient::connect("127.0.0.1:4139".to_owned())?
+        .credentials(Credentials::new("alice", "old"));
+    check_denied(alice.set("app1:x".to_owned(), "1".to_owned()));
+    check_denied(alice.reload());
+
+    let mut admin = KvsClient::connect("127.0.0.1:4139".to_owned())?
+        .credentials(Credentials::new("admin", "root"));
+    assert!(admin.hello()?.capabilities.contains(&"reload".to_owned()));
+    admin.reload()?;
+    alice.set("app1:x".to_owned(), "1".to_owned())?; // still logged in on the same connection
+
+    let mut stale = KvsClient::connect("127.0.0.1:4139".to_owned())?
+        .credentials(Credentials::new("alice", "old"));
+    check_denied(stale.get("app1:x"));
+    let mut fresh = KvsClient::connect("127.0.0.1:4139".to_owned())?
+        .credentials(Credentials::new("alice", "new"));
+    assert_eq!(fresh.get("app1:x")?, Some("1".to_owned()));
+
+    handle.set_acl(Acl::new());
+    check_denied(admin.get("app1:x"));
+
+    // The request size limit applies from the next request, size limits need a SizeLimitedEngine
+    handle.set_max_request(64);
+    assert!(fresh.set("app1:y".to_owned(), "x".repeat(100)).is_err());
+    assert!(matches!(
+        handle.set_limits(SizeLimits::default()),
+        Err(KvsError::InvalidArgument { .. })
+    ));
+    Ok(())
+}
+
+// Servers without a reloader should refuse reload requests
+#[test]
+fn server_reload_unsupported() -> Result<()> {
+    let _dir = spawn_server("127.0.0.1:4140");
+    let mut client = KvsClient::connect("127.0.0.1:4140".to_owned())?;
+    let error = client.reload().expect_err("reload should be unsupported");
+    assert_eq!(ErrorKind::from_code(error.code()), ErrorKind::Unsupported);
+    Ok(())
+}
+
+// Clients beyond the connection limit should wait until an open connection closes
+#[test]
+fn server_max_connections() -> Result<()> {
+    use std::sync::mpsc;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    thread::spawn(move || {
+        let mut server = KvsServer::new(store).max_connections(1);
+        server.run("127.0.0.1:4141").expect("server failed");
+    });
+    thread::sleep(Duration::from_millis(200));
+
+    let mut first = KvsClient::connect("127.0.0.1:4141".to_owned())?;
+    first.set("key".to_owned(), "value".to_owned())?;
+
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let result = KvsClient::connect("127.0.0.1:4141".to_owned()).and_then(|mut v| v.get("key"));
+        sender.send(result).unwrap();
+    });
+    assert!(receiver.recv_timeout(Duration::from_millis(300)).is_err()); // still queued
+
+    first.ping()?;
+    drop(first);
+    let value = receiver
+        .recv_timeout(Duration::from_secs(5))
+        .expect("queued client should be served")?;
+    assert_eq!(value, Some("value".to_owned()));
+    Ok(())
+}
+
+fn check_timeouts<F>(addr: &'static str, run: F) -> Result<()>
+where
+    F: FnOnce(&mut KvsServer<KvStore>) -> Result<()> + Send + 'static,
+{
+    use std::io::{Read, Write};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    thread::spawn(move || {
+        let mut server = KvsServer::new(store).timeouts(Timeouts {
+            idle: Some(Duration::from_millis(200)),
+            read: Some(Duration::from_millis(100)),
+            write: Some(Duration::from_secs(1)),
+        });
+        run(&mut server).expect("server failed");
+    });
+    thread::sleep(Duration::from_millis(200));
+
+    let begin = Instant::now();
+    let mut idle = std::net::TcpStream::connect(addr)?;
+    let mut half = std::net::TcpStream::connect(addr)?;
+    half.write_all(b"{\"Get\":")?;
+    let mut buf = vec![];
+    assert_eq!(idle.read_to_end(&mut buf)?, 0);
+    let _ = half.read_to_end(&mut buf); // closed, maybe with a reset since the request was never read
+    assert!(buf.is_empty());
+    assert!(begin.elapsed() < Duration::from_secs(5));
+
+    let mut client = KvsClient::connect(addr.to_owned())?;
+    client.set("key".to_owned(), "value".to_owned())?;
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(client.get("key")?, Some("value".to_owned())); // reconnects after being dropped
+    Ok(())
+}
+
+// Idle connections and half-sent requests should be dropped once they time out
+#[test]
+fn server_timeouts() -> Result<()> {
+    check_timeouts("127.0.0.1:4142", |server| server.run("127.0.0.1:4142"))
+}
+
+// The tokio server should time out connections the same way
+#[cfg(feature = "tokio")]
+#[test]
+fn server_timeouts_async() -> Result<()> {
+    check_timeouts("127.0.0.1:4143", |server| {
+        server.run_async("127.0.0.1:4143")
+    })
+}
+
+fn check_request_too_large(addr: &'static str) -> Result<()> {
+    use std::io::{Read, Write};
+
+    let mut client = KvsClient::connect(addr.to_owned())?;
+    client.set("key".to_owned(), "x".repeat(100))?;
+    let error = client
+        .set("key".to_owned(), "x".repeat(10_000))
+        .expect_err("request should be too large");
+    assert_eq!(ErrorKind::from_code(error.code()), ErrorKind::LimitExceeded);
+    assert_eq!(client.get("key")?.map(|v| v.len()), Some(100)); // reconnects
+
+    let mut client = KvsClient::connect(addr.to_owned())?.encoding(Encoding::Bincode);
+    let error = client
+        .set("key".to_owned(), "x".repeat(10_000))
+        .expect_err("request should be too large");
+    assert_eq!(ErrorKind::from_code(error.code()), ErrorKind::LimitExceeded);
+
+    // a line that never ends shouldn't be buffered forever
+    let mut stream = std::net::TcpStream::connect(addr)?;
+    stream.write_all(&[b'x'; 2000])?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    assert!(response.contains("RequestTooLarge"));
+    Ok(())
+}
+
+// Requests larger than the limit should get a LimitExceeded error instead of being buffered
+#[test]
+fn server_max_request() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let async_store = store.clone();
+    thread::spawn(move || {
+        let mut server = KvsServer::new(store).max_request(1000);
+        server.run("127.0.0.1:4144").expect("server failed");
+    });
+    #[cfg(feature = "tokio")]
+    thread::spawn(move || {
+        let mut server = KvsServer::new(async_store).max_request(1000);
+        server.run_async("127.0.0.1:4145").expect("server failed");
+    });
+    #[cfg(not(feature = "tokio"))]
+    drop(async_store);
+    thread::sleep(Duration::from_millis(200));
+
+    check_request_too_large("127.0.0.1:4144")?;
+    #[cfg(feature = "tokio")]
+    check_request_too_large("127.0.0.1:4145")?;
+    Ok(())
+}
+
+// RESP commands should reach monitor subscribers once they ran, and RESP clients can subscribe too
+#[test]
+fn resp_monitor() -> Result<()> {
+    use std::io::{BufRead, BufReader, Write};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?.size_limited(SizeLimits {
+        max_key: None,
+        max_value: Some(8),
+    });
+    thread::spawn(move || {
+        let mut server = KvsServer::new(store)
+            .protocol(Protocol::Resp)
+            .monitored(MonitorConfig {
+                token: "secret".to_owned(),
+                events_per_sec: 100.0,
+            });
+        server.run("127.0.0.1:4152").expect("server failed");
+    });
+    thread::sleep(Duration::from_millis(200));
+
+    let mut monitor = std::net::TcpStream::connect("127.0.0.1:4152")?;
+    monitor.write_all(b"MONITOR wrong\r\nMONITOR secret\r\n")?;
+    let mut monitor = BufReader::new(monitor);
+    let mut line = String::new();
+    monitor.read_line(&mut line)?;
+    assert!(line.starts_with("-NOPERM"));
+    line.clear();
+    monitor.read_line(&mut line)?;
+    assert_eq!(line, "+OK\r\n");
+
+    let mut client = std::net::TcpStream::connect("127.0.0.1:4152")?;
+    client.write_all(b"SET big toolargevalue\r\nSET key1 value1\r\nGET key2\r\n")?;
+    let mut events = vec![];
+    for _ in 0..2 {
+        line.clear();
+        monitor.read_line(&mut line)?;
+        let event: MonitorEvent = serde_json::from_str(&line)?;
+        events.push((event.op, event.key));
+    }
+    assert_eq!(
+        events,
+        vec![
+            ("set".to_owned(), Some("key1".to_owned())),
+            ("get".to_owned(), Some("key2".to_owned()))
+        ]
+    );
+    Ok(())
+}
+
+// RESP clients should get a protocol error for oversized commands
+#[test]
+fn resp_max_request() -> Result<()> {
+    use std::io::{Read, Write};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    thread::spawn(move || {
+        let mut server = KvsServer::new(store)
+            .protocol(Protocol::Resp)
+            .max_request(1000);
+        server.run("127.0.0.1:4146").expect("server failed");
+    });
+    thread::sleep(Duration::from_millis(200));
+
+    let mut stream = std::net::TcpStream::connect("127.0.0.1:4146")?;
+    stream.write_all(b"SET small value\r\n")?;
+    stream.write_all(
+        format!(
+            "*3\r\n$3\r\nSET\r\n$3\r\nbig\r\n$2000\r\n{}\r\n",
+            "x".repeat(2000)
+        )
+        .as_bytes(),
+    )?;
+    let mut response = String::new();
+    let _ = stream.read_to_string(&mut response);
+    assert_eq!(
+        response,
+        "+OK\r\n-ERR Protocol error: request too large\r\n"
+    );
+
+    // A bulk header alone is enough, the server shouldn't wait for 512 MiB that never comes
+    let mut stream = std::net::TcpStream::connect("127.0.0.1:4146")?;
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    stream.write_all(b"*3\r\n$3\r\nSET\r\n$3\r\nbig\r\n$536870912\r\n")?;
+    let mut response = String::new();
+    let _ = stream.read_to_string(&mut response);
+    assert_eq!(response, "-ERR Protocol error: request too large\r\n");
+    Ok(())
+}
+
+fn read_audit(path: &std::path::Path) -> Result<Vec<AuditEntry>> {
+    let text = std::fs::read_to_string(path)?;
+    text.lines()
+        .map(|line| serde_json::from_str(line).map_err(KvsError::from))
+        .collect()
+}
+
+// Writes that changed something should be audited with their user and key, reads and writes that failed or found nothing to change should not
+#[test]
+fn server_audit() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let audit_log = temp_dir.path().join("audit.log");
+    let store = KvStore::open(temp_dir.path())?;
+    let config = AuditConfig {
+        path: audit_log.clone(),
+        values: true,
+        max_bytes: None,
+        keep: 0,
+    };
+    let server = KvsServer::new(store)
+        .authenticated(Accounts::with_password("secret"))
+        .audited(config)?;
+    thread::spawn(move || {
+        let mut server = server;
+        server.run("127.0.0.1:4147").expect("server failed");
+    });
+    thread::sleep(Duration::from_millis(200));
+
+    let mut client = KvsClient::connect("127.0.0.1:4147".to_owned())?
+        .credentials(Credentials::new(DEFAULT_USER, "secret"));
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1")?, Some("value1".to_owned()));
+    client.remove("key1")?;
+    assert!(client.remove("key1").is_err());
+    assert_eq!(client.getdel("key1")?, None);
+    assert!(client.set_nx("key2".to_owned(), "value2".to_owned())?);
+    assert!(!client.set_nx("key2".to_owned(), "value3".to_owned())?);
+    assert_eq!(
+        client.get_or_insert("key2".to_owned(), "value4".to_owned())?,
+        "value2"
+    );
+    assert_eq!(client.getdel("key2")?, Some("value2".to_owned()));
+
+    let entries = read_audit(&audit_log)?;
+    let ops: Vec<_> = entries
+        .iter()
+        .map(|v| (&v.op[..], v.key.as_deref(), v.value.as_deref()))
+        .collect();
+    assert_eq!(
+        ops,
+        vec![
+            ("set", Some("key1"), Some("value1")),
+            ("remove", Some("key1"), None),
+            ("set_nx", Some("key2"), Some("value2")),
+            ("getdel", Some("key2"), None),
+        ]
+    );
+    assert!(entries
+        .iter()
+        .all(|v| v.user.as_deref() == Some(DEFAULT_USER) && v.peer.starts_with("127.0.0.1:")));
+    Ok(())
+}
+
+// The audit log should rotate to .1, .2, ... once it gets too big and drop the oldest
+#[test]
+fn server_audit_rotation() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let audit_log = temp_dir.path().join("audit.log");
+    let rotated = |i: usize| temp_dir.path().join(format!("audit.log.{}", i));
+    let store = KvStore::open(temp_dir.path())?;
+    let config = AuditConfig {
+        path: audit_log.clone(),
+        values: false,
+        max_bytes: Some(250),
+        keep: 2,
+    };
+    let server = KvsServer::new(store).audited(config)?;
+    thread::spawn(move || {
+        let mut server = server;
+        server.run("127.0.0.1:4148").expect("server failed");
+    });
+    thread::sleep(Duration::from_millis(200));
+
+    let mut client = KvsClient::connect("127.0.0.1:4148".to_owned())?;
+    for i in 0..20 {
+        client.set(format!("key{}", i), "value".to_owned())?;
+    }
+    assert!(rotated(1).exists());
+    assert!(rotated(2).exists());
+    assert!(!rotated(3).exists());
+    let newest = read_audit(&audit_log)?;
+    assert_eq!(newest.last().unwrap().key, Some("key19".to_owned()));
+    assert!(newest.iter().all(|v| v.value.is_none()));
+    let previous = read_audit(&rotated(1))?;
+    let next = previous.last().unwrap().key.as_deref().unwrap()[3..]
+        .parse::<usize>()
+        .unwrap()
+        + 1;
+    assert_eq!(newest[0].key, Some(format!("key{}", next)));
+    for path in [audit_log, rotated(1), rotated(2)] {
+        assert!(std::fs::metadata(path)?.len() <= 250);
+    }
+    Ok(())
+}
+
+// SET and DEL over RESP should be audited too, DEL only for the keys it removed
+#[test]
+fn resp_audit() -> Result<()> {
+    use std::io::{BufRead, BufReader, Write};
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let audit_log = temp_dir.path().join("audit.log");
+    let store = KvStore::open(temp_dir.path())?;
+    let config = AuditConfig {
+        path: audit_log.clone(),
+        values: false,
+        max_bytes: None,
+        keep: 0,
+    };
+    let server = KvsServer::new(store)
+        .protocol(Protocol::Resp)
+        .audited(config)?;
+    thread::spawn(move || {
+        let mut server = server;
+        server.run("127.0.0.1:4149").expect("server failed");
+    });
+    thread::sleep(Duration::from_millis(200));
+
+    let mut stream = std::net::TcpStream::connect("127.0.0.1:4149")?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = String::new();
+    for command in [
+        "SET key1 value1",
+        "EXISTS key1",
+        "SET key2",
+        "DEL key1 missing",
+        "DEL key1",
+    ] {
+        stream.write_all(format!("{}\r\n", command).as_bytes())?;
+        line.clear();
+        reader.read_line(&mut line)?;
+    }
+
+    let entries = read_audit(&audit_log)?;
+    let ops: Vec<_> = entries
+        .iter()
+        .map(|v| (&v.op[..], v.key.as_deref(), v.value.as_deref()))
+        .collect();
+    assert_eq!(
+        ops,
+        vec![("set", Some("key1"), None), ("remove", Some("key1"), None)]
+    );
+    Ok(())
+}
+
+// STATS should report the server version, engine, keys and open connections
+#[test]
+fn server_stats() -> Result<()> {
+    let _dir = spawn_server("127.0.0.1:4150");
+    let mut client = KvsClient::connect("127.0.0.1:4150".to_owned())?;
+    assert!(client.hello()?.capabilities.contains(&"stats".to_owned()));
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    client.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(client.get("key1")?, Some("value1".to_owned()));
+
+    let mut other = KvsClient::connect("127.0.0.1:4150".to_owned())?;
+    other.ping()?;
+    let stats = client.stats()?;
+    assert_eq!(stats.version, env!("CARGO_PKG_VERSION"));
+    assert_eq!(stats.engine, "kvs");
+    assert_eq!(stats.keys, 2);
+    assert!(stats.disk_bytes > 0);
+    assert!((0.0..=1.0).contains(&stats.cache_hit_rate));
+    assert_eq!(stats.connections, 2);
+    Ok(())
+}